@@ -0,0 +1,312 @@
+use core::ops::Range;
+
+use embedded_hal::{delay, spi};
+use embedded_storage::nor_flash::{self, MultiwriteNorFlash, NorFlash, ReadNorFlash};
+
+use crate::{io::W25QError, BLOCK_SIZE_32, BLOCK_SIZE_64, PAGE_SIZE, SECTOR_SIZE, W25Q};
+
+/// how the address space of a [`FlashArray`] maps onto its chips.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// chip 0 holds the first `capacity` bytes, chip 1 the next, and so on.
+    Concatenated,
+    /// consecutive `stripe` byte units rotate over the chips. `stripe` is a multiple of the
+    /// sector size.
+    Striped { stripe: u32 },
+}
+
+/// operation started on a chip and not waited for yet, with what to verify.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pending {
+    Idle,
+    /// `len` bytes at `offset` on the chip, from `at` in the data being programmed
+    Program {
+        offset: u32,
+        at: usize,
+        len: usize,
+    },
+    Erase {
+        offset: u32,
+        len: usize,
+    },
+    ChipErase,
+}
+
+/// `N` equally sized [`W25Q`] chips presented as one linear device.
+///
+/// programs and erases are started on a chip and only waited for when that chip is needed
+/// again, so operations spanning several chips run in parallel. chips configured to verify
+/// after write are checked when they are waited for; `VerifyFailed` carries the array
+/// address.
+pub struct FlashArray<SPI, DELAY, const N: usize>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    chips: [W25Q<SPI, DELAY>; N],
+    layout: Layout,
+    /// capacity of one chip
+    chip_size: u32,
+    pending: [Pending; N],
+}
+
+impl<SPI, DELAY, const N: usize> FlashArray<SPI, DELAY, N>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    /// combine `chips`. all chips must have the same capacity.
+    pub fn new(chips: [W25Q<SPI, DELAY>; N], layout: Layout) -> Result<Self, W25QError> {
+        let chip_size = match chips.first() {
+            Some(chip) => chip.capacity() as u32,
+            None => return Err(W25QError::InvalidConfig),
+        };
+        if chips.iter().any(|chip| chip.capacity() != chip_size as u64) {
+            return Err(W25QError::InvalidConfig);
+        }
+        if let Layout::Striped { stripe } = layout {
+            if stripe == 0
+                || !(stripe as usize).is_multiple_of(SECTOR_SIZE)
+                || !chip_size.is_multiple_of(stripe)
+            {
+                return Err(W25QError::InvalidConfig);
+            }
+        }
+        Ok(Self {
+            chips,
+            layout,
+            chip_size,
+            pending: [Pending::Idle; N],
+        })
+    }
+
+    pub fn into_inner(self) -> [W25Q<SPI, DELAY>; N] {
+        self.chips
+    }
+
+    pub fn chip_mut(&mut self, index: usize) -> &mut W25Q<SPI, DELAY> {
+        &mut self.chips[index]
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.chip_size as u64 * N as u64
+    }
+
+    /// chip index, address on that chip and bytes left until the chip or stripe ends.
+    fn locate(&self, address: u32) -> (usize, u32, u32) {
+        match self.layout {
+            Layout::Concatenated => {
+                let chip = (address / self.chip_size) as usize;
+                let offset = address % self.chip_size;
+                (chip, offset, self.chip_size - offset)
+            }
+            Layout::Striped { stripe } => {
+                let unit = address / stripe;
+                let offset = address % stripe;
+                let chip = (unit % N as u32) as usize;
+                (chip, (unit / N as u32) * stripe + offset, stripe - offset)
+            }
+        }
+    }
+
+    /// array address of `offset` on `chip`, the inverse of [`FlashArray::locate`].
+    fn array_address(&self, chip: usize, offset: u32) -> u32 {
+        match self.layout {
+            Layout::Concatenated => chip as u32 * self.chip_size + offset,
+            Layout::Striped { stripe } => {
+                let unit = (offset / stripe) * N as u32 + chip as u32;
+                unit * stripe + offset % stripe
+            }
+        }
+    }
+
+    fn check(&self, address: u32, len: usize) -> Result<(), W25QError> {
+        if address as u64 + len as u64 > self.capacity() {
+            return Err(W25QError::OutOfBounds);
+        }
+        Ok(())
+    }
+
+    /// wait for whatever was started on `chip` and verify it if the chip is configured to.
+    /// `data` is what [`FlashArray::program`] is programming, empty otherwise.
+    fn wait(&mut self, chip: usize, data: &[u8]) -> Result<(), W25QError> {
+        let pending = self.pending[chip];
+        self.pending[chip] = Pending::Idle;
+        let dev = &mut self.chips[chip];
+        let result = match pending {
+            Pending::Idle => Ok(()),
+            Pending::Program { offset, at, len } => dev
+                .wait_program()
+                .and_then(|()| dev.verify_programmed(offset, &data[at..at + len])),
+            Pending::Erase { offset, len } => dev
+                .wait_erase()
+                .and_then(|()| dev.verify_erased(offset, len)),
+            Pending::ChipErase => dev
+                .wait_chip_erase()
+                .and_then(|()| dev.verify_erased(0, self.chip_size as usize)),
+        };
+        result.map_err(|e| match e {
+            W25QError::VerifyFailed { addr } => W25QError::VerifyFailed {
+                addr: self.array_address(chip, addr),
+            },
+            e => e,
+        })
+    }
+
+    /// wait for every chip, even after an error, so nothing started outlives the call.
+    fn wait_all(&mut self, data: &[u8]) -> Result<(), W25QError> {
+        let mut result = Ok(());
+        for chip in 0..N {
+            let waited = self.wait(chip, data);
+            result = result.and(waited);
+        }
+        result
+    }
+
+    pub fn read_at(&mut self, address: u32, data: &mut [u8]) -> Result<(), W25QError> {
+        self.check(address, data.len())?;
+        let mut addr = address;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let (chip, offset, left) = self.locate(addr);
+            let len = (left as usize).min(rest.len());
+            let (now, later) = rest.split_at_mut(len);
+            self.wait(chip, &[])?;
+            self.chips[chip].read_at(offset, now)?;
+            addr += len as u32;
+            rest = later;
+        }
+        Ok(())
+    }
+
+    /// program `data` at `address`, page by page, overlapping programs on different chips.
+    pub fn program(&mut self, address: u32, data: &[u8]) -> Result<(), W25QError> {
+        self.check(address, data.len())?;
+        let started = self.start_programs(address, data);
+        let waited = self.wait_all(data);
+        started.and(waited)
+    }
+
+    fn start_programs(&mut self, address: u32, data: &[u8]) -> Result<(), W25QError> {
+        let mut at = 0;
+        while at < data.len() {
+            let (chip, offset, left) = self.locate(address + at as u32);
+            let page_left = PAGE_SIZE - offset as usize % PAGE_SIZE;
+            let len = (left as usize).min(page_left).min(data.len() - at);
+            self.wait(chip, data)?;
+            self.chips[chip].start_page_program(offset, &data[at..at + len])?;
+            self.pending[chip] = Pending::Program { offset, at, len };
+            at += len;
+        }
+        Ok(())
+    }
+
+    /// erase a sector aligned `range`, overlapping erases on different chips and using block
+    /// erases where a chip's part of the range allows it.
+    pub fn erase_range(&mut self, range: Range<u32>) -> Result<(), W25QError> {
+        if range.start > range.end {
+            return Err(W25QError::OutOfBounds);
+        }
+        self.check(range.start, (range.end - range.start) as usize)?;
+        if !(range.start as usize).is_multiple_of(SECTOR_SIZE)
+            || !(range.end as usize).is_multiple_of(SECTOR_SIZE)
+        {
+            return Err(W25QError::NotAligned);
+        }
+        let started = self.start_erases(range);
+        let waited = self.wait_all(&[]);
+        started.and(waited)
+    }
+
+    fn start_erases(&mut self, range: Range<u32>) -> Result<(), W25QError> {
+        let mut addr = range.start;
+        while addr < range.end {
+            let (chip, offset, left) = self.locate(addr);
+            let left = (left as usize).min((range.end - addr) as usize);
+            self.wait(chip, &[])?;
+            let dev = &mut self.chips[chip];
+            let size = if (offset as usize).is_multiple_of(BLOCK_SIZE_64) && left >= BLOCK_SIZE_64 {
+                dev.start_block_erase_64kb(offset)?;
+                BLOCK_SIZE_64
+            } else if (offset as usize).is_multiple_of(BLOCK_SIZE_32) && left >= BLOCK_SIZE_32 {
+                dev.start_block_erase_32kb(offset)?;
+                BLOCK_SIZE_32
+            } else {
+                dev.start_sector_erase(offset)?;
+                SECTOR_SIZE
+            };
+            self.pending[chip] = Pending::Erase { offset, len: size };
+            addr += size as u32;
+        }
+        Ok(())
+    }
+
+    /// erase every chip, all at once.
+    pub fn chip_erase(&mut self) -> Result<(), W25QError> {
+        let mut started = Ok(());
+        for chip in 0..N {
+            started = self
+                .wait(chip, &[])
+                .and_then(|()| self.chips[chip].start_chip_erase());
+            if started.is_err() {
+                break;
+            }
+            self.pending[chip] = Pending::ChipErase;
+        }
+        let waited = self.wait_all(&[]);
+        started.and(waited)
+    }
+}
+
+impl<SPI, DELAY, const N: usize> nor_flash::ErrorType for FlashArray<SPI, DELAY, N>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    type Error = W25QError;
+}
+
+impl<SPI, DELAY, const N: usize> ReadNorFlash for FlashArray<SPI, DELAY, N>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), W25QError> {
+        self.read_at(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        FlashArray::capacity(self) as usize
+    }
+}
+
+impl<SPI, DELAY, const N: usize> NorFlash for FlashArray<SPI, DELAY, N>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = SECTOR_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), W25QError> {
+        self.erase_range(from..to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), W25QError> {
+        self.program(offset, bytes)
+    }
+}
+
+impl<SPI, DELAY, const N: usize> MultiwriteNorFlash for FlashArray<SPI, DELAY, N>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+}
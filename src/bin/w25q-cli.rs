@@ -0,0 +1,401 @@
+//! inspect and program a W25Q from Linux through spidev, e.g. on a Raspberry Pi wired to the
+//! flash, or a simulated chip kept in an image file.
+//!
+//! ```text
+//! w25q-cli id
+//! w25q-cli --device /dev/spidev0.1 read 0x0 0x10000 -o dump.bin
+//! w25q-cli --sim flash.bin --chip W25Q128 write 0x10000 app.bin
+//! w25q-cli --emulator board.w25 --chip W25Q128 status
+//! ```
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use clap::{ArgGroup, Parser, Subcommand};
+use embedded_hal::{delay::DelayNs, spi::SpiDevice};
+use w25q::{
+    io::W25QError,
+    sim::{FileFlash, SimFlash},
+    Chip, PAGE_SIZE, SR, SR1, SR2, SR3, W25Q,
+};
+
+#[derive(Parser)]
+#[command(about = "dump, program and configure a W25Q over Linux spidev")]
+#[command(group(ArgGroup::new("simulated").args(["sim", "emulator"])))]
+struct Args {
+    /// spidev device node
+    #[arg(long, default_value = "/dev/spidev0.0")]
+    device: PathBuf,
+    /// SPI clock in Hz
+    #[arg(long, default_value_t = 10_000_000)]
+    speed: u32,
+    /// use a simulated chip stored in this image file instead of spidev. only the memory
+    /// array is stored, registers start from their power-up state
+    #[arg(long, conflicts_with = "device")]
+    sim: Option<PathBuf>,
+    /// use the persistent emulated chip in this file, keeping registers too. created if
+    /// missing
+    #[arg(long, conflicts_with = "device")]
+    emulator: Option<PathBuf>,
+    /// chip of the simulated device. for `--sim` by default the one matching the image size,
+    /// for `--emulator` the one in the file
+    #[arg(long, value_parser = parse_chip, requires = "simulated")]
+    chip: Option<Chip>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// JEDEC ID, detected chip and unique ID
+    Id,
+    /// dump the SFDP parameters
+    Sfdp {
+        #[arg(long, default_value_t = 256, value_parser = parse_u32)]
+        length: u32,
+    },
+    /// status registers of every die
+    Status,
+    /// read a range, as a hex dump or into a file
+    Read {
+        #[arg(value_parser = parse_u32)]
+        address: u32,
+        #[arg(value_parser = parse_u32)]
+        length: u32,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// write a file at a sector aligned address, erasing and programming only what differs
+    Write {
+        #[arg(value_parser = parse_u32)]
+        address: u32,
+        file: PathBuf,
+    },
+    /// erase a sector aligned range, or the whole chip
+    Erase {
+        #[arg(value_parser = parse_u32, required_unless_present = "all")]
+        address: Option<u32>,
+        #[arg(value_parser = parse_u32, required_unless_present = "all")]
+        length: Option<u32>,
+        #[arg(long, conflicts_with_all = ["address", "length"])]
+        all: bool,
+    },
+    /// compare a range with a file
+    Verify {
+        #[arg(value_parser = parse_u32)]
+        address: u32,
+        file: PathBuf,
+    },
+    /// lock the block holding an address, or all blocks. locks apply while WPS is set
+    Protect {
+        #[arg(value_parser = parse_u32)]
+        address: Option<u32>,
+    },
+    /// unlock the block holding an address, or clear the BP bits and unlock all blocks
+    Unprotect {
+        #[arg(value_parser = parse_u32)]
+        address: Option<u32>,
+    },
+    /// read, program or erase security register 1 to 3
+    Security {
+        #[command(subcommand)]
+        command: SecurityCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum SecurityCommand {
+    Read {
+        #[arg(value_parser = clap::value_parser!(u8).range(1..=3))]
+        register: u8,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// program a file into the register, from `offset`. erase it first
+    Write {
+        #[arg(value_parser = clap::value_parser!(u8).range(1..=3))]
+        register: u8,
+        file: PathBuf,
+        #[arg(long, default_value_t = 0, value_parser = parse_u32)]
+        offset: u32,
+    },
+    Erase {
+        #[arg(value_parser = clap::value_parser!(u8).range(1..=3))]
+        register: u8,
+    },
+}
+
+/// [`DelayNs`] sleeping the thread
+#[cfg(target_os = "linux")]
+struct StdDelay;
+
+#[cfg(target_os = "linux")]
+impl DelayNs for StdDelay {
+    fn delay_ns(&mut self, ns: u32) {
+        std::thread::sleep(std::time::Duration::from_nanos(ns as u64));
+    }
+}
+
+fn parse_chip(name: &str) -> Result<Chip, String> {
+    name.parse().map_err(|_| format!("unknown chip {name}"))
+}
+
+fn parse_u32(s: &str) -> Result<u32, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|e| format!("{s}: {e}"))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), String> {
+    fs::write(path, data).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+fn driver(e: W25QError) -> String {
+    format!("{e:?}")
+}
+
+fn hex_dump(address: u32, data: &[u8]) {
+    let mut out = std::io::stdout().lock();
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{b:02x}")).collect();
+        let text: String = line
+            .iter()
+            .map(|b| match b {
+                0x20..=0x7E => *b as char,
+                _ => '.',
+            })
+            .collect();
+        let _ = writeln!(
+            out,
+            "{:08x}: {:<47}  {text}",
+            address as usize + i * 16,
+            hex.join(" ")
+        );
+    }
+}
+
+/// security register `register` as a device address
+fn security_address(register: u8) -> u32 {
+    (register as u32) << 12
+}
+
+fn run<SPI, DELAY>(dev: &mut W25Q<SPI, DELAY>, command: &Command) -> Result<(), String>
+where
+    SPI: SpiDevice,
+    DELAY: DelayNs,
+{
+    match command {
+        Command::Id => {
+            let id = dev.read_jedec_id().map_err(driver)?;
+            let unique = dev.read_unique_id().map_err(driver)?;
+            println!("JEDEC ID  {:02x} {:02x} {:02x}", id[0], id[1], id[2]);
+            println!("chip      {} ({} bytes)", dev.chip().name(), dev.capacity());
+            println!("unique ID {:02x?}", unique);
+        }
+        Command::Sfdp { length } => {
+            let mut sfdp = vec![0u8; *length as usize];
+            dev.read_sfdp(0, &mut sfdp).map_err(driver)?;
+            if !sfdp.starts_with(b"SFDP") {
+                eprintln!("no SFDP signature");
+            }
+            hex_dump(0, &sfdp);
+        }
+        Command::Status => {
+            for die in 0..dev.chip().die_count() {
+                dev.select_die(die).map_err(driver)?;
+                let sr1 = dev.read_status_register(SR::SR1(SR1::default()));
+                let sr1 = sr1.map_err(driver)?;
+                let sr2 = dev.read_status_register(SR::SR2(SR2::default()));
+                let sr2 = sr2.map_err(driver)?;
+                let sr3 = dev.read_status_register(SR::SR3(SR3::default()));
+                let sr3 = sr3.map_err(driver)?;
+                if dev.chip().die_count() > 1 {
+                    println!("die {die}");
+                }
+                println!("SR1 {sr1:02x} {:?}", SR1::from(sr1));
+                println!("SR2 {sr2:02x} {:?}", SR2::from(sr2));
+                println!("SR3 {sr3:02x} {:?}", SR3::from(sr3));
+            }
+        }
+        Command::Read {
+            address,
+            length,
+            output,
+        } => {
+            let mut data = vec![0u8; *length as usize];
+            dev.read_at(*address, &mut data).map_err(driver)?;
+            match output {
+                Some(path) => write_file(path, &data)?,
+                None => hex_dump(*address, &data),
+            }
+        }
+        Command::Write { address, file } => {
+            let data = read_file(file)?;
+            let stats = dev.sync_image(*address, &mut &data[..]).map_err(driver)?;
+            println!(
+                "{} bytes: {} sectors unchanged, {} programmed, {} erased",
+                stats.bytes, stats.skipped, stats.programmed, stats.erased
+            );
+        }
+        Command::Erase {
+            address,
+            length,
+            all,
+        } => match (address, length) {
+            (Some(address), Some(length)) if !all => {
+                let end = address.checked_add(*length).ok_or("range overflows")?;
+                dev.erase_range(*address..end).map_err(driver)?;
+            }
+            _ => dev.chip_erase().map_err(driver)?,
+        },
+        Command::Verify { address, file } => {
+            let data = read_file(file)?;
+            match dev.verify(*address, &data) {
+                Ok(()) => println!("{} bytes match", data.len()),
+                Err(W25QError::VerifyFailed { addr }) => {
+                    return Err(format!("differs at {addr:#010x}"))
+                }
+                Err(e) => return Err(driver(e)),
+            }
+        }
+        Command::Protect { address } => match address {
+            Some(address) => dev.individual_block_lock(*address).map_err(driver)?,
+            None => {
+                for die in 0..dev.chip().die_count() {
+                    dev.select_die(die).map_err(driver)?;
+                    dev.global_block_lock().map_err(driver)?;
+                }
+            }
+        },
+        Command::Unprotect { address } => match address {
+            Some(address) => dev.individual_block_unlock(*address).map_err(driver)?,
+            None => {
+                for die in 0..dev.chip().die_count() {
+                    dev.select_die(die).map_err(driver)?;
+                    dev.write_status_register(SR::SR1(SR1::default()))
+                        .map_err(driver)?;
+                    dev.global_block_unlock().map_err(driver)?;
+                }
+            }
+        },
+        Command::Security { command } => match command {
+            SecurityCommand::Read { register, output } => {
+                let mut data = [0u8; PAGE_SIZE];
+                dev.read_security_register(security_address(*register), &mut data)
+                    .map_err(driver)?;
+                match output {
+                    Some(path) => write_file(path, &data)?,
+                    None => hex_dump(0, &data),
+                }
+            }
+            SecurityCommand::Write {
+                register,
+                file,
+                offset,
+            } => {
+                let data = read_file(file)?;
+                if *offset as usize + data.len() > PAGE_SIZE {
+                    return Err(format!("a security register holds {PAGE_SIZE} bytes"));
+                }
+                dev.program_security_register(security_address(*register) + offset, &data)
+                    .map_err(driver)?;
+            }
+            SecurityCommand::Erase { register } => {
+                dev.erase_security_register(security_address(*register))
+                    .map_err(driver)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+/// run `command` on the simulated chip in `path`, a blank chip if the file does not exist,
+/// and store the chip back.
+fn run_sim(path: &Path, chip: Option<Chip>, command: &Command) -> Result<(), String> {
+    let image = match fs::read(path) {
+        Ok(image) => Some(image),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(format!("{}: {e}", path.display())),
+    };
+    let chip = match (chip, &image) {
+        (Some(chip), _) => chip,
+        (None, Some(image)) => Chip::ALL
+            .into_iter()
+            .find(|chip| chip.capacity() as usize == image.len())
+            .ok_or("no chip matches the image size, give --chip")?,
+        (None, None) => return Err("give --chip for a new image".into()),
+    };
+    let sim = match image {
+        Some(image) => SimFlash::from_image(chip, &image).map_err(driver)?,
+        None => SimFlash::new(chip),
+    };
+    let mut dev = sim.into_device().map_err(driver)?;
+    run(&mut dev, command)?;
+    write_file(path, dev.periph.data())
+}
+
+/// run `command` on the emulated chip in `path`, creating a blank `chip` if the file does not
+/// exist.
+fn run_emulator(path: &Path, chip: Option<Chip>, command: &Command) -> Result<(), String> {
+    let flash = match chip {
+        Some(chip) => FileFlash::open_or_create(path, chip),
+        None => FileFlash::open(path),
+    };
+    let flash = flash.map_err(|e| format!("{}: {e}", path.display()))?;
+    let mut dev = flash.into_device().map_err(driver)?;
+    run(&mut dev, command)?;
+    dev.periph
+        .sync_all()
+        .map_err(|e| format!("{}: {e}", path.display()))
+}
+
+#[cfg(target_os = "linux")]
+fn run_spidev(path: &Path, speed: u32, command: &Command) -> Result<(), String> {
+    use linux_embedded_hal::{
+        spidev::{SpiModeFlags, SpidevOptions},
+        SpidevDevice,
+    };
+    use w25q::W25QConfig;
+
+    let mut spi = SpidevDevice::open(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let options = SpidevOptions::new()
+        .bits_per_word(8)
+        .max_speed_hz(speed)
+        .mode(SpiModeFlags::SPI_MODE_0)
+        .build();
+    spi.configure(&options)
+        .map_err(|e| format!("{}: {e}", path.display()))?;
+    let mut dev = W25Q::new(spi, StdDelay, W25QConfig::new()).map_err(driver)?;
+    run(&mut dev, command)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run_spidev(_path: &Path, _speed: u32, _command: &Command) -> Result<(), String> {
+    Err("spidev needs Linux, use --sim or --emulator".into())
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let result = match (&args.sim, &args.emulator) {
+        (Some(path), _) => run_sim(path, args.chip, &args.command),
+        (_, Some(path)) => run_emulator(path, args.chip, &args.command),
+        _ => run_spidev(&args.device, args.speed, &args.command),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("w25q-cli: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
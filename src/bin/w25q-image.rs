@@ -0,0 +1,375 @@
+//! build a W25Q image from a manifest, and read one back.
+//!
+//! ```toml
+//! chip = "W25Q128"
+//! table = 0x0
+//!
+//! [[partition]]
+//! name = "app"
+//! kind = "firmware"
+//! offset = 0x10000
+//! size = 0x100000
+//! firmware = "app.bin"
+//! version = 3
+//!
+//! [[partition]]
+//! name = "config"
+//! kind = "config"
+//! offset = 0x110000
+//! size = 0x4000
+//! [partition.kv]
+//! ssid = "home"
+//! retries = 3
+//!
+//! [[partition]]
+//! name = "fs"
+//! kind = "filesystem"
+//! offset = 0x200000
+//! size = 0x100000
+//! [partition.littlefs]
+//! "/etc/wifi.conf" = "wifi.conf"
+//! "/www/index.html" = "site/index.html"
+//! ```
+//!
+//! a partition takes `file` (raw contents), `firmware` (contents behind an image header), `kv`
+//! (a formatted key-value store) or `littlefs` (a file system holding the listed files, with
+//! the `littlefs2` feature). file paths are relative to the manifest. KV strings are stored
+//! as UTF-8, integers as little endian u32 and integer arrays as bytes.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use w25q::{
+    checksum::Crc32,
+    image::ImageBuilder,
+    io::W25QError,
+    kv::KvStore,
+    partition::{Partition, PartitionEntry, PartitionKind, PartitionTable, PARTITION_TABLE_SIZE},
+    sim::SimFlash,
+    slots::{ImageHeader, IMAGE_HEADER_SIZE},
+    Chip, SECTOR_SIZE,
+};
+
+#[derive(Parser)]
+#[command(about = "build and inspect W25Q flash images on the host")]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// build the image described by a manifest
+    Build {
+        manifest: PathBuf,
+        /// output image, the whole chip
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// load an image into the simulator and list what it holds
+    Inspect {
+        image: PathBuf,
+        /// defaults to the chip matching the image size
+        #[arg(long, value_parser = parse_chip)]
+        chip: Option<Chip>,
+        /// partition table address
+        #[arg(long, default_value_t = 0, value_parser = parse_u32)]
+        table: u32,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    chip: String,
+    /// partition table address
+    #[serde(default)]
+    table: u32,
+    #[serde(default, rename = "partition")]
+    partitions: Vec<ManifestPartition>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestPartition {
+    name: String,
+    kind: String,
+    offset: u32,
+    size: u32,
+    file: Option<PathBuf>,
+    firmware: Option<PathBuf>,
+    #[serde(default)]
+    version: u32,
+    kv: Option<toml::Table>,
+    /// path in the file system to file on the host
+    littlefs: Option<BTreeMap<String, PathBuf>>,
+}
+
+fn parse_chip(name: &str) -> Result<Chip, String> {
+    name.parse().map_err(|_| format!("unknown chip {name}"))
+}
+
+fn parse_u32(s: &str) -> Result<u32, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|e| format!("{s}: {e}"))
+}
+
+fn parse_kind(kind: &str) -> Result<PartitionKind, String> {
+    match kind {
+        "bootloader" => Ok(PartitionKind::Bootloader),
+        "firmware" => Ok(PartitionKind::Firmware),
+        "config" => Ok(PartitionKind::Config),
+        "log" => Ok(PartitionKind::Log),
+        "filesystem" => Ok(PartitionKind::Filesystem),
+        "data" => Ok(PartitionKind::Data),
+        _ => kind
+            .parse::<u8>()
+            .map(PartitionKind::from)
+            .map_err(|_| format!("unknown partition kind {kind}")),
+    }
+}
+
+fn kv_value(key: &str, value: &toml::Value) -> Result<Vec<u8>, String> {
+    match value {
+        toml::Value::String(s) => Ok(s.as_bytes().to_vec()),
+        toml::Value::Integer(n) => u32::try_from(*n)
+            .map(|n| n.to_le_bytes().to_vec())
+            .map_err(|_| format!("{key}: {n} does not fit a u32")),
+        toml::Value::Boolean(b) => Ok(vec![*b as u8]),
+        toml::Value::Array(bytes) => bytes
+            .iter()
+            .map(|b| {
+                b.as_integer()
+                    .and_then(|b| u8::try_from(b).ok())
+                    .ok_or_else(|| format!("{key}: arrays hold bytes"))
+            })
+            .collect(),
+        _ => Err(format!("{key}: unsupported value type")),
+    }
+}
+
+#[cfg(feature = "littlefs2")]
+fn littlefs_partition(
+    builder: &mut ImageBuilder,
+    part: &ManifestPartition,
+    files: &[(&String, Vec<u8>)],
+) -> Result<(), String> {
+    builder
+        .littlefs(part.offset..part.offset + part.size, files)
+        .map_err(|e| match e {
+            W25QError::InvalidConfig => format!(
+                "partition {}: littlefs needs a power of two of sectors, 2 to 16384",
+                part.name
+            ),
+            W25QError::Io(embedded_io::ErrorKind::InvalidInput) => format!(
+                "partition {}: littlefs paths are absolute, ASCII and up to 255 bytes",
+                part.name
+            ),
+            e => format!("partition {}: {e:?}", part.name),
+        })
+}
+
+#[cfg(not(feature = "littlefs2"))]
+fn littlefs_partition(
+    _builder: &mut ImageBuilder,
+    part: &ManifestPartition,
+    _files: &[(&String, Vec<u8>)],
+) -> Result<(), String> {
+    Err(format!(
+        "partition {}: built without the littlefs2 feature",
+        part.name
+    ))
+}
+
+fn build(manifest_path: &Path, output: &Path) -> Result<(), String> {
+    let text = fs::read_to_string(manifest_path)
+        .map_err(|e| format!("{}: {e}", manifest_path.display()))?;
+    let manifest: Manifest =
+        toml::from_str(&text).map_err(|e| format!("{}: {e}", manifest_path.display()))?;
+    let dir = manifest_path.parent().unwrap_or(Path::new("."));
+    let chip = parse_chip(&manifest.chip)?;
+    let mut builder = ImageBuilder::new(chip).map_err(|e| format!("{e:?}"))?;
+
+    let mut table = PartitionTable::new();
+    for part in &manifest.partitions {
+        let context = |e: W25QError| format!("partition {}: {e:?}", part.name);
+        if part.offset as u64 + part.size as u64 > chip.capacity() as u64 {
+            return Err(format!("partition {}: outside the chip", part.name));
+        }
+        let entry =
+            PartitionEntry::new(&part.name, parse_kind(&part.kind)?, part.offset, part.size)
+                .map_err(context)?;
+        table.add(entry).map_err(context)?;
+        let sources = [
+            part.file.is_some(),
+            part.firmware.is_some(),
+            part.kv.is_some(),
+            part.littlefs.is_some(),
+        ];
+        if sources.iter().filter(|s| **s).count() > 1 {
+            return Err(format!(
+                "partition {}: give only one of file, firmware, kv and littlefs",
+                part.name
+            ));
+        }
+        let read = |path: &Path| {
+            let path = dir.join(path);
+            fs::read(&path).map_err(|e| format!("{}: {e}", path.display()))
+        };
+        let fits = |len: usize| {
+            if len > part.size as usize {
+                return Err(format!("partition {}: {len} bytes do not fit", part.name));
+            }
+            Ok(())
+        };
+        if let Some(file) = &part.file {
+            let data = read(file)?;
+            fits(data.len())?;
+            builder.raw(part.offset, &data).map_err(context)?;
+        } else if let Some(firmware) = &part.firmware {
+            let payload = read(firmware)?;
+            fits(IMAGE_HEADER_SIZE + payload.len())?;
+            builder
+                .firmware(part.offset, part.version, &payload, None)
+                .map_err(context)?;
+        } else if let Some(kv) = &part.kv {
+            let entries = kv
+                .iter()
+                .map(|(key, value)| Ok((key.as_bytes(), kv_value(key, value)?)))
+                .collect::<Result<Vec<_>, String>>()?;
+            builder
+                .kv(part.offset..part.offset + part.size, &entries)
+                .map_err(context)?;
+        } else if let Some(littlefs) = &part.littlefs {
+            let files = littlefs
+                .iter()
+                .map(|(path, file)| Ok((path, read(file)?)))
+                .collect::<Result<Vec<_>, String>>()?;
+            littlefs_partition(&mut builder, part, &files)?;
+        }
+    }
+    // the table is written last and erases whole sectors
+    let table_end = manifest.table as u64
+        + PARTITION_TABLE_SIZE.div_ceil(SECTOR_SIZE) as u64 * SECTOR_SIZE as u64;
+    if let Some(entry) = table.entries().iter().find(|entry| {
+        entry.range().is_ok_and(|range| {
+            (manifest.table as u64) < range.end as u64 && (range.start as u64) < table_end
+        })
+    }) {
+        return Err(format!(
+            "partition {} overlaps the partition table",
+            entry.name()
+        ));
+    }
+    builder
+        .partition_table(&table, manifest.table)
+        .map_err(|e| format!("partition table: {e:?}"))?;
+
+    fs::write(output, builder.into_data()).map_err(|e| format!("{}: {e}", output.display()))
+}
+
+fn inspect(image: &Path, chip: Option<Chip>, table_address: u32) -> Result<(), String> {
+    let data = fs::read(image).map_err(|e| format!("{}: {e}", image.display()))?;
+    let chip = match chip {
+        Some(chip) => chip,
+        None => Chip::ALL
+            .into_iter()
+            .find(|chip| chip.capacity() as usize == data.len())
+            .ok_or("no chip matches the image size, give --chip")?,
+    };
+    let mut dev = SimFlash::from_image(chip, &data)
+        .and_then(SimFlash::into_device)
+        .map_err(|e| format!("{e:?}"))?;
+    let table = PartitionTable::read(&mut dev, table_address)
+        .map_err(|e| format!("partition table at {table_address:#x}: {e:?}"))?;
+
+    for entry in table.entries() {
+        println!(
+            "{:<16} {:<12} {:#010x} {:#010x}",
+            entry.name(),
+            format!("{:?}", entry.kind),
+            entry.offset,
+            entry.size
+        );
+        let mut partition = Partition::from_entry(&mut dev, entry).map_err(|e| format!("{e:?}"))?;
+        match entry.kind {
+            PartitionKind::Firmware => {
+                let mut buf = [0u8; IMAGE_HEADER_SIZE];
+                partition
+                    .read_at(0, &mut buf)
+                    .map_err(|e| format!("{e:?}"))?;
+                let Ok(header) = ImageHeader::from_bytes(&buf) else {
+                    println!("  no image");
+                    continue;
+                };
+                if IMAGE_HEADER_SIZE as u64 + header.length as u64 > entry.size as u64 {
+                    println!(
+                        "  version {} length {} exceeds the partition",
+                        header.version, header.length
+                    );
+                    continue;
+                }
+                let mut payload = vec![0u8; header.length as usize];
+                partition
+                    .read_at(IMAGE_HEADER_SIZE as u32, &mut payload)
+                    .map_err(|e| format!("{e:?}"))?;
+                let crc = if Crc32::checksum(&payload) == header.crc32 {
+                    "ok"
+                } else {
+                    "BAD"
+                };
+                println!(
+                    "  version {} length {} crc {:#010x} {crc}",
+                    header.version, header.length, header.crc32
+                );
+            }
+            PartitionKind::Config => {
+                let mut store = match KvStore::new(partition, 0..entry.size) {
+                    Ok(store) => store,
+                    Err(e) => {
+                        println!("  no store: {e:?}");
+                        continue;
+                    }
+                };
+                let mut value = vec![0u8; u16::MAX as usize];
+                store
+                    .for_each(&mut value, |key, value| {
+                        let key = String::from_utf8_lossy(key);
+                        match std::str::from_utf8(value) {
+                            Ok(text) if !text.chars().any(char::is_control) => {
+                                println!("  {key} = {text:?}")
+                            }
+                            _ => println!("  {key} = {value:02x?}"),
+                        }
+                    })
+                    .map_err(|e| format!("{e:?}"))?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let result = match &args.command {
+        Command::Build { manifest, output } => build(manifest, output),
+        Command::Inspect { image, chip, table } => inspect(image, *chip, *table),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("w25q-image: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
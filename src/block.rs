@@ -0,0 +1,118 @@
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::{io::W25QError, PAGE_SIZE, SECTOR_SIZE};
+
+/// size of the blocks of a [`FlashBlocks`]
+pub const BLOCK_SIZE: usize = 512;
+
+/// 512 byte block view of a flash device, for filesystems and mass storage.
+///
+/// block writes land in a one sector write-back cache. the sector is erased and programmed
+/// when a write moves to another sector or on [`FlashBlocks::flush`], so writing the eight
+/// blocks of a sector costs one erase. anything still cached is lost on power loss, call
+/// `flush` at sync points.
+pub struct FlashBlocks<F> {
+    flash: F,
+    cache: [u8; SECTOR_SIZE],
+    /// sector held in `cache`
+    cached: Option<u32>,
+    /// `cache` differs from the flash
+    dirty: bool,
+}
+
+impl<F> FlashBlocks<F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    pub fn new(flash: F) -> Result<Self, W25QError> {
+        if !SECTOR_SIZE.is_multiple_of(F::ERASE_SIZE)
+            || !PAGE_SIZE.is_multiple_of(F::WRITE_SIZE)
+            || !BLOCK_SIZE.is_multiple_of(F::READ_SIZE)
+        {
+            return Err(W25QError::NotAligned);
+        }
+        Ok(Self {
+            flash,
+            cache: [0xFF; SECTOR_SIZE],
+            cached: None,
+            dirty: false,
+        })
+    }
+
+    /// the flash device. flush first, it does not see cached writes.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    pub fn block_count(&self) -> u32 {
+        (self.flash.capacity() / BLOCK_SIZE) as u32
+    }
+
+    fn check(&self, block: u32, len: usize) -> Result<(), W25QError> {
+        if !len.is_multiple_of(BLOCK_SIZE) {
+            return Err(W25QError::NotAligned);
+        }
+        if block as u64 + (len / BLOCK_SIZE) as u64 > self.block_count() as u64 {
+            return Err(W25QError::OutOfBounds);
+        }
+        Ok(())
+    }
+
+    /// read whole blocks starting at `block`.
+    pub fn read_blocks(&mut self, block: u32, data: &mut [u8]) -> Result<(), W25QError> {
+        self.check(block, data.len())?;
+        for (i, chunk) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+            let address = (block as usize + i) * BLOCK_SIZE;
+            let sector = (address / SECTOR_SIZE) as u32;
+            if self.cached == Some(sector) {
+                let at = address % SECTOR_SIZE;
+                chunk.copy_from_slice(&self.cache[at..at + BLOCK_SIZE]);
+            } else {
+                self.flash.read(address as u32, chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// write whole blocks starting at `block` into the cache.
+    pub fn write_blocks(&mut self, block: u32, data: &[u8]) -> Result<(), W25QError> {
+        self.check(block, data.len())?;
+        for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+            let address = (block as usize + i) * BLOCK_SIZE;
+            let sector = (address / SECTOR_SIZE) as u32;
+            if self.cached != Some(sector) {
+                self.flush()?;
+                self.flash
+                    .read(sector * SECTOR_SIZE as u32, &mut self.cache)?;
+                self.cached = Some(sector);
+            }
+            let at = address % SECTOR_SIZE;
+            let cached = &mut self.cache[at..at + BLOCK_SIZE];
+            if cached != chunk {
+                cached.copy_from_slice(chunk);
+                self.dirty = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// write the cached sector back if it changed.
+    pub fn flush(&mut self) -> Result<(), W25QError> {
+        let Some(sector) = self.cached else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        let address = sector * SECTOR_SIZE as u32;
+        self.flash.erase(address, address + SECTOR_SIZE as u32)?;
+        for (i, page) in self.cache.chunks(PAGE_SIZE).enumerate() {
+            if page.iter().any(|b| *b != 0xFF) {
+                self.flash.write(address + (i * PAGE_SIZE) as u32, page)?;
+            }
+        }
+        self.dirty = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use core::{cell::RefCell, ops::Range};
+
+use embassy_boot::FirmwareUpdaterConfig;
+use embassy_embedded_hal::flash::partition::{BlockingPartition, Partition};
+use embassy_sync::{blocking_mutex, blocking_mutex::raw::RawMutex, mutex};
+use embedded_storage::nor_flash::NorFlash;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+
+use crate::{io::W25QError, SECTOR_SIZE};
+
+pub use embassy_embedded_hal::adapter::BlockingAsync;
+
+/// length of the aligned buffer embassy-boot's updaters need for the state partition, the
+/// write size of a [`W25Q`](crate::W25Q)
+pub const STATE_WRITE_SIZE: usize = 1;
+
+/// DFU and STATE partitions for `embassy_boot::BlockingFirmwareUpdater`
+pub type BlockingBootConfig<'a, M, F> =
+    FirmwareUpdaterConfig<BlockingPartition<'a, M, F>, BlockingPartition<'a, M, F>>;
+/// DFU and STATE partitions for `embassy_boot::FirmwareUpdater`
+pub type AsyncBootConfig<'a, M, F> =
+    FirmwareUpdaterConfig<Partition<'a, M, F>, Partition<'a, M, F>>;
+
+/// embassy-boot DFU and STATE partitions on one flash device.
+///
+/// the DFU partition holds the new image plus one spare sector for the bootloader's swap,
+/// the STATE partition the boot magic and the swap progress of every active sector. both
+/// are sector aligned, the erase size of a W25Q.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootLayout {
+    pub dfu: Range<u32>,
+    pub state: Range<u32>,
+}
+
+impl BootLayout {
+    /// DFU partition at `offset` for an active partition of `active_size` bytes, followed by
+    /// the STATE partition.
+    pub fn new(offset: u32, active_size: u32) -> Result<Self, W25QError> {
+        if !(offset as usize).is_multiple_of(SECTOR_SIZE)
+            || !(active_size as usize).is_multiple_of(SECTOR_SIZE)
+        {
+            return Err(W25QError::NotAligned);
+        }
+        let dfu_end = offset as u64 + Self::dfu_size(active_size) as u64;
+        let state_end = dfu_end + Self::state_size(active_size) as u64;
+        if state_end > u32::MAX as u64 {
+            return Err(W25QError::OutOfBounds);
+        }
+        Ok(Self {
+            dfu: offset..dfu_end as u32,
+            state: dfu_end as u32..state_end as u32,
+        })
+    }
+
+    /// DFU partition size for an active partition of `active_size` bytes.
+    pub const fn dfu_size(active_size: u32) -> u32 {
+        active_size + SECTOR_SIZE as u32
+    }
+
+    /// STATE partition size for an active partition of `active_size` bytes: the magic and
+    /// two progress words per active sector, rounded up to whole sectors.
+    pub const fn state_size(active_size: u32) -> u32 {
+        let sectors = active_size / SECTOR_SIZE as u32;
+        let used = (2 * sectors + 2) * STATE_WRITE_SIZE as u32;
+        used.div_ceil(SECTOR_SIZE as u32) * SECTOR_SIZE as u32
+    }
+
+    /// first address after the layout.
+    pub fn end(&self) -> u32 {
+        self.state.end
+    }
+
+    fn check(&self, capacity: usize) -> Result<(), W25QError> {
+        if self.end() as usize > capacity {
+            return Err(W25QError::OutOfBounds);
+        }
+        Ok(())
+    }
+
+    /// partitions for `embassy_boot::BlockingFirmwareUpdater` on a device shared through a
+    /// blocking mutex, e.g. a [`W25Q`](crate::W25Q).
+    pub fn blocking_config<'a, M, F>(
+        &self,
+        flash: &'a blocking_mutex::Mutex<M, RefCell<F>>,
+    ) -> Result<BlockingBootConfig<'a, M, F>, W25QError>
+    where
+        M: RawMutex,
+        F: NorFlash,
+    {
+        flash.lock(|flash| self.check(flash.borrow().capacity()))?;
+        Ok(FirmwareUpdaterConfig {
+            dfu: BlockingPartition::new(flash, self.dfu.start, self.dfu.len() as u32),
+            state: BlockingPartition::new(flash, self.state.start, self.state.len() as u32),
+        })
+    }
+
+    /// partitions for `embassy_boot::FirmwareUpdater` on a device behind an async mutex.
+    /// wrap a [`W25Q`](crate::W25Q) in [`BlockingAsync`] to get the async flash traits.
+    pub async fn async_config<'a, M, F>(
+        &self,
+        flash: &'a mutex::Mutex<M, F>,
+    ) -> Result<AsyncBootConfig<'a, M, F>, W25QError>
+    where
+        M: RawMutex,
+        F: AsyncNorFlash,
+    {
+        self.check(flash.lock().await.capacity())?;
+        Ok(FirmwareUpdaterConfig {
+            dfu: Partition::new(flash, self.dfu.start, self.dfu.len() as u32),
+            state: Partition::new(flash, self.state.start, self.state.len() as u32),
+        })
+    }
+}
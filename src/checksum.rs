@@ -0,0 +1,192 @@
+use core::ops::Range;
+
+use embedded_hal::{delay, spi};
+
+use crate::{io::W25QError, W25Q};
+
+/// bytes read per transaction by [`W25Q::checksum`]
+pub const CHECKSUM_CHUNK_SIZE: usize = 256;
+
+/// anything that can consume a stream of bytes, e.g. a CRC or a hash.
+///
+/// with the `digest` feature every `digest::Update` (SHA-256, ...) is a `Hasher`.
+pub trait Hasher {
+    fn update(&mut self, data: &[u8]);
+}
+
+#[cfg(feature = "digest")]
+impl<D: digest::Update> Hasher for D {
+    fn update(&mut self, data: &[u8]) {
+        digest::Update::update(self, data);
+    }
+}
+
+/// CRC-32/ISO-HDLC, as used by zlib, Ethernet and most bootloaders.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crc32 {
+    state: u32,
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self { state: 0xFFFF_FFFF }
+    }
+
+    /// CRC of `data` in one call.
+    pub fn checksum(data: &[u8]) -> u32 {
+        let mut crc = Self::new();
+        crc.update(data);
+        crc.finalize()
+    }
+
+    pub fn finalize(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Hasher for Crc32 {
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.state =
+                (self.state >> 8) ^ CRC32_TABLE[((self.state ^ *byte as u32) & 0xFF) as usize];
+        }
+    }
+}
+
+/// CRC-16/IBM-3740 (CCITT-FALSE).
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crc16 {
+    state: u16,
+}
+
+const CRC16_TABLE: [u16; 256] = {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Default for Crc16 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc16 {
+    pub const fn new() -> Self {
+        Self { state: 0xFFFF }
+    }
+
+    /// CRC of `data` in one call.
+    pub fn checksum(data: &[u8]) -> u16 {
+        let mut crc = Self::new();
+        crc.update(data);
+        crc.finalize()
+    }
+
+    pub fn finalize(&self) -> u16 {
+        self.state
+    }
+}
+
+impl Hasher for Crc16 {
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.state =
+                (self.state << 8) ^ CRC16_TABLE[((self.state >> 8) as u8 ^ *byte) as usize];
+        }
+    }
+}
+
+impl<SPI, DELAY> W25Q<SPI, DELAY>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    /// feed the flash contents in `range` through `hasher`, reading in bursts of
+    /// [`CHECKSUM_CHUNK_SIZE`] with the configured read command.
+    pub fn checksum<H: Hasher>(
+        &mut self,
+        range: Range<u32>,
+        hasher: &mut H,
+    ) -> Result<(), W25QError> {
+        self.checksum_with(range, hasher, &mut [0u8; CHECKSUM_CHUNK_SIZE])
+    }
+
+    /// like [`W25Q::checksum`], reading `buf.len()` bytes per transaction. a larger buffer
+    /// means fewer command headers on long ranges. fails with `InvalidConfig` if `buf` is
+    /// empty.
+    pub fn checksum_with<H: Hasher>(
+        &mut self,
+        range: Range<u32>,
+        hasher: &mut H,
+        buf: &mut [u8],
+    ) -> Result<(), W25QError> {
+        if buf.is_empty() {
+            return Err(W25QError::InvalidConfig);
+        }
+        let mut addr = range.start;
+        while addr < range.end {
+            let len = ((range.end - addr) as usize).min(buf.len());
+            self.read_at(addr, &mut buf[..len])?;
+            hasher.update(&buf[..len]);
+            addr += len as u32;
+        }
+        Ok(())
+    }
+
+    /// CRC-32 of the flash contents in `range`.
+    pub fn crc32(&mut self, range: Range<u32>) -> Result<u32, W25QError> {
+        let mut crc = Crc32::new();
+        self.checksum(range, &mut crc)?;
+        Ok(crc.finalize())
+    }
+
+    /// CRC-16 of the flash contents in `range`.
+    pub fn crc16(&mut self, range: Range<u32>) -> Result<u16, W25QError> {
+        let mut crc = Crc16::new();
+        self.checksum(range, &mut crc)?;
+        Ok(crc.finalize())
+    }
+}
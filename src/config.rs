@@ -0,0 +1,264 @@
+use core::str::FromStr;
+
+use crate::{io::W25QError, MANUFACTURER_ID, PAGE_SIZE, RESET_DELAY_US, SECTOR_SIZE};
+
+/// supported chip variants, identified by the capacity byte of the JEDEC ID.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chip {
+    W25Q16,
+    W25Q32,
+    W25Q64,
+    W25Q128,
+    W25Q256,
+    W25Q512,
+    /// two W25Q256 dies behind one chip select, switched with software die select
+    W25M512,
+}
+
+impl Chip {
+    /// every supported chip, smallest first
+    pub const ALL: [Chip; 7] = [
+        Chip::W25Q16,
+        Chip::W25Q32,
+        Chip::W25Q64,
+        Chip::W25Q128,
+        Chip::W25Q256,
+        Chip::W25Q512,
+        Chip::W25M512,
+    ];
+
+    /// part number, e.g. `"W25Q128"`
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Chip::W25Q16 => "W25Q16",
+            Chip::W25Q32 => "W25Q32",
+            Chip::W25Q64 => "W25Q64",
+            Chip::W25Q128 => "W25Q128",
+            Chip::W25Q256 => "W25Q256",
+            Chip::W25Q512 => "W25Q512",
+            Chip::W25M512 => "W25M512",
+        }
+    }
+
+    /// chip variant for a JEDEC ID, `None` if it is not a known Winbond part.
+    pub fn from_jedec_id(id: [u8; 3]) -> Option<Self> {
+        if id[0] != MANUFACTURER_ID {
+            return None;
+        }
+        if id[1] == 0x71 && id[2] == 0x19 {
+            return Some(Chip::W25M512);
+        }
+        match id[2] {
+            0x15 => Some(Chip::W25Q16),
+            0x16 => Some(Chip::W25Q32),
+            0x17 => Some(Chip::W25Q64),
+            0x18 => Some(Chip::W25Q128),
+            0x19 => Some(Chip::W25Q256),
+            0x20 => Some(Chip::W25Q512),
+            _ => None,
+        }
+    }
+
+    /// capacity in bytes
+    pub fn capacity(&self) -> u32 {
+        match self {
+            Chip::W25Q16 => 2 << 20,
+            Chip::W25Q32 => 4 << 20,
+            Chip::W25Q64 => 8 << 20,
+            Chip::W25Q128 => 16 << 20,
+            Chip::W25Q256 => 32 << 20,
+            Chip::W25Q512 => 64 << 20,
+            Chip::W25M512 => 64 << 20,
+        }
+    }
+
+    /// number of dies in the package
+    pub fn die_count(&self) -> u8 {
+        match self {
+            Chip::W25M512 => 2,
+            _ => 1,
+        }
+    }
+
+    /// capacity of one die in bytes
+    pub fn die_capacity(&self) -> u32 {
+        self.capacity() / self.die_count() as u32
+    }
+
+    pub fn sector_count(&self) -> u32 {
+        self.capacity() / SECTOR_SIZE as u32
+    }
+
+    pub fn page_count(&self) -> u32 {
+        self.capacity() / PAGE_SIZE as u32
+    }
+
+    /// address mode used when none is configured.
+    pub fn default_address_mode(&self) -> AddressMode {
+        if self.die_capacity() > 1 << 24 {
+            AddressMode::FourByte
+        } else {
+            AddressMode::ThreeByte
+        }
+    }
+}
+
+/// parses a part number as [`Chip::name`] gives it, ignoring case. fails with
+/// `InvalidConfig` for unknown parts.
+impl FromStr for Chip {
+    type Err = W25QError;
+
+    fn from_str(name: &str) -> Result<Self, W25QError> {
+        Chip::ALL
+            .into_iter()
+            .find(|chip| chip.name().eq_ignore_ascii_case(name))
+            .ok_or(W25QError::InvalidConfig)
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressMode {
+    ThreeByte,
+    FourByte,
+}
+
+impl AddressMode {
+    /// number of address bytes sent with a command
+    pub fn bytes(&self) -> usize {
+        match self {
+            AddressMode::ThreeByte => 3,
+            AddressMode::FourByte => 4,
+        }
+    }
+}
+
+/// command used for reads through the generic read paths.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadCommand {
+    /// Read Data (03h), limited to 50MHz
+    Read,
+    /// Fast Read (0Bh), one dummy byte
+    FastRead,
+}
+
+/// write protection applied by [`crate::W25Q::new`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteProtection {
+    /// leave the protection bits as found
+    Keep,
+    /// clear the block protect bits and unlock all blocks
+    UnlockAll,
+    /// set WPS, which is non-volatile, and lock all blocks
+    LockAll,
+}
+
+/// driver configuration, built with chained setters:
+///
+/// ```ignore
+/// let config = W25QConfig::new()
+///     .chip(Chip::W25Q128)
+///     .poll_interval_us(100)
+///     .verify_after_write(true);
+/// ```
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct W25QConfig {
+    pub(crate) chip: Option<Chip>,
+    pub(crate) address_mode: Option<AddressMode>,
+    pub(crate) poll_interval_us: u32,
+    pub(crate) program_timeout_ms: u32,
+    pub(crate) erase_timeout_ms: u32,
+    pub(crate) chip_erase_timeout_ms: u32,
+    pub(crate) reset_delay_us: u32,
+    pub(crate) read_command: ReadCommand,
+    pub(crate) verify_after_write: bool,
+    pub(crate) write_protection: WriteProtection,
+}
+
+impl Default for W25QConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl W25QConfig {
+    /// auto-detect the chip, 1ms polling and W25Q datasheet maximum timings.
+    pub const fn new() -> Self {
+        Self {
+            chip: None,
+            address_mode: None,
+            poll_interval_us: 1000,
+            program_timeout_ms: 15,
+            erase_timeout_ms: 2000,
+            chip_erase_timeout_ms: 400_000,
+            reset_delay_us: RESET_DELAY_US,
+            read_command: ReadCommand::FastRead,
+            verify_after_write: false,
+            write_protection: WriteProtection::Keep,
+        }
+    }
+
+    /// expected chip. `W25Q::new` fails if a different chip answers.
+    pub const fn chip(mut self, chip: Chip) -> Self {
+        self.chip = Some(chip);
+        self
+    }
+
+    /// address mode. defaults to 4-byte for chips larger than 16MiB. 3-byte mode on such a
+    /// chip limits the driver to its first 16MiB.
+    pub const fn address_mode(mut self, mode: AddressMode) -> Self {
+        self.address_mode = Some(mode);
+        self
+    }
+
+    /// interval between status register polls while busy. 0 polls back to back; the timeouts
+    /// then count each poll as 1us.
+    pub const fn poll_interval_us(mut self, us: u32) -> Self {
+        self.poll_interval_us = us;
+        self
+    }
+
+    /// timeout for page program, security register program and status register writes.
+    pub const fn program_timeout_ms(mut self, ms: u32) -> Self {
+        self.program_timeout_ms = ms;
+        self
+    }
+
+    /// timeout for sector and block erases.
+    pub const fn erase_timeout_ms(mut self, ms: u32) -> Self {
+        self.erase_timeout_ms = ms;
+        self
+    }
+
+    pub const fn chip_erase_timeout_ms(mut self, ms: u32) -> Self {
+        self.chip_erase_timeout_ms = ms;
+        self
+    }
+
+    /// wait after a software reset, tRST.
+    pub const fn reset_delay_us(mut self, us: u32) -> Self {
+        self.reset_delay_us = us;
+        self
+    }
+
+    pub const fn read_command(mut self, command: ReadCommand) -> Self {
+        self.read_command = command;
+        self
+    }
+
+    /// read back every programmed page and blank check every erase, failing with
+    /// `VerifyFailed` on mismatch.
+    pub const fn verify_after_write(mut self, verify: bool) -> Self {
+        self.verify_after_write = verify;
+        self
+    }
+
+    pub const fn write_protection(mut self, policy: WriteProtection) -> Self {
+        self.write_protection = policy;
+        self
+    }
+}
@@ -0,0 +1,563 @@
+use core::ops::Range;
+
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::{
+    checksum::{Crc32, Hasher},
+    io::W25QError,
+    SECTOR_SIZE,
+};
+
+/// size of the header at the start of a patch
+pub const PATCH_HEADER_SIZE: usize = 64;
+/// sectors taken by the progress record, written alternately so an erase never loses it
+pub const PROGRESS_SECTORS: u32 = 2;
+
+/// copy `len` bytes from `offset` of the old image: `[0x01, offset: u32, len: u32]`
+pub const OP_COPY: u8 = 0x01;
+/// insert the `len` bytes that follow: `[0x02, len: u32, data..]`
+pub const OP_INSERT: u8 = 0x02;
+
+const PATCH_MAGIC: [u8; 4] = *b"W25D";
+/// the header carries a SHA-256 of the new image
+const FLAG_SHA256: u32 = 0x01;
+
+const RECORD_SIZE: usize = 32;
+/// records follow a copy of the patch header in each progress sector
+const RECORDS_PER_SECTOR: usize = (SECTOR_SIZE - PATCH_HEADER_SIZE) / RECORD_SIZE;
+const RECORD_MARKER: u8 = 0x5A;
+/// bytes copied from the old image at once
+const CHUNK: usize = 256;
+
+/// header of a delta patch. integers in the patch are little endian.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PatchHeader {
+    /// length of the image the patch applies to
+    pub old_length: u32,
+    /// CRC-32 of the image the patch applies to
+    pub old_crc32: u32,
+    pub new_length: u32,
+    pub new_crc32: u32,
+    /// SHA-256 of the new image, if the patch carries one
+    pub sha256: Option<[u8; 32]>,
+}
+
+impl PatchHeader {
+    pub fn to_bytes(&self) -> [u8; PATCH_HEADER_SIZE] {
+        let mut buf = [0xFFu8; PATCH_HEADER_SIZE];
+        buf[0..4].copy_from_slice(&PATCH_MAGIC);
+        let flags = if self.sha256.is_some() {
+            FLAG_SHA256
+        } else {
+            0
+        };
+        buf[4..8].copy_from_slice(&flags.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.old_length.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.old_crc32.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.new_length.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.new_crc32.to_le_bytes());
+        buf[24..56].copy_from_slice(&self.sha256.unwrap_or([0; 32]));
+        let crc = Crc32::checksum(&buf[..56]);
+        buf[56..60].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// parse a header, failing with `Corrupt` on a bad magic or header CRC.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, W25QError> {
+        if buf.len() < 60
+            || buf[0..4] != PATCH_MAGIC
+            || Crc32::checksum(&buf[..56]).to_le_bytes() != buf[56..60]
+        {
+            return Err(W25QError::Corrupt);
+        }
+        let mut sha256 = [0u8; 32];
+        sha256.copy_from_slice(&buf[24..56]);
+        Ok(Self {
+            old_length: word(buf, 8),
+            old_crc32: word(buf, 12),
+            new_length: word(buf, 16),
+            new_crc32: word(buf, 20),
+            sha256: (word(buf, 4) & FLAG_SHA256 != 0).then_some(sha256),
+        })
+    }
+
+    /// identifies the patch in progress records, the header CRC
+    fn id(&self) -> u32 {
+        word(&self.to_bytes(), 56)
+    }
+}
+
+fn word(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    /// collecting the patch header
+    Header,
+    /// between operations, collecting the next opcode and arguments
+    Opcode,
+    Copy {
+        src: u32,
+        remaining: u32,
+    },
+    Insert {
+        remaining: u32,
+    },
+}
+
+/// streaming delta patch applier.
+///
+/// reads the old image from one region and writes the image rebuilt by a patch into
+/// another, erasing each sector ahead of it. a patch is a [`PatchHeader`] followed by
+/// [`OP_COPY`] and [`OP_INSERT`] operations until the new image is complete. RAM use is
+/// fixed, the patch can arrive in pieces of any size.
+///
+/// progress is checkpointed to flash after every output sector. after a reset, create the
+/// patcher again and send the patch from [`DeltaPatcher::resume_offset`]; at most one sector
+/// is redone. [`DeltaPatcher::finish`] checks the CRC of the result.
+pub struct DeltaPatcher<F> {
+    flash: F,
+    old: Range<u32>,
+    new: Range<u32>,
+    /// start of the [`PROGRESS_SECTORS`] progress sectors
+    progress: u32,
+    header: Option<PatchHeader>,
+    op: Op,
+    /// header or operation bytes being collected
+    pending: [u8; PATCH_HEADER_SIZE],
+    pending_len: usize,
+    /// patch bytes consumed
+    patch_pos: u32,
+    /// bytes of the new image written
+    out_pos: u32,
+    /// bytes of the new region erased
+    erased: u32,
+    seq: u32,
+    /// progress sector holding the newest record
+    sector: u32,
+    next_record: usize,
+}
+
+impl<F> DeltaPatcher<F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    /// patch from the image in `old` into `new`, with progress in the [`PROGRESS_SECTORS`]
+    /// sectors at `progress`. picks up a patch that was interrupted.
+    pub fn new(
+        flash: F,
+        old: Range<u32>,
+        new: Range<u32>,
+        progress: u32,
+    ) -> Result<Self, W25QError> {
+        let aligned = |addr: u32| (addr as usize).is_multiple_of(SECTOR_SIZE);
+        if !aligned(new.start)
+            || !aligned(new.end)
+            || !aligned(progress)
+            || !SECTOR_SIZE.is_multiple_of(F::ERASE_SIZE)
+            || F::WRITE_SIZE != 1
+            || F::READ_SIZE != 1
+        {
+            return Err(W25QError::NotAligned);
+        }
+        let progress_end = progress as u64 + PROGRESS_SECTORS as u64 * SECTOR_SIZE as u64;
+        if old.start > old.end
+            || new.start > new.end
+            || old.end as usize > flash.capacity()
+            || new.end as usize > flash.capacity()
+            || progress_end > flash.capacity() as u64
+        {
+            return Err(W25QError::OutOfBounds);
+        }
+        let overlaps =
+            |a: &Range<u32>, start: u64, end: u64| (a.start as u64) < end && start < a.end as u64;
+        if overlaps(&old, new.start as u64, new.end as u64)
+            || overlaps(&old, progress as u64, progress_end)
+            || overlaps(&new, progress as u64, progress_end)
+        {
+            return Err(W25QError::InvalidConfig);
+        }
+        let mut patcher = Self {
+            flash,
+            old,
+            new,
+            progress,
+            header: None,
+            op: Op::Header,
+            pending: [0; PATCH_HEADER_SIZE],
+            pending_len: 0,
+            patch_pos: 0,
+            out_pos: 0,
+            erased: 0,
+            seq: 0,
+            // with no record found, the first one goes to sector 0
+            sector: 1,
+            next_record: RECORDS_PER_SECTOR,
+        };
+        patcher.load()?;
+        Ok(patcher)
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// header of the patch being applied, once received.
+    pub fn header(&self) -> Option<PatchHeader> {
+        self.header
+    }
+
+    /// offset in the patch to send from, 0 unless an interrupted patch is being resumed.
+    pub fn resume_offset(&self) -> u32 {
+        self.patch_pos
+    }
+
+    /// bytes of the new image written so far
+    pub fn written(&self) -> u32 {
+        self.out_pos
+    }
+
+    /// the whole new image has been written.
+    pub fn is_complete(&self) -> bool {
+        matches!(self.header, Some(h) if h.new_length == self.out_pos && self.op == Op::Opcode)
+    }
+
+    fn sector_address(&self, sector: u32) -> u32 {
+        self.progress + sector * SECTOR_SIZE as u32
+    }
+
+    fn record_address(&self, sector: u32, record: usize) -> u32 {
+        self.sector_address(sector) + (PATCH_HEADER_SIZE + record * RECORD_SIZE) as u32
+    }
+
+    fn load(&mut self) -> Result<(), W25QError> {
+        let mut buf = [0u8; PATCH_HEADER_SIZE];
+        let mut record = [0u8; RECORD_SIZE];
+        for sector in 0..PROGRESS_SECTORS {
+            self.flash.read(self.sector_address(sector), &mut buf)?;
+            let Ok(header) = PatchHeader::from_bytes(&buf) else {
+                continue;
+            };
+            for index in 0..RECORDS_PER_SECTOR {
+                self.flash
+                    .read(self.record_address(sector, index), &mut record)?;
+                if record.iter().all(|b| *b == 0xFF) {
+                    break;
+                }
+                let Some((op, patch_pos, out_pos, seq)) = Self::decode(&record, header.id()) else {
+                    // torn record, nothing more can go into this sector
+                    if sector == self.sector {
+                        self.next_record = RECORDS_PER_SECTOR;
+                    }
+                    break;
+                };
+                if seq >= self.seq || self.header.is_none() {
+                    self.header = Some(header);
+                    self.op = op;
+                    self.patch_pos = patch_pos;
+                    self.out_pos = out_pos;
+                    self.seq = seq;
+                    self.sector = sector;
+                    self.next_record = index + 1;
+                }
+            }
+        }
+        // output past the checkpoint is redone, its sector erased again
+        self.erased = self.out_pos;
+        Ok(())
+    }
+
+    fn decode(record: &[u8; RECORD_SIZE], id: u32) -> Option<(Op, u32, u32, u32)> {
+        if record[0] != RECORD_MARKER
+            || word(record, 24) != id
+            || Crc32::checksum(&record[..28]).to_le_bytes() != record[28..32]
+        {
+            return None;
+        }
+        let op = match record[1] {
+            0 => Op::Opcode,
+            1 => Op::Copy {
+                src: word(record, 12),
+                remaining: word(record, 16),
+            },
+            2 => Op::Insert {
+                remaining: word(record, 16),
+            },
+            _ => return None,
+        };
+        Some((op, word(record, 4), word(record, 8), word(record, 20)))
+    }
+
+    /// record the current position. only called between operations or at sector
+    /// boundaries, when nothing is buffered.
+    fn checkpoint(&mut self) -> Result<(), W25QError> {
+        let Some(header) = self.header else {
+            return Ok(());
+        };
+        let seq = self.seq.wrapping_add(1);
+        let (kind, src, remaining) = match self.op {
+            Op::Copy { src, remaining } => (1, src, remaining),
+            Op::Insert { remaining } => (2, 0, remaining),
+            Op::Header | Op::Opcode => (0, 0, 0),
+        };
+        let mut record = [0xFFu8; RECORD_SIZE];
+        record[0] = RECORD_MARKER;
+        record[1] = kind;
+        record[4..8].copy_from_slice(&self.patch_pos.to_le_bytes());
+        record[8..12].copy_from_slice(&self.out_pos.to_le_bytes());
+        record[12..16].copy_from_slice(&src.to_le_bytes());
+        record[16..20].copy_from_slice(&remaining.to_le_bytes());
+        record[20..24].copy_from_slice(&seq.to_le_bytes());
+        record[24..28].copy_from_slice(&header.id().to_le_bytes());
+        let crc = Crc32::checksum(&record[..28]);
+        record[28..32].copy_from_slice(&crc.to_le_bytes());
+        if self.next_record == RECORDS_PER_SECTOR {
+            // the other sector holds older records only
+            let sector = 1 - self.sector;
+            let address = self.sector_address(sector);
+            self.flash.erase(address, address + SECTOR_SIZE as u32)?;
+            self.flash.write(address, &header.to_bytes())?;
+            self.sector = sector;
+            self.next_record = 0;
+        }
+        let address = self.record_address(self.sector, self.next_record);
+        self.next_record += 1;
+        self.flash.write(address, &record)?;
+        self.seq = seq;
+        Ok(())
+    }
+
+    /// drop the progress of an interrupted patch, to start another one.
+    pub fn reset(&mut self) -> Result<(), W25QError> {
+        let start = self.sector_address(0);
+        self.flash
+            .erase(start, start + PROGRESS_SECTORS * SECTOR_SIZE as u32)?;
+        self.header = None;
+        self.op = Op::Header;
+        self.pending_len = 0;
+        self.patch_pos = 0;
+        self.out_pos = 0;
+        self.erased = 0;
+        self.seq = 0;
+        self.sector = 1;
+        self.next_record = RECORDS_PER_SECTOR;
+        Ok(())
+    }
+
+    /// feed `f` with `len` bytes from `start`.
+    fn hash_range(
+        &mut self,
+        start: u32,
+        len: u32,
+        mut f: impl FnMut(&[u8]),
+    ) -> Result<(), W25QError> {
+        let mut chunk = [0u8; CHUNK];
+        for at in (0..len).step_by(CHUNK) {
+            let n = (len - at).min(CHUNK as u32) as usize;
+            self.flash.read(start + at, &mut chunk[..n])?;
+            f(&chunk[..n]);
+        }
+        Ok(())
+    }
+
+    /// take the patch header, check it against the regions and the old image.
+    fn start(&mut self) -> Result<(), W25QError> {
+        let header = PatchHeader::from_bytes(&self.pending)?;
+        if header.old_length > self.old.end - self.old.start
+            || header.new_length > self.new.end - self.new.start
+        {
+            return Err(W25QError::OutOfBounds);
+        }
+        let mut crc = Crc32::new();
+        self.hash_range(self.old.start, header.old_length, |data| crc.update(data))?;
+        if crc.finalize() != header.old_crc32 {
+            return Err(W25QError::Corrupt);
+        }
+        self.header = Some(header);
+        self.op = Op::Opcode;
+        self.checkpoint()
+    }
+
+    /// take a buffered operation.
+    fn decode_op(&mut self, header: &PatchHeader) -> Result<(), W25QError> {
+        let left = header.new_length - self.out_pos;
+        self.op = match self.pending[0] {
+            OP_COPY => {
+                let src = word(&self.pending, 1);
+                let len = word(&self.pending, 5);
+                if src as u64 + len as u64 > header.old_length as u64 || len > left {
+                    return Err(W25QError::Corrupt);
+                }
+                Op::Copy {
+                    src,
+                    remaining: len,
+                }
+            }
+            OP_INSERT => {
+                let len = word(&self.pending, 1);
+                if len > left {
+                    return Err(W25QError::Corrupt);
+                }
+                Op::Insert { remaining: len }
+            }
+            _ => return Err(W25QError::Corrupt),
+        };
+        self.pending_len = 0;
+        Ok(())
+    }
+
+    /// bytes that can be written before the next sector boundary.
+    fn sector_left(&self) -> u32 {
+        SECTOR_SIZE as u32 - self.out_pos % SECTOR_SIZE as u32
+    }
+
+    /// append to the new image, within one sector.
+    fn emit(&mut self, data: &[u8]) -> Result<(), W25QError> {
+        let address = self.new.start + self.out_pos;
+        if self.out_pos == self.erased {
+            self.flash.erase(address, address + SECTOR_SIZE as u32)?;
+            self.erased += SECTOR_SIZE as u32;
+        }
+        self.flash.write(address, data)?;
+        self.out_pos += data.len() as u32;
+        Ok(())
+    }
+
+    /// after output, back to waiting for an operation once `remaining` is used up and
+    /// checkpoint at sector boundaries.
+    fn advance(&mut self, op: Op) -> Result<(), W25QError> {
+        self.op = match op {
+            Op::Copy { remaining: 0, .. } | Op::Insert { remaining: 0 } => Op::Opcode,
+            op => op,
+        };
+        if self.out_pos.is_multiple_of(SECTOR_SIZE as u32) {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// apply the next part of the patch. the first call after [`DeltaPatcher::new`] takes the
+    /// patch from [`DeltaPatcher::resume_offset`]. fails with `Corrupt` on a malformed
+    /// patch, an old image that does not match or data past the end of the patch.
+    pub fn write(&mut self, mut data: &[u8]) -> Result<(), W25QError> {
+        loop {
+            match self.op {
+                Op::Header => {
+                    if data.is_empty() {
+                        return Ok(());
+                    }
+                    let n = (PATCH_HEADER_SIZE - self.pending_len).min(data.len());
+                    self.pending[self.pending_len..self.pending_len + n]
+                        .copy_from_slice(&data[..n]);
+                    self.pending_len += n;
+                    self.patch_pos += n as u32;
+                    data = &data[n..];
+                    if self.pending_len == PATCH_HEADER_SIZE {
+                        self.pending_len = 0;
+                        self.start()?;
+                    }
+                }
+                Op::Opcode => {
+                    if data.is_empty() {
+                        return Ok(());
+                    }
+                    let Some(header) = self.header else {
+                        return Err(W25QError::Corrupt);
+                    };
+                    if self.out_pos == header.new_length {
+                        return Err(W25QError::Corrupt);
+                    }
+                    if self.pending_len == 0 {
+                        self.pending[0] = data[0];
+                        self.pending_len = 1;
+                        self.patch_pos += 1;
+                        data = &data[1..];
+                    }
+                    let len = match self.pending[0] {
+                        OP_COPY => 9,
+                        OP_INSERT => 5,
+                        _ => return Err(W25QError::Corrupt),
+                    };
+                    let n = (len - self.pending_len).min(data.len());
+                    self.pending[self.pending_len..self.pending_len + n]
+                        .copy_from_slice(&data[..n]);
+                    self.pending_len += n;
+                    self.patch_pos += n as u32;
+                    data = &data[n..];
+                    if self.pending_len == len {
+                        self.decode_op(&header)?;
+                    }
+                }
+                Op::Insert { remaining } => {
+                    if remaining == 0 {
+                        self.advance(Op::Insert { remaining })?;
+                        continue;
+                    }
+                    if data.is_empty() {
+                        return Ok(());
+                    }
+                    let n = remaining.min(data.len() as u32).min(self.sector_left());
+                    self.emit(&data[..n as usize])?;
+                    self.patch_pos += n;
+                    data = &data[n as usize..];
+                    self.advance(Op::Insert {
+                        remaining: remaining - n,
+                    })?;
+                }
+                Op::Copy { src, remaining } => {
+                    if remaining == 0 {
+                        self.advance(Op::Copy { src, remaining })?;
+                        continue;
+                    }
+                    let n = remaining.min(CHUNK as u32).min(self.sector_left());
+                    let mut chunk = [0u8; CHUNK];
+                    self.flash
+                        .read(self.old.start + src, &mut chunk[..n as usize])?;
+                    self.emit(&chunk[..n as usize])?;
+                    self.advance(Op::Copy {
+                        src: src + n,
+                        remaining: remaining - n,
+                    })?;
+                }
+            }
+        }
+    }
+
+    /// check the new image against the CRC in the header and drop the progress record.
+    /// fails with `Corrupt` if the patch is incomplete or the CRC does not match.
+    pub fn finish(&mut self) -> Result<PatchHeader, W25QError> {
+        // a resumed patch can end in a copy with no patch data left to send
+        self.write(&[])?;
+        let Some(header) = self.header.filter(|_| self.is_complete()) else {
+            return Err(W25QError::Corrupt);
+        };
+        let mut crc = Crc32::new();
+        self.hash_range(self.new.start, header.new_length, |data| crc.update(data))?;
+        if crc.finalize() != header.new_crc32 {
+            return Err(W25QError::Corrupt);
+        }
+        self.reset()?;
+        Ok(header)
+    }
+
+    /// check the new image against the SHA-256 in `header` with `D`, e.g. `sha2::Sha256`.
+    /// fails with `Corrupt` if the patch has no digest or it does not match.
+    #[cfg(feature = "digest")]
+    pub fn verify_digest<D: digest::Digest>(
+        &mut self,
+        header: &PatchHeader,
+    ) -> Result<(), W25QError> {
+        let expected = header.sha256.ok_or(W25QError::Corrupt)?;
+        let mut hasher = D::new();
+        self.hash_range(self.new.start, header.new_length, |data| {
+            hasher.update(data)
+        })?;
+        if hasher.finalize()[..] != expected[..] {
+            return Err(W25QError::Corrupt);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use core::ops::Range;
+use std::vec::Vec;
+
+#[cfg(feature = "littlefs2")]
+use embedded_io::ErrorKind;
+#[cfg(feature = "littlefs2")]
+use embedded_storage::nor_flash::NorFlash;
+#[cfg(feature = "littlefs2")]
+use littlefs2::{fs::Filesystem, io, path::Path};
+
+use crate::{
+    checksum::Crc32,
+    io::W25QError,
+    kv::KvStore,
+    partition::{Partition, PartitionTable},
+    sim::{NoDelay, SimFlash},
+    slots::ImageHeader,
+    Chip, W25Q,
+};
+#[cfg(feature = "littlefs2")]
+use crate::{littlefs::LfsStorage, SECTOR_SIZE};
+
+/// builds a byte exact image of a chip on the host.
+///
+/// every step goes through the driver on a [`SimFlash`], with the partition table, KV store,
+/// firmware header and littlefs code the firmware runs, so the image reads back on the device
+/// exactly as if it had been written there. [`ImageBuilder::into_data`] returns the whole chip.
+pub struct ImageBuilder {
+    dev: W25Q<SimFlash, NoDelay>,
+}
+
+impl ImageBuilder {
+    /// blank `chip`, all bytes 0xFF.
+    pub fn new(chip: Chip) -> Result<Self, W25QError> {
+        Ok(Self {
+            dev: SimFlash::new(chip).into_device()?,
+        })
+    }
+
+    /// start from an existing image of `chip`, e.g. to add to it.
+    pub fn from_image(chip: Chip, image: &[u8]) -> Result<Self, W25QError> {
+        Ok(Self {
+            dev: SimFlash::from_image(chip, image)?.into_device()?,
+        })
+    }
+
+    /// the driver, for layers the builder does not wrap.
+    pub fn device_mut(&mut self) -> &mut W25Q<SimFlash, NoDelay> {
+        &mut self.dev
+    }
+
+    /// write `table` at `address`.
+    pub fn partition_table(
+        &mut self,
+        table: &PartitionTable,
+        address: u32,
+    ) -> Result<(), W25QError> {
+        table.write(&mut self.dev, address)
+    }
+
+    /// write `data` at the sector aligned `address`. the rest of its last sector is kept.
+    pub fn raw(&mut self, address: u32, mut data: &[u8]) -> Result<(), W25QError> {
+        self.dev.sync_image(address, &mut data)?;
+        Ok(())
+    }
+
+    /// write a firmware image as a slot holds it: an [`ImageHeader`] for `payload`, then the
+    /// payload.
+    pub fn firmware(
+        &mut self,
+        address: u32,
+        version: u32,
+        payload: &[u8],
+        sha256: Option<[u8; 32]>,
+    ) -> Result<ImageHeader, W25QError> {
+        let length = u32::try_from(payload.len()).map_err(|_| W25QError::OutOfBounds)?;
+        let header = ImageHeader {
+            version,
+            length,
+            crc32: Crc32::checksum(payload),
+            sha256,
+        };
+        let mut image = Vec::with_capacity(header.to_bytes().len() + payload.len());
+        image.extend_from_slice(&header.to_bytes());
+        image.extend_from_slice(payload);
+        self.raw(address, &image)?;
+        Ok(header)
+    }
+
+    /// format a [`KvStore`] in the sector aligned `range` and store `entries` in order.
+    pub fn kv<K, V>(&mut self, range: Range<u32>, entries: &[(K, V)]) -> Result<(), W25QError>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        if range.start > range.end {
+            return Err(W25QError::OutOfBounds);
+        }
+        let size = range.end - range.start;
+        let partition = Partition::new(&mut self.dev, range.start, size)?;
+        let mut store = KvStore::new(partition, 0..size)?;
+        store.format()?;
+        for (key, value) in entries {
+            store.set(key.as_ref(), value.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// format a littlefs file system in the sector aligned `range` and write `files`, each an
+    /// absolute ASCII path such as `/etc/wifi.conf` and its contents. missing directories are
+    /// created. the firmware mounts it with an [`LfsStorage`] of as many blocks as the range
+    /// has sectors, which must be a power of two from 2 to 16384.
+    #[cfg(feature = "littlefs2")]
+    pub fn littlefs<P, D>(&mut self, range: Range<u32>, files: &[(P, D)]) -> Result<(), W25QError>
+    where
+        P: AsRef<str>,
+        D: AsRef<[u8]>,
+    {
+        if range.start > range.end {
+            return Err(W25QError::OutOfBounds);
+        }
+        if files
+            .iter()
+            .any(|(path, _)| lfs_path(path.as_ref()).is_err())
+        {
+            return Err(W25QError::Io(ErrorKind::InvalidInput));
+        }
+        let size = range.end - range.start;
+        if !(size as usize).is_multiple_of(SECTOR_SIZE) {
+            return Err(W25QError::NotAligned);
+        }
+        let partition = Partition::new(&mut self.dev, range.start, size)?;
+        macro_rules! blocks {
+            ($($blocks:literal)*) => {
+                match size as usize / SECTOR_SIZE {
+                    $($blocks => write_littlefs::<_, _, _, $blocks>(partition, files),)*
+                    _ => Err(W25QError::InvalidConfig),
+                }
+            };
+        }
+        blocks!(2 4 8 16 32 64 128 256 512 1024 2048 4096 8192 16384)
+    }
+
+    /// contents of the whole chip
+    pub fn data(&self) -> &[u8] {
+        self.dev.periph.data()
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.dev.periph.into_data()
+    }
+}
+
+/// `path` with the NUL littlefs wants, if it is absolute, ASCII and short enough.
+#[cfg(feature = "littlefs2")]
+fn lfs_path(path: &str) -> Result<Vec<u8>, W25QError> {
+    let mut bytes = Vec::with_capacity(path.len() + 1);
+    bytes.extend_from_slice(path.as_bytes());
+    bytes.push(0);
+    if !path.starts_with('/') || Path::from_bytes_with_nul(&bytes).is_err() {
+        return Err(W25QError::Io(ErrorKind::InvalidInput));
+    }
+    Ok(bytes)
+}
+
+#[cfg(feature = "littlefs2")]
+fn write_littlefs<F, P, D, const BLOCK_COUNT: usize>(
+    flash: F,
+    files: &[(P, D)],
+) -> Result<(), W25QError>
+where
+    F: NorFlash,
+    P: AsRef<str>,
+    D: AsRef<[u8]>,
+{
+    let mut storage = LfsStorage::<_, BLOCK_COUNT>::new(flash)?;
+    Filesystem::format(&mut storage).map_err(lfs_error)?;
+    Filesystem::mount_and_then(&mut storage, |fs| {
+        for (path, data) in files {
+            let path = path.as_ref();
+            // every parent, e.g. `/etc` for `/etc/wifi.conf`
+            for (end, _) in path.match_indices('/').skip(1) {
+                let dir = lfs_path(&path[..end]).map_err(|_| io::Error::Invalid)?;
+                match fs.create_dir(Path::from_bytes_with_nul(&dir)?) {
+                    Ok(()) | Err(io::Error::EntryAlreadyExisted) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            let file = lfs_path(path).map_err(|_| io::Error::Invalid)?;
+            fs.write(Path::from_bytes_with_nul(&file)?, data.as_ref())?;
+        }
+        Ok(())
+    })
+    .map_err(lfs_error)
+}
+
+#[cfg(feature = "littlefs2")]
+fn lfs_error(e: io::Error) -> W25QError {
+    match e {
+        io::Error::NoSpace => W25QError::NoSpace,
+        io::Error::Corruption => W25QError::Corrupt,
+        _ => W25QError::Io(ErrorKind::Other),
+    }
+}
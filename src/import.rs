@@ -0,0 +1,380 @@
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::{io::W25QError, PAGE_SIZE, SECTOR_SIZE};
+
+/// size of a UF2 block
+pub const UF2_BLOCK_SIZE: usize = 512;
+
+const UF2_MAGIC_START0: u32 = 0x0A32_4655;
+const UF2_MAGIC_START1: u32 = 0x9E5D_5157;
+const UF2_MAGIC_END: u32 = 0x0AB1_6F30;
+/// the block is not for the main flash, e.g. a comment
+const UF2_FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+/// the file size field holds a family ID
+const UF2_FLAG_FAMILY_ID: u32 = 0x0000_2000;
+const UF2_MAX_PAYLOAD: usize = 476;
+
+/// image file formats [`ImageImporter`] reads.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    IntelHex,
+    /// Motorola S-record
+    Srec,
+    Uf2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Text {
+    /// between records
+    Start,
+    /// after the `S` of an S-record
+    Type,
+    /// hex digits of the record
+    Body,
+}
+
+/// streaming Intel HEX, S-record and UF2 import into a flash device.
+///
+/// feed the file in pieces of any size to [`ImageImporter::write`]; no allocation, the
+/// importer holds one record and a one sector write-back cache. every touched sector is
+/// read, merged, erased and programmed page by page, so bytes the file does not cover are
+/// kept. file addresses are taken relative to the base given to [`ImageImporter::new`].
+pub struct ImageImporter<F> {
+    flash: F,
+    format: Format,
+    /// file address of flash address 0
+    base: u32,
+    cache: [u8; SECTOR_SIZE],
+    /// sector held in `cache`
+    cached: Option<u32>,
+    /// `cache` differs from the flash
+    dirty: bool,
+    /// decoded bytes of the current record, or the current UF2 block
+    record: [u8; UF2_BLOCK_SIZE],
+    len: usize,
+    text: Text,
+    /// high nibble of a half read byte
+    nibble: Option<u8>,
+    srec_type: u8,
+    /// Intel HEX extended segment or linear address
+    upper: u32,
+    /// records or UF2 blocks taken, for error reports
+    records: u32,
+    /// family of the UF2 blocks written, `Some(None)` for blocks without one. taken from the
+    /// first block unless set.
+    family: Option<Option<u32>>,
+    /// UF2 blocks of that family in the file, from its first block
+    uf2_blocks: Option<u32>,
+    /// UF2 blocks of that family taken
+    uf2_taken: u32,
+    /// the end record has been seen
+    done: bool,
+    bytes: u32,
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn word(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+impl<F> ImageImporter<F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    /// import a `format` file whose address `base` lands at flash address 0.
+    pub fn new(flash: F, format: Format, base: u32) -> Result<Self, W25QError> {
+        if !SECTOR_SIZE.is_multiple_of(F::ERASE_SIZE)
+            || !PAGE_SIZE.is_multiple_of(F::WRITE_SIZE)
+            || !SECTOR_SIZE.is_multiple_of(F::READ_SIZE)
+        {
+            return Err(W25QError::NotAligned);
+        }
+        Ok(Self {
+            flash,
+            format,
+            base,
+            cache: [0xFF; SECTOR_SIZE],
+            cached: None,
+            dirty: false,
+            record: [0; UF2_BLOCK_SIZE],
+            len: 0,
+            text: Text::Start,
+            nibble: None,
+            srec_type: 0,
+            upper: 0,
+            records: 0,
+            family: None,
+            uf2_blocks: None,
+            uf2_taken: 0,
+            done: false,
+            bytes: 0,
+        })
+    }
+
+    /// write only UF2 blocks tagged with `family`, e.g. 0xE48BFF56 for the RP2040. without
+    /// one, the family of the first block is written; a file can hold several.
+    pub fn set_uf2_family(&mut self, family: Option<u32>) {
+        self.family = family.map(Some);
+    }
+
+    /// the flash device. call [`ImageImporter::finish`] first, it does not see cached writes.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// data bytes taken from the file so far
+    pub fn bytes(&self) -> u32 {
+        self.bytes
+    }
+
+    /// the end of the file has been seen: the Intel HEX end of file record, an S7, S8 or S9
+    /// termination record or the last UF2 block of the family.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn bad_record(&self) -> W25QError {
+        W25QError::BadRecord {
+            record: self.records + 1,
+        }
+    }
+
+    /// take the next part of the file.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), W25QError> {
+        match self.format {
+            Format::Uf2 => self.write_uf2(data),
+            Format::IntelHex | Format::Srec => {
+                for c in data {
+                    self.text_byte(*c)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// write back the cached sector and check that the whole file arrived. fails with
+    /// `BadRecord` if the end record is missing.
+    pub fn finish(&mut self) -> Result<(), W25QError> {
+        // the last record may lack a line break
+        if self.text == Text::Body {
+            self.end_record()?;
+        }
+        self.flush()?;
+        if !self.done || self.len != 0 {
+            return Err(self.bad_record());
+        }
+        Ok(())
+    }
+
+    fn text_byte(&mut self, c: u8) -> Result<(), W25QError> {
+        match (self.text, c) {
+            (Text::Start, b' ' | b'\t' | b'\r' | b'\n') => {}
+            (Text::Start, _) if self.done => return Err(self.bad_record()),
+            (Text::Start, b':') if self.format == Format::IntelHex => self.text = Text::Body,
+            (Text::Start, b'S' | b's') if self.format == Format::Srec => self.text = Text::Type,
+            (Text::Type, b'0'..=b'9') => {
+                self.srec_type = c - b'0';
+                self.text = Text::Body;
+            }
+            (Text::Body, b'\r' | b'\n') => self.end_record()?,
+            (Text::Body, _) => {
+                let Some(digit) = hex_digit(c) else {
+                    return Err(self.bad_record());
+                };
+                match self.nibble.take() {
+                    None => self.nibble = Some(digit),
+                    Some(high) => {
+                        if self.len == self.record.len() {
+                            return Err(self.bad_record());
+                        }
+                        self.record[self.len] = high << 4 | digit;
+                        self.len += 1;
+                    }
+                }
+            }
+            _ => return Err(self.bad_record()),
+        }
+        Ok(())
+    }
+
+    fn end_record(&mut self) -> Result<(), W25QError> {
+        if self.nibble.is_some() {
+            return Err(self.bad_record());
+        }
+        match self.format {
+            Format::IntelHex => self.hex_record()?,
+            _ => self.srec_record()?,
+        }
+        self.records += 1;
+        self.len = 0;
+        self.text = Text::Start;
+        Ok(())
+    }
+
+    /// `:` count, address, type, data, checksum; all bytes sum to 0.
+    fn hex_record(&mut self) -> Result<(), W25QError> {
+        let record = &self.record[..self.len];
+        if record.len() < 5
+            || record.len() != record[0] as usize + 5
+            || record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0
+        {
+            return Err(self.bad_record());
+        }
+        let address = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let data = 4..record.len() - 1;
+        let value = || u16::from_be_bytes([record[4], record[5]]) as u32;
+        match record[3] {
+            0x00 => {
+                let address = self.upper.wrapping_add(address);
+                self.write_data(address, data)?;
+            }
+            0x01 => self.done = true,
+            0x02 if data.len() == 2 => self.upper = value() << 4,
+            0x04 if data.len() == 2 => self.upper = value() << 16,
+            // start addresses do not go to the flash
+            0x03 | 0x05 => {}
+            _ => return Err(self.bad_record()),
+        }
+        Ok(())
+    }
+
+    /// `S` type, count, address, data, checksum; count to checksum sum to 0xFF.
+    fn srec_record(&mut self) -> Result<(), W25QError> {
+        let record = &self.record[..self.len];
+        let address_len = match self.srec_type {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            _ => return Err(self.bad_record()),
+        };
+        if record.len() < address_len + 2
+            || record.len() != record[0] as usize + 1
+            || record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xFF
+        {
+            return Err(self.bad_record());
+        }
+        let address = record[1..1 + address_len]
+            .iter()
+            .fold(0u32, |address, b| address << 8 | *b as u32);
+        match self.srec_type {
+            1..=3 => self.write_data(address, 1 + address_len..record.len() - 1)?,
+            7..=9 => self.done = true,
+            // header and record counts
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn write_uf2(&mut self, mut data: &[u8]) -> Result<(), W25QError> {
+        while !data.is_empty() {
+            let n = (UF2_BLOCK_SIZE - self.len).min(data.len());
+            self.record[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+            if self.len == UF2_BLOCK_SIZE {
+                self.uf2_block()?;
+                self.records += 1;
+                self.len = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn uf2_block(&mut self) -> Result<(), W25QError> {
+        let block = &self.record;
+        let flags = word(block, 8);
+        let address = word(block, 12);
+        let size = word(block, 16) as usize;
+        let number = word(block, 20);
+        let count = word(block, 24);
+        if word(block, 0) != UF2_MAGIC_START0
+            || word(block, 4) != UF2_MAGIC_START1
+            || word(block, UF2_BLOCK_SIZE - 4) != UF2_MAGIC_END
+            || size > UF2_MAX_PAYLOAD
+        {
+            return Err(self.bad_record());
+        }
+        // each family's blocks are numbered on their own
+        let family = (flags & UF2_FLAG_FAMILY_ID != 0).then(|| word(block, 28));
+        if *self.family.get_or_insert(family) != family {
+            return Ok(());
+        }
+        if self.done || number >= count || self.uf2_blocks.is_some_and(|blocks| blocks != count) {
+            return Err(self.bad_record());
+        }
+        self.uf2_blocks = Some(count);
+        self.uf2_taken += 1;
+        if flags & UF2_FLAG_NOT_MAIN_FLASH == 0 {
+            self.write_data(address, 32..32 + size)?;
+        }
+        if self.uf2_taken == count {
+            self.done = true;
+        }
+        Ok(())
+    }
+
+    /// write `range` of the record buffer at file address `address`.
+    fn write_data(
+        &mut self,
+        address: u32,
+        range: core::ops::Range<usize>,
+    ) -> Result<(), W25QError> {
+        let Some(start) = address.checked_sub(self.base) else {
+            return Err(W25QError::OutOfBounds);
+        };
+        if start as u64 + range.len() as u64 > self.flash.capacity() as u64 {
+            return Err(W25QError::OutOfBounds);
+        }
+        let mut address = start as usize;
+        let mut at = range.start;
+        while at < range.end {
+            let sector = (address / SECTOR_SIZE) as u32;
+            if self.cached != Some(sector) {
+                self.flush()?;
+                self.flash
+                    .read(sector * SECTOR_SIZE as u32, &mut self.cache)?;
+                self.cached = Some(sector);
+            }
+            let offset = address % SECTOR_SIZE;
+            let n = (SECTOR_SIZE - offset).min(range.end - at);
+            let cached = &mut self.cache[offset..offset + n];
+            if cached != &self.record[at..at + n] {
+                cached.copy_from_slice(&self.record[at..at + n]);
+                self.dirty = true;
+            }
+            address += n;
+            at += n;
+        }
+        self.bytes += range.len() as u32;
+        Ok(())
+    }
+
+    /// write the cached sector back if it changed.
+    fn flush(&mut self) -> Result<(), W25QError> {
+        let Some(sector) = self.cached else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        let address = sector * SECTOR_SIZE as u32;
+        self.flash.erase(address, address + SECTOR_SIZE as u32)?;
+        for (i, page) in self.cache.chunks(PAGE_SIZE).enumerate() {
+            if page.iter().any(|b| *b != 0xFF) {
+                self.flash.write(address + (i * PAGE_SIZE) as u32, page)?;
+            }
+        }
+        self.dirty = false;
+        Ok(())
+    }
+}
@@ -2,7 +2,6 @@ use crate::W25Q;
 
 use embedded_hal::delay;
 use embedded_hal::spi;
-use embedded_hal::spi::Error;
 use embedded_io::{BufRead, ErrorType, Read, ReadReady, Seek, SeekFrom, Write, WriteReady};
 
 use embedded_io::ErrorKind;
@@ -11,12 +10,54 @@ use embedded_io::ErrorKind;
 pub enum W25QError {
     Io(ErrorKind),
     Spi(spi::ErrorKind),
+    /// the chip answered with an unexpected JEDEC ID
+    UnexpectedId([u8; 3]),
+    /// the chip stayed busy longer than the configured timeout
+    Timeout,
+    /// the configuration does not fit the detected chip
+    InvalidConfig,
+    /// read back data differs from what was written
+    VerifyFailed {
+        addr: u32,
+    },
+    /// the access is outside the chip or partition
+    OutOfBounds,
+    /// the access is not aligned to the erase size
+    NotAligned,
+    /// on-flash data failed its magic or CRC check
+    Corrupt,
+    /// not enough free space, e.g. no replacement block left
+    NoSpace,
+    /// the chip reported a program failure
+    ProgramFailed,
+    /// the chip reported an erase failure
+    EraseFailed,
+    /// the internal ECC could not correct a NAND page
+    EccUncorrectable {
+        page: u32,
+    },
+    /// an image file record is malformed or fails its checksum, counted from 1
+    BadRecord {
+        record: u32,
+    },
 }
 
 impl embedded_io::Error for W25QError {
     fn kind(&self) -> ErrorKind {
         match self {
             W25QError::Io(kind) => *kind,
+            W25QError::UnexpectedId(_) => ErrorKind::NotConnected,
+            W25QError::Timeout => ErrorKind::TimedOut,
+            W25QError::InvalidConfig => ErrorKind::InvalidInput,
+            W25QError::VerifyFailed { .. } => ErrorKind::InvalidData,
+            W25QError::OutOfBounds => ErrorKind::InvalidInput,
+            W25QError::NotAligned => ErrorKind::InvalidInput,
+            W25QError::Corrupt => ErrorKind::InvalidData,
+            W25QError::NoSpace => ErrorKind::OutOfMemory,
+            W25QError::ProgramFailed => ErrorKind::Other,
+            W25QError::EraseFailed => ErrorKind::Other,
+            W25QError::EccUncorrectable { .. } => ErrorKind::InvalidData,
+            W25QError::BadRecord { .. } => ErrorKind::InvalidData,
             W25QError::Spi(e) => match e {
                 spi::ErrorKind::Overrun => ErrorKind::OutOfMemory,
                 spi::ErrorKind::ModeFault => ErrorKind::PermissionDenied,
@@ -29,9 +70,9 @@ impl embedded_io::Error for W25QError {
     }
 }
 
-impl From<spi::ErrorKind> for W25QError {
-    fn from(e: spi::ErrorKind) -> Self {
-        match e {
+impl<E: spi::Error> From<E> for W25QError {
+    fn from(e: E) -> Self {
+        match e.kind() {
             spi::ErrorKind::Overrun => W25QError::Spi(spi::ErrorKind::Overrun),
             spi::ErrorKind::ModeFault => W25QError::Spi(spi::ErrorKind::ModeFault),
             spi::ErrorKind::FrameFormat => W25QError::Spi(spi::ErrorKind::FrameFormat),
@@ -57,8 +98,8 @@ where
 {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, W25QError> {
         let address = self.seek_ptr as u32;
-        self.fast_read(address, buf)
-            .map_err(|e| W25QError::Spi(e.kind()))?;
+        self.invalidate_buffer();
+        self.read_at(address, buf)?;
         self.seek_ptr += buf.len();
         Ok(buf.len())
     }
@@ -71,8 +112,7 @@ where
 {
     fn write(&mut self, buf: &[u8]) -> Result<usize, W25QError> {
         let address = self.seek_ptr as u32;
-        self.page_program(address, buf)
-            .map_err(|e| W25QError::Spi(e.kind()))?;
+        self.program(address, buf)?;
         self.seek_ptr += buf.len();
         Ok(buf.len())
     }
@@ -88,6 +128,7 @@ where
     DELAY: delay::DelayNs,
 {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64, W25QError> {
+        self.invalidate_buffer();
         let seeked: u64;
         match pos {
             SeekFrom::Start(pos) => {
@@ -98,19 +139,19 @@ where
                 self.seek_ptr = seeked as usize;
             }
             SeekFrom::End(pos) => {
-                if pos > 0 {
+                if pos > 0 || pos.unsigned_abs() > self.capacity() {
                     return Err(W25QError::Io(ErrorKind::InvalidInput));
                 }
-                seeked = self.capacity() - pos.abs() as u64;
+                seeked = self.capacity() - pos.unsigned_abs();
                 self.seek_ptr = seeked as usize;
             }
             SeekFrom::Current(pos) => {
-                if (self.seek_ptr as i64 + pos) < 0
-                    || (self.seek_ptr as i64 + pos) as u64 > self.capacity()
-                {
-                    return Err(W25QError::Io(ErrorKind::InvalidInput));
+                match (self.seek_ptr as i64).checked_add(pos) {
+                    Some(target) if target >= 0 && target as u64 <= self.capacity() => {
+                        seeked = target as u64;
+                    }
+                    _ => return Err(W25QError::Io(ErrorKind::InvalidInput)),
                 }
-                seeked = (self.seek_ptr as i64 + pos) as u64;
                 self.seek_ptr = seeked as usize;
             }
         }
@@ -146,8 +187,7 @@ where
     fn fill_buf(&mut self) -> Result<&[u8], Self::Error> {
         if self.buffer_start >= self.buffer_end {
             let page_start = (self.seek_ptr / crate::PAGE_SIZE) * crate::PAGE_SIZE;
-            self.fast_read_into_internal_buffer(page_start as u32)
-                .map_err(|e| W25QError::Spi(e.kind()))?;
+            self.read_into_internal_buffer(page_start as u32)?;
             self.buffer_start = self.seek_ptr - page_start;
             self.buffer_end = crate::PAGE_SIZE;
         }
@@ -160,3 +200,142 @@ where
         self.seek_ptr += amt;
     }
 }
+
+/// read-ahead buffered reader borrowing a [`W25Q`].
+///
+/// refills `N` bytes per read command, so larger buffers trade RAM for fewer transactions.
+/// reads at least `N` long bypass the buffer. the reader starts at the device seek pointer
+/// but keeps its own position afterwards.
+pub struct BufferedReader<'a, SPI, DELAY, const N: usize>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    dev: &'a mut W25Q<SPI, DELAY>,
+    buffer: [u8; N],
+    /// flash address of `buffer[0]`
+    buffer_addr: u64,
+    /// buffer start index
+    buffer_start: usize,
+    /// buffer end index
+    buffer_end: usize,
+    /// flash address of the next byte returned
+    pos: u64,
+}
+
+impl<'a, SPI, DELAY, const N: usize> BufferedReader<'a, SPI, DELAY, N>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    pub fn new(dev: &'a mut W25Q<SPI, DELAY>) -> Self {
+        let pos = dev.seek_ptr as u64;
+        Self {
+            dev,
+            buffer: [0; N],
+            buffer_addr: pos,
+            buffer_start: 0,
+            buffer_end: 0,
+            pos,
+        }
+    }
+
+    /// drop buffered data, e.g. after the flash was written through another path.
+    pub fn invalidate(&mut self) {
+        self.buffer_start = 0;
+        self.buffer_end = 0;
+    }
+
+    /// current flash address
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+}
+
+impl<SPI, DELAY, const N: usize> ErrorType for BufferedReader<'_, SPI, DELAY, N>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    type Error = W25QError;
+}
+
+impl<SPI, DELAY, const N: usize> BufRead for BufferedReader<'_, SPI, DELAY, N>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    fn fill_buf(&mut self) -> Result<&[u8], Self::Error> {
+        if self.buffer_start >= self.buffer_end {
+            let len = (self.dev.capacity() - self.pos).min(N as u64) as usize;
+            self.dev.read_at(self.pos as u32, &mut self.buffer[..len])?;
+            self.buffer_addr = self.pos;
+            self.buffer_start = 0;
+            self.buffer_end = len;
+        }
+        Ok(&self.buffer[self.buffer_start..self.buffer_end])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = amt.min(self.buffer_end - self.buffer_start);
+        self.buffer_start += amt;
+        self.pos += amt as u64;
+    }
+}
+
+impl<SPI, DELAY, const N: usize> Read for BufferedReader<'_, SPI, DELAY, N>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, W25QError> {
+        if self.buffer_start >= self.buffer_end && buf.len() >= N {
+            let len = (self.dev.capacity() - self.pos).min(buf.len() as u64) as usize;
+            self.dev.read_at(self.pos as u32, &mut buf[..len])?;
+            self.pos += len as u64;
+            return Ok(len);
+        }
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<SPI, DELAY, const N: usize> ReadReady for BufferedReader<'_, SPI, DELAY, N>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    fn read_ready(&mut self) -> Result<bool, W25QError> {
+        Ok(true)
+    }
+}
+
+impl<SPI, DELAY, const N: usize> Seek for BufferedReader<'_, SPI, DELAY, N>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, W25QError> {
+        let target = match pos {
+            SeekFrom::Start(pos) => i64::try_from(pos).ok(),
+            SeekFrom::End(pos) => (self.dev.capacity() as i64).checked_add(pos),
+            SeekFrom::Current(pos) => (self.pos as i64).checked_add(pos),
+        };
+        let target = match target {
+            Some(target) if target >= 0 && target as u64 <= self.dev.capacity() => target as u64,
+            _ => return Err(W25QError::Io(ErrorKind::InvalidInput)),
+        };
+        // keep the buffer if the target is inside it
+        let buffered = self.buffer_addr..self.buffer_addr + self.buffer_end as u64;
+        if self.buffer_end != 0 && buffered.contains(&target) {
+            self.buffer_start = (target - self.buffer_addr) as usize;
+        } else {
+            self.invalidate();
+        }
+        self.pos = target;
+        Ok(target)
+    }
+}
@@ -0,0 +1,498 @@
+use core::ops::Range;
+
+use embedded_storage::nor_flash::MultiwriteNorFlash;
+
+use crate::{
+    checksum::{Crc32, Hasher},
+    io::W25QError,
+    sector::{self, ALIGN, CHUNK, SECTOR_HEADER_SIZE},
+    SECTOR_SIZE,
+};
+
+/// longest key accepted by [`KvStore`]
+pub const MAX_KEY_LEN: usize = 64;
+
+const SECTOR_MAGIC: [u8; 4] = *b"W25K";
+const RECORD_HEADER_SIZE: usize = 8;
+
+const KIND_VALUE: u8 = 1;
+const KIND_TOMBSTONE: u8 = 2;
+
+/// a record found in a sector
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Record {
+    sector: u32,
+    /// offset in the sector
+    offset: u32,
+    key_len: usize,
+    kind: u8,
+    value_len: usize,
+}
+
+impl Record {
+    /// size on flash, including padding
+    fn size(&self) -> u32 {
+        record_size(self.key_len, self.value_len)
+    }
+}
+
+fn record_size(key_len: usize, value_len: usize) -> u32 {
+    (RECORD_HEADER_SIZE + key_len + value_len).next_multiple_of(ALIGN) as u32
+}
+
+enum Scan {
+    /// erased space, the sector can take more records
+    End,
+    /// a record that failed its checks, nothing can be appended after it
+    Torn,
+    Record(Record),
+}
+
+/// log structured key-value store in a range of sectors.
+///
+/// `set` and `delete` append a record with a CRC to the newest sector; a record that was cut
+/// short by a power loss fails its CRC and is ignored. when the last free sector is opened,
+/// the live entries of the oldest sector are copied forward and that sector is erased, so one
+/// sector is always kept free. every step is ordered so that a power loss at any point leaves
+/// the store with either the old or the new value of the key being written.
+///
+/// invalidating a sector before erasing it reprograms its header, so the flash must allow
+/// writing already written words.
+pub struct KvStore<F> {
+    flash: F,
+    /// start of the range on the device
+    offset: u32,
+    sectors: u32,
+    /// sector taking new records
+    head: u32,
+    head_seq: u32,
+    /// sectors holding records, ending at `head`
+    active: u32,
+    /// next free offset in the head sector
+    write_offset: u32,
+}
+
+impl<F> KvStore<F>
+where
+    F: MultiwriteNorFlash,
+    W25QError: From<F::Error>,
+{
+    /// open the store in the sector aligned `range`, finishing any operation a power loss
+    /// interrupted. an empty range is initialized.
+    pub fn new(flash: F, range: Range<u32>) -> Result<Self, W25QError> {
+        if range.start > range.end {
+            return Err(W25QError::OutOfBounds);
+        }
+        if !(range.start as usize).is_multiple_of(SECTOR_SIZE)
+            || !(range.end as usize).is_multiple_of(SECTOR_SIZE)
+            || !SECTOR_SIZE.is_multiple_of(F::ERASE_SIZE)
+            || !ALIGN.is_multiple_of(F::WRITE_SIZE)
+            || F::READ_SIZE != 1
+        {
+            return Err(W25QError::NotAligned);
+        }
+        if range.end as u64 > flash.capacity() as u64 {
+            return Err(W25QError::OutOfBounds);
+        }
+        let sectors = (range.end - range.start) / SECTOR_SIZE as u32;
+        if sectors < 2 {
+            return Err(W25QError::InvalidConfig);
+        }
+        let mut store = Self {
+            flash,
+            offset: range.start,
+            sectors,
+            head: 0,
+            head_seq: 0,
+            active: 0,
+            write_offset: SECTOR_HEADER_SIZE,
+        };
+        store.mount()?;
+        Ok(store)
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// erase every entry.
+    pub fn format(&mut self) -> Result<(), W25QError> {
+        let end = self.offset + self.sectors * SECTOR_SIZE as u32;
+        self.flash.erase(self.offset, end)?;
+        self.active = 0;
+        self.head = self.sectors - 1;
+        self.open_head()
+    }
+
+    fn sector_address(&self, sector: u32) -> u32 {
+        self.offset + sector * SECTOR_SIZE as u32
+    }
+
+    /// `n`th active sector, oldest first.
+    fn nth_active(&self, n: u32) -> u32 {
+        (self.head + self.sectors + 1 - self.active + n) % self.sectors
+    }
+
+    fn read_sector_seq(&mut self, sector: u32) -> Result<Option<u32>, W25QError> {
+        let address = self.sector_address(sector);
+        sector::read_seq(&mut self.flash, address, SECTOR_MAGIC)
+    }
+
+    fn is_erased(&mut self, range: Range<u32>) -> Result<bool, W25QError> {
+        sector::is_erased(&mut self.flash, range)
+    }
+
+    fn erase_sector(&mut self, sector: u32) -> Result<(), W25QError> {
+        let base = self.sector_address(sector);
+        self.flash.erase(base, base + SECTOR_SIZE as u32)?;
+        Ok(())
+    }
+
+    fn mount(&mut self) -> Result<(), W25QError> {
+        let mut newest: Option<(u32, u32)> = None;
+        for sector in 0..self.sectors {
+            if let Some(seq) = self.read_sector_seq(sector)? {
+                if newest.is_none_or(|(_, newest)| seq > newest) {
+                    newest = Some((sector, seq));
+                }
+            }
+        }
+        let Some((head, head_seq)) = newest else {
+            return self.format();
+        };
+        self.head = head;
+        self.head_seq = head_seq;
+        // the active sectors are the run of consecutive sequence numbers ending at the head
+        self.active = 1;
+        while self.active < self.sectors {
+            let sector = (head + self.sectors - self.active) % self.sectors;
+            if self.read_sector_seq(sector)? != Some(head_seq.wrapping_sub(self.active)) {
+                break;
+            }
+            self.active += 1;
+        }
+        // anything else is a sector whose erase or opening was interrupted
+        for n in 0..self.sectors - self.active {
+            let sector = (head + 1 + n) % self.sectors;
+            let base = self.sector_address(sector);
+            if !self.is_erased(base..base + SECTOR_SIZE as u32)? {
+                self.erase_sector(sector)?;
+            }
+        }
+        self.write_offset = SECTOR_HEADER_SIZE;
+        loop {
+            match self.scan(head, self.write_offset)? {
+                Scan::End => {
+                    // a cut program can leave bits set behind an erased record header
+                    let base = self.sector_address(head);
+                    let tail = base + self.write_offset..base + SECTOR_SIZE as u32;
+                    if !self.is_erased(tail)? {
+                        self.write_offset = SECTOR_SIZE as u32;
+                    }
+                    break;
+                }
+                Scan::Torn => {
+                    self.write_offset = SECTOR_SIZE as u32;
+                    break;
+                }
+                Scan::Record(record) => self.write_offset += record.size(),
+            }
+        }
+        // the live records of the oldest sector were already copied to the head, but its
+        // erase was interrupted
+        if self.active == self.sectors {
+            self.retire_oldest()?;
+        }
+        Ok(())
+    }
+
+    /// check the record at `offset` in `sector`, including its CRC.
+    fn scan(&mut self, sector: u32, offset: u32) -> Result<Scan, W25QError> {
+        if offset + RECORD_HEADER_SIZE as u32 > SECTOR_SIZE as u32 {
+            return Ok(Scan::End);
+        }
+        let base = self.sector_address(sector) + offset;
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        self.flash.read(base, &mut header)?;
+        if header.iter().all(|b| *b == 0xFF) {
+            return Ok(Scan::End);
+        }
+        let record = Record {
+            sector,
+            offset,
+            key_len: header[0] as usize,
+            kind: header[1],
+            value_len: u16::from_le_bytes([header[2], header[3]]) as usize,
+        };
+        if record.key_len == 0
+            || record.key_len > MAX_KEY_LEN
+            || !matches!(record.kind, KIND_VALUE | KIND_TOMBSTONE)
+            || offset + record.size() > SECTOR_SIZE as u32
+        {
+            return Ok(Scan::Torn);
+        }
+        let mut crc = Crc32::new();
+        crc.update(&header[..4]);
+        let mut chunk = [0u8; CHUNK];
+        let body = (record.key_len + record.value_len) as u32;
+        let body_start = base + RECORD_HEADER_SIZE as u32;
+        for at in (0..body).step_by(CHUNK) {
+            let len = (body - at).min(CHUNK as u32) as usize;
+            self.flash.read(body_start + at, &mut chunk[..len])?;
+            crc.update(&chunk[..len]);
+        }
+        if crc.finalize().to_le_bytes() != header[4..8] {
+            return Ok(Scan::Torn);
+        }
+        Ok(Scan::Record(record))
+    }
+
+    /// call `f` for every intact record of `sector`, in write order.
+    fn for_each_record(
+        &mut self,
+        sector: u32,
+        mut f: impl FnMut(&mut Self, Record) -> Result<(), W25QError>,
+    ) -> Result<(), W25QError> {
+        let mut offset = SECTOR_HEADER_SIZE;
+        while let Scan::Record(record) = self.scan(sector, offset)? {
+            f(self, record)?;
+            offset += record.size();
+        }
+        Ok(())
+    }
+
+    fn read_key<'k>(
+        &mut self,
+        record: &Record,
+        buf: &'k mut [u8; MAX_KEY_LEN],
+    ) -> Result<&'k [u8], W25QError> {
+        let address = self.sector_address(record.sector) + record.offset;
+        let key = &mut buf[..record.key_len];
+        self.flash.read(address + RECORD_HEADER_SIZE as u32, key)?;
+        Ok(key)
+    }
+
+    fn read_value(&mut self, record: &Record, buf: &mut [u8]) -> Result<(), W25QError> {
+        let address = self.sector_address(record.sector)
+            + record.offset
+            + (RECORD_HEADER_SIZE + record.key_len) as u32;
+        self.flash.read(address, &mut buf[..record.value_len])?;
+        Ok(())
+    }
+
+    /// newest record for `key`, value or tombstone.
+    fn latest(&mut self, key: &[u8]) -> Result<Option<Record>, W25QError> {
+        let mut buf = [0u8; MAX_KEY_LEN];
+        for n in (0..self.active).rev() {
+            let sector = self.nth_active(n);
+            let mut found = None;
+            self.for_each_record(sector, |store, record| {
+                if record.key_len == key.len() && store.read_key(&record, &mut buf)? == key {
+                    found = Some(record);
+                }
+                Ok(())
+            })?;
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        Ok(None)
+    }
+
+    /// copy the value of `key` into `buf`, returning its length. fails with `OutOfBounds` if
+    /// `buf` is too small.
+    pub fn get(&mut self, key: &[u8], buf: &mut [u8]) -> Result<Option<usize>, W25QError> {
+        match self.latest(key)? {
+            Some(record) if record.kind == KIND_VALUE => {
+                if buf.len() < record.value_len {
+                    return Err(W25QError::OutOfBounds);
+                }
+                self.read_value(&record, buf)?;
+                Ok(Some(record.value_len))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn contains(&mut self, key: &[u8]) -> Result<bool, W25QError> {
+        Ok(matches!(self.latest(key)?, Some(record) if record.kind == KIND_VALUE))
+    }
+
+    /// store `value` under `key`. writing the current value again is a no-op.
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), W25QError> {
+        if let Some(record) = self.latest(key)? {
+            if record.kind == KIND_VALUE
+                && record.value_len == value.len()
+                && self.value_equals(&record, value)?
+            {
+                return Ok(());
+            }
+        }
+        self.append(KIND_VALUE, key, value)
+    }
+
+    /// remove `key`, returning whether it was present.
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool, W25QError> {
+        if !self.contains(key)? {
+            return Ok(false);
+        }
+        self.append(KIND_TOMBSTONE, key, &[])?;
+        Ok(true)
+    }
+
+    /// call `f` with every live key and its value, read into `value_buf`. fails with
+    /// `OutOfBounds` if a value does not fit.
+    pub fn for_each(
+        &mut self,
+        value_buf: &mut [u8],
+        mut f: impl FnMut(&[u8], &[u8]),
+    ) -> Result<(), W25QError> {
+        let mut buf = [0u8; MAX_KEY_LEN];
+        for n in 0..self.active {
+            let sector = self.nth_active(n);
+            self.for_each_record(sector, |store, record| {
+                if record.kind != KIND_VALUE {
+                    return Ok(());
+                }
+                let key = store.read_key(&record, &mut buf)?;
+                if store.latest(key)? != Some(record) {
+                    return Ok(());
+                }
+                if value_buf.len() < record.value_len {
+                    return Err(W25QError::OutOfBounds);
+                }
+                store.read_value(&record, value_buf)?;
+                f(&buf[..record.key_len], &value_buf[..record.value_len]);
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    fn value_equals(&mut self, record: &Record, value: &[u8]) -> Result<bool, W25QError> {
+        let start = self.sector_address(record.sector)
+            + record.offset
+            + (RECORD_HEADER_SIZE + record.key_len) as u32;
+        let mut chunk = [0u8; CHUNK];
+        for (i, expected) in value.chunks(CHUNK).enumerate() {
+            let actual = &mut chunk[..expected.len()];
+            self.flash.read(start + (i * CHUNK) as u32, actual)?;
+            if actual != expected {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn append(&mut self, kind: u8, key: &[u8], value: &[u8]) -> Result<(), W25QError> {
+        if key.is_empty() || key.len() > MAX_KEY_LEN || value.len() > u16::MAX as usize {
+            return Err(W25QError::InvalidConfig);
+        }
+        let size = record_size(key.len(), value.len());
+        if SECTOR_HEADER_SIZE + size > SECTOR_SIZE as u32 {
+            return Err(W25QError::OutOfBounds);
+        }
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        header[0] = key.len() as u8;
+        header[1] = kind;
+        header[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        let mut crc = Crc32::new();
+        crc.update(&header[..4]);
+        crc.update(key);
+        crc.update(value);
+        header[4..8].copy_from_slice(&crc.finalize().to_le_bytes());
+        // each new sector moves the live entries of one old sector, a full store makes no
+        // progress after one round
+        for _ in 0..=self.sectors {
+            if self.write_offset + size <= SECTOR_SIZE as u32 {
+                let address = self.sector_address(self.head) + self.write_offset;
+                if let Err(e) =
+                    sector::write_padded(&mut self.flash, address, &[&header, key, value])
+                {
+                    // a partly written record ends the sector, as on mount
+                    self.write_offset = SECTOR_SIZE as u32;
+                    return Err(e);
+                }
+                self.write_offset += size;
+                return Ok(());
+            }
+            self.open_head()?;
+        }
+        Err(W25QError::NoSpace)
+    }
+
+    /// start a new head in the next free sector. when that is the last free sector, the live
+    /// records of the oldest sector are copied into it before its header is written, and the
+    /// oldest sector is erased afterwards.
+    fn open_head(&mut self) -> Result<(), W25QError> {
+        // finish the retirement of a sector whose invalidation or erase failed
+        if self.active == self.sectors {
+            self.retire_oldest()?;
+        }
+        let sector = (self.head + 1) % self.sectors;
+        // an earlier attempt that failed, e.g. with `NoSpace` while collecting, can have left
+        // copied records behind
+        let base = self.sector_address(sector);
+        if !self.is_erased(base..base + SECTOR_SIZE as u32)? {
+            self.erase_sector(sector)?;
+        }
+        let mut write_offset = SECTOR_HEADER_SIZE;
+        if self.active + 1 == self.sectors {
+            write_offset = self.collect(sector, write_offset)?;
+        }
+        let seq = self.head_seq.wrapping_add(1);
+        sector::write_header(&mut self.flash, base, SECTOR_MAGIC, seq)?;
+        self.head = sector;
+        self.head_seq = seq;
+        self.active += 1;
+        self.write_offset = write_offset;
+        if self.active == self.sectors {
+            self.retire_oldest()?;
+        }
+        Ok(())
+    }
+
+    /// copy the live records of the oldest sector to `sector` from `offset` on, returning
+    /// the offset after the last copy.
+    fn collect(&mut self, sector: u32, mut offset: u32) -> Result<u32, W25QError> {
+        let oldest = self.nth_active(0);
+        let mut buf = [0u8; MAX_KEY_LEN];
+        self.for_each_record(oldest, |store, record| {
+            if record.kind != KIND_VALUE {
+                return Ok(());
+            }
+            let key = store.read_key(&record, &mut buf)?;
+            if store.latest(key)? == Some(record) {
+                offset = store.copy_record(&record, sector, offset)?;
+            }
+            Ok(())
+        })?;
+        Ok(offset)
+    }
+
+    /// invalidate and erase the oldest sector.
+    fn retire_oldest(&mut self) -> Result<(), W25QError> {
+        let oldest = self.nth_active(0);
+        // a cleared magic marks the sector stale even if the erase is cut short
+        self.flash.write(self.sector_address(oldest), &[0u8; 4])?;
+        self.erase_sector(oldest)?;
+        self.active -= 1;
+        Ok(())
+    }
+
+    fn copy_record(&mut self, record: &Record, sector: u32, offset: u32) -> Result<u32, W25QError> {
+        let size = record.size();
+        if offset + size > SECTOR_SIZE as u32 {
+            return Err(W25QError::NoSpace);
+        }
+        let from = self.sector_address(record.sector) + record.offset;
+        let to = self.sector_address(sector) + offset;
+        let mut chunk = [0u8; CHUNK];
+        for at in (0..size).step_by(CHUNK) {
+            let len = (size - at).min(CHUNK as u32) as usize;
+            self.flash.read(from + at, &mut chunk[..len])?;
+            self.flash.write(to + at, &chunk[..len])?;
+        }
+        Ok(offset + size)
+    }
+}
@@ -1,10 +1,43 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 #![deny(unsafe_code)]
 
 #[cfg(feature = "defmt")]
 use defmt::Format;
 
+pub mod array;
+pub mod block;
+#[cfg(feature = "embassy-boot")]
+pub mod boot;
+pub mod checksum;
+pub mod config;
+pub mod delta;
+#[cfg(feature = "std")]
+pub mod image;
+pub mod import;
 pub mod io;
+pub mod kv;
+#[cfg(feature = "littlefs2")]
+pub mod littlefs;
+pub mod partition;
+pub mod ringlog;
+pub mod scsi;
+#[cfg(feature = "embedded-sdmmc")]
+pub mod sdmmc;
+mod sector;
+#[cfg(feature = "embassy-sync")]
+pub mod shared;
+#[cfg(feature = "std")]
+pub mod sim;
+pub mod slots;
+pub mod storage;
+pub mod sync;
+pub mod w25n;
+pub mod wear;
+
+pub use config::{AddressMode, Chip, ReadCommand, W25QConfig, WriteProtection};
+use io::W25QError;
+
+use core::ops::Range;
 
 use embedded_hal::{
     delay::{self, DelayNs},
@@ -19,6 +52,19 @@ pub const PAGE_SIZE: usize = 256;
 pub const PAGE_COUNT: usize = 65536;
 pub const TOTAL_SIZE: usize = SECTOR_COUNT * SECTOR_SIZE;
 
+/// time from power down command (B9h) to entering deep power-down, tDP
+pub const POWER_DOWN_DELAY_US: u32 = 3;
+/// time from release power down command (ABh) to standby, tRES1
+pub const RELEASE_POWER_DOWN_DELAY_US: u32 = 3;
+/// time from reset device command (99h) to the chip accepting commands, tRST
+pub const RESET_DELAY_US: u32 = 30;
+
+/// bytes read per transaction by verify and blank check
+const VERIFY_CHUNK_SIZE: usize = 64;
+
+/// Winbond JEDEC manufacturer ID
+pub const MANUFACTURER_ID: u8 = 0xEF;
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Debug, PartialEq)]
 pub enum SR {
@@ -189,6 +235,37 @@ pub enum Register {
     POWER_DOWN = 0xB9,
     ENABLE_RESET = 0x66,
     RESET_DEVICE = 0x99,
+    ENTER_QPI_MODE = 0x38,
+    EXIT_QPI_MODE = 0xFF,
+    ENTER_4_BYTE_ADDRESS_MODE = 0xB7,
+    EXIT_4_BYTE_ADDRESS_MODE = 0xE9,
+    SOFTWARE_DIE_SELECT = 0xC2,
+}
+
+/// state the chip was found in by [`W25Q::recover`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RecoveryReport {
+    /// the chip did not answer the JEDEC ID command before recovery
+    pub unresponsive: bool,
+    /// the chip answered only after exiting QPI / continuous read mode
+    pub qpi_or_continuous_read: bool,
+    /// the chip answered only after release power down
+    pub powered_down: bool,
+    /// an erase or program was suspended (SUS)
+    pub suspended: bool,
+    /// the chip was in 4-byte address mode (ADS)
+    pub four_byte_address: bool,
+    /// JEDEC ID read after reset
+    pub jedec_id: [u8; 3],
+}
+
+/// power state of the chip, as tracked by the driver.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerState {
+    Active,
+    PoweredDown,
 }
 
 ///  device object.
@@ -200,6 +277,14 @@ where
     pub periph: SPI,
     /// erase and write delays
     pub delay: DELAY,
+    /// timings and behaviour
+    config: W25QConfig,
+    /// detected or configured chip
+    chip: Chip,
+    /// number of address bytes sent with a command
+    address_mode: AddressMode,
+    /// die selected in a multi-die package
+    active_die: u8,
     /// address pointer for seek operations
     seek_ptr: usize,
     /// buffer for read/write operations
@@ -208,6 +293,12 @@ where
     buffer_start: usize,
     /// buffer end index
     buffer_end: usize,
+    /// whether the chip is in deep power-down
+    power_state: PowerState,
+    /// idle time (ms) after which the chip is put into deep power-down
+    auto_power_down: Option<u32>,
+    /// time (ms) since the last access
+    idle_ms: u32,
 }
 
 #[cfg(feature = "defmt")]
@@ -221,11 +312,13 @@ where
 
         defmt::write!(
             f,
-            "W25Q@{:#x}: seek ptr: {:#x}, internal buf={:#x}..{:#x}",
+            "W25Q@{:#x}: {}, seek ptr: {:#x}, internal buf={:#x}..{:#x}, {}",
             addr,
+            self.chip,
             self.seek_ptr,
             self.buffer_start,
             self.buffer_end,
+            self.power_state,
         );
     }
 }
@@ -235,16 +328,107 @@ where
     SPI: SpiDevice,
     DELAY: DelayNs,
 {
+    /// W25Q128 with the default configuration. does not talk to the chip.
     pub fn new_with_spi(spi_dev: SPI, delay: DELAY) -> Self {
+        Self::with_config(spi_dev, delay, W25QConfig::new(), Chip::W25Q128)
+    }
+
+    /// create the driver and check the chip against `config`.
+    ///
+    /// reads the JEDEC ID, detects the chip (or checks it against the configured one),
+    /// selects the address mode and applies the write protection policy.
+    pub fn new(spi_dev: SPI, delay: DELAY, config: W25QConfig) -> Result<Self, W25QError> {
+        let chip = config.chip.unwrap_or(Chip::W25Q128);
+        let mut dev = Self::with_config(spi_dev, delay, config, chip);
+
+        let id = dev.read_jedec_id()?;
+        let found = Chip::from_jedec_id(id).ok_or(W25QError::UnexpectedId(id))?;
+        if config.chip.is_some_and(|chip| chip != found) {
+            return Err(W25QError::UnexpectedId(id));
+        }
+        dev.chip = found;
+
+        // 4-byte addressing needs a part supporting it. 3-byte addressing on a larger part
+        // limits the driver to the first 16MiB, see `capacity`.
+        let mode = config.address_mode.unwrap_or(found.default_address_mode());
+        if mode == AddressMode::FourByte && found.default_address_mode() != mode {
+            return Err(W25QError::InvalidConfig);
+        }
+        dev.address_mode = mode;
+
+        // address mode and protection are per die
+        for die in 0..found.die_count() {
+            dev.select_die(die)?;
+            dev.enter_address_mode()?;
+            match config.write_protection {
+                WriteProtection::Keep => {}
+                WriteProtection::UnlockAll => {
+                    let sr1 = SR1::from(dev.read_status_register(SR::SR1(SR1::default()))?);
+                    if sr1.bp != 0 || sr1.tb || sr1.sec {
+                        dev.write_status_register(SR::SR1(SR1::default()))?;
+                    }
+                    dev.global_block_unlock()?;
+                }
+                // the block locks only apply while WPS is set, which is not the default
+                WriteProtection::LockAll => {
+                    let sr3 = SR3::from(dev.read_status_register(SR::SR3(SR3::default()))?);
+                    if !sr3.wps {
+                        dev.write_status_register(SR::SR3(SR3 { wps: true, ..sr3 }))?;
+                    }
+                    dev.global_block_lock()?;
+                }
+            }
+        }
+        Ok(dev)
+    }
+
+    fn with_config(spi_dev: SPI, delay: DELAY, config: W25QConfig, chip: Chip) -> Self {
         Self {
             periph: spi_dev,
-            delay: delay,
+            delay,
+            config,
+            chip,
+            address_mode: config.address_mode.unwrap_or(chip.default_address_mode()),
+            active_die: 0,
             seek_ptr: 0x000000,
             buffer: [0x00; crate::PAGE_SIZE],
             buffer_start: 0x000000,
-            buffer_end: 0x000100,
+            buffer_end: 0x000000,
+            power_state: PowerState::Active,
+            auto_power_down: None,
+            idle_ms: 0,
         }
     }
+
+    pub fn config(&self) -> &W25QConfig {
+        &self.config
+    }
+
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    pub fn address_mode(&self) -> AddressMode {
+        self.address_mode
+    }
+
+    /// die selected in a multi-die package, always 0 on single die chips.
+    pub fn active_die(&self) -> u8 {
+        self.active_die
+    }
+
+    /// current power state of the chip.
+    pub fn power_state(&self) -> PowerState {
+        self.power_state
+    }
+
+    /// put the chip into deep power-down after `idle_ms` without access. `None` disables.
+    ///
+    /// idle time is accounted by [`W25Q::idle`].
+    pub fn set_auto_power_down(&mut self, idle_ms: Option<u32>) {
+        self.auto_power_down = idle_ms;
+        self.idle_ms = 0;
+    }
 }
 
 impl<SPI, DELAY> W25Q<SPI, DELAY>
@@ -252,23 +436,67 @@ where
     SPI: spi::SpiDevice,
     DELAY: delay::DelayNs,
 {
+    /// run `operations` as one transaction, waking the chip first if it is powered down.
+    pub(crate) fn transaction(
+        &mut self,
+        operations: &mut [spi::Operation<'_, u8>],
+    ) -> Result<(), SPI::Error> {
+        self.wake()?;
+        self.periph.transaction(operations)
+    }
+
+    /// release power down if needed and reset the idle timer.
+    fn wake(&mut self) -> Result<(), SPI::Error> {
+        self.idle_ms = 0;
+        if self.power_state == PowerState::PoweredDown {
+            self.periph
+                .transaction(&mut [spi::Operation::Write(&[Register::RELEASE_POWER_DOWN as u8])])?;
+            self.delay.delay_us(RELEASE_POWER_DOWN_DELAY_US);
+            self.power_state = PowerState::Active;
+        }
+        Ok(())
+    }
+
+    /// select the die holding `address` and return the address within that die.
+    fn die_address(&mut self, address: u32) -> Result<u32, SPI::Error> {
+        if self.chip.die_count() == 1 {
+            return Ok(address);
+        }
+        let die_capacity = self.chip.die_capacity();
+        let die = (address / die_capacity) as u8;
+        if die != self.active_die {
+            self.transaction(&mut [spi::Operation::Write(&[
+                Register::SOFTWARE_DIE_SELECT as u8,
+                die,
+            ])])?;
+            self.active_die = die;
+        }
+        Ok(address % die_capacity)
+    }
+
+    /// `command` followed by `address` in the current address mode and `dummy` zero bytes.
+    /// returns the header and its length.
+    fn command_header(&self, command: u8, address: u32, dummy: usize) -> ([u8; 6], usize) {
+        let n = self.address_mode.bytes();
+        let mut header = [0; 6];
+        header[0] = command;
+        header[1..=n].copy_from_slice(&address.to_be_bytes()[4 - n..]);
+        (header, 1 + n + dummy)
+    }
+
     /// with `command` at `address`, read bytes into payload.
     pub(crate) fn read_from_address(
         &mut self,
         command: u8,
         address: u32,
         payload: &mut [u8],
-    ) -> Result<u8, SPI::Error> {
-        // [command, address, address, address] MSB
-        let mut data = [0; 4];
-        data[0] = command;
-        data[1] = ((address >> 16) & 0xFF) as u8;
-        data[2] = ((address >> 8) & 0xFF) as u8;
-        data[3] = (address & 0xFF) as u8;
-
-        self.periph
-            .transaction(&mut [spi::Operation::Write(&data), spi::Operation::Read(payload)])?;
-        Ok(data[0])
+    ) -> Result<(), SPI::Error> {
+        let address = self.die_address(address)?;
+        let (header, len) = self.command_header(command, address, 0);
+        self.transaction(&mut [
+            spi::Operation::Write(&header[..len]),
+            spi::Operation::Read(payload),
+        ])
     }
 
     /// with `command` (no address), read bytes into payload.
@@ -277,41 +505,37 @@ where
         command: u8,
         payload: &mut [u8],
     ) -> Result<(), SPI::Error> {
-        self.periph.transaction(&mut [
+        self.transaction(&mut [
             spi::Operation::Write(&[command]),
             spi::Operation::Read(payload),
         ])?;
         Ok(())
     }
 
-    /// write bytes from `payload` with command `command` at `address`
+    /// write bytes from `payload` with command `command` at `address`, after a write enable.
     pub(crate) fn write_address(
         &mut self,
         command: u8,
         address: u32,
         payload: &[u8],
     ) -> Result<(), SPI::Error> {
-        // [command, address, address, address] MSB
-        let mut data = [0; 4];
-        data[0] = command;
-        data[1] = ((address >> 16) & 0xFF) as u8;
-        data[2] = ((address >> 8) & 0xFF) as u8;
-        data[3] = (address & 0xFF) as u8;
-
-        // write enable first
-        self.periph.transaction(&mut [spi::Operation::Write(&[
-            Register::VOLATILE_SR_WRITE_ENABLE as u8,
-        ])])?;
+        let address = self.die_address(address)?;
+        let (header, len) = self.command_header(command, address, 0);
+        self.invalidate_buffer();
 
-        self.periph
-            .transaction(&mut [spi::Operation::Write(&data), spi::Operation::Write(payload)])?;
+        // write enable after the die select, every die has its own latch
+        self.transaction(&mut [spi::Operation::Write(&[Register::WRITE_ENABLE as u8])])?;
+
+        self.transaction(&mut [
+            spi::Operation::Write(&header[..len]),
+            spi::Operation::Write(payload),
+        ])?;
         Ok(())
     }
 
-    /// with `command`, write data from `payload`
+    /// with `command`, write data from `payload`. write enable must be set first.
     pub(crate) fn write_data(&mut self, command: u8, payload: &[u8]) -> Result<(), SPI::Error> {
-        self.periph.transaction(&mut [
-            spi::Operation::Write(&[Register::VOLATILE_SR_WRITE_ENABLE as u8]),
+        self.transaction(&mut [
             spi::Operation::Write(&[command]),
             spi::Operation::Write(payload),
         ])?;
@@ -319,21 +543,35 @@ where
         Ok(())
     }
 
-    pub fn read_jedec_id(&mut self) -> Result<[u8; 3], SPI::Error> {
+    pub fn read_jedec_id(&mut self) -> Result<[u8; 3], W25QError> {
         let mut id = [0u8; 3];
         self.read_register(Register::JEDEC_ID as u8, &mut id)?;
         Ok(id)
     }
 
-    pub fn read_unique_id(&mut self) -> Result<[u8; 8], SPI::Error> {
+    pub fn read_unique_id(&mut self) -> Result<[u8; 8], W25QError> {
         let mut id = [0u8; 8];
-        let cmd = [Register::READ_UNIQUE_ID as u8, 0, 0, 0, 0];
-        self.periph
-            .transaction(&mut [spi::Operation::Write(&cmd), spi::Operation::Read(&mut id)])?;
+        // four dummy bytes, five in 4-byte address mode
+        let (cmd, len) = self.command_header(Register::READ_UNIQUE_ID as u8, 0, 1);
+        self.transaction(&mut [
+            spi::Operation::Write(&cmd[..len]),
+            spi::Operation::Read(&mut id),
+        ])?;
         Ok(id)
     }
 
-    pub fn read_status_register(&mut self, register: SR) -> Result<u8, SPI::Error> {
+    /// read the SFDP parameters from `address`. SFDP takes a 3-byte address and one dummy
+    /// byte in either address mode.
+    pub fn read_sfdp(&mut self, address: u32, data: &mut [u8]) -> Result<(), W25QError> {
+        let [_, a2, a1, a0] = address.to_be_bytes();
+        self.transaction(&mut [
+            spi::Operation::Write(&[Register::READ_SFDP_REGISTER as u8, a2, a1, a0, 0]),
+            spi::Operation::Read(data),
+        ])?;
+        Ok(())
+    }
+
+    pub fn read_status_register(&mut self, register: SR) -> Result<u8, W25QError> {
         let cmd = match register {
             SR::SR1(_) => Register::READ_STATUS_REGISTER_1,
             SR::SR2(_) => Register::READ_STATUS_REGISTER_2,
@@ -344,7 +582,7 @@ where
         Ok(status[0])
     }
 
-    pub fn write_status_register(&mut self, register: SR) -> Result<(), SPI::Error> {
+    pub fn write_status_register(&mut self, register: SR) -> Result<(), W25QError> {
         let (cmd, value) = match register {
             SR::SR1(sr1) => (Register::WRITE_STATUS_REGISTER_1, sr1.to_writable_u8()),
             SR::SR2(sr2) => (Register::WRITE_STATUS_REGISTER_2, sr2.to_writable_u8()),
@@ -352,71 +590,260 @@ where
         };
         self.write_enable()?;
         self.write_data(cmd as u8, &[value])?;
-        self.wait_until_ready()
+        self.wait_until_ready(self.config.program_timeout_ms)
     }
 
-    pub fn write_enable(&mut self) -> Result<(), SPI::Error> {
-        self.periph
-            .transaction(&mut [spi::Operation::Write(&[Register::WRITE_ENABLE as u8])])
+    pub fn write_enable(&mut self) -> Result<(), W25QError> {
+        self.transaction(&mut [spi::Operation::Write(&[Register::WRITE_ENABLE as u8])])?;
+        Ok(())
     }
 
-    pub fn write_disable(&mut self) -> Result<(), SPI::Error> {
-        self.periph
-            .transaction(&mut [spi::Operation::Write(&[Register::WRITE_DISABLE as u8])])
+    pub fn write_disable(&mut self) -> Result<(), W25QError> {
+        self.transaction(&mut [spi::Operation::Write(&[Register::WRITE_DISABLE as u8])])?;
+        Ok(())
     }
 
-    pub fn chip_erase(&mut self) -> Result<(), SPI::Error> {
-        self.write_enable()?;
-        self.periph
-            .transaction(&mut [spi::Operation::Write(&[Register::CHIP_ERASE as u8])])?;
-        self.wait_until_ready()
+    pub fn chip_erase(&mut self) -> Result<(), W25QError> {
+        self.start_chip_erase()?;
+        self.wait_chip_erase()?;
+        self.verify_erased(0, self.capacity() as usize)
     }
 
-    pub fn sector_erase(&mut self, address: u32) -> Result<(), SPI::Error> {
-        self.write_enable()?;
+    pub fn sector_erase(&mut self, address: u32) -> Result<(), W25QError> {
+        self.start_sector_erase(address)?;
+        self.wait_erase()?;
+        self.verify_erased(address, SECTOR_SIZE)
+    }
+
+    pub fn block_erase_32kb(&mut self, address: u32) -> Result<(), W25QError> {
+        self.start_block_erase_32kb(address)?;
+        self.wait_erase()?;
+        self.verify_erased(address, BLOCK_SIZE_32)
+    }
+
+    pub fn block_erase_64kb(&mut self, address: u32) -> Result<(), W25QError> {
+        self.start_block_erase_64kb(address)?;
+        self.wait_erase()?;
+        self.verify_erased(address, BLOCK_SIZE_64)
+    }
+
+    pub fn page_program(&mut self, address: u32, data: &[u8]) -> Result<(), W25QError> {
+        self.start_page_program(address, data)?;
+        self.wait_program()?;
+        self.verify_programmed(address, data)
+    }
+
+    /// issue a chip erase without waiting for it; see [`W25Q::wait_chip_erase`].
+    ///
+    /// on multi-die packages every die is erased, in parallel.
+    pub fn start_chip_erase(&mut self) -> Result<(), W25QError> {
+        self.invalidate_buffer();
+        for die in 0..self.chip.die_count() {
+            self.select_die(die)?;
+            self.write_enable()?;
+            self.transaction(&mut [spi::Operation::Write(&[Register::CHIP_ERASE as u8])])?;
+        }
+        Ok(())
+    }
+
+    /// issue a sector erase without waiting for it; see [`W25Q::wait_erase`].
+    pub fn start_sector_erase(&mut self, address: u32) -> Result<(), W25QError> {
         self.write_address(Register::SECTOR_ERASE as u8, address, &[])?;
-        self.wait_until_ready()
+        Ok(())
     }
 
-    pub fn block_erase_32kb(&mut self, address: u32) -> Result<(), SPI::Error> {
-        self.write_enable()?;
+    /// issue a 32KB block erase without waiting for it; see [`W25Q::wait_erase`].
+    pub fn start_block_erase_32kb(&mut self, address: u32) -> Result<(), W25QError> {
         self.write_address(Register::BLOCK_ERASE_32KB as u8, address, &[])?;
-        self.wait_until_ready()
+        Ok(())
     }
 
-    pub fn block_erase_64kb(&mut self, address: u32) -> Result<(), SPI::Error> {
-        self.write_enable()?;
+    /// issue a 64KB block erase without waiting for it; see [`W25Q::wait_erase`].
+    pub fn start_block_erase_64kb(&mut self, address: u32) -> Result<(), W25QError> {
         self.write_address(Register::BLOCK_ERASE_64KB as u8, address, &[])?;
-        self.wait_until_ready()
+        Ok(())
     }
 
-    pub fn page_program(&mut self, address: u32, data: &[u8]) -> Result<(), SPI::Error> {
-        self.write_enable()?;
+    /// issue a page program without waiting for it; see [`W25Q::wait_program`].
+    ///
+    /// the started operations do not verify, even with verify after write enabled;
+    /// [`FlashArray`](crate::array::FlashArray) verifies them when it waits.
+    pub fn start_page_program(&mut self, address: u32, data: &[u8]) -> Result<(), W25QError> {
         self.write_address(Register::PAGE_PROGRAM as u8, address, data)?;
-        self.wait_until_ready()
+        Ok(())
+    }
+
+    /// wait for a started page program, up to the program timeout.
+    pub fn wait_program(&mut self) -> Result<(), W25QError> {
+        self.wait_until_ready(self.config.program_timeout_ms)
+    }
+
+    /// wait for a started sector or block erase, up to the erase timeout.
+    pub fn wait_erase(&mut self) -> Result<(), W25QError> {
+        self.wait_until_ready(self.config.erase_timeout_ms)
+    }
+
+    /// wait for a started chip erase, up to the chip erase timeout.
+    pub fn wait_chip_erase(&mut self) -> Result<(), W25QError> {
+        for die in 0..self.chip.die_count() {
+            self.select_die(die)?;
+            self.wait_until_ready(self.config.chip_erase_timeout_ms)?;
+        }
+        Ok(())
     }
 
-    pub fn read_data(&mut self, address: u32, data: &mut [u8]) -> Result<(), SPI::Error> {
+    /// program `data` at `address`, split at page boundaries.
+    pub fn program(&mut self, address: u32, data: &[u8]) -> Result<(), W25QError> {
+        if address as u64 + data.len() as u64 > self.capacity() {
+            return Err(W25QError::OutOfBounds);
+        }
+        let mut addr = address;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let len = (PAGE_SIZE - addr as usize % PAGE_SIZE).min(rest.len());
+            self.page_program(addr, &rest[..len])?;
+            addr += len as u32;
+            rest = &rest[len..];
+        }
+        Ok(())
+    }
+
+    /// erase a sector aligned `range`, using 64KB and 32KB block erases where possible.
+    pub fn erase_range(&mut self, range: Range<u32>) -> Result<(), W25QError> {
+        if range.start > range.end || range.end as u64 > self.capacity() {
+            return Err(W25QError::OutOfBounds);
+        }
+        if !(range.start as usize).is_multiple_of(SECTOR_SIZE)
+            || !(range.end as usize).is_multiple_of(SECTOR_SIZE)
+        {
+            return Err(W25QError::NotAligned);
+        }
+        let mut addr = range.start;
+        while addr < range.end {
+            let left = (range.end - addr) as usize;
+            let size = if (addr as usize).is_multiple_of(BLOCK_SIZE_64) && left >= BLOCK_SIZE_64 {
+                self.block_erase_64kb(addr)?;
+                BLOCK_SIZE_64
+            } else if (addr as usize).is_multiple_of(BLOCK_SIZE_32) && left >= BLOCK_SIZE_32 {
+                self.block_erase_32kb(addr)?;
+                BLOCK_SIZE_32
+            } else {
+                self.sector_erase(addr)?;
+                SECTOR_SIZE
+            };
+            addr += size as u32;
+        }
+        Ok(())
+    }
+
+    /// compare flash at `address` with `expected` in small chunks, without a full copy.
+    ///
+    /// fails with `VerifyFailed` at the first differing address.
+    pub fn verify(&mut self, address: u32, expected: &[u8]) -> Result<(), W25QError> {
+        let mut chunk = [0u8; VERIFY_CHUNK_SIZE];
+        for (i, part) in expected.chunks(chunk.len()).enumerate() {
+            let addr = address + (i * chunk.len()) as u32;
+            let read = &mut chunk[..part.len()];
+            self.read_at(addr, read)?;
+            if let Some(offset) = read.iter().zip(part).position(|(a, b)| a != b) {
+                return Err(W25QError::VerifyFailed {
+                    addr: addr + offset as u32,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// blank check: whether every byte in `range` reads 0xFF.
+    pub fn is_erased(&mut self, range: Range<u32>) -> Result<bool, W25QError> {
+        Ok(self.find_programmed(range)?.is_none())
+    }
+
+    /// address of the first byte in `range` that is not 0xFF.
+    fn find_programmed(&mut self, range: Range<u32>) -> Result<Option<u32>, W25QError> {
+        let mut chunk = [0u8; VERIFY_CHUNK_SIZE];
+        let mut addr = range.start;
+        while addr < range.end {
+            let len = ((range.end - addr) as usize).min(chunk.len());
+            self.read_at(addr, &mut chunk[..len])?;
+            if let Some(offset) = chunk[..len].iter().position(|b| *b != 0xFF) {
+                return Ok(Some(addr + offset as u32));
+            }
+            addr += len as u32;
+        }
+        Ok(None)
+    }
+
+    /// with verify after write enabled, compare `data` programmed at `address`.
+    pub(crate) fn verify_programmed(&mut self, address: u32, data: &[u8]) -> Result<(), W25QError> {
+        if !self.config.verify_after_write {
+            return Ok(());
+        }
+        self.verify(address, data)
+    }
+
+    /// with verify after write enabled, blank check `len` bytes erased at `address`.
+    pub(crate) fn verify_erased(&mut self, address: u32, len: usize) -> Result<(), W25QError> {
+        if !self.config.verify_after_write {
+            return Ok(());
+        }
+        let start = address - address % len as u32;
+        match self.find_programmed(start..start + len as u32)? {
+            Some(addr) => Err(W25QError::VerifyFailed { addr }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn read_data(&mut self, address: u32, data: &mut [u8]) -> Result<(), W25QError> {
         self.read_from_address(Register::READ_DATA as u8, address, data)?;
         Ok(())
     }
 
-    pub fn fast_read(&mut self, address: u32, data: &mut [u8]) -> Result<(), SPI::Error> {
-        let mut cmd = [Register::FAST_READ as u8, 0, 0, 0, 0];
-        cmd[1..4].copy_from_slice(&address.to_be_bytes()[1..]);
-        self.periph
-            .transaction(&mut [spi::Operation::Write(&cmd), spi::Operation::Read(data)])?;
+    pub fn fast_read(&mut self, address: u32, data: &mut [u8]) -> Result<(), W25QError> {
+        let address = self.die_address(address)?;
+        let (cmd, len) = self.command_header(Register::FAST_READ as u8, address, 1);
+        self.transaction(&mut [
+            spi::Operation::Write(&cmd[..len]),
+            spi::Operation::Read(data),
+        ])?;
         Ok(())
     }
 
-    pub(crate) fn fast_read_into_internal_buffer(
-        &mut self,
-        address: u32,
-    ) -> Result<(), SPI::Error> {
-        let mut cmd = [Register::FAST_READ as u8, 0, 0, 0, 0];
-        cmd[1..4].copy_from_slice(&address.to_be_bytes()[1..]);
+    /// read with the configured read command.
+    ///
+    /// on multi-die packages the read is split at die boundaries.
+    pub fn read_at(&mut self, address: u32, data: &mut [u8]) -> Result<(), W25QError> {
+        let die_capacity = self.chip.die_capacity();
+        let mut addr = address;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let len = ((die_capacity - addr % die_capacity) as usize).min(rest.len());
+            let (now, later) = rest.split_at_mut(len);
+            match self.config.read_command {
+                ReadCommand::Read => self.read_data(addr, now)?,
+                ReadCommand::FastRead => self.fast_read(addr, now)?,
+            }
+            addr += len as u32;
+            rest = later;
+        }
+        Ok(())
+    }
+
+    /// header for the configured read command at `address`.
+    fn read_command_header(&self, address: u32) -> ([u8; 6], usize) {
+        match self.config.read_command {
+            ReadCommand::Read => self.command_header(Register::READ_DATA as u8, address, 0),
+            ReadCommand::FastRead => self.command_header(Register::FAST_READ as u8, address, 1),
+        }
+    }
+
+    /// fill the `BufRead` buffer from `address` with the configured read command.
+    pub(crate) fn read_into_internal_buffer(&mut self, address: u32) -> Result<(), W25QError> {
+        let address = self.die_address(address)?;
+        let (cmd, len) = self.read_command_header(address);
+        self.wake()?;
         self.periph.transaction(&mut [
-            spi::Operation::Write(&cmd),
+            spi::Operation::Write(&cmd[..len]),
             spi::Operation::Read(&mut self.buffer),
         ])?;
         self.buffer_start = 0;
@@ -424,104 +851,270 @@ where
         Ok(())
     }
 
-    pub fn power_down(&mut self) -> Result<(), SPI::Error> {
+    /// drop the `BufRead` buffer, e.g. after the flash contents changed.
+    pub(crate) fn invalidate_buffer(&mut self) {
+        self.buffer_start = 0;
+        self.buffer_end = 0;
+    }
+
+    /// enter deep power-down. any later access releases power down automatically.
+    pub fn power_down(&mut self) -> Result<(), W25QError> {
+        if self.power_state == PowerState::PoweredDown {
+            return Ok(());
+        }
         self.periph
-            .transaction(&mut [spi::Operation::Write(&[Register::POWER_DOWN as u8])])
+            .transaction(&mut [spi::Operation::Write(&[Register::POWER_DOWN as u8])])?;
+        self.delay.delay_us(POWER_DOWN_DELAY_US);
+        self.power_state = PowerState::PoweredDown;
+        Ok(())
     }
 
-    pub fn release_power_down(&mut self) -> Result<(), SPI::Error> {
+    pub fn release_power_down(&mut self) -> Result<(), W25QError> {
         self.periph
-            .transaction(&mut [spi::Operation::Write(&[Register::RELEASE_POWER_DOWN as u8])])
+            .transaction(&mut [spi::Operation::Write(&[Register::RELEASE_POWER_DOWN as u8])])?;
+        self.delay.delay_us(RELEASE_POWER_DOWN_DELAY_US);
+        self.power_state = PowerState::Active;
+        Ok(())
     }
 
-    pub fn erase_security_register(&mut self, address: u32) -> Result<(), SPI::Error> {
-        self.write_enable()?;
+    /// account `elapsed_ms` of idle time; powers down once the auto power down period is exceeded.
+    ///
+    /// call periodically, e.g. from a timer or the main loop.
+    pub fn idle(&mut self, elapsed_ms: u32) -> Result<(), W25QError> {
+        let Some(limit) = self.auto_power_down else {
+            return Ok(());
+        };
+        self.idle_ms = self.idle_ms.saturating_add(elapsed_ms);
+        if self.idle_ms >= limit {
+            self.power_down()?;
+        }
+        Ok(())
+    }
+
+    pub fn erase_security_register(&mut self, address: u32) -> Result<(), W25QError> {
         self.write_address(Register::ERASE_SECURITY_REGISTER as u8, address, &[])?;
-        self.wait_until_ready()
+        self.wait_until_ready(self.config.erase_timeout_ms)
     }
 
     pub fn program_security_register(
         &mut self,
         address: u32,
         data: &[u8],
-    ) -> Result<(), SPI::Error> {
-        self.write_enable()?;
+    ) -> Result<(), W25QError> {
         self.write_address(Register::PROGRAM_SECURITY_REGISTER as u8, address, data)?;
-        self.wait_until_ready()
+        self.wait_until_ready(self.config.program_timeout_ms)
     }
 
     pub fn read_security_register(
         &mut self,
         address: u32,
         data: &mut [u8],
-    ) -> Result<(), SPI::Error> {
-        self.read_from_address(Register::READ_SECURITY_REGISTER as u8, address, data)?;
+    ) -> Result<(), W25QError> {
+        // one dummy byte, as fast read
+        let (cmd, len) = self.command_header(Register::READ_SECURITY_REGISTER as u8, address, 1);
+        self.transaction(&mut [
+            spi::Operation::Write(&cmd[..len]),
+            spi::Operation::Read(data),
+        ])?;
         Ok(())
     }
 
-    pub fn global_block_lock(&mut self) -> Result<(), SPI::Error> {
+    pub fn global_block_lock(&mut self) -> Result<(), W25QError> {
         self.write_enable()?;
-        self.periph
-            .transaction(&mut [spi::Operation::Write(&[Register::GLOBAL_BLOCK_LOCK as u8])])
+        self.transaction(&mut [spi::Operation::Write(&[Register::GLOBAL_BLOCK_LOCK as u8])])?;
+        Ok(())
     }
 
-    pub fn global_block_unlock(&mut self) -> Result<(), SPI::Error> {
+    pub fn global_block_unlock(&mut self) -> Result<(), W25QError> {
         self.write_enable()?;
-        self.periph.transaction(&mut [spi::Operation::Write(
+        self.transaction(&mut [spi::Operation::Write(
             &[Register::GLOBAL_BLOCK_UNLOCK as u8],
-        )])
+        )])?;
+        Ok(())
     }
 
-    pub fn read_block_lock(&mut self, address: u32) -> Result<bool, SPI::Error> {
+    pub fn read_block_lock(&mut self, address: u32) -> Result<bool, W25QError> {
         let mut status = [0u8; 1];
         self.read_from_address(Register::READ_BLOCK_LOCK as u8, address, &mut status)?;
         Ok(status[0] & 0x01 != 0)
     }
 
-    pub fn individual_block_lock(&mut self, address: u32) -> Result<(), SPI::Error> {
-        self.write_enable()?;
+    pub fn individual_block_lock(&mut self, address: u32) -> Result<(), W25QError> {
         self.write_address(Register::INDIVIDUAL_BLOCK_LOCK as u8, address, &[])?;
-        self.wait_until_ready()
+        self.wait_until_ready(self.config.program_timeout_ms)
     }
 
-    pub fn individual_block_unlock(&mut self, address: u32) -> Result<(), SPI::Error> {
-        self.write_enable()?;
+    pub fn individual_block_unlock(&mut self, address: u32) -> Result<(), W25QError> {
         self.write_address(Register::INDIVIDUAL_BLOCK_UNLOCK as u8, address, &[])?;
-        self.wait_until_ready()
+        self.wait_until_ready(self.config.program_timeout_ms)
     }
 
-    pub fn erase_program_suspend(&mut self) -> Result<(), SPI::Error> {
-        self.periph.transaction(&mut [spi::Operation::Write(&[
+    pub fn erase_program_suspend(&mut self) -> Result<(), W25QError> {
+        self.transaction(&mut [spi::Operation::Write(&[
             Register::ERASE_PROGRAM_SUSPEND as u8
-        ])])
+        ])])?;
+        Ok(())
     }
 
-    pub fn erase_program_resume(&mut self) -> Result<(), SPI::Error> {
-        self.periph.transaction(&mut [spi::Operation::Write(&[
+    pub fn erase_program_resume(&mut self) -> Result<(), W25QError> {
+        self.transaction(&mut [spi::Operation::Write(&[
             Register::ERASE_PROGRAM_RESUME as u8
-        ])])
+        ])])?;
+        Ok(())
+    }
+
+    /// software reset. enable reset and reset device are sent as separate commands,
+    /// CS must be deasserted in between.
+    ///
+    /// on multi-die packages only the active die is reset.
+    pub fn reset_device(&mut self) -> Result<(), W25QError> {
+        self.transaction(&mut [spi::Operation::Write(&[Register::ENABLE_RESET as u8])])?;
+        self.transaction(&mut [spi::Operation::Write(&[Register::RESET_DEVICE as u8])])?;
+        self.delay.delay_us(self.config.reset_delay_us);
+        self.invalidate_buffer();
+        // reset returns to the power-up address mode
+        self.enter_address_mode()?;
+        Ok(())
+    }
+
+    /// switch the active die to the configured address mode. 3-byte only parts are left alone.
+    fn enter_address_mode(&mut self) -> Result<(), W25QError> {
+        if self.chip.default_address_mode() == AddressMode::ThreeByte {
+            return Ok(());
+        }
+        let command = match self.address_mode {
+            AddressMode::ThreeByte => Register::EXIT_4_BYTE_ADDRESS_MODE,
+            AddressMode::FourByte => Register::ENTER_4_BYTE_ADDRESS_MODE,
+        };
+        self.transaction(&mut [spi::Operation::Write(&[command as u8])])?;
+        Ok(())
+    }
+
+    /// select `die` of a multi-die package (W25M). status register commands, polling and
+    /// reset apply to the selected die; addressed commands select their die automatically.
+    pub fn select_die(&mut self, die: u8) -> Result<(), W25QError> {
+        if die >= self.chip.die_count() {
+            return Err(W25QError::OutOfBounds);
+        }
+        if self.chip.die_count() > 1 {
+            self.transaction(&mut [spi::Operation::Write(&[
+                Register::SOFTWARE_DIE_SELECT as u8,
+                die,
+            ])])?;
+        }
+        self.active_die = die;
+        Ok(())
     }
 
-    pub fn reset_device(&mut self) -> Result<(), SPI::Error> {
+    /// whether `die` is busy. the other die of a W25M stays accessible meanwhile.
+    pub fn is_die_busy(&mut self, die: u8) -> Result<bool, W25QError> {
+        self.select_die(die)?;
+        self.is_busy()
+    }
+
+    /// bring the chip back to a known state after a warm MCU reset.
+    ///
+    /// exits QPI and continuous read mode, releases power down, resumes a suspended
+    /// erase/program, exits 4-byte address mode and resets the device, then checks the
+    /// JEDEC ID.
+    ///
+    /// on multi-die packages every die is recovered in turn and the report covers all of
+    /// them; the active die is selected again afterwards.
+    pub fn recover(&mut self) -> Result<RecoveryReport, W25QError> {
+        let active = self.active_die;
+        let mut report = RecoveryReport::default();
+        let result = (0..self.chip.die_count()).try_for_each(|die| {
+            self.select_die(die)?;
+            self.recover_die(&mut report)
+        });
+        self.select_die(active)?;
+        result.map(|()| report)
+    }
+
+    /// recover the selected die, adding what was found to `report`.
+    fn recover_die(&mut self, report: &mut RecoveryReport) -> Result<(), W25QError> {
+        let responsive = |id: [u8; 3]| id[0] == MANUFACTURER_ID;
+
+        // the chip may ignore everything but release power down, so do not rely on the
+        // tracked power state here.
+        let unresponsive = !responsive(self.raw_jedec_id()?);
+        report.unresponsive |= unresponsive;
+
+        // continuous read mode reset (FFFFh covers dual and quad), then exit QPI.
+        self.periph
+            .transaction(&mut [spi::Operation::Write(&[0xFF, 0xFF])])?;
+        self.periph
+            .transaction(&mut [spi::Operation::Write(&[Register::EXIT_QPI_MODE as u8])])?;
+        let after_exit = responsive(self.raw_jedec_id()?);
+        report.qpi_or_continuous_read |= unresponsive && after_exit;
+
+        self.release_power_down()?;
+        let id = self.raw_jedec_id()?;
+        report.powered_down |= !after_exit && responsive(id);
+        if !responsive(id) {
+            return Err(W25QError::UnexpectedId(id));
+        }
+
+        let sr2 = SR2::from(self.read_status_register(SR::SR2(SR2::default()))?);
+        if sr2.sus {
+            report.suspended = true;
+            self.erase_program_resume()?;
+        }
+        // never reset in the middle of an erase or program
+        self.wait_until_ready(self.config.chip_erase_timeout_ms)?;
+
+        // ADS is SR3 bit 0 on parts with 4-byte addressing, reserved (0) otherwise
+        let sr3 = self.read_status_register(SR::SR3(SR3::default()))?;
+        if sr3 & 0b0000_0001 != 0 {
+            report.four_byte_address = true;
+            self.transaction(&mut [spi::Operation::Write(&[
+                Register::EXIT_4_BYTE_ADDRESS_MODE as u8
+            ])])?;
+        }
+
+        self.reset_device()?;
+        report.jedec_id = self.read_jedec_id()?;
+        if !responsive(report.jedec_id) {
+            return Err(W25QError::UnexpectedId(report.jedec_id));
+        }
+        Ok(())
+    }
+
+    /// read the JEDEC ID without waking the chip first.
+    fn raw_jedec_id(&mut self) -> Result<[u8; 3], SPI::Error> {
+        let mut id = [0u8; 3];
         self.periph.transaction(&mut [
-            spi::Operation::Write(&[Register::ENABLE_RESET as u8]),
-            spi::Operation::Write(&[Register::RESET_DEVICE as u8]),
+            spi::Operation::Write(&[Register::JEDEC_ID as u8]),
+            spi::Operation::Read(&mut id),
         ])?;
-        self.delay.delay_ms(30); // Wait for reset to complete
-        Ok(())
+        Ok(id)
     }
 
+    /// bytes the driver can address: the whole chip, or the first 16MiB in 3-byte address mode.
     pub fn capacity(&self) -> u64 {
-        TOTAL_SIZE as u64
+        match self.address_mode {
+            AddressMode::ThreeByte => self.chip.capacity().min(1 << 24) as u64,
+            AddressMode::FourByte => self.chip.capacity() as u64,
+        }
+    }
+
+    /// whether an erase, program or status register write is in progress.
+    pub fn is_busy(&mut self) -> Result<bool, W25QError> {
+        let status = self.read_status_register(SR::SR1(SR1::default()))?;
+        Ok(status & 0x01 != 0)
     }
 
-    fn wait_until_ready(&mut self) -> Result<(), SPI::Error> {
-        loop {
-            let status = self.read_status_register(SR::SR1(SR1::default()))?;
-            if status & 0x01 == 0 {
-                break;
+    /// poll BUSY every `poll_interval_us` until clear, or fail after `timeout_ms`. a poll
+    /// counts as at least 1us, so an interval of 0 still times out.
+    fn wait_until_ready(&mut self, timeout_ms: u32) -> Result<(), W25QError> {
+        let timeout_us = timeout_ms as u64 * 1000;
+        let mut waited_us = 0u64;
+        while self.is_busy()? {
+            if waited_us >= timeout_us {
+                return Err(W25QError::Timeout);
             }
-            self.delay.delay_ms(1);
+            self.delay.delay_us(self.config.poll_interval_us);
+            waited_us += self.config.poll_interval_us.max(1) as u64;
         }
         Ok(())
     }
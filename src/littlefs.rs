@@ -0,0 +1,90 @@
+use embedded_storage::nor_flash::NorFlash;
+use littlefs2::{consts, driver::Storage, io};
+
+use crate::{io::W25QError, PAGE_SIZE, SECTOR_SIZE};
+
+/// littlefs2 [`Storage`] on a [`NorFlash`], one block per sector.
+///
+/// the flash is usually a [`Partition`](crate::partition::Partition), so the file system
+/// starts at the partition offset and other data can share the chip; the whole [`W25Q`]
+/// works too. littlefs2 needs the block count at compile time; [`LfsStorage::new`] checks it
+/// against the flash, e.g. `LfsStorage::<_, 256>::new(Partition::new(&mut dev, 0x100000,
+/// 0x100000)?)` for a 1MiB file system at 1MiB. a smaller count leaves the rest to other
+/// uses.
+///
+/// [`W25Q`]: crate::W25Q
+pub struct LfsStorage<F, const BLOCK_COUNT: usize> {
+    flash: F,
+}
+
+impl<F, const BLOCK_COUNT: usize> LfsStorage<F, BLOCK_COUNT>
+where
+    F: NorFlash,
+{
+    /// fails with `InvalidConfig` if the flash has fewer than `BLOCK_COUNT` sectors and with
+    /// `NotAligned` if it cannot erase single sectors or program single pages.
+    pub fn new(flash: F) -> Result<Self, W25QError> {
+        if !SECTOR_SIZE.is_multiple_of(F::ERASE_SIZE)
+            || !PAGE_SIZE.is_multiple_of(F::WRITE_SIZE)
+            || F::READ_SIZE != 1
+        {
+            return Err(W25QError::NotAligned);
+        }
+        if BLOCK_COUNT as u64 * SECTOR_SIZE as u64 > flash.capacity() as u64 {
+            return Err(W25QError::InvalidConfig);
+        }
+        Ok(Self { flash })
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    pub fn flash_mut(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    fn check(off: usize, len: usize) -> io::Result<()> {
+        if off + len > BLOCK_COUNT * SECTOR_SIZE {
+            return Err(io::Error::Io);
+        }
+        Ok(())
+    }
+}
+
+impl<F, const BLOCK_COUNT: usize> Storage for LfsStorage<F, BLOCK_COUNT>
+where
+    F: NorFlash,
+{
+    const READ_SIZE: usize = 1;
+    const WRITE_SIZE: usize = PAGE_SIZE;
+    const BLOCK_SIZE: usize = SECTOR_SIZE;
+    const BLOCK_COUNT: usize = BLOCK_COUNT;
+    type CACHE_SIZE = consts::U256;
+    type LOOKAHEAD_SIZE = consts::U16;
+
+    fn read(&mut self, off: usize, buf: &mut [u8]) -> io::Result<usize> {
+        Self::check(off, buf.len())?;
+        self.flash
+            .read(off as u32, buf)
+            .map_err(|_| io::Error::Io)?;
+        Ok(buf.len())
+    }
+
+    /// littlefs writes whole pages; the driver splits at page boundaries.
+    fn write(&mut self, off: usize, data: &[u8]) -> io::Result<usize> {
+        Self::check(off, data.len())?;
+        self.flash
+            .write(off as u32, data)
+            .map_err(|_| io::Error::Io)?;
+        Ok(data.len())
+    }
+
+    fn erase(&mut self, off: usize, len: usize) -> io::Result<usize> {
+        Self::check(off, len)?;
+        self.flash
+            .erase(off as u32, (off + len) as u32)
+            .map_err(|_| io::Error::Io)?;
+        Ok(len)
+    }
+}
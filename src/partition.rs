@@ -0,0 +1,453 @@
+use core::ops::Range;
+
+use embedded_io::{ErrorKind, SeekFrom};
+use embedded_storage::nor_flash::{self, MultiwriteNorFlash, NorFlash, ReadNorFlash};
+
+use crate::{
+    checksum::{Crc32, Hasher},
+    io::W25QError,
+};
+
+/// a range of a flash device, rebased to 0 and bounds checked.
+///
+/// implements the same `embedded_storage` and `embedded_io` traits as the device, so any
+/// layer built on those can be confined to one partition.
+pub struct Partition<'a, F> {
+    flash: &'a mut F,
+    /// start address on the device
+    offset: u32,
+    /// length in bytes
+    size: u32,
+    /// address pointer for seek operations, relative to `offset`
+    seek_ptr: u32,
+}
+
+impl<'a, F> Partition<'a, F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    /// partition of `size` bytes at `offset`, both aligned to the erase size.
+    pub fn new(flash: &'a mut F, offset: u32, size: u32) -> Result<Self, W25QError> {
+        if !(offset as usize).is_multiple_of(F::ERASE_SIZE)
+            || !(size as usize).is_multiple_of(F::ERASE_SIZE)
+        {
+            return Err(W25QError::NotAligned);
+        }
+        if offset as u64 + size as u64 > flash.capacity() as u64 {
+            return Err(W25QError::OutOfBounds);
+        }
+        Ok(Self {
+            flash,
+            offset,
+            size,
+            seek_ptr: 0,
+        })
+    }
+
+    /// partition described by a partition table entry.
+    pub fn from_entry(flash: &'a mut F, entry: &PartitionEntry) -> Result<Self, W25QError> {
+        Self::new(flash, entry.offset, entry.size)
+    }
+
+    /// start address on the device
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// device address of `address`, if `len` bytes from there fit in the partition.
+    fn absolute(&self, address: u32, len: usize) -> Result<u32, W25QError> {
+        if address as u64 + len as u64 > self.size as u64 {
+            return Err(W25QError::OutOfBounds);
+        }
+        Ok(self.offset + address)
+    }
+
+    pub fn read_at(&mut self, address: u32, data: &mut [u8]) -> Result<(), W25QError> {
+        let address = self.absolute(address, data.len())?;
+        Ok(self.flash.read(address, data)?)
+    }
+
+    pub fn program(&mut self, address: u32, data: &[u8]) -> Result<(), W25QError> {
+        let address = self.absolute(address, data.len())?;
+        Ok(self.flash.write(address, data)?)
+    }
+
+    /// erase an erase-size aligned `range` of the partition.
+    pub fn erase_range(&mut self, range: Range<u32>) -> Result<(), W25QError> {
+        if range.start > range.end {
+            return Err(W25QError::OutOfBounds);
+        }
+        let start = self.absolute(range.start, (range.end - range.start) as usize)?;
+        Ok(self.flash.erase(start, start + (range.end - range.start))?)
+    }
+
+    /// erase the whole partition.
+    pub fn erase_all(&mut self) -> Result<(), W25QError> {
+        self.erase_range(0..self.size)
+    }
+}
+
+impl<F> nor_flash::ErrorType for Partition<'_, F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    type Error = W25QError;
+}
+
+impl<F> ReadNorFlash for Partition<'_, F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), W25QError> {
+        self.read_at(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl<F> NorFlash for Partition<'_, F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), W25QError> {
+        self.erase_range(from..to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), W25QError> {
+        self.program(offset, bytes)
+    }
+}
+
+impl<F> MultiwriteNorFlash for Partition<'_, F>
+where
+    F: MultiwriteNorFlash,
+    W25QError: From<F::Error>,
+{
+}
+
+impl<F> embedded_io::ErrorType for Partition<'_, F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    type Error = W25QError;
+}
+
+impl<F> embedded_io::Read for Partition<'_, F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, W25QError> {
+        let len = buf.len().min((self.size - self.seek_ptr) as usize);
+        self.read_at(self.seek_ptr, &mut buf[..len])?;
+        self.seek_ptr += len as u32;
+        Ok(len)
+    }
+}
+
+impl<F> embedded_io::Write for Partition<'_, F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, W25QError> {
+        let len = buf.len().min((self.size - self.seek_ptr) as usize);
+        if len == 0 && !buf.is_empty() {
+            return Err(W25QError::OutOfBounds);
+        }
+        self.program(self.seek_ptr, &buf[..len])?;
+        self.seek_ptr += len as u32;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), W25QError> {
+        Ok(())
+    }
+}
+
+impl<F> embedded_io::Seek for Partition<'_, F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, W25QError> {
+        let target = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(pos) => self.size as i64 + pos,
+            SeekFrom::Current(pos) => self.seek_ptr as i64 + pos,
+        };
+        if target < 0 || target > self.size as i64 {
+            return Err(W25QError::Io(ErrorKind::InvalidInput));
+        }
+        self.seek_ptr = target as u32;
+        Ok(target as u64)
+    }
+}
+
+/// maximum length of a partition name
+pub const PARTITION_NAME_LEN: usize = 16;
+/// maximum number of entries in a partition table
+pub const MAX_PARTITIONS: usize = 16;
+
+const TABLE_MAGIC: [u8; 4] = *b"W25P";
+const TABLE_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 32;
+/// size of an encoded partition table: header, entries, CRC-32
+pub const PARTITION_TABLE_SIZE: usize = HEADER_SIZE + MAX_PARTITIONS * ENTRY_SIZE + 4;
+
+/// what a partition holds.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartitionKind {
+    Bootloader,
+    Firmware,
+    Config,
+    Log,
+    Filesystem,
+    Data,
+    /// a kind not known to this version
+    Unknown(u8),
+}
+
+impl From<u8> for PartitionKind {
+    fn from(byte: u8) -> Self {
+        match byte {
+            0 => PartitionKind::Bootloader,
+            1 => PartitionKind::Firmware,
+            2 => PartitionKind::Config,
+            3 => PartitionKind::Log,
+            4 => PartitionKind::Filesystem,
+            5 => PartitionKind::Data,
+            other => PartitionKind::Unknown(other),
+        }
+    }
+}
+
+impl From<PartitionKind> for u8 {
+    fn from(kind: PartitionKind) -> Self {
+        match kind {
+            PartitionKind::Bootloader => 0,
+            PartitionKind::Firmware => 1,
+            PartitionKind::Config => 2,
+            PartitionKind::Log => 3,
+            PartitionKind::Filesystem => 4,
+            PartitionKind::Data => 5,
+            PartitionKind::Unknown(other) => other,
+        }
+    }
+}
+
+/// one named, typed range in a partition table.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PartitionEntry {
+    name: [u8; PARTITION_NAME_LEN],
+    pub kind: PartitionKind,
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl PartitionEntry {
+    const EMPTY: Self = Self {
+        name: [0; PARTITION_NAME_LEN],
+        kind: PartitionKind::Data,
+        offset: 0,
+        size: 0,
+    };
+
+    /// fails with `OutOfBounds` if `offset + size` overflows a u32.
+    pub fn new(name: &str, kind: PartitionKind, offset: u32, size: u32) -> Result<Self, W25QError> {
+        if name.len() > PARTITION_NAME_LEN {
+            return Err(W25QError::Io(ErrorKind::InvalidInput));
+        }
+        if offset.checked_add(size).is_none() {
+            return Err(W25QError::OutOfBounds);
+        }
+        let mut entry = Self {
+            kind,
+            offset,
+            size,
+            ..Self::EMPTY
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(entry)
+    }
+
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(PARTITION_NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    /// device address range, `OutOfBounds` if its end overflows a u32.
+    pub fn range(&self) -> Result<Range<u32>, W25QError> {
+        let end = self
+            .offset
+            .checked_add(self.size)
+            .ok_or(W25QError::OutOfBounds)?;
+        Ok(self.offset..end)
+    }
+
+    // name[16] kind flags reserved[2] offset size reserved[4], little endian
+    fn encode(&self, buf: &mut [u8]) {
+        buf[..PARTITION_NAME_LEN].copy_from_slice(&self.name);
+        buf[16] = self.kind.into();
+        buf[17..20].fill(0);
+        buf[20..24].copy_from_slice(&self.offset.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.size.to_le_bytes());
+        buf[28..32].fill(0);
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        let mut name = [0; PARTITION_NAME_LEN];
+        name.copy_from_slice(&buf[..PARTITION_NAME_LEN]);
+        let word = |at: usize| u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]);
+        Self {
+            name,
+            kind: buf[16].into(),
+            offset: word(20),
+            size: word(24),
+        }
+    }
+}
+
+/// partition table, stored on flash as a CRC protected block of [`PARTITION_TABLE_SIZE`] bytes.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionTable {
+    entries: [PartitionEntry; MAX_PARTITIONS],
+    len: usize,
+}
+
+impl Default for PartitionTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PartitionTable {
+    pub const fn new() -> Self {
+        Self {
+            entries: [PartitionEntry::EMPTY; MAX_PARTITIONS],
+            len: 0,
+        }
+    }
+
+    /// add `entry`. fails if the table is full, the entry overflows or it overlaps another
+    /// one.
+    pub fn add(&mut self, entry: PartitionEntry) -> Result<(), W25QError> {
+        if self.len == MAX_PARTITIONS {
+            return Err(W25QError::Io(ErrorKind::OutOfMemory));
+        }
+        let range = entry.range()?;
+        // entries in the table were checked when added
+        if self
+            .entries()
+            .iter()
+            .filter_map(|other| other.range().ok())
+            .any(|other| range.start < other.end && other.start < range.end)
+        {
+            return Err(W25QError::Io(ErrorKind::InvalidInput));
+        }
+        self.entries[self.len] = entry;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[PartitionEntry] {
+        &self.entries[..self.len]
+    }
+
+    pub fn find(&self, name: &str) -> Option<&PartitionEntry> {
+        self.entries().iter().find(|entry| entry.name() == name)
+    }
+
+    pub fn find_kind(&self, kind: PartitionKind) -> impl Iterator<Item = &PartitionEntry> {
+        self.entries()
+            .iter()
+            .filter(move |entry| entry.kind == kind)
+    }
+
+    pub fn to_bytes(&self) -> [u8; PARTITION_TABLE_SIZE] {
+        let mut buf = [0u8; PARTITION_TABLE_SIZE];
+        buf[..4].copy_from_slice(&TABLE_MAGIC);
+        buf[4] = TABLE_VERSION;
+        buf[5] = self.len as u8;
+        for (i, entry) in self.entries().iter().enumerate() {
+            let at = HEADER_SIZE + i * ENTRY_SIZE;
+            entry.encode(&mut buf[at..at + ENTRY_SIZE]);
+        }
+        let crc_at = PARTITION_TABLE_SIZE - 4;
+        let crc = Crc32::checksum(&buf[..crc_at]);
+        buf[crc_at..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// decode a table, failing with `Corrupt` on a bad magic, version, CRC or entry, or on
+    /// overlapping entries.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, W25QError> {
+        if buf.len() < PARTITION_TABLE_SIZE || buf[..4] != TABLE_MAGIC || buf[4] != TABLE_VERSION {
+            return Err(W25QError::Corrupt);
+        }
+        let crc_at = PARTITION_TABLE_SIZE - 4;
+        let mut crc = Crc32::new();
+        crc.update(&buf[..crc_at]);
+        if crc.finalize().to_le_bytes() != buf[crc_at..PARTITION_TABLE_SIZE] {
+            return Err(W25QError::Corrupt);
+        }
+        let len = buf[5] as usize;
+        if len > MAX_PARTITIONS {
+            return Err(W25QError::Corrupt);
+        }
+        let mut table = Self::new();
+        for i in 0..len {
+            let at = HEADER_SIZE + i * ENTRY_SIZE;
+            let entry = PartitionEntry::decode(&buf[at..at + ENTRY_SIZE]);
+            table.add(entry).map_err(|_| W25QError::Corrupt)?;
+        }
+        Ok(table)
+    }
+
+    /// read the table stored at `address`.
+    pub fn read<F>(flash: &mut F, address: u32) -> Result<Self, W25QError>
+    where
+        F: ReadNorFlash,
+        W25QError: From<F::Error>,
+    {
+        let mut buf = [0u8; PARTITION_TABLE_SIZE];
+        flash.read(address, &mut buf)?;
+        Self::from_bytes(&buf)
+    }
+
+    /// erase the erase blocks at `address` holding the table and write it.
+    pub fn write<F>(&self, flash: &mut F, address: u32) -> Result<(), W25QError>
+    where
+        F: NorFlash,
+        W25QError: From<F::Error>,
+    {
+        let len = PARTITION_TABLE_SIZE.div_ceil(F::ERASE_SIZE) * F::ERASE_SIZE;
+        flash.erase(address, address + len as u32)?;
+        flash.write(address, &self.to_bytes())?;
+        Ok(())
+    }
+}
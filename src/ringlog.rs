@@ -0,0 +1,402 @@
+use core::ops::Range;
+
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::{
+    checksum::{Crc32, Hasher},
+    io::W25QError,
+    sector::{self, ALIGN, CHUNK, SECTOR_HEADER_SIZE},
+    SECTOR_SIZE,
+};
+
+const SECTOR_MAGIC: [u8; 4] = *b"W25L";
+const RECORD_HEADER_SIZE: usize = 8;
+const RECORD_FOOTER_SIZE: usize = 4;
+
+/// longest record a [`RingLog`] accepts
+pub const MAX_RECORD_LEN: usize =
+    SECTOR_SIZE - SECTOR_HEADER_SIZE as usize - RECORD_HEADER_SIZE - RECORD_FOOTER_SIZE;
+
+fn record_size(len: usize) -> u32 {
+    (RECORD_HEADER_SIZE + len + RECORD_FOOTER_SIZE).next_multiple_of(ALIGN) as u32
+}
+
+enum Scan {
+    /// erased space
+    End,
+    /// a record cut short by a power loss or otherwise damaged
+    Torn,
+    /// an intact record with this payload length
+    Record(usize),
+}
+
+/// circular log of variable length records in a range of sectors.
+///
+/// records are appended to the head sector. when it is full, the next sector is erased,
+/// dropping the oldest records, and becomes the new head. each sector starts with a sequence
+/// number that grows by one per sector, so the head is found at boot by a binary search over
+/// the sector headers.
+///
+/// every record has a header with its length and a CRC, and a footer repeating the length so
+/// the log can be walked backwards. a record torn by a power loss fails its checks and ends
+/// its sector; appending continues in the next sector.
+pub struct RingLog<F> {
+    flash: F,
+    /// start of the range on the device
+    offset: u32,
+    sectors: u32,
+    head: u32,
+    head_seq: u32,
+    oldest: u32,
+    /// next free offset in the head sector
+    write_offset: u32,
+}
+
+impl<F> RingLog<F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    /// open the log in the sector aligned `range`. a range without a log is erased and an
+    /// empty log started.
+    pub fn new(flash: F, range: Range<u32>) -> Result<Self, W25QError> {
+        if range.start > range.end {
+            return Err(W25QError::OutOfBounds);
+        }
+        if !(range.start as usize).is_multiple_of(SECTOR_SIZE)
+            || !(range.end as usize).is_multiple_of(SECTOR_SIZE)
+            || !SECTOR_SIZE.is_multiple_of(F::ERASE_SIZE)
+            || !ALIGN.is_multiple_of(F::WRITE_SIZE)
+            || F::READ_SIZE != 1
+        {
+            return Err(W25QError::NotAligned);
+        }
+        if range.end as u64 > flash.capacity() as u64 {
+            return Err(W25QError::OutOfBounds);
+        }
+        let sectors = (range.end - range.start) / SECTOR_SIZE as u32;
+        if sectors < 2 {
+            return Err(W25QError::InvalidConfig);
+        }
+        let mut log = Self {
+            flash,
+            offset: range.start,
+            sectors,
+            head: 0,
+            head_seq: 0,
+            oldest: 0,
+            write_offset: SECTOR_HEADER_SIZE,
+        };
+        log.mount()?;
+        Ok(log)
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// erase every record.
+    pub fn clear(&mut self) -> Result<(), W25QError> {
+        let end = self.sector_address(self.sectors);
+        self.flash.erase(self.offset, end)?;
+        self.head = 0;
+        self.head_seq = 0;
+        self.oldest = 0;
+        self.write_header(0, 0)?;
+        self.write_offset = SECTOR_HEADER_SIZE;
+        Ok(())
+    }
+
+    pub fn is_empty(&mut self) -> Result<bool, W25QError> {
+        let mut sector = self.oldest;
+        loop {
+            if self.valid_end(sector)?.0 > SECTOR_HEADER_SIZE {
+                return Ok(false);
+            }
+            if sector == self.head {
+                return Ok(true);
+            }
+            sector = self.next_sector(sector);
+        }
+    }
+
+    fn sector_address(&self, sector: u32) -> u32 {
+        self.offset + sector * SECTOR_SIZE as u32
+    }
+
+    fn next_sector(&self, sector: u32) -> u32 {
+        (sector + 1) % self.sectors
+    }
+
+    fn prev_sector(&self, sector: u32) -> u32 {
+        (sector + self.sectors - 1) % self.sectors
+    }
+
+    fn read_seq(&mut self, sector: u32) -> Result<Option<u32>, W25QError> {
+        let address = self.sector_address(sector);
+        sector::read_seq(&mut self.flash, address, SECTOR_MAGIC)
+    }
+
+    fn write_header(&mut self, sector: u32, seq: u32) -> Result<(), W25QError> {
+        let address = self.sector_address(sector);
+        sector::write_header(&mut self.flash, address, SECTOR_MAGIC, seq)
+    }
+
+    fn mount(&mut self) -> Result<(), W25QError> {
+        // sequence numbers rise from sector 0 up to the head and drop after it, where the
+        // older sectors of the previous round or erased sectors follow
+        let head = match self.read_seq(0)? {
+            Some(first) => {
+                let (mut lo, mut hi) = (0, self.sectors);
+                while hi - lo > 1 {
+                    let mid = lo + (hi - lo) / 2;
+                    if self.read_seq(mid)?.is_some_and(|seq| seq >= first) {
+                        lo = mid;
+                    } else {
+                        hi = mid;
+                    }
+                }
+                lo
+            }
+            // opening sector 0 as the head was interrupted
+            None if self.read_seq(self.sectors - 1)?.is_some() => self.sectors - 1,
+            None => return self.clear(),
+        };
+        let head_seq = self.read_seq(head)?.ok_or(W25QError::Corrupt)?;
+        self.head = head;
+        self.head_seq = head_seq;
+        // the oldest sector follows the head, or the one after that if erasing ahead of the
+        // head was interrupted; before the first wrap it is sector 0
+        self.oldest = 0;
+        let mut sector = head;
+        for back in [self.sectors - 1, self.sectors - 2] {
+            sector = self.next_sector(sector);
+            if back > 0 && self.read_seq(sector)? == Some(head_seq.wrapping_sub(back)) {
+                self.oldest = sector;
+                break;
+            }
+        }
+        let (end, clean) = self.valid_end(head)?;
+        self.write_offset = end;
+        if !clean
+            || !self.is_erased(self.sector_address(head) + end..self.sector_address(head + 1))?
+        {
+            // nothing can be appended behind a torn record
+            self.write_offset = SECTOR_SIZE as u32;
+        }
+        Ok(())
+    }
+
+    fn is_erased(&mut self, range: Range<u32>) -> Result<bool, W25QError> {
+        sector::is_erased(&mut self.flash, range)
+    }
+
+    /// check the record at `offset` in `sector`.
+    fn scan(&mut self, sector: u32, offset: u32) -> Result<Scan, W25QError> {
+        if offset + (RECORD_HEADER_SIZE + RECORD_FOOTER_SIZE) as u32 > SECTOR_SIZE as u32 {
+            return Ok(Scan::End);
+        }
+        let base = self.sector_address(sector) + offset;
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        self.flash.read(base, &mut header)?;
+        if header.iter().all(|b| *b == 0xFF) {
+            return Ok(Scan::End);
+        }
+        let len = u16::from_le_bytes([header[0], header[1]]);
+        if len != !u16::from_le_bytes([header[2], header[3]])
+            || offset + record_size(len as usize) > SECTOR_SIZE as u32
+        {
+            return Ok(Scan::Torn);
+        }
+        let size = record_size(len as usize);
+        let mut footer = [0u8; RECORD_FOOTER_SIZE];
+        self.flash
+            .read(base + size - RECORD_FOOTER_SIZE as u32, &mut footer)?;
+        if footer != header[..4] {
+            return Ok(Scan::Torn);
+        }
+        let mut crc = Crc32::new();
+        let mut chunk = [0u8; CHUNK];
+        let payload = base + RECORD_HEADER_SIZE as u32;
+        for at in (0..len as u32).step_by(CHUNK) {
+            let n = (len as u32 - at).min(CHUNK as u32) as usize;
+            self.flash.read(payload + at, &mut chunk[..n])?;
+            crc.update(&chunk[..n]);
+        }
+        if crc.finalize().to_le_bytes() != header[4..8] {
+            return Ok(Scan::Torn);
+        }
+        Ok(Scan::Record(len as usize))
+    }
+
+    /// offset after the last intact record of `sector`, and whether the records end in
+    /// erased space rather than a torn record.
+    fn valid_end(&mut self, sector: u32) -> Result<(u32, bool), W25QError> {
+        let mut offset = SECTOR_HEADER_SIZE;
+        loop {
+            match self.scan(sector, offset)? {
+                Scan::End => return Ok((offset, true)),
+                Scan::Torn => return Ok((offset, false)),
+                Scan::Record(len) => offset += record_size(len),
+            }
+        }
+    }
+
+    fn read_payload(
+        &mut self,
+        sector: u32,
+        offset: u32,
+        buf: &mut [u8],
+        len: usize,
+    ) -> Result<(), W25QError> {
+        if buf.len() < len {
+            return Err(W25QError::OutOfBounds);
+        }
+        let address = self.sector_address(sector) + offset + RECORD_HEADER_SIZE as u32;
+        self.flash.read(address, &mut buf[..len])?;
+        Ok(())
+    }
+
+    /// append `data` as one record, erasing the oldest sector when the head is full.
+    pub fn append(&mut self, data: &[u8]) -> Result<(), W25QError> {
+        if data.len() > MAX_RECORD_LEN {
+            return Err(W25QError::OutOfBounds);
+        }
+        let size = record_size(data.len());
+        if self.write_offset + size > SECTOR_SIZE as u32 {
+            self.advance()?;
+        }
+        let len = data.len() as u16;
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        header[0..2].copy_from_slice(&len.to_le_bytes());
+        header[2..4].copy_from_slice(&(!len).to_le_bytes());
+        header[4..8].copy_from_slice(&Crc32::checksum(data).to_le_bytes());
+        let padding = [0xFFu8; ALIGN];
+        let pad = size as usize - RECORD_HEADER_SIZE - data.len() - RECORD_FOOTER_SIZE;
+        let address = self.sector_address(self.head) + self.write_offset;
+        sector::write_padded(
+            &mut self.flash,
+            address,
+            &[&header, data, &padding[..pad], &header[..4]],
+        )?;
+        self.write_offset += size;
+        Ok(())
+    }
+
+    /// erase the sector after the head and make it the new head.
+    fn advance(&mut self) -> Result<(), W25QError> {
+        let sector = self.next_sector(self.head);
+        let address = self.sector_address(sector);
+        self.flash.erase(address, address + SECTOR_SIZE as u32)?;
+        if self.oldest == sector {
+            self.oldest = self.next_sector(sector);
+        }
+        let seq = self.head_seq.wrapping_add(1);
+        self.write_header(sector, seq)?;
+        self.head = sector;
+        self.head_seq = seq;
+        self.write_offset = SECTOR_HEADER_SIZE;
+        Ok(())
+    }
+
+    /// records from the oldest to the newest.
+    pub fn oldest_first(&mut self) -> Records<'_, F> {
+        Records {
+            sector: self.oldest,
+            offset: SECTOR_HEADER_SIZE,
+            reverse: false,
+            done: false,
+            log: self,
+        }
+    }
+
+    /// records from the newest to the oldest.
+    pub fn newest_first(&mut self) -> Records<'_, F> {
+        Records {
+            sector: self.head,
+            // found on the first call
+            offset: 0,
+            reverse: true,
+            done: false,
+            log: self,
+        }
+    }
+}
+
+/// cursor over the records of a [`RingLog`], from [`RingLog::oldest_first`] or
+/// [`RingLog::newest_first`].
+pub struct Records<'a, F> {
+    log: &'a mut RingLog<F>,
+    sector: u32,
+    /// next record going forward, end of the next record going backward
+    offset: u32,
+    reverse: bool,
+    done: bool,
+}
+
+impl<F> Records<'_, F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    /// read the next record into `buf`, returning its length. fails with `OutOfBounds` if
+    /// `buf` is too small.
+    pub fn next(&mut self, buf: &mut [u8]) -> Result<Option<usize>, W25QError> {
+        if self.reverse {
+            self.next_backward(buf)
+        } else {
+            self.next_forward(buf)
+        }
+    }
+
+    fn next_forward(&mut self, buf: &mut [u8]) -> Result<Option<usize>, W25QError> {
+        while !self.done {
+            match self.log.scan(self.sector, self.offset)? {
+                Scan::Record(len) => {
+                    self.log.read_payload(self.sector, self.offset, buf, len)?;
+                    self.offset += record_size(len);
+                    return Ok(Some(len));
+                }
+                Scan::End | Scan::Torn if self.sector == self.log.head => self.done = true,
+                Scan::End | Scan::Torn => {
+                    self.sector = self.log.next_sector(self.sector);
+                    self.offset = SECTOR_HEADER_SIZE;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_backward(&mut self, buf: &mut [u8]) -> Result<Option<usize>, W25QError> {
+        if self.offset == 0 && !self.done {
+            self.offset = self.log.valid_end(self.sector)?.0;
+        }
+        while !self.done {
+            if self.offset <= SECTOR_HEADER_SIZE {
+                if self.sector == self.log.oldest {
+                    self.done = true;
+                } else {
+                    self.sector = self.log.prev_sector(self.sector);
+                    self.offset = self.log.valid_end(self.sector)?.0;
+                }
+                continue;
+            }
+            let mut footer = [0u8; RECORD_FOOTER_SIZE];
+            let address = self.log.sector_address(self.sector) + self.offset;
+            self.log
+                .flash
+                .read(address - RECORD_FOOTER_SIZE as u32, &mut footer)?;
+            let len = u16::from_le_bytes([footer[0], footer[1]]) as usize;
+            let size = record_size(len);
+            // valid_end only passes intact records, so a mismatch means the sector changed
+            if size > self.offset - SECTOR_HEADER_SIZE {
+                return Err(W25QError::Corrupt);
+            }
+            let start = self.offset - size;
+            self.log.read_payload(self.sector, start, buf, len)?;
+            self.offset = start;
+            return Ok(Some(len));
+        }
+        Ok(None)
+    }
+}
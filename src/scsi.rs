@@ -0,0 +1,364 @@
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::{
+    block::{FlashBlocks, BLOCK_SIZE},
+    io::W25QError,
+};
+
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1A;
+const START_STOP_UNIT: u8 = 0x1B;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1E;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2A;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+
+const INQUIRY_LEN: usize = 36;
+const REQUEST_SENSE_LEN: usize = 18;
+
+/// SCSI sense data: key, additional sense code and qualifier.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sense {
+    pub key: u8,
+    pub asc: u8,
+    pub ascq: u8,
+}
+
+impl Sense {
+    pub const NO_SENSE: Sense = Sense::new(0x00, 0x00, 0x00);
+    pub const INVALID_COMMAND: Sense = Sense::new(0x05, 0x20, 0x00);
+    pub const LBA_OUT_OF_RANGE: Sense = Sense::new(0x05, 0x21, 0x00);
+    pub const INVALID_FIELD_IN_CDB: Sense = Sense::new(0x05, 0x24, 0x00);
+    pub const READ_ERROR: Sense = Sense::new(0x03, 0x11, 0x00);
+    pub const WRITE_ERROR: Sense = Sense::new(0x03, 0x0C, 0x00);
+
+    pub const fn new(key: u8, asc: u8, ascq: u8) -> Self {
+        Self { key, asc, ascq }
+    }
+}
+
+/// data stage the transport runs after [`ScsiHandler::command`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    NoData,
+    /// send this many bytes to the host, read with [`ScsiHandler::data_in`]
+    DataIn(u32),
+    /// receive this many bytes from the host, pass to [`ScsiHandler::data_out`]
+    DataOut(u32),
+}
+
+/// command result, for the status stage.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Passed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// `len` bytes prepared in the buffer, `pos` sent
+    Response {
+        len: usize,
+        pos: usize,
+    },
+    /// blocks left to send, `pos` bytes of the buffered block sent
+    Read {
+        lba: u32,
+        blocks: u32,
+        pos: usize,
+    },
+    /// blocks left to receive, `pos` bytes of the next block buffered
+    Write {
+        lba: u32,
+        blocks: u32,
+        pos: usize,
+    },
+}
+
+/// transport independent SCSI block command handler over a [`FlashBlocks`].
+///
+/// a USB mass storage class (bulk only transport) feeds it the command block of each CBW,
+/// runs the returned data stage through [`ScsiHandler::data_in`] or
+/// [`ScsiHandler::data_out`] in packets of any size, and reports [`ScsiHandler::status`] in
+/// the CSW. errors are reported to the host through REQUEST SENSE.
+///
+/// the block cache is flushed at the end of every WRITE(10), on SYNCHRONIZE CACHE, START
+/// STOP UNIT and PREVENT ALLOW MEDIUM REMOVAL, so a host ejecting or unplugging after a
+/// write finds its data on the flash.
+pub struct ScsiHandler<F> {
+    blocks: FlashBlocks<F>,
+    state: State,
+    status: Status,
+    sense: Sense,
+    /// one block of read or write data, or a response
+    buf: [u8; BLOCK_SIZE],
+    vendor: [u8; 8],
+    product: [u8; 16],
+    revision: [u8; 4],
+}
+
+/// copy `text` into `field`, padded with spaces as INQUIRY expects.
+fn pad(field: &mut [u8], text: &str) {
+    field.fill(b' ');
+    let len = text.len().min(field.len());
+    field[..len].copy_from_slice(&text.as_bytes()[..len]);
+}
+
+impl<F> ScsiHandler<F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    pub fn new(blocks: FlashBlocks<F>) -> Self {
+        let mut handler = Self {
+            blocks,
+            state: State::Idle,
+            status: Status::Passed,
+            sense: Sense::NO_SENSE,
+            buf: [0; BLOCK_SIZE],
+            vendor: [0; 8],
+            product: [0; 16],
+            revision: [0; 4],
+        };
+        handler.set_inquiry("Winbond", "W25Q Flash", "0.1");
+        handler
+    }
+
+    /// vendor, product and revision reported by INQUIRY, at most 8, 16 and 4 bytes.
+    pub fn set_inquiry(&mut self, vendor: &str, product: &str, revision: &str) {
+        pad(&mut self.vendor, vendor);
+        pad(&mut self.product, product);
+        pad(&mut self.revision, revision);
+    }
+
+    pub fn blocks_mut(&mut self) -> &mut FlashBlocks<F> {
+        &mut self.blocks
+    }
+
+    pub fn into_inner(self) -> FlashBlocks<F> {
+        self.blocks
+    }
+
+    /// status of the last command.
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    fn fail(&mut self, sense: Sense) -> Phase {
+        self.sense = sense;
+        self.status = Status::Failed;
+        self.state = State::Idle;
+        Phase::NoData
+    }
+
+    /// write the block cache to the flash, failing the command if that fails.
+    fn flush(&mut self) -> Phase {
+        match self.blocks.flush() {
+            Ok(()) => Phase::NoData,
+            Err(_) => self.fail(Sense::WRITE_ERROR),
+        }
+    }
+
+    fn respond(&mut self, len: usize, allocation: usize) -> Phase {
+        let len = len.min(allocation);
+        self.state = State::Response { len, pos: 0 };
+        Phase::DataIn(len as u32)
+    }
+
+    /// LBA and block count of a READ(10) or WRITE(10), checked against the capacity.
+    fn transfer(&self, cb: &[u8]) -> Result<(u32, u32), Sense> {
+        let lba = u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]);
+        let blocks = u16::from_be_bytes([cb[7], cb[8]]) as u32;
+        if lba as u64 + blocks as u64 > self.blocks.block_count() as u64 {
+            return Err(Sense::LBA_OUT_OF_RANGE);
+        }
+        Ok((lba, blocks))
+    }
+
+    /// start the command in `cb`, a command block as carried by a CBW.
+    pub fn command(&mut self, cb: &[u8]) -> Phase {
+        self.status = Status::Passed;
+        self.state = State::Idle;
+        let Some(&opcode) = cb.first() else {
+            return self.fail(Sense::INVALID_COMMAND);
+        };
+        // sense data describes the last command only, REQUEST SENSE reports it
+        if opcode != REQUEST_SENSE {
+            self.sense = Sense::NO_SENSE;
+        }
+        let len = match opcode {
+            TEST_UNIT_READY
+            | PREVENT_ALLOW_MEDIUM_REMOVAL
+            | START_STOP_UNIT
+            | REQUEST_SENSE
+            | INQUIRY
+            | MODE_SENSE_6 => 6,
+            READ_CAPACITY_10 | READ_10 | WRITE_10 | SYNCHRONIZE_CACHE_10 => 10,
+            _ => return self.fail(Sense::INVALID_COMMAND),
+        };
+        if cb.len() < len {
+            return self.fail(Sense::INVALID_FIELD_IN_CDB);
+        }
+        match opcode {
+            TEST_UNIT_READY => Phase::NoData,
+            // the host is about to eject or lock the medium
+            PREVENT_ALLOW_MEDIUM_REMOVAL | START_STOP_UNIT => self.flush(),
+            REQUEST_SENSE => {
+                self.buf[..REQUEST_SENSE_LEN].fill(0);
+                self.buf[0] = 0x70;
+                self.buf[2] = self.sense.key;
+                self.buf[7] = (REQUEST_SENSE_LEN - 8) as u8;
+                self.buf[12] = self.sense.asc;
+                self.buf[13] = self.sense.ascq;
+                self.sense = Sense::NO_SENSE;
+                self.respond(REQUEST_SENSE_LEN, cb[4] as usize)
+            }
+            INQUIRY => {
+                // vital product data pages are not supported
+                if cb[1] & 0x01 != 0 {
+                    return self.fail(Sense::INVALID_FIELD_IN_CDB);
+                }
+                self.buf[..INQUIRY_LEN].fill(0);
+                // direct access device, removable, SPC-2, response format 2
+                self.buf[1] = 0x80;
+                self.buf[2] = 0x04;
+                self.buf[3] = 0x02;
+                self.buf[4] = (INQUIRY_LEN - 5) as u8;
+                self.buf[8..16].copy_from_slice(&self.vendor);
+                self.buf[16..32].copy_from_slice(&self.product);
+                self.buf[32..36].copy_from_slice(&self.revision);
+                let allocation = u16::from_be_bytes([cb[3], cb[4]]) as usize;
+                self.respond(INQUIRY_LEN, allocation)
+            }
+            MODE_SENSE_6 => {
+                // header only: no block descriptors or pages, not write protected
+                self.buf[..4].copy_from_slice(&[3, 0, 0, 0]);
+                self.respond(4, cb[4] as usize)
+            }
+            READ_CAPACITY_10 => {
+                let last = self.blocks.block_count().saturating_sub(1);
+                self.buf[..4].copy_from_slice(&last.to_be_bytes());
+                self.buf[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                self.respond(8, 8)
+            }
+            READ_10 => match self.transfer(cb) {
+                Ok((lba, blocks)) => {
+                    self.state = State::Read {
+                        lba,
+                        blocks,
+                        pos: BLOCK_SIZE,
+                    };
+                    Phase::DataIn(blocks * BLOCK_SIZE as u32)
+                }
+                Err(sense) => self.fail(sense),
+            },
+            WRITE_10 => match self.transfer(cb) {
+                Ok((lba, blocks)) => {
+                    self.state = State::Write {
+                        lba,
+                        blocks,
+                        pos: 0,
+                    };
+                    Phase::DataOut(blocks * BLOCK_SIZE as u32)
+                }
+                Err(sense) => self.fail(sense),
+            },
+            SYNCHRONIZE_CACHE_10 => self.flush(),
+            _ => self.fail(Sense::INVALID_COMMAND),
+        }
+    }
+
+    /// fill `buf` with the next bytes of the data-in stage, returning how many were written.
+    /// 0 means the stage is over; earlier than announced if a read failed.
+    pub fn data_in(&mut self, buf: &mut [u8]) -> usize {
+        let mut written = 0;
+        while written < buf.len() {
+            match self.state {
+                State::Response { len, pos } => {
+                    let n = (len - pos).min(buf.len() - written);
+                    buf[written..written + n].copy_from_slice(&self.buf[pos..pos + n]);
+                    written += n;
+                    self.state = if pos + n == len {
+                        State::Idle
+                    } else {
+                        State::Response { len, pos: pos + n }
+                    };
+                }
+                State::Read { blocks: 0, pos, .. } if pos == BLOCK_SIZE => {
+                    self.state = State::Idle;
+                }
+                State::Read { lba, blocks, pos } if pos == BLOCK_SIZE => {
+                    if self.blocks.read_blocks(lba, &mut self.buf).is_err() {
+                        self.fail(Sense::READ_ERROR);
+                        break;
+                    }
+                    self.state = State::Read {
+                        lba: lba + 1,
+                        blocks: blocks - 1,
+                        pos: 0,
+                    };
+                }
+                State::Read { lba, blocks, pos } => {
+                    let n = (BLOCK_SIZE - pos).min(buf.len() - written);
+                    buf[written..written + n].copy_from_slice(&self.buf[pos..pos + n]);
+                    written += n;
+                    self.state = State::Read {
+                        lba,
+                        blocks,
+                        pos: pos + n,
+                    };
+                }
+                State::Idle | State::Write { .. } => break,
+            }
+        }
+        written
+    }
+
+    /// take the next bytes of the data-out stage, returning how many were used. a failed
+    /// write ends the stage, so later data is not taken.
+    pub fn data_out(&mut self, data: &[u8]) -> usize {
+        let mut used = 0;
+        while used < data.len() {
+            let State::Write { lba, blocks, pos } = self.state else {
+                break;
+            };
+            if blocks == 0 {
+                self.state = State::Idle;
+                break;
+            }
+            let n = (BLOCK_SIZE - pos).min(data.len() - used);
+            self.buf[pos..pos + n].copy_from_slice(&data[used..used + n]);
+            used += n;
+            if pos + n < BLOCK_SIZE {
+                self.state = State::Write {
+                    lba,
+                    blocks,
+                    pos: pos + n,
+                };
+                continue;
+            }
+            if self.blocks.write_blocks(lba, &self.buf).is_err() {
+                self.fail(Sense::WRITE_ERROR);
+                break;
+            }
+            if blocks == 1 {
+                self.state = State::Idle;
+                self.flush();
+                break;
+            }
+            self.state = State::Write {
+                lba: lba + 1,
+                blocks: blocks - 1,
+                pos: 0,
+            };
+        }
+        used
+    }
+}
@@ -0,0 +1,71 @@
+use core::cell::RefCell;
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::{block::FlashBlocks, io::W25QError};
+
+/// `embedded_sdmmc` [`BlockDevice`] over a [`FlashBlocks`], for FAT volumes.
+///
+/// writes are cached per sector; call [`SdmmcBlocks::sync`] after closing files or before
+/// power off.
+pub struct SdmmcBlocks<F> {
+    blocks: RefCell<FlashBlocks<F>>,
+}
+
+impl<F> SdmmcBlocks<F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    pub fn new(flash: F) -> Result<Self, W25QError> {
+        Ok(Self {
+            blocks: RefCell::new(FlashBlocks::new(flash)?),
+        })
+    }
+
+    /// write cached blocks to the flash.
+    pub fn sync(&self) -> Result<(), W25QError> {
+        self.blocks.borrow_mut().flush()
+    }
+
+    /// flush and return the flash device.
+    pub fn into_inner(self) -> Result<F, W25QError> {
+        let mut blocks = self.blocks.into_inner();
+        blocks.flush()?;
+        Ok(blocks.into_inner())
+    }
+}
+
+impl<F> BlockDevice for SdmmcBlocks<F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    type Error = W25QError;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), W25QError> {
+        let mut dev = self.blocks.borrow_mut();
+        for (i, block) in blocks.iter_mut().enumerate() {
+            dev.read_blocks(start_block_idx.0 + i as u32, &mut block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), W25QError> {
+        let mut dev = self.blocks.borrow_mut();
+        for (i, block) in blocks.iter().enumerate() {
+            dev.write_blocks(start_block_idx.0 + i as u32, &block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, W25QError> {
+        Ok(BlockCount(self.blocks.borrow().block_count()))
+    }
+}
@@ -0,0 +1,100 @@
+//! layout and flash helpers shared by the logs that fill sectors with records, [`crate::kv`]
+//! and [`crate::ringlog`].
+//!
+//! every sector starts with a header holding a magic, a sequence number and a CRC of both;
+//! records follow on `ALIGN` byte boundaries.
+
+use core::ops::Range;
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::{checksum::Crc32, io::W25QError};
+
+pub(crate) const SECTOR_HEADER_SIZE: u32 = 16;
+/// records start on this alignment
+pub(crate) const ALIGN: usize = 4;
+/// bytes read or written at once when scanning or copying
+pub(crate) const CHUNK: usize = 64;
+
+/// sequence number of the sector header at `address`, `None` if it is not a valid header with
+/// `magic`.
+pub(crate) fn read_seq<F>(
+    flash: &mut F,
+    address: u32,
+    magic: [u8; 4],
+) -> Result<Option<u32>, W25QError>
+where
+    F: ReadNorFlash,
+    W25QError: From<F::Error>,
+{
+    let mut header = [0u8; SECTOR_HEADER_SIZE as usize];
+    flash.read(address, &mut header)?;
+    if header[0..4] != magic || Crc32::checksum(&header[..8]).to_le_bytes() != header[8..12] {
+        return Ok(None);
+    }
+    Ok(Some(u32::from_le_bytes([
+        header[4], header[5], header[6], header[7],
+    ])))
+}
+
+/// program a sector header with `magic` and `seq` at `address`.
+pub(crate) fn write_header<F>(
+    flash: &mut F,
+    address: u32,
+    magic: [u8; 4],
+    seq: u32,
+) -> Result<(), W25QError>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    let mut header = [0xFFu8; SECTOR_HEADER_SIZE as usize];
+    header[0..4].copy_from_slice(&magic);
+    header[4..8].copy_from_slice(&seq.to_le_bytes());
+    let crc = Crc32::checksum(&header[..8]);
+    header[8..12].copy_from_slice(&crc.to_le_bytes());
+    flash.write(address, &header)?;
+    Ok(())
+}
+
+pub(crate) fn is_erased<F>(flash: &mut F, range: Range<u32>) -> Result<bool, W25QError>
+where
+    F: ReadNorFlash,
+    W25QError: From<F::Error>,
+{
+    let mut chunk = [0u8; CHUNK];
+    for at in range.clone().step_by(CHUNK) {
+        let len = (range.end - at).min(CHUNK as u32) as usize;
+        flash.read(at, &mut chunk[..len])?;
+        if chunk[..len].iter().any(|b| *b != 0xFF) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// write `parts` back to back at `address`, padding the end with 0xFF to `ALIGN`.
+pub(crate) fn write_padded<F>(flash: &mut F, address: u32, parts: &[&[u8]]) -> Result<(), W25QError>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    let mut chunk = [0xFFu8; CHUNK];
+    let mut len = 0;
+    let mut at = address;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        chunk[len] = *byte;
+        len += 1;
+        if len == CHUNK {
+            flash.write(at, &chunk)?;
+            at += CHUNK as u32;
+            len = 0;
+        }
+    }
+    if len > 0 {
+        let padded = len.next_multiple_of(ALIGN);
+        chunk[len..padded].fill(0xFF);
+        flash.write(at, &chunk[..padded])?;
+    }
+    Ok(())
+}
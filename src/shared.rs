@@ -0,0 +1,196 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::RawMutex, Mutex};
+use embedded_hal::{delay, spi};
+use embedded_io::{ErrorKind, SeekFrom};
+use embedded_storage::nor_flash::{self, MultiwriteNorFlash, NorFlash, ReadNorFlash};
+
+use crate::{io::W25QError, W25Q};
+
+/// mutex holding a [`W25Q`] shared between tasks.
+///
+/// use `NoopRawMutex` within one executor or `ThreadModeRawMutex` for thread mode tasks. the
+/// lock is held while a program or erase polls busy, so a `CriticalSectionRawMutex` would
+/// keep interrupts disabled for up to a chip erase.
+pub type SharedMutex<M, SPI, DELAY> = Mutex<M, RefCell<W25Q<SPI, DELAY>>>;
+
+/// handle to a [`W25Q`] shared through a [`SharedMutex`].
+///
+/// every operation locks the device for its whole duration, so a program or erase is atomic
+/// with respect to other handles. each handle has its own seek pointer for the `embedded_io`
+/// traits.
+pub struct SharedW25Q<'a, M, SPI, DELAY>
+where
+    M: RawMutex,
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    shared: &'a SharedMutex<M, SPI, DELAY>,
+    /// address pointer for seek operations
+    seek_ptr: u32,
+}
+
+impl<'a, M, SPI, DELAY> SharedW25Q<'a, M, SPI, DELAY>
+where
+    M: RawMutex,
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    pub fn new(shared: &'a SharedMutex<M, SPI, DELAY>) -> Self {
+        Self {
+            shared,
+            seek_ptr: 0,
+        }
+    }
+
+    /// run `f` with exclusive access to the device.
+    ///
+    /// the lock is held until `f` returns; do not use another handle to the same device
+    /// from inside `f`.
+    pub fn lock<R>(&self, f: impl FnOnce(&mut W25Q<SPI, DELAY>) -> R) -> R {
+        self.shared.lock(|dev| f(&mut dev.borrow_mut()))
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.lock(|dev| W25Q::capacity(dev))
+    }
+}
+
+impl<M, SPI, DELAY> Clone for SharedW25Q<'_, M, SPI, DELAY>
+where
+    M: RawMutex,
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared,
+            seek_ptr: self.seek_ptr,
+        }
+    }
+}
+
+impl<M, SPI, DELAY> nor_flash::ErrorType for SharedW25Q<'_, M, SPI, DELAY>
+where
+    M: RawMutex,
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    type Error = W25QError;
+}
+
+impl<M, SPI, DELAY> ReadNorFlash for SharedW25Q<'_, M, SPI, DELAY>
+where
+    M: RawMutex,
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), W25QError> {
+        self.lock(|dev| ReadNorFlash::read(dev, offset, bytes))
+    }
+
+    fn capacity(&self) -> usize {
+        SharedW25Q::capacity(self) as usize
+    }
+}
+
+impl<M, SPI, DELAY> NorFlash for SharedW25Q<'_, M, SPI, DELAY>
+where
+    M: RawMutex,
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = crate::SECTOR_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), W25QError> {
+        self.lock(|dev| dev.erase_range(from..to))
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), W25QError> {
+        self.lock(|dev| dev.program(offset, bytes))
+    }
+}
+
+impl<M, SPI, DELAY> MultiwriteNorFlash for SharedW25Q<'_, M, SPI, DELAY>
+where
+    M: RawMutex,
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+}
+
+impl<M, SPI, DELAY> embedded_io::ErrorType for SharedW25Q<'_, M, SPI, DELAY>
+where
+    M: RawMutex,
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    type Error = W25QError;
+}
+
+impl<M, SPI, DELAY> embedded_io::Read for SharedW25Q<'_, M, SPI, DELAY>
+where
+    M: RawMutex,
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, W25QError> {
+        let address = self.seek_ptr;
+        // stop at the end of the device, where 0 means end of file
+        let len = (SharedW25Q::capacity(self) - address as u64).min(buf.len() as u64) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        self.lock(|dev| dev.read_at(address, &mut buf[..len]))?;
+        self.seek_ptr = address + len as u32;
+        Ok(len)
+    }
+}
+
+impl<M, SPI, DELAY> embedded_io::Write for SharedW25Q<'_, M, SPI, DELAY>
+where
+    M: RawMutex,
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, W25QError> {
+        let address = self.seek_ptr;
+        let end = u32::try_from(buf.len())
+            .ok()
+            .and_then(|len| address.checked_add(len))
+            .ok_or(W25QError::OutOfBounds)?;
+        self.lock(|dev| dev.program(address, buf))?;
+        self.seek_ptr = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), W25QError> {
+        Ok(())
+    }
+}
+
+impl<M, SPI, DELAY> embedded_io::Seek for SharedW25Q<'_, M, SPI, DELAY>
+where
+    M: RawMutex,
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, W25QError> {
+        let capacity = SharedW25Q::capacity(self) as i64;
+        let target = match pos {
+            SeekFrom::Start(pos) => i64::try_from(pos).ok(),
+            SeekFrom::End(pos) => capacity.checked_add(pos),
+            SeekFrom::Current(pos) => (self.seek_ptr as i64).checked_add(pos),
+        };
+        match target {
+            Some(target) if (0..=capacity).contains(&target) => {
+                self.seek_ptr = target as u32;
+                Ok(target as u64)
+            }
+            _ => Err(W25QError::Io(ErrorKind::InvalidInput)),
+        }
+    }
+}
@@ -0,0 +1,958 @@
+use core::ops::Range;
+use std::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    convert::Infallible,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    vec,
+    vec::Vec,
+};
+
+use embedded_hal::{
+    delay::DelayNs,
+    spi::{self, ErrorType, Operation, SpiDevice},
+};
+
+use crate::{
+    config::Chip,
+    io::W25QError,
+    w25n::{
+        EccStatus, NandRegister, NAND_LUT_SIZE, NAND_PAGES_PER_BLOCK, NAND_PAGE_SIZE,
+        NAND_SPARE_SIZE, W25N,
+    },
+    Register, W25QConfig, BLOCK_SIZE_64, MANUFACTURER_ID, PAGE_SIZE, SECTOR_SIZE, W25Q,
+};
+
+const WRITE_ENABLE: u8 = Register::WRITE_ENABLE as u8;
+const VOLATILE_SR_WRITE_ENABLE: u8 = Register::VOLATILE_SR_WRITE_ENABLE as u8;
+const WRITE_DISABLE: u8 = Register::WRITE_DISABLE as u8;
+const RELEASE_POWER_DOWN: u8 = Register::RELEASE_POWER_DOWN as u8;
+const JEDEC_ID: u8 = Register::JEDEC_ID as u8;
+const READ_SFDP_REGISTER: u8 = Register::READ_SFDP_REGISTER as u8;
+const READ_UNIQUE_ID: u8 = Register::READ_UNIQUE_ID as u8;
+const READ_DATA: u8 = Register::READ_DATA as u8;
+const FAST_READ: u8 = Register::FAST_READ as u8;
+const PAGE_PROGRAM: u8 = Register::PAGE_PROGRAM as u8;
+const SECTOR_ERASE: u8 = Register::SECTOR_ERASE as u8;
+const BLOCK_ERASE_32KB: u8 = Register::BLOCK_ERASE_32KB as u8;
+const BLOCK_ERASE_64KB: u8 = Register::BLOCK_ERASE_64KB as u8;
+const CHIP_ERASE: u8 = Register::CHIP_ERASE as u8;
+const CHIP_ERASE_2: u8 = Register::CHIP_ERASE_2 as u8;
+const READ_STATUS_REGISTER_1: u8 = Register::READ_STATUS_REGISTER_1 as u8;
+const WRITE_STATUS_REGISTER_1: u8 = Register::WRITE_STATUS_REGISTER_1 as u8;
+const READ_STATUS_REGISTER_2: u8 = Register::READ_STATUS_REGISTER_2 as u8;
+const WRITE_STATUS_REGISTER_2: u8 = Register::WRITE_STATUS_REGISTER_2 as u8;
+const READ_STATUS_REGISTER_3: u8 = Register::READ_STATUS_REGISTER_3 as u8;
+const WRITE_STATUS_REGISTER_3: u8 = Register::WRITE_STATUS_REGISTER_3 as u8;
+const ERASE_SECURITY_REGISTER: u8 = Register::ERASE_SECURITY_REGISTER as u8;
+const PROGRAM_SECURITY_REGISTER: u8 = Register::PROGRAM_SECURITY_REGISTER as u8;
+const READ_SECURITY_REGISTER: u8 = Register::READ_SECURITY_REGISTER as u8;
+const GLOBAL_BLOCK_LOCK: u8 = Register::GLOBAL_BLOCK_LOCK as u8;
+const GLOBAL_BLOCK_UNLOCK: u8 = Register::GLOBAL_BLOCK_UNLOCK as u8;
+const READ_BLOCK_LOCK: u8 = Register::READ_BLOCK_LOCK as u8;
+const INDIVIDUAL_BLOCK_LOCK: u8 = Register::INDIVIDUAL_BLOCK_LOCK as u8;
+const INDIVIDUAL_BLOCK_UNLOCK: u8 = Register::INDIVIDUAL_BLOCK_UNLOCK as u8;
+const POWER_DOWN: u8 = Register::POWER_DOWN as u8;
+const ENABLE_RESET: u8 = Register::ENABLE_RESET as u8;
+const RESET_DEVICE: u8 = Register::RESET_DEVICE as u8;
+const ENTER_4_BYTE_ADDRESS_MODE: u8 = Register::ENTER_4_BYTE_ADDRESS_MODE as u8;
+const EXIT_4_BYTE_ADDRESS_MODE: u8 = Register::EXIT_4_BYTE_ADDRESS_MODE as u8;
+const SOFTWARE_DIE_SELECT: u8 = Register::SOFTWARE_DIE_SELECT as u8;
+
+const NAND_RESET: u8 = NandRegister::RESET as u8;
+const NAND_JEDEC_ID: u8 = NandRegister::JEDEC_ID as u8;
+const NAND_READ_STATUS_REGISTER: u8 = NandRegister::READ_STATUS_REGISTER as u8;
+const NAND_WRITE_STATUS_REGISTER: u8 = NandRegister::WRITE_STATUS_REGISTER as u8;
+const NAND_WRITE_ENABLE: u8 = NandRegister::WRITE_ENABLE as u8;
+const NAND_WRITE_DISABLE: u8 = NandRegister::WRITE_DISABLE as u8;
+const NAND_BAD_BLOCK_MANAGEMENT: u8 = NandRegister::BAD_BLOCK_MANAGEMENT as u8;
+const NAND_READ_BBM_LUT: u8 = NandRegister::READ_BBM_LUT as u8;
+const NAND_LAST_ECC_FAILURE_PAGE: u8 = NandRegister::LAST_ECC_FAILURE_PAGE as u8;
+const NAND_BLOCK_ERASE: u8 = NandRegister::BLOCK_ERASE as u8;
+const NAND_PROGRAM_DATA_LOAD: u8 = NandRegister::PROGRAM_DATA_LOAD as u8;
+const NAND_RANDOM_PROGRAM_DATA_LOAD: u8 = NandRegister::RANDOM_PROGRAM_DATA_LOAD as u8;
+const NAND_PROGRAM_EXECUTE: u8 = NandRegister::PROGRAM_EXECUTE as u8;
+const NAND_PAGE_DATA_READ: u8 = NandRegister::PAGE_DATA_READ as u8;
+const NAND_READ: u8 = NandRegister::READ as u8;
+const NAND_FAST_READ: u8 = NandRegister::FAST_READ as u8;
+
+/// WEL, write enable latch, in status register 1
+const SR1_WEL: u8 = 0x02;
+/// ADS, the current address mode, in status register 3
+const SR3_ADS: u8 = 0x01;
+/// WPS, individual block locks instead of the BP bits, in status register 3
+const SR3_WPS: u8 = 0x04;
+/// offset of the basic flash parameter table in the SFDP area
+const SFDP_BFPT: usize = 0x80;
+
+/// [`DelayNs`] that returns at once, the simulator is never busy.
+pub struct NoDelay;
+
+impl DelayNs for NoDelay {
+    fn delay_ns(&mut self, _ns: u32) {}
+}
+
+/// in-memory W25Q behind an [`SpiDevice`], for host tools and tests.
+///
+/// answers the command set the driver uses: JEDEC and unique ID, status registers, read,
+/// fast read, page program with wrap-around, sector, block and chip erase, security
+/// registers, SFDP, individual block locks, power down, reset, 4-byte address mode and W25M
+/// software die select. programming only clears bits and needs a write enable, like the real
+/// chip, and with WPS set locked blocks are neither programmed nor erased. the BP bits are
+/// stored but do not protect anything. operations complete at once.
+///
+/// every die of a W25M keeps its own status registers, write enable latch, address mode and
+/// power state; commands other than die select go to the selected die only.
+pub struct SimFlash {
+    chip: Chip,
+    data: Vec<u8>,
+    security: [[u8; PAGE_SIZE]; 3],
+    unique_id: [u8; 8],
+    /// individual lock bit of every sector
+    locks: Vec<bool>,
+    dies: Vec<Die>,
+    die: u8,
+    changes: Changes,
+}
+
+/// state each die of a package keeps for itself
+#[derive(Default)]
+struct Die {
+    status: [u8; 3],
+    /// status register values at power-up, written without a volatile write enable
+    status_nv: [u8; 3],
+    write_enabled: bool,
+    volatile_sr_write: bool,
+    four_byte: bool,
+    powered_down: bool,
+    reset_enabled: bool,
+}
+
+/// what the last command changed, for [`FileFlash`] to store
+#[derive(Default)]
+struct Changes {
+    data: Option<Range<usize>>,
+    security: Option<usize>,
+    status: bool,
+}
+
+impl SimFlash {
+    /// blank `chip`, all bytes 0xFF.
+    pub fn new(chip: Chip) -> Self {
+        Self {
+            chip,
+            data: vec![0xFF; chip.capacity() as usize],
+            security: [[0xFF; PAGE_SIZE]; 3],
+            unique_id: [0; 8],
+            // all blocks come up locked, effective once WPS is set
+            locks: vec![true; chip.capacity() as usize / SECTOR_SIZE],
+            dies: (0..chip.die_count()).map(|_| Die::default()).collect(),
+            die: 0,
+            changes: Changes::default(),
+        }
+    }
+
+    /// `chip` holding `image` from address 0, the rest erased. fails with `OutOfBounds` if
+    /// the image is larger than the chip.
+    pub fn from_image(chip: Chip, image: &[u8]) -> Result<Self, W25QError> {
+        let mut sim = Self::new(chip);
+        if image.len() > sim.data.len() {
+            return Err(W25QError::OutOfBounds);
+        }
+        sim.data[..image.len()].copy_from_slice(image);
+        Ok(sim)
+    }
+
+    /// the driver on this simulator, with the chip preset in the configuration.
+    pub fn into_device(self) -> Result<W25Q<SimFlash, NoDelay>, W25QError> {
+        let chip = self.chip;
+        W25Q::new(self, NoDelay, W25QConfig::new().chip(chip))
+    }
+
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    /// contents of the whole chip
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn set_unique_id(&mut self, id: [u8; 8]) {
+        self.unique_id = id;
+    }
+
+    /// non-volatile status registers of every die, in die order
+    fn status_nv(&self) -> Vec<u8> {
+        self.dies.iter().flat_map(|die| die.status_nv).collect()
+    }
+
+    fn jedec_id(&self) -> [u8; 3] {
+        let capacity = match self.chip {
+            Chip::W25Q16 => 0x15,
+            Chip::W25Q32 => 0x16,
+            Chip::W25Q64 => 0x17,
+            Chip::W25Q128 => 0x18,
+            Chip::W25Q256 => 0x19,
+            Chip::W25Q512 => 0x20,
+            Chip::W25M512 => return [MANUFACTURER_ID, 0x71, 0x19],
+        };
+        [MANUFACTURER_ID, 0x40, capacity]
+    }
+
+    /// the selected die
+    fn die(&self) -> &Die {
+        &self.dies[self.die as usize]
+    }
+
+    fn die_mut(&mut self) -> &mut Die {
+        &mut self.dies[self.die as usize]
+    }
+
+    fn address_len(&self) -> usize {
+        if self.die().four_byte {
+            4
+        } else {
+            3
+        }
+    }
+
+    /// address sent after the command, on the selected die. `None` if too few bytes came.
+    fn address(&self, tx: &[u8]) -> Option<usize> {
+        let bytes = tx.get(1..1 + self.address_len())?;
+        let address = bytes.iter().fold(0usize, |a, b| a << 8 | *b as usize);
+        let die_capacity = self.chip.die_capacity() as usize;
+        Some(self.die as usize * die_capacity + address % die_capacity)
+    }
+
+    /// sectors sharing the lock bit of `address`: single sectors in the first and last 64KB
+    /// block of a die, whole 64KB blocks in between.
+    fn lock_unit(&self, address: usize) -> Range<usize> {
+        let die_capacity = self.chip.die_capacity() as usize;
+        let offset = address % die_capacity;
+        let sector = address / SECTOR_SIZE;
+        if offset < BLOCK_SIZE_64 || offset >= die_capacity - BLOCK_SIZE_64 {
+            return sector..sector + 1;
+        }
+        let first = address / BLOCK_SIZE_64 * (BLOCK_SIZE_64 / SECTOR_SIZE);
+        first..first + BLOCK_SIZE_64 / SECTOR_SIZE
+    }
+
+    /// whether a program or erase of `range` is refused by a block lock.
+    fn locked(&self, range: Range<usize>) -> bool {
+        self.die().status[2] & SR3_WPS != 0
+            && self.locks[range.start / SECTOR_SIZE..range.end.div_ceil(SECTOR_SIZE)]
+                .iter()
+                .any(|locked| *locked)
+    }
+
+    /// a minimal SFDP area: the header, one parameter header and the first two words of the
+    /// basic flash parameter table, 4KB erase and the density.
+    fn sfdp_byte(&self, address: usize) -> u8 {
+        let bits = self.chip.capacity() as u64 * 8 - 1;
+        let mut sfdp = [0xFFu8; SFDP_BFPT + 8];
+        sfdp[..8].copy_from_slice(&[b'S', b'F', b'D', b'P', 0x06, 0x01, 0x00, 0xFF]);
+        sfdp[8..16].copy_from_slice(&[0x00, 0x06, 0x01, 0x10, SFDP_BFPT as u8, 0x00, 0x00, 0xFF]);
+        sfdp[SFDP_BFPT..SFDP_BFPT + 4].copy_from_slice(&0xFFF1_20E5u32.to_le_bytes());
+        sfdp[SFDP_BFPT + 4..].copy_from_slice(&(bits as u32).to_le_bytes());
+        sfdp.get(address).copied().unwrap_or(0xFF)
+    }
+
+    /// security register 1 to 3 from bits 13:12 of the address
+    fn security_register(address: usize) -> Option<usize> {
+        match (address >> 12) & 0x3 {
+            0 => None,
+            n => Some(n - 1),
+        }
+    }
+
+    fn read_byte(&self, tx: &[u8], index: usize) -> u8 {
+        let address = || self.address(tx).unwrap_or(0);
+        match tx[0] {
+            JEDEC_ID => self.jedec_id().get(index).copied().unwrap_or(0),
+            READ_UNIQUE_ID => self.unique_id[index % 8],
+            READ_STATUS_REGISTER_1 => {
+                let die = self.die();
+                die.status[0] & !SR1_WEL | if die.write_enabled { SR1_WEL } else { 0 }
+            }
+            READ_STATUS_REGISTER_2 => self.die().status[1],
+            READ_STATUS_REGISTER_3 => {
+                let die = self.die();
+                die.status[2] & !SR3_ADS | if die.four_byte { SR3_ADS } else { 0 }
+            }
+            // SFDP takes a 3-byte address in either mode
+            READ_SFDP_REGISTER => {
+                let address = tx.get(1..4).map_or(0, |a| {
+                    a.iter()
+                        .fold(0usize, |address, b| address << 8 | *b as usize)
+                });
+                self.sfdp_byte(address + index)
+            }
+            READ_BLOCK_LOCK => self.locks[self.lock_unit(address()).start] as u8,
+            READ_DATA | FAST_READ => self.data[(address() + index) % self.data.len()],
+            READ_SECURITY_REGISTER => match Self::security_register(address()) {
+                Some(register) => self.security[register][(address() + index) % PAGE_SIZE],
+                None => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn execute(&mut self, tx: &[u8]) {
+        let command = tx[0];
+        // every die listens to die select, whatever its state
+        if command == SOFTWARE_DIE_SELECT && self.chip.die_count() > 1 {
+            self.die = tx.get(1).copied().unwrap_or(0) % self.chip.die_count();
+            return;
+        }
+        if self.die().powered_down {
+            if command == RELEASE_POWER_DOWN {
+                self.die_mut().powered_down = false;
+            }
+            return;
+        }
+        self.changes = Changes::default();
+        let die_capacity = self.chip.die_capacity() as usize;
+        let die_range = self.die as usize * die_capacity..(self.die as usize + 1) * die_capacity;
+        let die = &mut self.dies[self.die as usize];
+        let write_enabled = die.write_enabled;
+        // WEL stays set until the next write, program or erase
+        if matches!(
+            command,
+            WRITE_DISABLE
+                | WRITE_STATUS_REGISTER_1
+                | WRITE_STATUS_REGISTER_2
+                | WRITE_STATUS_REGISTER_3
+                | PAGE_PROGRAM
+                | SECTOR_ERASE
+                | BLOCK_ERASE_32KB
+                | BLOCK_ERASE_64KB
+                | CHIP_ERASE
+                | CHIP_ERASE_2
+                | PROGRAM_SECURITY_REGISTER
+                | ERASE_SECURITY_REGISTER
+                | GLOBAL_BLOCK_LOCK
+                | GLOBAL_BLOCK_UNLOCK
+                | INDIVIDUAL_BLOCK_LOCK
+                | INDIVIDUAL_BLOCK_UNLOCK
+        ) {
+            die.write_enabled = false;
+        }
+        let volatile_sr_write = core::mem::take(&mut die.volatile_sr_write);
+        let reset_enabled = core::mem::take(&mut die.reset_enabled);
+        match command {
+            WRITE_ENABLE => die.write_enabled = true,
+            VOLATILE_SR_WRITE_ENABLE => die.volatile_sr_write = true,
+            WRITE_DISABLE => {}
+            POWER_DOWN => die.powered_down = true,
+            ENABLE_RESET => die.reset_enabled = true,
+            RESET_DEVICE if reset_enabled => {
+                die.write_enabled = false;
+                die.four_byte = false;
+                die.status = die.status_nv;
+            }
+            ENTER_4_BYTE_ADDRESS_MODE => die.four_byte = true,
+            EXIT_4_BYTE_ADDRESS_MODE => die.four_byte = false,
+            WRITE_STATUS_REGISTER_1 | WRITE_STATUS_REGISTER_2 | WRITE_STATUS_REGISTER_3
+                if write_enabled || volatile_sr_write =>
+            {
+                let register = match command {
+                    WRITE_STATUS_REGISTER_1 => 0,
+                    WRITE_STATUS_REGISTER_2 => 1,
+                    _ => 2,
+                };
+                if let Some(value) = tx.get(1) {
+                    die.status[register] = *value;
+                    if !volatile_sr_write {
+                        die.status_nv[register] = *value;
+                        self.changes.status = true;
+                    }
+                }
+            }
+            _ => self.execute_array(tx, write_enabled, die_range),
+        }
+    }
+
+    /// commands on the memory array, the locks and the security registers
+    fn execute_array(&mut self, tx: &[u8], write_enabled: bool, die_range: Range<usize>) {
+        let command = tx[0];
+        let payload = tx.get(1 + self.address_len()..).unwrap_or(&[]);
+        match command {
+            PAGE_PROGRAM if write_enabled => {
+                let Some(address) = self.address(tx) else {
+                    return;
+                };
+                // addresses wrap within the page
+                let page = address & !(PAGE_SIZE - 1);
+                if self.locked(page..page + PAGE_SIZE) {
+                    return;
+                }
+                for (i, byte) in payload.iter().enumerate() {
+                    self.data[page + (address + i) % PAGE_SIZE] &= byte;
+                }
+                self.changes.data = Some(page..page + PAGE_SIZE);
+            }
+            SECTOR_ERASE | BLOCK_ERASE_32KB | BLOCK_ERASE_64KB if write_enabled => {
+                let Some(address) = self.address(tx) else {
+                    return;
+                };
+                let size = match command {
+                    SECTOR_ERASE => SECTOR_SIZE,
+                    BLOCK_ERASE_32KB => 32 << 10,
+                    _ => 64 << 10,
+                };
+                let start = address & !(size - 1);
+                if !self.locked(start..start + size) {
+                    self.data[start..start + size].fill(0xFF);
+                    self.changes.data = Some(start..start + size);
+                }
+            }
+            // a W25M erases the selected die
+            CHIP_ERASE | CHIP_ERASE_2 if write_enabled && !self.locked(die_range.clone()) => {
+                self.data[die_range.clone()].fill(0xFF);
+                self.changes.data = Some(die_range);
+            }
+            GLOBAL_BLOCK_LOCK | GLOBAL_BLOCK_UNLOCK if write_enabled => {
+                let sectors = die_range.start / SECTOR_SIZE..die_range.end / SECTOR_SIZE;
+                self.locks[sectors].fill(command == GLOBAL_BLOCK_LOCK);
+            }
+            INDIVIDUAL_BLOCK_LOCK | INDIVIDUAL_BLOCK_UNLOCK if write_enabled => {
+                if let Some(address) = self.address(tx) {
+                    let unit = self.lock_unit(address);
+                    self.locks[unit].fill(command == INDIVIDUAL_BLOCK_LOCK);
+                }
+            }
+            PROGRAM_SECURITY_REGISTER if write_enabled => {
+                let Some(address) = self.address(tx) else {
+                    return;
+                };
+                if let Some(register) = Self::security_register(address) {
+                    for (i, byte) in payload.iter().enumerate() {
+                        self.security[register][(address + i) % PAGE_SIZE] &= byte;
+                    }
+                    self.changes.security = Some(register);
+                }
+            }
+            ERASE_SECURITY_REGISTER if write_enabled => {
+                if let Some(register) = self.address(tx).and_then(Self::security_register) {
+                    self.security[register].fill(0xFF);
+                    self.changes.security = Some(register);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl ErrorType for SimFlash {
+    type Error = Infallible;
+}
+
+impl SpiDevice for SimFlash {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+        let mut tx = Vec::new();
+        let mut index = 0;
+        for operation in operations.iter_mut() {
+            match operation {
+                Operation::Write(bytes) => tx.extend_from_slice(bytes),
+                Operation::Read(buf) => {
+                    for byte in buf.iter_mut() {
+                        *byte = match tx.first() {
+                            Some(_) if !self.die().powered_down => self.read_byte(&tx, index),
+                            _ => 0xFF,
+                        };
+                        index += 1;
+                    }
+                }
+                Operation::Transfer(read, write) => {
+                    tx.extend_from_slice(write);
+                    read.fill(0xFF);
+                }
+                Operation::TransferInPlace(buf) => {
+                    tx.extend_from_slice(buf);
+                    buf.fill(0xFF);
+                }
+                Operation::DelayNs(_) => {}
+            }
+        }
+        if !tx.is_empty() {
+            self.execute(&tx);
+        }
+        Ok(())
+    }
+}
+
+/// bytes of a NAND page with its spare area
+const NAND_RAW_PAGE_SIZE: usize = NAND_PAGE_SIZE + NAND_SPARE_SIZE;
+const NAND_ERASED_PAGE: [u8; NAND_RAW_PAGE_SIZE] = [0xFF; NAND_RAW_PAGE_SIZE];
+/// BP3..BP0 and TB, all set at power up
+const NAND_SR1_PROTECTION: u8 = 0b0111_1100;
+const NAND_SR2_ECC_E: u8 = 0b0001_0000;
+const NAND_SR3_BUSY: u8 = 0b0000_0001;
+const NAND_SR3_WEL: u8 = 0b0000_0010;
+const NAND_SR3_E_FAIL: u8 = 0b0000_0100;
+const NAND_SR3_P_FAIL: u8 = 0b0000_1000;
+const NAND_SR3_ECC: u8 = 0b0011_0000;
+const NAND_SR3_LUT_F: u8 = 0b0100_0000;
+
+/// in-memory W25N01GV behind an [`SpiDevice`], for host tools and tests.
+///
+/// answers the command set [`W25N`] uses: reset, JEDEC ID, the status registers, the data
+/// buffer loads and reads, page data read, program execute, block erase and the bad block
+/// LUT, which redirects page and block addresses like the real chip. pages are stored
+/// sparsely, an unwritten page reads erased. programming only clears bits and needs a write
+/// enable; while any BP bit is set every program and erase fails. operations complete at once.
+///
+/// failing blocks and ECC results are injected with [`SimNand::fail_block`] and
+/// [`SimNand::set_ecc`].
+pub struct SimNand {
+    /// written pages by physical page number
+    pages: BTreeMap<u32, Box<[u8; NAND_RAW_PAGE_SIZE]>>,
+    buffer: Box<[u8; NAND_RAW_PAGE_SIZE]>,
+    /// protection, configuration and status register
+    status: [u8; 3],
+    write_enabled: bool,
+    /// logical and physical block of each LUT entry
+    lut: Vec<(u16, u16)>,
+    last_ecc_failure: u16,
+    /// physical blocks whose programs and erases fail
+    failing: BTreeSet<u32>,
+    /// ECC result of reading a physical page
+    ecc: BTreeMap<u32, EccStatus>,
+    busy: bool,
+}
+
+impl SimNand {
+    /// blank chip, all pages erased and all blocks protected.
+    pub fn new() -> Self {
+        Self {
+            pages: BTreeMap::new(),
+            buffer: Box::new(NAND_ERASED_PAGE),
+            status: [NAND_SR1_PROTECTION, NAND_SR2_ECC_E, 0],
+            write_enabled: false,
+            lut: Vec::new(),
+            last_ecc_failure: 0,
+            failing: BTreeSet::new(),
+            ecc: BTreeMap::new(),
+            busy: false,
+        }
+    }
+
+    /// the driver on this simulator.
+    pub fn into_device(self) -> Result<W25N<SimNand, NoDelay>, W25QError> {
+        W25N::new(self, NoDelay)
+    }
+
+    /// data and spare area of physical page `page`
+    pub fn page(&self, page: u32) -> &[u8] {
+        self.pages
+            .get(&page)
+            .map_or(&NAND_ERASED_PAGE, |page| &page[..])
+    }
+
+    /// write a factory bad block marker into physical block `block`.
+    pub fn mark_bad(&mut self, block: u32) {
+        self.raw_page(block * NAND_PAGES_PER_BLOCK)[NAND_PAGE_SIZE] = 0x00;
+    }
+
+    /// make programs and erases of physical block `block` fail.
+    pub fn fail_block(&mut self, block: u32) {
+        self.failing.insert(block);
+    }
+
+    /// report `ecc` when physical page `page` is read.
+    pub fn set_ecc(&mut self, page: u32, ecc: EccStatus) {
+        self.ecc.insert(page, ecc);
+    }
+
+    /// hold BUSY set, as a chip stuck in an operation would.
+    pub fn set_busy(&mut self, busy: bool) {
+        self.busy = busy;
+    }
+
+    fn raw_page(&mut self, page: u32) -> &mut [u8; NAND_RAW_PAGE_SIZE] {
+        self.pages
+            .entry(page)
+            .or_insert_with(|| Box::new(NAND_ERASED_PAGE))
+    }
+
+    /// physical page of `page` after the LUT
+    fn physical(&self, page: u32) -> u32 {
+        let block = page / NAND_PAGES_PER_BLOCK;
+        match self
+            .lut
+            .iter()
+            .find(|(logical, _)| *logical as u32 == block)
+        {
+            Some((_, physical)) => {
+                *physical as u32 * NAND_PAGES_PER_BLOCK + page % NAND_PAGES_PER_BLOCK
+            }
+            None => page,
+        }
+    }
+
+    fn protected(&self) -> bool {
+        self.status[0] & NAND_SR1_PROTECTION != 0
+    }
+
+    fn status_register(&self, register: u8) -> u8 {
+        match register {
+            0xA0 => self.status[0],
+            0xB0 => self.status[1],
+            0xC0 => {
+                let mut status = self.status[2];
+                if self.busy {
+                    status |= NAND_SR3_BUSY;
+                }
+                if self.write_enabled {
+                    status |= NAND_SR3_WEL;
+                }
+                if self.lut.len() == NAND_LUT_SIZE {
+                    status |= NAND_SR3_LUT_F;
+                }
+                status
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn read_byte(&self, tx: &[u8], index: usize) -> u8 {
+        let arg = |i: usize| tx.get(i).copied().unwrap_or(0);
+        let column = u16::from_be_bytes([arg(1), arg(2)]) as usize;
+        match tx[0] {
+            NAND_JEDEC_ID => [MANUFACTURER_ID, 0xAA, 0x21]
+                .get(index)
+                .copied()
+                .unwrap_or(0),
+            NAND_READ_STATUS_REGISTER => self.status_register(arg(1)),
+            NAND_READ | NAND_FAST_READ => self.buffer.get(column + index).copied().unwrap_or(0xFF),
+            NAND_READ_BBM_LUT => {
+                let (logical, physical) = match self.lut.get(index / 4) {
+                    Some((logical, physical)) => (0x8000 | *logical, *physical),
+                    None => (0, 0),
+                };
+                let entry = [logical.to_be_bytes(), physical.to_be_bytes()];
+                entry[index % 4 / 2][index % 2]
+            }
+            NAND_LAST_ECC_FAILURE_PAGE => self.last_ecc_failure.to_be_bytes()[index % 2],
+            _ => 0xFF,
+        }
+    }
+
+    fn execute(&mut self, tx: &[u8]) {
+        let arg = |i: usize| tx.get(i).copied().unwrap_or(0);
+        // a dummy byte, then the page address
+        let page = u16::from_be_bytes([arg(2), arg(3)]) as u32;
+        let column = u16::from_be_bytes([arg(1), arg(2)]) as usize;
+        let write_enabled = self.write_enabled;
+        match tx[0] {
+            NAND_RESET => {
+                self.write_enabled = false;
+                self.status[2] = 0;
+            }
+            NAND_WRITE_ENABLE => self.write_enabled = true,
+            NAND_WRITE_DISABLE => self.write_enabled = false,
+            NAND_WRITE_STATUS_REGISTER => match arg(1) {
+                0xA0 => self.status[0] = arg(2),
+                0xB0 => self.status[1] = arg(2),
+                _ => {}
+            },
+            NAND_PROGRAM_DATA_LOAD | NAND_RANDOM_PROGRAM_DATA_LOAD if write_enabled => {
+                if tx[0] == NAND_PROGRAM_DATA_LOAD {
+                    self.buffer.fill(0xFF);
+                }
+                let data = tx.get(3..).unwrap_or(&[]);
+                for (i, byte) in data.iter().enumerate() {
+                    if let Some(slot) = self.buffer.get_mut(column + i) {
+                        *slot = *byte;
+                    }
+                }
+            }
+            NAND_PAGE_DATA_READ => {
+                let physical = self.physical(page);
+                let data = self
+                    .pages
+                    .get(&physical)
+                    .map_or(&NAND_ERASED_PAGE, |page| page);
+                self.buffer.copy_from_slice(data);
+                let ecc = match self.ecc.get(&physical) {
+                    Some(ecc) if self.status[1] & NAND_SR2_ECC_E != 0 => *ecc,
+                    _ => EccStatus::Clean,
+                };
+                let bits = match ecc {
+                    EccStatus::Clean => 0b00,
+                    EccStatus::Corrected => 0b01,
+                    EccStatus::Uncorrectable => 0b10,
+                    EccStatus::UncorrectableMultiple => 0b11,
+                };
+                self.status[2] = self.status[2] & !NAND_SR3_ECC | bits << 4;
+                if bits & 0b10 != 0 {
+                    self.last_ecc_failure = page as u16;
+                }
+            }
+            NAND_PROGRAM_EXECUTE if write_enabled => {
+                self.write_enabled = false;
+                let physical = self.physical(page);
+                self.status[2] &= !NAND_SR3_P_FAIL;
+                if self.protected() || self.failing.contains(&(physical / NAND_PAGES_PER_BLOCK)) {
+                    self.status[2] |= NAND_SR3_P_FAIL;
+                    return;
+                }
+                let buffer = self.buffer.clone();
+                for (byte, new) in self.raw_page(physical).iter_mut().zip(buffer.iter()) {
+                    *byte &= new;
+                }
+            }
+            NAND_BLOCK_ERASE if write_enabled => {
+                self.write_enabled = false;
+                let block = self.physical(page) / NAND_PAGES_PER_BLOCK;
+                self.status[2] &= !NAND_SR3_E_FAIL;
+                if self.protected() || self.failing.contains(&block) {
+                    self.status[2] |= NAND_SR3_E_FAIL;
+                    return;
+                }
+                let first = block * NAND_PAGES_PER_BLOCK;
+                let pages: Vec<u32> = self
+                    .pages
+                    .range(first..first + NAND_PAGES_PER_BLOCK)
+                    .map(|(page, _)| *page)
+                    .collect();
+                for page in pages {
+                    self.pages.remove(&page);
+                }
+            }
+            NAND_BAD_BLOCK_MANAGEMENT if write_enabled => {
+                self.write_enabled = false;
+                if self.lut.len() < NAND_LUT_SIZE {
+                    let logical = u16::from_be_bytes([arg(1), arg(2)]) & 0x03FF;
+                    let physical = u16::from_be_bytes([arg(3), arg(4)]) & 0x03FF;
+                    self.lut.push((logical, physical));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Default for SimNand {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ErrorType for SimNand {
+    type Error = Infallible;
+}
+
+impl SpiDevice for SimNand {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+        let mut tx = Vec::new();
+        let mut index = 0;
+        for operation in operations.iter_mut() {
+            match operation {
+                Operation::Write(bytes) => tx.extend_from_slice(bytes),
+                Operation::Read(buf) => {
+                    for byte in buf.iter_mut() {
+                        *byte = match tx.first() {
+                            Some(_) => self.read_byte(&tx, index),
+                            None => 0xFF,
+                        };
+                        index += 1;
+                    }
+                }
+                Operation::Transfer(read, write) => {
+                    tx.extend_from_slice(write);
+                    read.fill(0xFF);
+                }
+                Operation::TransferInPlace(buf) => {
+                    tx.extend_from_slice(buf);
+                    buf.fill(0xFF);
+                }
+                Operation::DelayNs(_) => {}
+            }
+        }
+        if !tx.is_empty() {
+            self.execute(&tx);
+        }
+        Ok(())
+    }
+}
+
+const FILE_MAGIC: [u8; 4] = *b"W25E";
+const FILE_VERSION: u8 = 1;
+/// the header takes one sector, the memory array follows
+const FILE_HEADER_SIZE: usize = SECTOR_SIZE;
+// header layout: magic, version, JEDEC ID, non-volatile status registers of every die, unique
+// ID and the security registers
+const FILE_JEDEC_ID: usize = 5;
+const FILE_STATUS: usize = 8;
+const FILE_UNIQUE_ID: usize = 16;
+const FILE_SECURITY: usize = PAGE_SIZE;
+
+/// a [`FileFlash`] could not store a change in its file.
+#[derive(Debug)]
+pub struct FileError(pub io::Error);
+
+impl spi::Error for FileError {
+    fn kind(&self) -> spi::ErrorKind {
+        spi::ErrorKind::Other
+    }
+}
+
+impl From<io::Error> for FileError {
+    fn from(e: io::Error) -> Self {
+        Self(e)
+    }
+}
+
+/// [`SimFlash`] persisted in a file, so a chip outlives the process.
+///
+/// the file holds a header with the chip, the non-volatile status registers, the unique ID
+/// and the security registers, followed by the memory array. every transaction writes what
+/// it changed back to the file before it returns; [`FileFlash::sync_all`] also flushes it to
+/// disk. opening a file is a power-up: volatile status bits, block locks, 4-byte mode and the
+/// write enable latch start from their reset state.
+pub struct FileFlash {
+    sim: SimFlash,
+    file: File,
+}
+
+impl FileFlash {
+    /// blank `chip` in a new file at `path`, replacing any file there.
+    pub fn create(path: impl AsRef<Path>, chip: Chip) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let sim = SimFlash::new(chip);
+        let mut header = [0xFFu8; FILE_HEADER_SIZE];
+        header[..4].copy_from_slice(&FILE_MAGIC);
+        header[4] = FILE_VERSION;
+        header[FILE_JEDEC_ID..FILE_JEDEC_ID + 3].copy_from_slice(&sim.jedec_id());
+        header[FILE_STATUS..FILE_STATUS + 3 * sim.dies.len()].copy_from_slice(&sim.status_nv());
+        header[FILE_UNIQUE_ID..FILE_UNIQUE_ID + 8].copy_from_slice(&sim.unique_id);
+        file.write_all(&header)?;
+        for block in sim.data.chunks(BLOCK_SIZE_64) {
+            file.write_all(block)?;
+        }
+        Ok(Self { sim, file })
+    }
+
+    /// the chip stored at `path`, powered up. fails with `InvalidData` if the file is not an
+    /// emulated chip.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+        let mut header = [0u8; FILE_HEADER_SIZE];
+        file.read_exact(&mut header)?;
+        if header[..4] != FILE_MAGIC || header[4] != FILE_VERSION {
+            return Err(invalid("not an emulated W25Q"));
+        }
+        let mut id = [0u8; 3];
+        id.copy_from_slice(&header[FILE_JEDEC_ID..FILE_JEDEC_ID + 3]);
+        let chip = Chip::from_jedec_id(id).ok_or_else(|| invalid("unknown JEDEC ID"))?;
+        let mut sim = SimFlash::new(chip);
+        if file.metadata()?.len() != (FILE_HEADER_SIZE + sim.data.len()) as u64 {
+            return Err(invalid("file size does not match the chip"));
+        }
+        for (i, die) in sim.dies.iter_mut().enumerate() {
+            let at = FILE_STATUS + 3 * i;
+            die.status_nv.copy_from_slice(&header[at..at + 3]);
+            die.status = die.status_nv;
+        }
+        sim.unique_id
+            .copy_from_slice(&header[FILE_UNIQUE_ID..FILE_UNIQUE_ID + 8]);
+        for (i, register) in sim.security.iter_mut().enumerate() {
+            let at = FILE_SECURITY + i * PAGE_SIZE;
+            register.copy_from_slice(&header[at..at + PAGE_SIZE]);
+        }
+        file.read_exact(&mut sim.data)?;
+        Ok(Self { sim, file })
+    }
+
+    /// open `path`, creating a blank `chip` there if the file does not exist. fails with
+    /// `InvalidData` if the file holds a different chip.
+    pub fn open_or_create(path: impl AsRef<Path>, chip: Chip) -> io::Result<Self> {
+        let flash = match Self::open(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Self::create(path, chip),
+            result => result?,
+        };
+        if flash.sim.chip != chip {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the file holds a different chip",
+            ));
+        }
+        Ok(flash)
+    }
+
+    /// the driver on this chip, with the chip preset in the configuration.
+    pub fn into_device(self) -> Result<W25Q<FileFlash, NoDelay>, W25QError> {
+        let chip = self.sim.chip;
+        W25Q::new(self, NoDelay, W25QConfig::new().chip(chip))
+    }
+
+    /// the simulated chip, as stored in the file
+    pub fn sim(&self) -> &SimFlash {
+        &self.sim
+    }
+
+    pub fn set_unique_id(&mut self, id: [u8; 8]) -> io::Result<()> {
+        self.sim.unique_id = id;
+        self.write_at(FILE_UNIQUE_ID, &id)
+    }
+
+    /// flush the file to disk.
+    pub fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn write_at(&mut self, offset: usize, data: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.write_all(data)
+    }
+
+    /// write what the last command changed to the file.
+    fn store(&mut self) -> io::Result<()> {
+        let changes = core::mem::take(&mut self.sim.changes);
+        if let Some(range) = changes.data {
+            let start = FILE_HEADER_SIZE + range.start;
+            self.file.seek(SeekFrom::Start(start as u64))?;
+            self.file.write_all(&self.sim.data[range])?;
+        }
+        if let Some(register) = changes.security {
+            let at = FILE_SECURITY + register * PAGE_SIZE;
+            self.file.seek(SeekFrom::Start(at as u64))?;
+            self.file.write_all(&self.sim.security[register])?;
+        }
+        if changes.status {
+            let status = self.sim.status_nv();
+            self.write_at(FILE_STATUS, &status)?;
+        }
+        Ok(())
+    }
+}
+
+impl ErrorType for FileFlash {
+    type Error = FileError;
+}
+
+impl SpiDevice for FileFlash {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), FileError> {
+        let Ok(()) = self.sim.transaction(operations);
+        self.store()?;
+        Ok(())
+    }
+}
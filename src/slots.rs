@@ -0,0 +1,520 @@
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::{
+    checksum::{Crc32, Hasher},
+    io::W25QError,
+    PAGE_SIZE, SECTOR_SIZE,
+};
+
+/// size of the header in front of every image, one page
+pub const IMAGE_HEADER_SIZE: usize = PAGE_SIZE;
+/// sectors taken by the boot state, written alternately so an erase never loses it
+pub const STATE_SECTORS: u32 = 2;
+
+const IMAGE_MAGIC: [u8; 4] = *b"W25I";
+/// the header carries a SHA-256 of the payload
+const FLAG_SHA256: u32 = 0x01;
+
+const RECORD_SIZE: usize = 16;
+const RECORDS_PER_SECTOR: usize = SECTOR_SIZE / RECORD_SIZE;
+const RECORD_MARKER: u8 = 0x5A;
+/// bytes read at once when checking an image
+const CHUNK: usize = 256;
+
+/// header in front of a firmware image.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageHeader {
+    /// firmware version, not interpreted
+    pub version: u32,
+    /// payload length, without the header
+    pub length: u32,
+    /// CRC-32 of the payload
+    pub crc32: u32,
+    /// SHA-256 of the payload, if the image carries one
+    pub sha256: Option<[u8; 32]>,
+}
+
+impl ImageHeader {
+    pub fn to_bytes(&self) -> [u8; IMAGE_HEADER_SIZE] {
+        let mut buf = [0xFFu8; IMAGE_HEADER_SIZE];
+        buf[0..4].copy_from_slice(&IMAGE_MAGIC);
+        let flags = if self.sha256.is_some() {
+            FLAG_SHA256
+        } else {
+            0
+        };
+        buf[4..8].copy_from_slice(&flags.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.version.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.length.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.crc32.to_le_bytes());
+        buf[20..52].copy_from_slice(&self.sha256.unwrap_or([0; 32]));
+        let crc = Crc32::checksum(&buf[..52]);
+        buf[52..56].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// parse a header, failing with `Corrupt` on a bad magic or header CRC.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, W25QError> {
+        if buf.len() < 56
+            || buf[0..4] != IMAGE_MAGIC
+            || Crc32::checksum(&buf[..52]).to_le_bytes() != buf[52..56]
+        {
+            return Err(W25QError::Corrupt);
+        }
+        let word = |at: usize| u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]);
+        let mut sha256 = [0u8; 32];
+        sha256.copy_from_slice(&buf[20..52]);
+        Ok(Self {
+            version: word(8),
+            length: word(12),
+            crc32: word(16),
+            sha256: (word(4) & FLAG_SHA256 != 0).then_some(sha256),
+        })
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlotState {
+    /// no usable image, possibly being written
+    Empty,
+    /// new image on trial, booted at most `max_attempts` times until confirmed
+    Pending,
+    /// image confirmed by the application
+    Confirmed,
+    /// image that was not confirmed in time or was rejected
+    Failed,
+}
+
+impl SlotState {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => SlotState::Empty,
+            1 => SlotState::Pending,
+            2 => SlotState::Confirmed,
+            3 => SlotState::Failed,
+            _ => return None,
+        })
+    }
+}
+
+/// persistent boot state.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BootState {
+    /// slot the bootloader starts
+    pub active: Slot,
+    pub slots: [SlotState; 2],
+    /// boots of the pending active image so far
+    pub attempts: u8,
+}
+
+impl Default for BootState {
+    fn default() -> Self {
+        Self {
+            active: Slot::A,
+            slots: [SlotState::Empty; 2],
+            attempts: 0,
+        }
+    }
+}
+
+/// where the slots and the boot state live. all addresses are sector aligned.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SlotLayout {
+    /// start of the [`STATE_SECTORS`] state sectors
+    pub state: u32,
+    pub slot_a: u32,
+    pub slot_b: u32,
+    /// size of each slot, header included
+    pub slot_size: u32,
+}
+
+/// A/B firmware slots with a power-safe boot state.
+///
+/// an update is streamed into the inactive slot, checked and marked pending; the bootloader
+/// then starts it on trial, counting attempts with [`SlotManager::boot`]. the application
+/// confirms it with [`SlotManager::confirm`]. after `max_attempts` unconfirmed boots the
+/// image is marked failed and the previous confirmed slot becomes active again.
+///
+/// every state change appends one 16 byte record with a CRC; the newest intact record wins,
+/// so a power loss at any point leaves the previous or the new state.
+pub struct SlotManager<F> {
+    flash: F,
+    layout: SlotLayout,
+    max_attempts: u8,
+    state: BootState,
+    seq: u32,
+    /// state sector holding the newest record
+    sector: u32,
+    next_record: usize,
+}
+
+impl<F> SlotManager<F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    /// load the boot state. without a stored state both slots are empty and A is active.
+    pub fn new(flash: F, layout: SlotLayout, max_attempts: u8) -> Result<Self, W25QError> {
+        let aligned = |addr: u32| (addr as usize).is_multiple_of(SECTOR_SIZE);
+        if !aligned(layout.state)
+            || !aligned(layout.slot_a)
+            || !aligned(layout.slot_b)
+            || !aligned(layout.slot_size)
+            || !SECTOR_SIZE.is_multiple_of(F::ERASE_SIZE)
+            || F::WRITE_SIZE != 1
+            || F::READ_SIZE != 1
+        {
+            return Err(W25QError::NotAligned);
+        }
+        let state_size = STATE_SECTORS * SECTOR_SIZE as u32;
+        let regions = [
+            (layout.state, state_size),
+            (layout.slot_a, layout.slot_size),
+            (layout.slot_b, layout.slot_size),
+        ];
+        for (i, (start, size)) in regions.iter().enumerate() {
+            if *start as u64 + *size as u64 > flash.capacity() as u64 {
+                return Err(W25QError::OutOfBounds);
+            }
+            for (other, other_size) in &regions[i + 1..] {
+                if start < &(other + other_size) && other < &(start + size) {
+                    return Err(W25QError::InvalidConfig);
+                }
+            }
+        }
+        if layout.slot_size as usize <= IMAGE_HEADER_SIZE || max_attempts == 0 {
+            return Err(W25QError::InvalidConfig);
+        }
+        let mut manager = Self {
+            flash,
+            layout,
+            max_attempts,
+            state: BootState::default(),
+            seq: 0,
+            // with no record found, the first one goes to sector 0
+            sector: 1,
+            next_record: RECORDS_PER_SECTOR,
+        };
+        manager.load()?;
+        Ok(manager)
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    pub fn state(&self) -> BootState {
+        self.state
+    }
+
+    pub fn active(&self) -> Slot {
+        self.state.active
+    }
+
+    /// start address of `slot`, where its image header is.
+    pub fn slot_address(&self, slot: Slot) -> u32 {
+        match slot {
+            Slot::A => self.layout.slot_a,
+            Slot::B => self.layout.slot_b,
+        }
+    }
+
+    fn record_address(&self, sector: u32, record: usize) -> u32 {
+        self.layout.state + sector * SECTOR_SIZE as u32 + (record * RECORD_SIZE) as u32
+    }
+
+    fn load(&mut self) -> Result<(), W25QError> {
+        let mut record = [0u8; RECORD_SIZE];
+        for sector in 0..STATE_SECTORS {
+            for index in 0..RECORDS_PER_SECTOR {
+                self.flash
+                    .read(self.record_address(sector, index), &mut record)?;
+                if record.iter().all(|b| *b == 0xFF) {
+                    break;
+                }
+                let Some((state, seq)) = Self::decode(&record) else {
+                    // torn record, nothing more can go into this sector
+                    if sector == self.sector {
+                        self.next_record = RECORDS_PER_SECTOR;
+                    }
+                    break;
+                };
+                if seq >= self.seq || self.seq == 0 {
+                    self.state = state;
+                    self.seq = seq;
+                    self.sector = sector;
+                    self.next_record = index + 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(record: &[u8; RECORD_SIZE]) -> Option<(BootState, u32)> {
+        if record[0] != RECORD_MARKER
+            || Crc32::checksum(&record[..12]).to_le_bytes() != record[12..16]
+        {
+            return None;
+        }
+        let active = match record[1] {
+            0 => Slot::A,
+            1 => Slot::B,
+            _ => return None,
+        };
+        let state = BootState {
+            active,
+            slots: [
+                SlotState::from_u8(record[2])?,
+                SlotState::from_u8(record[3])?,
+            ],
+            attempts: record[4],
+        };
+        let seq = u32::from_le_bytes([record[8], record[9], record[10], record[11]]);
+        Some((state, seq))
+    }
+
+    /// append `state` as the newest record.
+    fn store(&mut self, state: BootState) -> Result<(), W25QError> {
+        let seq = self.seq.wrapping_add(1);
+        let mut record = [0xFFu8; RECORD_SIZE];
+        record[0] = RECORD_MARKER;
+        record[1] = state.active.index() as u8;
+        record[2] = state.slots[0] as u8;
+        record[3] = state.slots[1] as u8;
+        record[4] = state.attempts;
+        record[8..12].copy_from_slice(&seq.to_le_bytes());
+        let crc = Crc32::checksum(&record[..12]);
+        record[12..16].copy_from_slice(&crc.to_le_bytes());
+        if self.next_record == RECORDS_PER_SECTOR {
+            // the other sector holds older records only
+            let sector = 1 - self.sector;
+            let address = self.record_address(sector, 0);
+            self.flash.erase(address, address + SECTOR_SIZE as u32)?;
+            self.sector = sector;
+            self.next_record = 0;
+        }
+        let address = self.record_address(self.sector, self.next_record);
+        self.next_record += 1;
+        self.flash.write(address, &record)?;
+        self.state = state;
+        self.seq = seq;
+        Ok(())
+    }
+
+    /// mark the inactive slot empty and start streaming a new image into it.
+    ///
+    /// fails with `InvalidConfig` unless the active image is confirmed: while it is pending
+    /// the inactive slot holds the image to fall back to. on a fresh device, confirm the
+    /// running image first.
+    pub fn begin_update(&mut self) -> Result<ImageWriter<'_, F>, W25QError> {
+        if self.state.slots[self.state.active.index()] != SlotState::Confirmed {
+            return Err(W25QError::InvalidConfig);
+        }
+        let slot = self.state.active.other();
+        let mut state = self.state;
+        state.slots[slot.index()] = SlotState::Empty;
+        self.store(state)?;
+        Ok(ImageWriter {
+            base: self.slot_address(slot),
+            manager: self,
+            slot,
+            written: 0,
+            erased: 0,
+        })
+    }
+
+    /// read and check the header of `slot` and the CRC of its payload.
+    pub fn validate(&mut self, slot: Slot) -> Result<ImageHeader, W25QError> {
+        let base = self.slot_address(slot);
+        let header = self.read_header(slot)?;
+        let mut crc = Crc32::new();
+        self.hash_payload(base, header.length, &mut crc)?;
+        if crc.finalize() != header.crc32 {
+            return Err(W25QError::Corrupt);
+        }
+        Ok(header)
+    }
+
+    /// check the SHA-256 of `slot` with `D`, e.g. `sha2::Sha256`. fails with `Corrupt` if
+    /// the image has no digest or it does not match.
+    #[cfg(feature = "digest")]
+    pub fn validate_digest<D: digest::Digest>(
+        &mut self,
+        slot: Slot,
+    ) -> Result<ImageHeader, W25QError> {
+        let header = self.validate(slot)?;
+        let expected = header.sha256.ok_or(W25QError::Corrupt)?;
+        let mut hasher = D::new();
+        let mut chunk = [0u8; CHUNK];
+        let start = self.slot_address(slot) + IMAGE_HEADER_SIZE as u32;
+        for at in (0..header.length).step_by(CHUNK) {
+            let len = (header.length - at).min(CHUNK as u32) as usize;
+            self.flash.read(start + at, &mut chunk[..len])?;
+            hasher.update(&chunk[..len]);
+        }
+        if hasher.finalize()[..] != expected[..] {
+            return Err(W25QError::Corrupt);
+        }
+        Ok(header)
+    }
+
+    fn read_header(&mut self, slot: Slot) -> Result<ImageHeader, W25QError> {
+        let mut buf = [0u8; 56];
+        self.flash.read(self.slot_address(slot), &mut buf)?;
+        let header = ImageHeader::from_bytes(&buf)?;
+        if header.length as u64 + IMAGE_HEADER_SIZE as u64 > self.layout.slot_size as u64 {
+            return Err(W25QError::Corrupt);
+        }
+        Ok(header)
+    }
+
+    fn hash_payload<H: Hasher>(
+        &mut self,
+        base: u32,
+        length: u32,
+        hasher: &mut H,
+    ) -> Result<(), W25QError> {
+        let mut chunk = [0u8; CHUNK];
+        let start = base + IMAGE_HEADER_SIZE as u32;
+        for at in (0..length).step_by(CHUNK) {
+            let len = (length - at).min(CHUNK as u32) as usize;
+            self.flash.read(start + at, &mut chunk[..len])?;
+            hasher.update(&chunk[..len]);
+        }
+        Ok(())
+    }
+
+    /// called by the bootloader on every boot, returns the slot to start.
+    ///
+    /// a pending image uses up one attempt; once `max_attempts` are used without a
+    /// confirmation it is marked failed and the other slot is started if it is confirmed.
+    pub fn boot(&mut self) -> Result<Slot, W25QError> {
+        let active = self.state.active;
+        if self.state.slots[active.index()] != SlotState::Pending {
+            return Ok(active);
+        }
+        let mut state = self.state;
+        if state.attempts >= self.max_attempts {
+            state.slots[active.index()] = SlotState::Failed;
+            state.attempts = 0;
+            if state.slots[active.other().index()] == SlotState::Confirmed {
+                state.active = active.other();
+            }
+        } else {
+            state.attempts += 1;
+        }
+        self.store(state)?;
+        Ok(self.state.active)
+    }
+
+    /// called by the application once a pending image runs well.
+    pub fn confirm(&mut self) -> Result<(), W25QError> {
+        let active = self.state.active;
+        if self.state.slots[active.index()] == SlotState::Confirmed {
+            return Ok(());
+        }
+        let mut state = self.state;
+        state.slots[active.index()] = SlotState::Confirmed;
+        state.attempts = 0;
+        self.store(state)
+    }
+
+    /// mark the active image failed and go back to the other slot. fails with
+    /// `InvalidConfig` if the other slot is not confirmed.
+    pub fn rollback(&mut self) -> Result<(), W25QError> {
+        let active = self.state.active;
+        if self.state.slots[active.other().index()] != SlotState::Confirmed {
+            return Err(W25QError::InvalidConfig);
+        }
+        let mut state = self.state;
+        state.slots[active.index()] = SlotState::Failed;
+        state.active = active.other();
+        state.attempts = 0;
+        self.store(state)
+    }
+}
+
+/// streams an image, header first, into the inactive slot. from
+/// [`SlotManager::begin_update`].
+///
+/// each sector is erased just before the first byte lands in it.
+pub struct ImageWriter<'a, F> {
+    manager: &'a mut SlotManager<F>,
+    slot: Slot,
+    /// start of the slot
+    base: u32,
+    written: u32,
+    /// bytes of the slot erased so far
+    erased: u32,
+}
+
+impl<F> ImageWriter<'_, F>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    pub fn slot(&self) -> Slot {
+        self.slot
+    }
+
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    /// append the next part of the image.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), W25QError> {
+        let end = self.written as u64 + data.len() as u64;
+        if end > self.manager.layout.slot_size as u64 {
+            return Err(W25QError::OutOfBounds);
+        }
+        let end = end as u32;
+        while self.erased < end {
+            let address = self.base + self.erased;
+            self.manager
+                .flash
+                .erase(address, address + SECTOR_SIZE as u32)?;
+            self.erased += SECTOR_SIZE as u32;
+        }
+        self.manager.flash.write(self.base + self.written, data)?;
+        self.written = end;
+        Ok(())
+    }
+
+    /// check the image and make it the active, pending slot.
+    pub fn finish(self) -> Result<ImageHeader, W25QError> {
+        let header = self.manager.validate(self.slot)?;
+        if self.written != IMAGE_HEADER_SIZE as u32 + header.length {
+            return Err(W25QError::Corrupt);
+        }
+        let mut state = self.manager.state;
+        state.slots[self.slot.index()] = SlotState::Pending;
+        state.active = self.slot;
+        state.attempts = 0;
+        self.manager.store(state)?;
+        Ok(header)
+    }
+}
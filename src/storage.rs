@@ -0,0 +1,68 @@
+use embedded_hal::{delay, spi};
+use embedded_storage::nor_flash::{
+    ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+use crate::{io::W25QError, W25Q};
+
+impl NorFlashError for W25QError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            W25QError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            W25QError::NotAligned => NorFlashErrorKind::NotAligned,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+impl<SPI, DELAY> ErrorType for W25Q<SPI, DELAY>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    type Error = W25QError;
+}
+
+impl<SPI, DELAY> ReadNorFlash for W25Q<SPI, DELAY>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), W25QError> {
+        if offset as u64 + bytes.len() as u64 > W25Q::capacity(self) {
+            return Err(W25QError::OutOfBounds);
+        }
+        self.read_at(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        W25Q::capacity(self) as usize
+    }
+}
+
+impl<SPI, DELAY> NorFlash for W25Q<SPI, DELAY>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = crate::SECTOR_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), W25QError> {
+        self.erase_range(from..to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), W25QError> {
+        self.program(offset, bytes)
+    }
+}
+
+/// NOR programming only clears bits, so already programmed bytes can be written again.
+impl<SPI, DELAY> MultiwriteNorFlash for W25Q<SPI, DELAY>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+}
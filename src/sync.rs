@@ -0,0 +1,104 @@
+use embedded_hal::{delay, spi};
+use embedded_io::Error;
+
+use crate::{io::W25QError, PAGE_SIZE, SECTOR_SIZE, W25Q};
+
+const PAGES_PER_SECTOR: usize = SECTOR_SIZE / PAGE_SIZE;
+
+/// what [`W25Q::sync_image`] did, in sectors.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SyncStats {
+    /// already identical to the source
+    pub skipped: u32,
+    /// programmed without an erase, only clearing bits
+    pub programmed: u32,
+    /// erased and programmed
+    pub erased: u32,
+    /// bytes taken from the source
+    pub bytes: u32,
+}
+
+impl<SPI, DELAY> W25Q<SPI, DELAY>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    /// write the image read from `source` to the flash at the sector aligned `address`,
+    /// touching only what differs.
+    ///
+    /// each sector is compared with the source: identical sectors are skipped, sectors that
+    /// only need bits cleared get the differing pages programmed, others are erased and
+    /// programmed. the rest of a partly covered last sector is kept.
+    pub fn sync_image<R: embedded_io::Read>(
+        &mut self,
+        address: u32,
+        source: &mut R,
+    ) -> Result<SyncStats, W25QError> {
+        if !(address as usize).is_multiple_of(SECTOR_SIZE) {
+            return Err(W25QError::NotAligned);
+        }
+        let mut stats = SyncStats::default();
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut page = [0u8; PAGE_SIZE];
+        let mut address = address;
+        loop {
+            let mut len = 0;
+            while len < SECTOR_SIZE {
+                let n = source
+                    .read(&mut sector[len..])
+                    .map_err(|e| W25QError::Io(e.kind()))?;
+                if n == 0 {
+                    break;
+                }
+                len += n;
+            }
+            if len == 0 {
+                return Ok(stats);
+            }
+            if address as u64 + len as u64 > self.capacity() {
+                return Err(W25QError::OutOfBounds);
+            }
+            // keep what follows the image in its last sector
+            if len < SECTOR_SIZE {
+                self.read_at(address + len as u32, &mut sector[len..])?;
+            }
+
+            let mut differs = 0u16;
+            let mut needs_erase = false;
+            for (i, wanted) in sector.chunks(PAGE_SIZE).enumerate() {
+                self.read_at(address + (i * PAGE_SIZE) as u32, &mut page)?;
+                if page != wanted {
+                    differs |= 1 << i;
+                    needs_erase |= page
+                        .iter()
+                        .zip(wanted)
+                        .any(|(have, want)| have & want != *want);
+                }
+            }
+
+            if differs == 0 {
+                stats.skipped += 1;
+            } else if needs_erase {
+                self.erase_range(address..address + SECTOR_SIZE as u32)?;
+                for (i, wanted) in sector.chunks(PAGE_SIZE).enumerate() {
+                    if wanted.iter().any(|b| *b != 0xFF) {
+                        self.program(address + (i * PAGE_SIZE) as u32, wanted)?;
+                    }
+                }
+                stats.erased += 1;
+            } else {
+                for i in (0..PAGES_PER_SECTOR).filter(|i| differs & (1 << i) != 0) {
+                    let at = i * PAGE_SIZE;
+                    self.program(address + at as u32, &sector[at..at + PAGE_SIZE])?;
+                }
+                stats.programmed += 1;
+            }
+            stats.bytes += len as u32;
+            if len < SECTOR_SIZE {
+                return Ok(stats);
+            }
+            address += SECTOR_SIZE as u32;
+        }
+    }
+}
@@ -0,0 +1,568 @@
+use core::ops::Range;
+
+use embedded_hal::{delay, spi};
+use embedded_storage::nor_flash::{self, NorFlash, ReadNorFlash};
+
+use crate::{io::W25QError, MANUFACTURER_ID};
+
+/// data bytes per NAND page
+pub const NAND_PAGE_SIZE: usize = 2048;
+/// spare (OOB) bytes per NAND page
+pub const NAND_SPARE_SIZE: usize = 64;
+pub const NAND_PAGES_PER_BLOCK: u32 = 64;
+/// data bytes per erase block, 128KiB
+pub const NAND_BLOCK_SIZE: usize = NAND_PAGE_SIZE * NAND_PAGES_PER_BLOCK as usize;
+/// blocks on a W25N01GV
+pub const NAND_BLOCK_COUNT: u32 = 1024;
+/// entries in the bad block LUT
+pub const NAND_LUT_SIZE: usize = 20;
+
+/// W25N01GV JEDEC ID
+const W25N01GV_ID: [u8; 3] = [MANUFACTURER_ID, 0xAA, 0x21];
+
+/// page read to cache with ECC, tRD
+const READ_TIMEOUT_US: u32 = 60;
+/// page program, tPP
+const PROGRAM_TIMEOUT_US: u32 = 700;
+/// block erase, tBE
+const ERASE_TIMEOUT_US: u32 = 10_000;
+/// reset, tRST
+const RESET_TIMEOUT_US: u32 = 500;
+
+#[allow(non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NandRegister {
+    RESET = 0xFF,
+    JEDEC_ID = 0x9F,
+    READ_STATUS_REGISTER = 0x0F,
+    WRITE_STATUS_REGISTER = 0x1F,
+    WRITE_ENABLE = 0x06,
+    WRITE_DISABLE = 0x04,
+    BAD_BLOCK_MANAGEMENT = 0xA1,
+    READ_BBM_LUT = 0xA5,
+    LAST_ECC_FAILURE_PAGE = 0xA9,
+    BLOCK_ERASE = 0xD8,
+    PROGRAM_DATA_LOAD = 0x02,
+    RANDOM_PROGRAM_DATA_LOAD = 0x84,
+    PROGRAM_EXECUTE = 0x10,
+    PAGE_DATA_READ = 0x13,
+    READ = 0x03,
+    FAST_READ = 0x0B,
+}
+
+/// status register addresses, used with the read/write status register commands.
+#[allow(clippy::upper_case_acronyms)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NandSR {
+    /// block protection
+    Protection = 0xA0,
+    /// configuration: OTP, ECC enable, buffer mode
+    Config = 0xB0,
+    /// status: busy, WEL, erase/program failure, ECC, LUT full
+    Status = 0xC0,
+}
+
+const SR2_ECC_E: u8 = 0b0001_0000;
+const SR2_BUF: u8 = 0b0000_1000;
+const SR3_BUSY: u8 = 0b0000_0001;
+const SR3_E_FAIL: u8 = 0b0000_0100;
+const SR3_P_FAIL: u8 = 0b0000_1000;
+const SR3_LUT_F: u8 = 0b0100_0000;
+
+/// ECC result of the last page read.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EccStatus {
+    /// no bit errors
+    Clean,
+    /// bit errors corrected by the internal ECC
+    Corrected,
+    /// uncorrectable errors in the page
+    Uncorrectable,
+    /// uncorrectable errors in several pages of a continuous read
+    UncorrectableMultiple,
+}
+
+impl From<u8> for EccStatus {
+    fn from(sr3: u8) -> Self {
+        match (sr3 >> 4) & 0b11 {
+            0b00 => EccStatus::Clean,
+            0b01 => EccStatus::Corrected,
+            0b10 => EccStatus::Uncorrectable,
+            _ => EccStatus::UncorrectableMultiple,
+        }
+    }
+}
+
+/// one entry of the bad block LUT: accesses to `logical` go to `physical`.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LutEntry {
+    pub enabled: bool,
+    pub invalid: bool,
+    pub logical: u16,
+    pub physical: u16,
+}
+
+/// W25N SPI NAND device object (W25N01GV).
+///
+/// uses buffer read mode with the internal ECC enabled.
+pub struct W25N<SPI, DELAY>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    pub periph: SPI,
+    pub delay: DELAY,
+    /// interval between status register polls while busy
+    poll_interval_us: u32,
+}
+
+impl<SPI, DELAY> W25N<SPI, DELAY>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    /// reset the chip, check its JEDEC ID, clear block protection and enable ECC and buffer
+    /// read mode.
+    pub fn new(spi_dev: SPI, delay: DELAY) -> Result<Self, W25QError> {
+        let mut dev = Self {
+            periph: spi_dev,
+            delay,
+            poll_interval_us: 10,
+        };
+        dev.reset()?;
+        let id = dev.read_jedec_id()?;
+        if id != W25N01GV_ID {
+            return Err(W25QError::UnexpectedId(id));
+        }
+        // all blocks are protected after power up
+        dev.write_status_register(NandSR::Protection, 0x00)?;
+        let config = dev.read_status_register(NandSR::Config)?;
+        dev.write_status_register(NandSR::Config, config | SR2_ECC_E | SR2_BUF)?;
+        Ok(dev)
+    }
+
+    /// 0 polls back to back, each poll then counts as 1us towards the timeouts.
+    pub fn set_poll_interval_us(&mut self, us: u32) {
+        self.poll_interval_us = us;
+    }
+
+    fn command(&mut self, bytes: &[u8]) -> Result<(), W25QError> {
+        self.periph
+            .transaction(&mut [spi::Operation::Write(bytes)])?;
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> Result<(), W25QError> {
+        self.command(&[NandRegister::RESET as u8])?;
+        self.wait_until_ready(RESET_TIMEOUT_US)?;
+        Ok(())
+    }
+
+    pub fn read_jedec_id(&mut self) -> Result<[u8; 3], W25QError> {
+        let mut id = [0u8; 3];
+        // one dummy byte
+        self.periph.transaction(&mut [
+            spi::Operation::Write(&[NandRegister::JEDEC_ID as u8, 0]),
+            spi::Operation::Read(&mut id),
+        ])?;
+        Ok(id)
+    }
+
+    pub fn read_status_register(&mut self, register: NandSR) -> Result<u8, W25QError> {
+        let mut status = [0u8; 1];
+        self.periph.transaction(&mut [
+            spi::Operation::Write(&[NandRegister::READ_STATUS_REGISTER as u8, register as u8]),
+            spi::Operation::Read(&mut status),
+        ])?;
+        Ok(status[0])
+    }
+
+    pub fn write_status_register(&mut self, register: NandSR, value: u8) -> Result<(), W25QError> {
+        self.command(&[
+            NandRegister::WRITE_STATUS_REGISTER as u8,
+            register as u8,
+            value,
+        ])
+    }
+
+    pub fn write_enable(&mut self) -> Result<(), W25QError> {
+        self.command(&[NandRegister::WRITE_ENABLE as u8])
+    }
+
+    pub fn write_disable(&mut self) -> Result<(), W25QError> {
+        self.command(&[NandRegister::WRITE_DISABLE as u8])
+    }
+
+    pub fn is_busy(&mut self) -> Result<bool, W25QError> {
+        Ok(self.read_status_register(NandSR::Status)? & SR3_BUSY != 0)
+    }
+
+    /// poll until not busy, returning the final status register 3. a poll counts as at least
+    /// 1us, so an interval of 0 still times out.
+    fn wait_until_ready(&mut self, timeout_us: u32) -> Result<u8, W25QError> {
+        let mut waited_us = 0u32;
+        loop {
+            let status = self.read_status_register(NandSR::Status)?;
+            if status & SR3_BUSY == 0 {
+                return Ok(status);
+            }
+            if waited_us >= timeout_us {
+                return Err(W25QError::Timeout);
+            }
+            self.delay.delay_us(self.poll_interval_us);
+            waited_us += self.poll_interval_us.max(1);
+        }
+    }
+
+    /// `command`, a dummy byte and a 16 bit page address.
+    fn page_command(&mut self, command: NandRegister, page: u32) -> Result<(), W25QError> {
+        let [_, _, hi, lo] = page.to_be_bytes();
+        self.command(&[command as u8, 0, hi, lo])
+    }
+
+    /// load `page` into the data buffer and report its ECC status.
+    pub fn page_data_read(&mut self, page: u32) -> Result<EccStatus, W25QError> {
+        self.page_command(NandRegister::PAGE_DATA_READ, page)?;
+        let status = self.wait_until_ready(READ_TIMEOUT_US)?;
+        Ok(EccStatus::from(status))
+    }
+
+    /// read from the data buffer starting at `column`. columns past the page size address
+    /// the spare area.
+    pub fn read_from_cache(&mut self, column: u16, data: &mut [u8]) -> Result<(), W25QError> {
+        let [hi, lo] = column.to_be_bytes();
+        self.periph.transaction(&mut [
+            spi::Operation::Write(&[NandRegister::FAST_READ as u8, hi, lo, 0]),
+            spi::Operation::Read(data),
+        ])?;
+        Ok(())
+    }
+
+    /// read `data` from `page` at `column`, failing with `EccUncorrectable` if the internal
+    /// ECC could not correct the page.
+    pub fn read_page(
+        &mut self,
+        page: u32,
+        column: u16,
+        data: &mut [u8],
+    ) -> Result<EccStatus, W25QError> {
+        let ecc = self.page_data_read(page)?;
+        if matches!(
+            ecc,
+            EccStatus::Uncorrectable | EccStatus::UncorrectableMultiple
+        ) {
+            return Err(W25QError::EccUncorrectable { page });
+        }
+        self.read_from_cache(column, data)?;
+        Ok(ecc)
+    }
+
+    /// clear the data buffer to 0xFF and load `data` at `column`.
+    pub fn program_data_load(&mut self, column: u16, data: &[u8]) -> Result<(), W25QError> {
+        self.load(NandRegister::PROGRAM_DATA_LOAD, column, data)
+    }
+
+    /// load `data` at `column`, keeping the rest of the data buffer.
+    pub fn random_program_data_load(&mut self, column: u16, data: &[u8]) -> Result<(), W25QError> {
+        self.load(NandRegister::RANDOM_PROGRAM_DATA_LOAD, column, data)
+    }
+
+    fn load(&mut self, command: NandRegister, column: u16, data: &[u8]) -> Result<(), W25QError> {
+        self.write_enable()?;
+        let [hi, lo] = column.to_be_bytes();
+        self.periph.transaction(&mut [
+            spi::Operation::Write(&[command as u8, hi, lo]),
+            spi::Operation::Write(data),
+        ])?;
+        Ok(())
+    }
+
+    /// program the data buffer into `page`.
+    pub fn program_execute(&mut self, page: u32) -> Result<(), W25QError> {
+        self.write_enable()?;
+        self.page_command(NandRegister::PROGRAM_EXECUTE, page)?;
+        let status = self.wait_until_ready(PROGRAM_TIMEOUT_US)?;
+        if status & SR3_P_FAIL != 0 {
+            return Err(W25QError::ProgramFailed);
+        }
+        Ok(())
+    }
+
+    /// program `data` into `page` at `column`.
+    pub fn program_page(&mut self, page: u32, column: u16, data: &[u8]) -> Result<(), W25QError> {
+        self.program_data_load(column, data)?;
+        self.program_execute(page)
+    }
+
+    /// erase 128KiB block `block`.
+    pub fn block_erase(&mut self, block: u32) -> Result<(), W25QError> {
+        self.write_enable()?;
+        self.page_command(NandRegister::BLOCK_ERASE, block * NAND_PAGES_PER_BLOCK)?;
+        let status = self.wait_until_ready(ERASE_TIMEOUT_US)?;
+        if status & SR3_E_FAIL != 0 {
+            return Err(W25QError::EraseFailed);
+        }
+        Ok(())
+    }
+
+    /// whether `block` carries a bad block marker: the first spare byte of its first page is
+    /// not 0xFF.
+    pub fn is_marked_bad(&mut self, block: u32) -> Result<bool, W25QError> {
+        let mut marker = [0u8; 1];
+        self.page_data_read(block * NAND_PAGES_PER_BLOCK)?;
+        self.read_from_cache(NAND_PAGE_SIZE as u16, &mut marker)?;
+        Ok(marker[0] != 0xFF)
+    }
+
+    /// write a bad block marker into `block`.
+    pub fn mark_bad(&mut self, block: u32) -> Result<(), W25QError> {
+        self.program_page(block * NAND_PAGES_PER_BLOCK, NAND_PAGE_SIZE as u16, &[0x00])
+    }
+
+    /// find blocks with a bad block marker, calling `f` for each.
+    pub fn scan_bad_blocks(&mut self, mut f: impl FnMut(u32)) -> Result<(), W25QError> {
+        for block in 0..NAND_BLOCK_COUNT {
+            if self.is_marked_bad(block)? {
+                f(block);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_bbm_lut(&mut self) -> Result<[LutEntry; NAND_LUT_SIZE], W25QError> {
+        let mut raw = [0u8; NAND_LUT_SIZE * 4];
+        self.periph.transaction(&mut [
+            spi::Operation::Write(&[NandRegister::READ_BBM_LUT as u8, 0]),
+            spi::Operation::Read(&mut raw),
+        ])?;
+        let mut lut = [LutEntry::default(); NAND_LUT_SIZE];
+        for (entry, raw) in lut.iter_mut().zip(raw.chunks(4)) {
+            let logical = u16::from_be_bytes([raw[0], raw[1]]);
+            *entry = LutEntry {
+                enabled: logical & 0x8000 != 0,
+                invalid: logical & 0x4000 != 0,
+                logical: logical & 0x03FF,
+                physical: u16::from_be_bytes([raw[2], raw[3]]) & 0x03FF,
+            };
+        }
+        Ok(lut)
+    }
+
+    /// add a LUT entry redirecting block `logical` to block `physical`. fails with `NoSpace`
+    /// when the LUT is full.
+    pub fn swap_blocks(&mut self, logical: u16, physical: u16) -> Result<(), W25QError> {
+        if self.read_status_register(NandSR::Status)? & SR3_LUT_F != 0 {
+            return Err(W25QError::NoSpace);
+        }
+        self.write_enable()?;
+        let [lh, ll] = logical.to_be_bytes();
+        let [ph, pl] = physical.to_be_bytes();
+        self.command(&[NandRegister::BAD_BLOCK_MANAGEMENT as u8, lh, ll, ph, pl])?;
+        self.wait_until_ready(PROGRAM_TIMEOUT_US)?;
+        Ok(())
+    }
+
+    /// page of the last uncorrectable ECC failure.
+    pub fn last_ecc_failure_page(&mut self) -> Result<u16, W25QError> {
+        let mut page = [0u8; 2];
+        self.periph.transaction(&mut [
+            spi::Operation::Write(&[NandRegister::LAST_ECC_FAILURE_PAGE as u8, 0]),
+            spi::Operation::Read(&mut page),
+        ])?;
+        Ok(u16::from_be_bytes(page))
+    }
+}
+
+/// linear, bad block free view of a [`W25N`].
+///
+/// the last `reserved` blocks are kept as replacements. bad blocks in the logical range are
+/// redirected to a good replacement through the chip's bad block LUT, so the mapping survives
+/// power cycles and costs nothing at runtime.
+pub struct ManagedW25N<SPI, DELAY>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    nand: W25N<SPI, DELAY>,
+    /// blocks exposed to the user
+    logical_blocks: u32,
+}
+
+impl<SPI, DELAY> ManagedW25N<SPI, DELAY>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    /// scan the logical range for factory bad blocks and remap those not yet in the LUT.
+    pub fn new(nand: W25N<SPI, DELAY>, reserved: u32) -> Result<Self, W25QError> {
+        if reserved == 0 || reserved >= NAND_BLOCK_COUNT {
+            return Err(W25QError::InvalidConfig);
+        }
+        let mut managed = Self {
+            nand,
+            logical_blocks: NAND_BLOCK_COUNT - reserved,
+        };
+        let lut = managed.nand.read_bbm_lut()?;
+        for block in 0..managed.logical_blocks {
+            let mapped = lut
+                .iter()
+                .any(|entry| entry.enabled && entry.logical as u32 == block);
+            if !mapped && managed.nand.is_marked_bad(block)? {
+                managed.remap(block)?;
+            }
+        }
+        Ok(managed)
+    }
+
+    pub fn into_inner(self) -> W25N<SPI, DELAY> {
+        self.nand
+    }
+
+    pub fn nand_mut(&mut self) -> &mut W25N<SPI, DELAY> {
+        &mut self.nand
+    }
+
+    pub fn logical_blocks(&self) -> u32 {
+        self.logical_blocks
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.logical_blocks as u64 * NAND_BLOCK_SIZE as u64
+    }
+
+    /// redirect `block` to the next good, unused replacement block.
+    fn remap(&mut self, block: u32) -> Result<(), W25QError> {
+        let lut = self.nand.read_bbm_lut()?;
+        for spare in self.logical_blocks..NAND_BLOCK_COUNT {
+            let used = lut
+                .iter()
+                .any(|entry| entry.enabled && entry.physical as u32 == spare);
+            if !used && !self.nand.is_marked_bad(spare)? {
+                return self.nand.swap_blocks(block as u16, spare as u16);
+            }
+        }
+        Err(W25QError::NoSpace)
+    }
+
+    fn check(&self, address: u32, len: usize) -> Result<(), W25QError> {
+        if address as u64 + len as u64 > self.capacity() {
+            return Err(W25QError::OutOfBounds);
+        }
+        Ok(())
+    }
+
+    pub fn read_at(&mut self, address: u32, data: &mut [u8]) -> Result<(), W25QError> {
+        self.check(address, data.len())?;
+        let mut addr = address;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let column = addr as usize % NAND_PAGE_SIZE;
+            let len = (NAND_PAGE_SIZE - column).min(rest.len());
+            let (now, later) = rest.split_at_mut(len);
+            self.nand
+                .read_page(addr / NAND_PAGE_SIZE as u32, column as u16, now)?;
+            addr += len as u32;
+            rest = later;
+        }
+        Ok(())
+    }
+
+    /// program whole pages. a failed program marks the block bad and remaps it; the data
+    /// already in that block is lost and `ProgramFailed` is returned.
+    pub fn program(&mut self, address: u32, data: &[u8]) -> Result<(), W25QError> {
+        self.check(address, data.len())?;
+        if !(address as usize).is_multiple_of(NAND_PAGE_SIZE)
+            || !data.len().is_multiple_of(NAND_PAGE_SIZE)
+        {
+            return Err(W25QError::NotAligned);
+        }
+        for (i, page_data) in data.chunks(NAND_PAGE_SIZE).enumerate() {
+            let page = address / NAND_PAGE_SIZE as u32 + i as u32;
+            if let Err(e) = self.nand.program_page(page, 0, page_data) {
+                if matches!(e, W25QError::ProgramFailed) {
+                    self.retire(page / NAND_PAGES_PER_BLOCK)?;
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// erase whole blocks. a block failing to erase is remapped and the replacement erased.
+    pub fn erase_range(&mut self, range: Range<u32>) -> Result<(), W25QError> {
+        if range.start > range.end {
+            return Err(W25QError::OutOfBounds);
+        }
+        self.check(range.start, (range.end - range.start) as usize)?;
+        if !(range.start as usize).is_multiple_of(NAND_BLOCK_SIZE)
+            || !(range.end as usize).is_multiple_of(NAND_BLOCK_SIZE)
+        {
+            return Err(W25QError::NotAligned);
+        }
+        let block_size = NAND_BLOCK_SIZE as u32;
+        for block in range.start / block_size..range.end / block_size {
+            match self.nand.block_erase(block) {
+                Err(W25QError::EraseFailed) => {
+                    self.retire(block)?;
+                    self.nand.block_erase(block)?;
+                }
+                other => other?,
+            }
+        }
+        Ok(())
+    }
+
+    /// mark the physical block behind `block` bad and remap `block`.
+    fn retire(&mut self, block: u32) -> Result<(), W25QError> {
+        // best effort, the block may not accept the marker
+        let _ = self.nand.mark_bad(block);
+        self.remap(block)
+    }
+}
+
+impl<SPI, DELAY> nor_flash::ErrorType for ManagedW25N<SPI, DELAY>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    type Error = W25QError;
+}
+
+impl<SPI, DELAY> ReadNorFlash for ManagedW25N<SPI, DELAY>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), W25QError> {
+        self.read_at(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        ManagedW25N::capacity(self) as usize
+    }
+}
+
+/// NAND pages can only be programmed whole and once per erase.
+impl<SPI, DELAY> NorFlash for ManagedW25N<SPI, DELAY>
+where
+    SPI: spi::SpiDevice,
+    DELAY: delay::DelayNs,
+{
+    const WRITE_SIZE: usize = NAND_PAGE_SIZE;
+    const ERASE_SIZE: usize = NAND_BLOCK_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), W25QError> {
+        self.erase_range(from..to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), W25QError> {
+        self.program(offset, bytes)
+    }
+}
@@ -0,0 +1,472 @@
+use embedded_storage::nor_flash::{self, NorFlash, ReadNorFlash};
+
+use crate::{checksum::Crc32, io::W25QError, SECTOR_SIZE};
+
+const JOURNAL_MAGIC: [u8; 4] = *b"W25W";
+/// size of the journal header and of every journal record
+const RECORD_SIZE: usize = 16;
+/// records after the header of a journal sector
+const JOURNAL_RECORDS: usize = SECTOR_SIZE / RECORD_SIZE - 1;
+/// bytes moved per read/program when copying a sector
+const COPY_CHUNK: usize = 256;
+/// no logical sector maps here
+const FREE: u16 = u16::MAX;
+
+const RECORD_MAP: u8 = 1;
+const RECORD_FREE: u8 = 2;
+
+/// default erase count spread that triggers static wear leveling
+pub const DEFAULT_STATIC_THRESHOLD: u32 = 1000;
+
+/// wear leveled block device over a range of `N + 2` sectors.
+///
+/// logical sectors are mapped onto `N` physical data sectors. every write goes to the least
+/// worn free sector and only then commits the new mapping to a journal, so a power loss
+/// leaves either the old or the new contents. when the erase counts drift apart by more than
+/// the static threshold, the coldest data is moved onto the most worn free sector.
+///
+/// the journal lives in the first two sectors of the range. each holds a header and 16 byte
+/// records; when one fills up, a snapshot of the whole mapping is written to the other.
+///
+/// a sector's erase count is stored with the record that maps data onto it, and every
+/// snapshot stores the counts of all sectors, free ones included. an erase whose mapping is
+/// never committed, because the power was lost in between, is not counted, and neither is
+/// the erase done by [`WearLeveler::format`].
+pub struct WearLeveler<F, const N: usize> {
+    flash: F,
+    /// start of the range on the device
+    offset: u32,
+    /// number of logical sectors, less than `N`
+    logical: usize,
+    /// logical to physical sector
+    map: [u16; N],
+    /// physical to logical sector, or `FREE`
+    owner: [u16; N],
+    erase_counts: [u32; N],
+    /// journal sector in use, 0 or 1
+    journal: usize,
+    generation: u32,
+    /// next free record slot in the journal
+    next_record: usize,
+    static_threshold: u32,
+}
+
+impl<F, const N: usize> WearLeveler<F, N>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    /// bytes of flash used by a wear leveler with `N` data sectors.
+    pub const REGION_SIZE: u32 = ((N + 2) * SECTOR_SIZE) as u32;
+
+    fn empty(flash: F, offset: u32, logical: usize) -> Result<Self, W25QError> {
+        if N > JOURNAL_RECORDS || logical == 0 || logical >= N {
+            return Err(W25QError::InvalidConfig);
+        }
+        if !SECTOR_SIZE.is_multiple_of(F::ERASE_SIZE)
+            || !RECORD_SIZE.is_multiple_of(F::WRITE_SIZE)
+            || !RECORD_SIZE.is_multiple_of(F::READ_SIZE)
+            || !(offset as usize).is_multiple_of(SECTOR_SIZE)
+        {
+            return Err(W25QError::NotAligned);
+        }
+        if offset as u64 + Self::REGION_SIZE as u64 > flash.capacity() as u64 {
+            return Err(W25QError::OutOfBounds);
+        }
+        Ok(Self {
+            flash,
+            offset,
+            logical,
+            map: [FREE; N],
+            owner: [FREE; N],
+            erase_counts: [0; N],
+            journal: 0,
+            generation: 0,
+            next_record: 0,
+            static_threshold: DEFAULT_STATIC_THRESHOLD,
+        })
+    }
+
+    /// erase the range at `offset` and start with `logical` empty logical sectors.
+    pub fn format(flash: F, offset: u32, logical: usize) -> Result<Self, W25QError> {
+        let mut wl = Self::empty(flash, offset, logical)?;
+        wl.flash.erase(offset, offset + Self::REGION_SIZE)?;
+        for sector in 0..logical {
+            wl.map[sector] = sector as u16;
+            wl.owner[sector] = sector as u16;
+        }
+        // the snapshot goes to the other sector, make it land in journal 0
+        wl.journal = 1;
+        wl.compact()?;
+        Ok(wl)
+    }
+
+    /// load the mapping from the journal at `offset`. fails with `Corrupt` if there is no
+    /// valid journal.
+    pub fn mount(flash: F, offset: u32, logical: usize) -> Result<Self, W25QError> {
+        let mut wl = Self::empty(flash, offset, logical)?;
+        let mut newest = None;
+        for journal in 0..2 {
+            if let Some(generation) = wl.read_header(journal)? {
+                if newest.is_none_or(|(_, newest)| generation > newest) {
+                    newest = Some((journal, generation));
+                }
+            }
+        }
+        let (journal, generation) = newest.ok_or(W25QError::Corrupt)?;
+        wl.journal = journal;
+        wl.generation = generation;
+        let torn = wl.replay()?;
+        if wl.map[..logical].contains(&FREE) {
+            return Err(W25QError::Corrupt);
+        }
+        // records after a torn one would be lost on the next mount
+        if torn {
+            wl.compact()?;
+        }
+        Ok(wl)
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.logical
+    }
+
+    pub fn capacity(&self) -> u32 {
+        (self.logical * SECTOR_SIZE) as u32
+    }
+
+    /// erase counts of the physical data sectors.
+    pub fn erase_counts(&self) -> &[u32; N] {
+        &self.erase_counts
+    }
+
+    /// erase count spread above which cold data is moved, `u32::MAX` disables static wear
+    /// leveling.
+    pub fn set_static_threshold(&mut self, threshold: u32) {
+        self.static_threshold = threshold;
+    }
+
+    fn journal_address(&self, journal: usize) -> u32 {
+        self.offset + (journal * SECTOR_SIZE) as u32
+    }
+
+    fn data_address(&self, physical: usize) -> u32 {
+        self.offset + ((2 + physical) * SECTOR_SIZE) as u32
+    }
+
+    /// generation of a valid journal header matching this geometry.
+    fn read_header(&mut self, journal: usize) -> Result<Option<u32>, W25QError> {
+        let mut header = [0u8; RECORD_SIZE];
+        self.flash
+            .read(self.journal_address(journal), &mut header)?;
+        if header[0..4] != JOURNAL_MAGIC
+            || Crc32::checksum(&header[..12]).to_le_bytes() != header[12..16]
+        {
+            return Ok(None);
+        }
+        let sectors = u16::from_le_bytes([header[8], header[9]]) as usize;
+        let logical = u16::from_le_bytes([header[10], header[11]]) as usize;
+        if sectors != N || logical != self.logical {
+            return Err(W25QError::InvalidConfig);
+        }
+        Ok(Some(u32::from_le_bytes([
+            header[4], header[5], header[6], header[7],
+        ])))
+    }
+
+    /// apply the records of the active journal, returning whether a torn record ended it.
+    fn replay(&mut self) -> Result<bool, W25QError> {
+        let base = self.journal_address(self.journal);
+        let mut record = [0u8; RECORD_SIZE];
+        for slot in 0..JOURNAL_RECORDS {
+            self.flash
+                .read(base + ((slot + 1) * RECORD_SIZE) as u32, &mut record)?;
+            if record.iter().all(|b| *b == 0xFF) {
+                self.next_record = slot;
+                return Ok(false);
+            }
+            if Crc32::checksum(&record[..12]).to_le_bytes() != record[12..16] {
+                self.next_record = slot;
+                return Ok(true);
+            }
+            let logical = u16::from_le_bytes([record[2], record[3]]);
+            let physical = u16::from_le_bytes([record[4], record[5]]) as usize;
+            let count = u32::from_le_bytes([record[8], record[9], record[10], record[11]]);
+            if physical >= N {
+                return Err(W25QError::Corrupt);
+            }
+            match record[0] {
+                RECORD_MAP if (logical as usize) < self.logical => {
+                    let previous = self.owner[physical];
+                    if previous != FREE && previous != logical {
+                        return Err(W25QError::Corrupt);
+                    }
+                    self.assign(logical as usize, physical);
+                }
+                RECORD_FREE if self.owner[physical] == FREE => {}
+                _ => return Err(W25QError::Corrupt),
+            }
+            self.erase_counts[physical] = count;
+        }
+        self.next_record = JOURNAL_RECORDS;
+        Ok(false)
+    }
+
+    fn assign(&mut self, logical: usize, physical: usize) {
+        let previous = self.map[logical];
+        if previous != FREE {
+            self.owner[previous as usize] = FREE;
+        }
+        self.map[logical] = physical as u16;
+        self.owner[physical] = logical as u16;
+    }
+
+    fn encode(&self, physical: usize) -> [u8; RECORD_SIZE] {
+        let mut record = [0u8; RECORD_SIZE];
+        let owner = self.owner[physical];
+        record[0] = if owner == FREE {
+            RECORD_FREE
+        } else {
+            RECORD_MAP
+        };
+        record[2..4].copy_from_slice(&owner.to_le_bytes());
+        record[4..6].copy_from_slice(&(physical as u16).to_le_bytes());
+        record[8..12].copy_from_slice(&self.erase_counts[physical].to_le_bytes());
+        let crc = Crc32::checksum(&record[..12]);
+        record[12..16].copy_from_slice(&crc.to_le_bytes());
+        record
+    }
+
+    /// write a snapshot of the mapping and erase counts into the other journal sector. its
+    /// header is written last, so the old journal stays valid until the snapshot is complete.
+    fn compact(&mut self) -> Result<(), W25QError> {
+        let target = 1 - self.journal;
+        let base = self.journal_address(target);
+        self.flash.erase(base, base + SECTOR_SIZE as u32)?;
+        for physical in 0..N {
+            let record = self.encode(physical);
+            self.flash
+                .write(base + ((physical + 1) * RECORD_SIZE) as u32, &record)?;
+        }
+        let generation = self.generation.wrapping_add(1);
+        let mut header = [0u8; RECORD_SIZE];
+        header[0..4].copy_from_slice(&JOURNAL_MAGIC);
+        header[4..8].copy_from_slice(&generation.to_le_bytes());
+        header[8..10].copy_from_slice(&(N as u16).to_le_bytes());
+        header[10..12].copy_from_slice(&(self.logical as u16).to_le_bytes());
+        let crc = Crc32::checksum(&header[..12]);
+        header[12..16].copy_from_slice(&crc.to_le_bytes());
+        self.flash.write(base, &header)?;
+        self.journal = target;
+        self.generation = generation;
+        self.next_record = N;
+        Ok(())
+    }
+
+    /// map `logical` to `physical` and make it durable.
+    fn commit(&mut self, logical: usize, physical: usize) -> Result<(), W25QError> {
+        self.assign(logical, physical);
+        if self.next_record == JOURNAL_RECORDS {
+            return self.compact();
+        }
+        let record = self.encode(physical);
+        let address =
+            self.journal_address(self.journal) + ((self.next_record + 1) * RECORD_SIZE) as u32;
+        self.next_record += 1;
+        self.flash.write(address, &record)?;
+        Ok(())
+    }
+
+    /// least worn free sector, for dynamic wear leveling.
+    fn allocate(&self) -> usize {
+        (0..N)
+            .filter(|p| self.owner[*p] == FREE)
+            .min_by_key(|p| self.erase_counts[*p])
+            .unwrap_or(0)
+    }
+
+    fn erase_physical(&mut self, physical: usize) -> Result<(), W25QError> {
+        let address = self.data_address(physical);
+        self.flash.erase(address, address + SECTOR_SIZE as u32)?;
+        self.erase_counts[physical] = self.erase_counts[physical].saturating_add(1);
+        Ok(())
+    }
+
+    /// copy `logical` onto the free sector `target`, replacing the bytes at `patch`, and
+    /// commit the move.
+    fn rewrite(
+        &mut self,
+        logical: usize,
+        target: usize,
+        patch: Option<(usize, &[u8])>,
+    ) -> Result<(), W25QError> {
+        let source = self.map[logical] as usize;
+        self.erase_physical(target)?;
+        let mut chunk = [0u8; COPY_CHUNK];
+        for start in (0..SECTOR_SIZE).step_by(COPY_CHUNK) {
+            self.flash
+                .read(self.data_address(source) + start as u32, &mut chunk)?;
+            if let Some((at, data)) = patch {
+                let from = at.max(start);
+                let to = (at + data.len()).min(start + COPY_CHUNK);
+                if from < to {
+                    chunk[from - start..to - start].copy_from_slice(&data[from - at..to - at]);
+                }
+            }
+            if chunk.iter().any(|b| *b != 0xFF) {
+                self.flash
+                    .write(self.data_address(target) + start as u32, &chunk)?;
+            }
+        }
+        self.commit(logical, target)
+    }
+
+    /// move the coldest data onto the most worn free sector if the erase counts have drifted
+    /// apart by more than the static threshold.
+    fn level(&mut self) -> Result<(), W25QError> {
+        let hottest = self.erase_counts.iter().copied().max().unwrap_or(0);
+        let cold = (0..N)
+            .filter(|p| self.owner[*p] != FREE)
+            .min_by_key(|p| self.erase_counts[*p]);
+        let hot = (0..N)
+            .filter(|p| self.owner[*p] == FREE)
+            .max_by_key(|p| self.erase_counts[*p]);
+        if let (Some(cold), Some(hot)) = (cold, hot) {
+            if hottest - self.erase_counts[cold] > self.static_threshold {
+                return self.rewrite(self.owner[cold] as usize, hot, None);
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&self, address: u32, len: usize) -> Result<(), W25QError> {
+        if address as u64 + len as u64 > self.capacity() as u64 {
+            return Err(W25QError::OutOfBounds);
+        }
+        Ok(())
+    }
+
+    pub fn read_at(&mut self, address: u32, data: &mut [u8]) -> Result<(), W25QError> {
+        self.check(address, data.len())?;
+        let mut addr = address as usize;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let offset = addr % SECTOR_SIZE;
+            let len = (SECTOR_SIZE - offset).min(rest.len());
+            let (now, later) = rest.split_at_mut(len);
+            let physical = self.map[addr / SECTOR_SIZE] as usize;
+            self.flash
+                .read(self.data_address(physical) + offset as u32, now)?;
+            addr += len;
+            rest = later;
+        }
+        Ok(())
+    }
+
+    /// replace the bytes at `address`. every touched sector is copied to a fresh sector with
+    /// `data` merged in, so no erase is needed beforehand.
+    pub fn write_at(&mut self, address: u32, data: &[u8]) -> Result<(), W25QError> {
+        self.check(address, data.len())?;
+        let mut addr = address as usize;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let offset = addr % SECTOR_SIZE;
+            let len = (SECTOR_SIZE - offset).min(rest.len());
+            let target = self.allocate();
+            self.rewrite(addr / SECTOR_SIZE, target, Some((offset, &rest[..len])))?;
+            self.level()?;
+            addr += len;
+            rest = &rest[len..];
+        }
+        Ok(())
+    }
+
+    pub fn read_block(
+        &mut self,
+        block: usize,
+        data: &mut [u8; SECTOR_SIZE],
+    ) -> Result<(), W25QError> {
+        self.read_at((block * SECTOR_SIZE) as u32, data)
+    }
+
+    pub fn write_block(&mut self, block: usize, data: &[u8; SECTOR_SIZE]) -> Result<(), W25QError> {
+        self.write_at((block * SECTOR_SIZE) as u32, data)
+    }
+
+    /// point `logical` at a freshly erased sector.
+    fn erase_logical(&mut self, logical: usize) -> Result<(), W25QError> {
+        let target = self.allocate();
+        self.erase_physical(target)?;
+        self.commit(logical, target)?;
+        self.level()
+    }
+}
+
+impl<F, const N: usize> nor_flash::ErrorType for WearLeveler<F, N> {
+    type Error = W25QError;
+}
+
+impl<F, const N: usize> ReadNorFlash for WearLeveler<F, N>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), W25QError> {
+        self.read_at(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        WearLeveler::capacity(self) as usize
+    }
+}
+
+/// erasing a logical sector remaps it to the least worn free sector, writes program the
+/// mapped sector in place.
+impl<F, const N: usize> NorFlash for WearLeveler<F, N>
+where
+    F: NorFlash,
+    W25QError: From<F::Error>,
+{
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = SECTOR_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), W25QError> {
+        if from > to {
+            return Err(W25QError::OutOfBounds);
+        }
+        self.check(from, (to - from) as usize)?;
+        if !(from as usize).is_multiple_of(SECTOR_SIZE)
+            || !(to as usize).is_multiple_of(SECTOR_SIZE)
+        {
+            return Err(W25QError::NotAligned);
+        }
+        for logical in from as usize / SECTOR_SIZE..to as usize / SECTOR_SIZE {
+            self.erase_logical(logical)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), W25QError> {
+        self.check(offset, bytes.len())?;
+        let mut addr = offset as usize;
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let sector_offset = addr % SECTOR_SIZE;
+            let len = (SECTOR_SIZE - sector_offset).min(rest.len());
+            let physical = self.map[addr / SECTOR_SIZE] as usize;
+            self.flash.write(
+                self.data_address(physical) + sector_offset as u32,
+                &rest[..len],
+            )?;
+            addr += len;
+            rest = &rest[len..];
+        }
+        Ok(())
+    }
+}
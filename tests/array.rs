@@ -0,0 +1,145 @@
+#![cfg(feature = "std")]
+
+use w25q::{
+    array::{FlashArray, Layout},
+    io::W25QError,
+    sim::{NoDelay, SimFlash},
+    Chip, W25QConfig, WriteProtection, SECTOR_SIZE, W25Q,
+};
+
+const CHIP: u32 = 2 << 20;
+const STRIPE: u32 = 2 * SECTOR_SIZE as u32;
+
+fn chip(config: W25QConfig) -> W25Q<SimFlash, NoDelay> {
+    W25Q::new(SimFlash::new(Chip::W25Q16), NoDelay, config).unwrap()
+}
+
+fn array(layout: Layout) -> FlashArray<SimFlash, NoDelay, 3> {
+    FlashArray::new([0, 1, 2].map(|_| chip(W25QConfig::new())), layout).unwrap()
+}
+
+fn data(len: usize) -> Vec<u8> {
+    (0..len as u32).map(|i| (i * 13 + i / 509) as u8).collect()
+}
+
+#[test]
+fn concatenated_across_chips() {
+    let mut array = array(Layout::Concatenated);
+    assert_eq!(array.capacity(), 3 * CHIP as u64);
+    let image = data(3 * SECTOR_SIZE);
+    let at = CHIP - 5000;
+    array
+        .erase_range(CHIP - 2 * SECTOR_SIZE as u32..CHIP + 2 * SECTOR_SIZE as u32)
+        .unwrap();
+    array.program(at, &image).unwrap();
+
+    let mut back = vec![0; image.len()];
+    array.read_at(at, &mut back).unwrap();
+    assert_eq!(back, image);
+    let chips = array.into_inner();
+    assert_eq!(&chips[0].periph.data()[at as usize..], &image[..5000]);
+    assert_eq!(
+        &chips[1].periph.data()[..image.len() - 5000],
+        &image[5000..]
+    );
+    assert!(chips[2].periph.data().iter().all(|b| *b == 0xFF));
+}
+
+#[test]
+fn striped_across_chips() {
+    let mut array = array(Layout::Striped { stripe: STRIPE });
+    // four stripes from the middle of one, wrapping from the last chip to the first
+    let at = 2 * STRIPE + 100;
+    let image = data(4 * STRIPE as usize);
+    array.program(at, &image).unwrap();
+    let mut back = vec![0; image.len()];
+    array.read_at(at, &mut back).unwrap();
+    assert_eq!(back, image);
+
+    let stripe = STRIPE as usize;
+    let chips = array.into_inner();
+    let on = |chip: usize, offset: usize, len: usize| &chips[chip].periph.data()[offset..][..len];
+    assert_eq!(on(2, 100, stripe - 100), &image[..stripe - 100]);
+    assert_eq!(
+        on(0, stripe, stripe),
+        &image[stripe - 100..2 * stripe - 100]
+    );
+    assert_eq!(
+        on(1, stripe, stripe),
+        &image[2 * stripe - 100..3 * stripe - 100]
+    );
+    assert_eq!(
+        on(2, stripe, stripe),
+        &image[3 * stripe - 100..4 * stripe - 100]
+    );
+    assert_eq!(on(0, 2 * stripe, 100), &image[4 * stripe - 100..]);
+
+    // erasing the middle stripes leaves the ends
+    let mut array = FlashArray::new(chips, Layout::Striped { stripe: STRIPE }).unwrap();
+    array.erase_range(3 * STRIPE..5 * STRIPE).unwrap();
+    array.read_at(at, &mut back).unwrap();
+    assert_eq!(&back[..stripe - 100], &image[..stripe - 100]);
+    assert!(back[stripe - 100..3 * stripe - 100]
+        .iter()
+        .all(|b| *b == 0xFF));
+    assert_eq!(&back[3 * stripe - 100..], &image[3 * stripe - 100..]);
+}
+
+#[test]
+fn bad_layouts_and_ranges() {
+    let chips = [chip(W25QConfig::new()), chip(W25QConfig::new())];
+    assert!(FlashArray::new(chips, Layout::Striped { stripe: 100 }).is_err());
+    let chips = [
+        chip(W25QConfig::new()),
+        W25Q::new(SimFlash::new(Chip::W25Q32), NoDelay, W25QConfig::new()).unwrap(),
+    ];
+    assert!(FlashArray::new(chips, Layout::Concatenated).is_err());
+
+    let mut array = array(Layout::Concatenated);
+    let mut buf = [0; 2];
+    assert!(matches!(
+        array.read_at(3 * CHIP - 1, &mut buf),
+        Err(W25QError::OutOfBounds)
+    ));
+    assert!(matches!(
+        array.erase_range(1..SECTOR_SIZE as u32),
+        Err(W25QError::NotAligned)
+    ));
+}
+
+#[test]
+fn chips_verify_after_write() {
+    // the middle chip holds data and is locked, both verify
+    let verify = W25QConfig::new().verify_after_write(true);
+    let sim = SimFlash::from_image(Chip::W25Q16, &[0; 16]).unwrap();
+    let locked = verify.write_protection(WriteProtection::LockAll);
+    let chips = [
+        chip(verify),
+        W25Q::new(sim, NoDelay, locked).unwrap(),
+        chip(W25QConfig::new()),
+    ];
+    let mut array = FlashArray::new(chips, Layout::Striped { stripe: STRIPE }).unwrap();
+
+    let image = data(3 * STRIPE as usize);
+    assert!(matches!(
+        array.program(STRIPE - 10, &image),
+        Err(W25QError::VerifyFailed { addr: STRIPE })
+    ));
+    // what was started before is finished
+    let mut back = [0; 10];
+    array.read_at(STRIPE - 10, &mut back).unwrap();
+    assert_eq!(back, image[..10]);
+
+    // erases are blank checked on every chip that verifies
+    array.chip_mut(0).program(0x10, &[0]).unwrap();
+    array.erase_range(0..SECTOR_SIZE as u32).unwrap();
+    assert_eq!(array.chip_mut(0).periph.data()[0x10], 0xFF);
+    assert!(matches!(
+        array.erase_range(0..2 * STRIPE),
+        Err(W25QError::VerifyFailed { addr: STRIPE })
+    ));
+    assert!(matches!(
+        array.chip_erase(),
+        Err(W25QError::VerifyFailed { addr: STRIPE })
+    ));
+}
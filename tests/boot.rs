@@ -0,0 +1,115 @@
+#![cfg(all(feature = "std", feature = "embassy-boot"))]
+
+use core::cell::RefCell;
+
+use embassy_boot::{BlockingFirmwareUpdater, FirmwareUpdater, State};
+use embassy_sync::{
+    blocking_mutex::{raw::NoopRawMutex, Mutex},
+    mutex,
+};
+use embedded_storage_async::nor_flash::ReadNorFlash;
+use w25q::{
+    boot::{BlockingAsync, BootLayout, STATE_WRITE_SIZE},
+    sim::{NoDelay, SimFlash},
+    Chip, W25Q,
+};
+
+const ACTIVE_SIZE: u32 = 0x20000;
+/// DFU right after the active partition
+const DFU: u32 = ACTIVE_SIZE;
+
+const SWAP_MAGIC: u8 = 0xF0;
+const BOOT_MAGIC: u8 = 0xD0;
+
+/// a device running an image that fills the active partition
+fn device() -> W25Q<SimFlash, NoDelay> {
+    SimFlash::from_image(Chip::W25Q64, &[0xA5; ACTIVE_SIZE as usize])
+        .unwrap()
+        .into_device()
+        .unwrap()
+}
+
+/// a new image, not a whole number of sectors
+fn firmware() -> Vec<u8> {
+    (0..ACTIVE_SIZE - 0x900)
+        .map(|i| (i * 5 + i / 4093) as u8)
+        .collect()
+}
+
+#[test]
+fn blocking_update_cycle() {
+    let layout = BootLayout::new(DFU, ACTIVE_SIZE).unwrap();
+    let flash = Mutex::<NoopRawMutex, _>::new(RefCell::new(device()));
+    let fw = firmware();
+
+    let mut aligned = [0; STATE_WRITE_SIZE];
+    let mut updater =
+        BlockingFirmwareUpdater::new(layout.blocking_config(&flash).unwrap(), &mut aligned);
+    assert_eq!(updater.get_state().unwrap(), State::Boot);
+    for (i, chunk) in fw.chunks(1000).enumerate() {
+        updater.write_firmware(i * 1000, chunk).unwrap();
+    }
+    updater.mark_updated().unwrap();
+    assert_eq!(updater.get_state().unwrap(), State::Swap);
+
+    {
+        let dev = flash.lock(|dev| dev.borrow().periph.data().to_vec());
+        assert_eq!(&dev[DFU as usize..][..fw.len()], &fw[..]);
+        assert_eq!(dev[layout.state.start as usize], SWAP_MAGIC);
+        // the running image and the rest of the chip are left alone
+        assert!(dev[..DFU as usize].iter().all(|b| *b == 0xA5));
+        assert!(dev[layout.end() as usize..].iter().all(|b| *b == 0xFF));
+    }
+
+    // after the swap the new image confirms itself
+    let mut aligned = [0; STATE_WRITE_SIZE];
+    let mut updater =
+        BlockingFirmwareUpdater::new(layout.blocking_config(&flash).unwrap(), &mut aligned);
+    assert_eq!(updater.get_state().unwrap(), State::Swap);
+    updater.mark_booted().unwrap();
+    assert_eq!(updater.get_state().unwrap(), State::Boot);
+    let dev = flash.into_inner().into_inner();
+    assert_eq!(dev.periph.data()[layout.state.start as usize], BOOT_MAGIC);
+}
+
+#[test]
+fn async_update_cycle() {
+    let layout = BootLayout::new(DFU, ACTIVE_SIZE).unwrap();
+    let flash = mutex::Mutex::<NoopRawMutex, _>::new(BlockingAsync::new(device()));
+    let fw = firmware();
+
+    embassy_futures::block_on(async {
+        let mut aligned = [0; STATE_WRITE_SIZE];
+        let mut updater =
+            FirmwareUpdater::new(layout.async_config(&flash).await.unwrap(), &mut aligned);
+        assert_eq!(updater.get_state().await.unwrap(), State::Boot);
+        for (i, chunk) in fw.chunks(1000).enumerate() {
+            updater.write_firmware(i * 1000, chunk).await.unwrap();
+        }
+        updater.mark_updated().await.unwrap();
+        assert_eq!(updater.get_state().await.unwrap(), State::Swap);
+
+        let mut dev = flash.lock().await;
+        let mut dfu = vec![0; fw.len()];
+        dev.read(DFU, &mut dfu).await.unwrap();
+        assert_eq!(dfu, fw);
+        let mut magic = [0];
+        dev.read(layout.state.start, &mut magic).await.unwrap();
+        assert_eq!(magic, [SWAP_MAGIC]);
+        let mut active = vec![0; DFU as usize];
+        dev.read(0, &mut active).await.unwrap();
+        assert!(active.iter().all(|b| *b == 0xA5));
+        drop(dev);
+
+        let mut aligned = [0; STATE_WRITE_SIZE];
+        let mut updater =
+            FirmwareUpdater::new(layout.async_config(&flash).await.unwrap(), &mut aligned);
+        updater.mark_booted().await.unwrap();
+        assert_eq!(updater.get_state().await.unwrap(), State::Boot);
+    });
+
+    // a layout that does not fit the chip is refused
+    let end = Chip::W25Q64.capacity() - ACTIVE_SIZE;
+    let layout = BootLayout::new(end, ACTIVE_SIZE).unwrap();
+    assert!(embassy_futures::block_on(layout.async_config(&flash)).is_err());
+}
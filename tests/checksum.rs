@@ -0,0 +1,62 @@
+use w25q::checksum::{Crc16, Crc32, Hasher};
+
+const CHECK: &[u8] = b"123456789";
+
+fn data() -> Vec<u8> {
+    (0..5000u32).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+#[test]
+fn known_vectors() {
+    // the check values of the CRC catalogue
+    assert_eq!(Crc32::checksum(CHECK), 0xCBF4_3926);
+    assert_eq!(Crc16::checksum(CHECK), 0x29B1);
+    assert_eq!(Crc32::checksum(b""), 0);
+    assert_eq!(Crc16::checksum(b""), 0xFFFF);
+    assert_eq!(
+        Crc32::checksum(b"The quick brown fox jumps over the lazy dog"),
+        0x414F_A339
+    );
+}
+
+#[test]
+fn incremental_updates_match_one_shot() {
+    let data = data();
+    let (crc32, crc16) = (Crc32::checksum(&data), Crc16::checksum(&data));
+    for size in [1, 3, 64, 255, 256, 4999] {
+        let mut a = Crc32::new();
+        let mut b = Crc16::default();
+        for chunk in data.chunks(size) {
+            a.update(chunk);
+            b.update(chunk);
+            // finalizing does not end the CRC
+            let _ = (a.finalize(), b.finalize());
+        }
+        assert_eq!(a.finalize(), crc32, "chunks of {size}");
+        assert_eq!(b.finalize(), crc16, "chunks of {size}");
+    }
+    // empty updates change nothing
+    let mut crc = Crc32::new();
+    crc.update(&data[..100]);
+    crc.update(&[]);
+    crc.update(&data[100..]);
+    assert_eq!(crc.finalize(), crc32);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn device_checksums_match() {
+    use w25q::{sim::SimFlash, Chip};
+
+    let data = data();
+    let mut dev = SimFlash::from_image(Chip::W25Q16, &data)
+        .unwrap()
+        .into_device()
+        .unwrap();
+    assert_eq!(dev.crc32(0..5000).unwrap(), Crc32::checksum(&data));
+    assert_eq!(
+        dev.crc16(10..4000).unwrap(),
+        Crc16::checksum(&data[10..4000])
+    );
+    assert_eq!(dev.crc16(7..7).unwrap(), 0xFFFF);
+}
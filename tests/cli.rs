@@ -0,0 +1,177 @@
+#![cfg(feature = "std")]
+
+use std::{
+    ffi::OsStr,
+    fs,
+    path::Path,
+    process::{Command, Output},
+};
+
+use w25q::{sim::FileFlash, Chip, SR, SR3};
+
+fn data(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect()
+}
+
+/// run w25q-cli, failing the test if it does not exit as `success`
+fn cli(args: &[&OsStr], success: bool) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_w25q-cli"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.success(), success, "{args:?}: {output:?}");
+    output
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn sim_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let image = dir.path().join("flash.bin");
+    let file = dir.path().join("app.bin");
+    let dump = dir.path().join("dump.bin");
+    let app = data(10_000, 1);
+    fs::write(&file, &app).unwrap();
+    let sim = |args: &[&OsStr], success| {
+        let mut all = vec!["--sim".as_ref(), image.as_os_str()];
+        all.extend_from_slice(args);
+        cli(&all, success)
+    };
+
+    // a new image needs the chip, later ones are recognised by size
+    sim(
+        &["write".as_ref(), "0x10000".as_ref(), file.as_ref()],
+        false,
+    );
+    let write = cli(
+        &[
+            "--sim".as_ref(),
+            image.as_ref(),
+            "--chip".as_ref(),
+            "W25Q16".as_ref(),
+            "write".as_ref(),
+            "0x10000".as_ref(),
+            file.as_ref(),
+        ],
+        true,
+    );
+    assert!(stdout(&write).contains("10000 bytes"), "{write:?}");
+    assert_eq!(
+        fs::metadata(&image).unwrap().len(),
+        Chip::W25Q16.capacity() as u64
+    );
+
+    let verify = sim(
+        &["verify".as_ref(), "0x10000".as_ref(), file.as_ref()],
+        true,
+    );
+    assert!(stdout(&verify).contains("10000 bytes match"));
+    sim(
+        &[
+            "read".as_ref(),
+            "0x10000".as_ref(),
+            "10000".as_ref(),
+            "-o".as_ref(),
+            dump.as_ref(),
+        ],
+        true,
+    );
+    assert_eq!(fs::read(&dump).unwrap(), app);
+    let read = sim(&["read".as_ref(), "0x10000".as_ref(), "16".as_ref()], true);
+    assert!(stdout(&read).starts_with("00010000: "), "{read:?}");
+
+    // a changed byte is found, and writing again only touches its sector
+    let mut other = app.clone();
+    other[5000] ^= 0x80;
+    fs::write(&file, &other).unwrap();
+    let verify = sim(
+        &["verify".as_ref(), "0x10000".as_ref(), file.as_ref()],
+        false,
+    );
+    let stderr = String::from_utf8_lossy(&verify.stderr);
+    assert!(stderr.contains("differs at 0x00011388"), "{stderr}");
+    let write = sim(&["write".as_ref(), "0x10000".as_ref(), file.as_ref()], true);
+    assert!(stdout(&write).contains("2 sectors unchanged"), "{write:?}");
+    sim(
+        &["verify".as_ref(), "0x10000".as_ref(), file.as_ref()],
+        true,
+    );
+}
+
+/// the array of the emulated chip in `path`
+fn emulated(path: &Path) -> Vec<u8> {
+    FileFlash::open(path).unwrap().sim().data().to_vec()
+}
+
+#[test]
+fn emulator_erase_and_protect() {
+    let dir = tempfile::tempdir().unwrap();
+    let chip = dir.path().join("board.w25");
+    let file = dir.path().join("data.bin");
+    let contents = data(3 * 4096, 2);
+    fs::write(&file, &contents).unwrap();
+    let emulator = |args: &[&OsStr], success| {
+        let mut all = vec!["--emulator".as_ref(), chip.as_os_str()];
+        all.extend_from_slice(args);
+        cli(&all, success)
+    };
+
+    // created on first use
+    cli(
+        &[
+            "--emulator".as_ref(),
+            chip.as_ref(),
+            "--chip".as_ref(),
+            "W25Q64".as_ref(),
+            "write".as_ref(),
+            "0".as_ref(),
+            file.as_ref(),
+        ],
+        true,
+    );
+    assert_eq!(&emulated(&chip)[..contents.len()], &contents[..]);
+
+    emulator(
+        &["erase".as_ref(), "0x1000".as_ref(), "0x1000".as_ref()],
+        true,
+    );
+    let array = emulated(&chip);
+    assert_eq!(&array[..0x1000], &contents[..0x1000]);
+    assert!(array[0x1000..0x2000].iter().all(|b| *b == 0xFF));
+    assert_eq!(&array[0x2000..0x3000], &contents[0x2000..]);
+    emulator(
+        &["erase".as_ref(), "0x1001".as_ref(), "0x1000".as_ref()],
+        false,
+    );
+
+    // with WPS set every block powers up locked
+    let mut dev = FileFlash::open(&chip).unwrap().into_device().unwrap();
+    dev.write_status_register(SR::SR3(SR3 {
+        wps: true,
+        ..SR3::default()
+    }))
+    .unwrap();
+    drop(dev);
+    let status = emulator(&["status".as_ref()], true);
+    assert!(stdout(&status).contains("wps: true"), "{status:?}");
+    emulator(&["erase".as_ref(), "--all".as_ref()], true);
+    assert_eq!(&emulated(&chip)[..0x1000], &contents[..0x1000]);
+    emulator(&["protect".as_ref()], true);
+    emulator(&["unprotect".as_ref(), "0x400000".as_ref()], true);
+
+    // clearing WPS lifts the locks
+    let mut dev = FileFlash::open(&chip).unwrap().into_device().unwrap();
+    dev.write_status_register(SR::SR3(SR3::default())).unwrap();
+    drop(dev);
+    emulator(&["erase".as_ref(), "--all".as_ref()], true);
+    assert!(emulated(&chip).iter().all(|b| *b == 0xFF));
+
+    // a file that is not an emulated chip
+    fs::write(&chip, b"not a chip").unwrap();
+    emulator(&["status".as_ref()], false);
+}
@@ -0,0 +1,146 @@
+//! helpers shared by the integration tests
+#![allow(dead_code)]
+
+use embedded_storage::nor_flash::{ErrorType, MultiwriteNorFlash, NorFlash, ReadNorFlash};
+use w25q::{io::W25QError, SECTOR_SIZE};
+
+/// NOR flash in RAM that can lose power or fail one operation.
+///
+/// programming only clears bits, like the real chip. a program cut by a power loss or a
+/// failure programs the first half of its bytes, a cut erase erases the first half of the
+/// sector. after a power loss every access fails until [`MemFlash::power_cycle`].
+pub struct MemFlash {
+    pub data: Vec<u8>,
+    /// programs and erases since creation
+    pub ops: usize,
+    /// the power is cut during this operation
+    cut_at: Option<usize>,
+    /// this operation fails, the flash keeps working
+    fail_at: Option<usize>,
+    powered: bool,
+}
+
+impl MemFlash {
+    /// `size` erased bytes
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: vec![0xFF; size],
+            ops: 0,
+            cut_at: None,
+            fail_at: None,
+            powered: true,
+        }
+    }
+
+    /// cut the power during the `n`th program or erase from now, counted from 0.
+    pub fn cut_after(&mut self, n: usize) {
+        self.cut_at = Some(self.ops + n);
+    }
+
+    /// fail the `n`th program or erase from now, counted from 0.
+    pub fn fail_after(&mut self, n: usize) {
+        self.fail_at = Some(self.ops + n);
+    }
+
+    /// whether the power was cut
+    pub fn lost_power(&self) -> bool {
+        !self.powered
+    }
+
+    /// power up again, with no cut or failure pending.
+    pub fn power_cycle(&mut self) {
+        self.powered = true;
+        self.cut_at = None;
+        self.fail_at = None;
+    }
+
+    /// count an operation. `Err` with whether it is cut if it does not complete.
+    fn start(&mut self) -> Result<(), bool> {
+        let op = self.ops;
+        self.ops += 1;
+        if self.cut_at == Some(op) {
+            self.powered = false;
+            return Err(true);
+        }
+        if self.fail_at == Some(op) {
+            return Err(false);
+        }
+        Ok(())
+    }
+
+    fn check(&self, offset: u32, len: usize) -> Result<(), W25QError> {
+        if !self.powered {
+            return Err(W25QError::Timeout);
+        }
+        if offset as usize + len > self.data.len() {
+            return Err(W25QError::OutOfBounds);
+        }
+        Ok(())
+    }
+}
+
+impl ErrorType for MemFlash {
+    type Error = W25QError;
+}
+
+impl ReadNorFlash for MemFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), W25QError> {
+        self.check(offset, bytes.len())?;
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for MemFlash {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = SECTOR_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), W25QError> {
+        self.check(from, to.saturating_sub(from) as usize)?;
+        if from > to
+            || !(from as usize).is_multiple_of(SECTOR_SIZE)
+            || !(to as usize).is_multiple_of(SECTOR_SIZE)
+        {
+            return Err(W25QError::NotAligned);
+        }
+        for sector in (from as usize..to as usize).step_by(SECTOR_SIZE) {
+            if let Err(cut) = self.start() {
+                self.data[sector..sector + SECTOR_SIZE / 2].fill(0xFF);
+                return Err(if cut {
+                    W25QError::Timeout
+                } else {
+                    W25QError::EraseFailed
+                });
+            }
+            self.data[sector..sector + SECTOR_SIZE].fill(0xFF);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), W25QError> {
+        self.check(offset, bytes.len())?;
+        let result = self.start();
+        let len = match result {
+            Ok(()) => bytes.len(),
+            Err(_) => bytes.len() / 2,
+        };
+        let offset = offset as usize;
+        for (cell, byte) in self.data[offset..offset + len].iter_mut().zip(bytes) {
+            *cell &= byte;
+        }
+        match result {
+            Ok(()) => Ok(()),
+            Err(true) => Err(W25QError::Timeout),
+            Err(false) => Err(W25QError::ProgramFailed),
+        }
+    }
+}
+
+impl MultiwriteNorFlash for MemFlash {}
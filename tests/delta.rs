@@ -0,0 +1,139 @@
+mod common;
+
+use common::MemFlash;
+use w25q::{
+    checksum::Crc32,
+    delta::{DeltaPatcher, PatchHeader, OP_COPY, OP_INSERT},
+    io::W25QError,
+    SECTOR_SIZE,
+};
+
+const OLD: u32 = 0;
+const NEW: u32 = 6 * SECTOR_SIZE as u32;
+const PROGRESS: u32 = 12 * SECTOR_SIZE as u32;
+const SIZE: usize = 14 * SECTOR_SIZE;
+
+fn old_image() -> Vec<u8> {
+    (0..20_000u32).map(|i| (i * 7 + i / 255) as u8).collect()
+}
+
+/// a patch of copies, each followed by an insert, and the image it builds
+fn patch(old: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let inserted: Vec<u8> = (0..3000u32).map(|i| (i * 13 + 5) as u8).collect();
+    let mut ops = Vec::new();
+    let mut image = Vec::new();
+    for (src, len, insert) in [
+        (0u32, 5000u32, 3000usize),
+        (8000, 9000, 100),
+        (100, 4000, 0),
+    ] {
+        ops.push(OP_COPY);
+        ops.extend_from_slice(&src.to_le_bytes());
+        ops.extend_from_slice(&len.to_le_bytes());
+        image.extend_from_slice(&old[src as usize..(src + len) as usize]);
+        if insert > 0 {
+            ops.push(OP_INSERT);
+            ops.extend_from_slice(&(insert as u32).to_le_bytes());
+            ops.extend_from_slice(&inserted[..insert]);
+            image.extend_from_slice(&inserted[..insert]);
+        }
+    }
+
+    let header = PatchHeader {
+        old_length: old.len() as u32,
+        old_crc32: Crc32::checksum(old),
+        new_length: image.len() as u32,
+        new_crc32: Crc32::checksum(&image),
+        sha256: None,
+    };
+    let mut patch = header.to_bytes().to_vec();
+    patch.extend_from_slice(&ops);
+    (patch, image)
+}
+
+fn flash_with(old: &[u8]) -> MemFlash {
+    let mut flash = MemFlash::new(SIZE);
+    flash.data[OLD as usize..OLD as usize + old.len()].copy_from_slice(old);
+    flash
+}
+
+fn patcher(flash: MemFlash) -> DeltaPatcher<MemFlash> {
+    DeltaPatcher::new(flash, OLD..NEW, NEW..PROGRESS, PROGRESS).unwrap()
+}
+
+/// send `patch` from the resume offset in pieces of `piece` bytes, then finish
+fn apply(p: &mut DeltaPatcher<MemFlash>, patch: &[u8], piece: usize) -> Result<(), W25QError> {
+    for chunk in patch[p.resume_offset() as usize..].chunks(piece) {
+        p.write(chunk)?;
+    }
+    p.finish().map(|_| ())
+}
+
+fn new_region(flash: &MemFlash, len: usize) -> &[u8] {
+    &flash.data[NEW as usize..NEW as usize + len]
+}
+
+#[test]
+fn resume_after_a_reset() {
+    let old = old_image();
+    let (patch, image) = patch(&old);
+
+    let mut p = patcher(flash_with(&old));
+    // stop in the middle of the last insert
+    let sent = patch.len() - 60;
+    for chunk in patch[..sent].chunks(333) {
+        p.write(chunk).unwrap();
+    }
+    let written = p.written();
+    assert!(written > 2 * SECTOR_SIZE as u32);
+
+    let mut p = patcher(p.into_inner());
+    assert!(p.resume_offset() > 0 && p.resume_offset() <= sent as u32);
+    assert!(p.written() + SECTOR_SIZE as u32 >= written);
+    assert!(p.header().is_some());
+    apply(&mut p, &patch, 1000).unwrap();
+    assert_eq!(new_region(&p.into_inner(), image.len()), &image[..]);
+}
+
+#[test]
+fn power_cut_at_any_point() {
+    let old = old_image();
+    let (patch, image) = patch(&old);
+
+    for cut in 0.. {
+        let mut flash = flash_with(&old);
+        flash.cut_after(cut);
+        let mut p = patcher(flash);
+        let result = apply(&mut p, &patch, 777);
+        let mut flash = p.into_inner();
+        if !flash.lost_power() {
+            result.unwrap();
+            assert_eq!(new_region(&flash, image.len()), &image[..]);
+            break;
+        }
+        assert!(result.is_err());
+
+        flash.power_cycle();
+        let mut p = patcher(flash);
+        apply(&mut p, &patch, 101).unwrap_or_else(|e| panic!("cut at {cut}: {e:?}"));
+        let flash = p.into_inner();
+        assert_eq!(new_region(&flash, image.len()), &image[..], "cut at {cut}");
+        assert_eq!(&flash.data[..old.len()], &old[..]);
+    }
+}
+
+#[test]
+fn wrong_old_image() {
+    let old = old_image();
+    let (patch, _) = patch(&old);
+    let mut other = old.clone();
+    other[1234] ^= 1;
+    let mut p = patcher(flash_with(&other));
+    assert!(matches!(p.write(&patch[..200]), Err(W25QError::Corrupt)));
+    assert!(p.header().is_none());
+    assert_eq!(p.resume_offset() as usize, 64);
+
+    // nothing was recorded, the next attempt starts over
+    let p = patcher(p.into_inner());
+    assert_eq!(p.resume_offset(), 0);
+}
@@ -0,0 +1,285 @@
+#![cfg(feature = "std")]
+
+use core::convert::Infallible;
+
+use embedded_hal::spi::{ErrorType, Operation, SpiDevice};
+use embedded_io::{BufRead, Seek, SeekFrom};
+use w25q::{
+    checksum::Crc32,
+    io::W25QError,
+    sim::{NoDelay, SimFlash},
+    AddressMode, Chip, PowerState, ReadCommand, W25QConfig, WriteProtection, SR, SR3, W25Q,
+};
+
+/// a W25Q64 that stays busy, e.g. hung in an erase
+struct StuckBusy;
+
+impl ErrorType for StuckBusy {
+    type Error = Infallible;
+}
+
+impl SpiDevice for StuckBusy {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+        let mut command = None;
+        for operation in operations {
+            match operation {
+                Operation::Write(tx) if command.is_none() => command = tx.first().copied(),
+                Operation::Read(rx) if command == Some(0x9F) => {
+                    rx.copy_from_slice(&[0xEF, 0x40, 0x17]);
+                }
+                Operation::Read(rx) => rx.fill(0x01),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// a [`SimFlash`] recording the command byte of every transaction
+struct Recorded {
+    sim: SimFlash,
+    commands: Vec<u8>,
+}
+
+impl ErrorType for Recorded {
+    type Error = Infallible;
+}
+
+impl SpiDevice for Recorded {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+        if let Some(Operation::Write(tx)) = operations.first() {
+            self.commands.extend(tx.first());
+        }
+        self.sim.transaction(operations)
+    }
+}
+
+fn recorded(config: W25QConfig) -> W25Q<Recorded, NoDelay> {
+    let image: Vec<u8> = (0..4096u32).map(|i| i as u8).collect();
+    let sim = Recorded {
+        sim: SimFlash::from_image(Chip::W25Q16, &image).unwrap(),
+        commands: Vec::new(),
+    };
+    W25Q::new(sim, NoDelay, config.chip(Chip::W25Q16)).unwrap()
+}
+
+#[test]
+fn three_byte_mode_on_a_four_byte_part() {
+    let config = W25QConfig::new()
+        .chip(Chip::W25Q256)
+        .address_mode(AddressMode::ThreeByte);
+    let mut dev = W25Q::new(SimFlash::new(Chip::W25Q256), NoDelay, config).unwrap();
+    assert_eq!(dev.address_mode(), AddressMode::ThreeByte);
+    assert_eq!(dev.capacity(), 16 << 20);
+
+    let end = (16 << 20) - 4;
+    dev.sector_erase(end & !0xFFF).unwrap();
+    dev.program(end, &[1, 2, 3, 4]).unwrap();
+    assert!(dev.program(end, &[0; 8]).is_err());
+    assert_eq!(&dev.periph.data()[end as usize..][..4], &[1, 2, 3, 4]);
+
+    let mut buf = [0; 4];
+    dev.read_at(end, &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3, 4]);
+
+    dev.reset_device().unwrap();
+    dev.read_at(end, &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3, 4]);
+}
+
+#[test]
+fn four_byte_mode_needs_a_four_byte_part() {
+    let config = W25QConfig::new().address_mode(AddressMode::FourByte);
+    assert!(W25Q::new(SimFlash::new(Chip::W25Q128), NoDelay, config).is_err());
+}
+
+#[test]
+fn checksum_with_any_buffer_size() {
+    let image: Vec<u8> = (0..10_000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+    let mut dev = SimFlash::from_image(Chip::W25Q64, &image)
+        .unwrap()
+        .into_device()
+        .unwrap();
+    let range = 3..9_999;
+    let expected = Crc32::checksum(&image[3..9_999]);
+    assert_eq!(dev.crc32(range.clone()).unwrap(), expected);
+
+    for size in [1, 100, 4096, 20_000] {
+        let mut crc = Crc32::new();
+        dev.checksum_with(range.clone(), &mut crc, &mut vec![0; size])
+            .unwrap();
+        assert_eq!(crc.finalize(), expected, "buffer of {size}");
+    }
+    assert!(dev
+        .checksum_with(range, &mut Crc32::new(), &mut [])
+        .is_err());
+}
+
+#[test]
+fn writes_across_the_die_boundary() {
+    let mut dev = SimFlash::new(Chip::W25M512).into_device().unwrap();
+    let die = Chip::W25M512.die_capacity();
+
+    // every call switches dies. check each one, a lost write leaves a stale write enable
+    // behind that would let the next one through.
+    dev.program(die - 2, &[1, 2, 3, 4]).unwrap();
+    assert_eq!(&dev.periph.data()[die as usize - 2..][..4], &[1, 2, 3, 4]);
+    dev.start_page_program(0x100, &[5]).unwrap();
+    dev.wait_program().unwrap();
+    assert_eq!(dev.periph.data()[0x100], 5);
+    dev.start_page_program(die + 0x1000, &[6]).unwrap();
+    dev.wait_program().unwrap();
+    assert_eq!(dev.periph.data()[die as usize + 0x1000], 6);
+    dev.sector_erase(0).unwrap();
+    assert_eq!(dev.periph.data()[0x100], 0xFF);
+    dev.start_sector_erase(die + 0x1000).unwrap();
+    dev.wait_erase().unwrap();
+    assert_eq!(dev.periph.data()[die as usize + 0x1000], 0xFF);
+
+    let mut buf = [0; 4];
+    dev.read_at(die - 2, &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3, 4]);
+
+    // block locks come up set on every die
+    dev.individual_block_unlock(0).unwrap();
+    assert!(!dev.read_block_lock(0).unwrap());
+    dev.individual_block_unlock(die).unwrap();
+    assert!(!dev.read_block_lock(die).unwrap());
+    dev.individual_block_lock(0).unwrap();
+    assert!(dev.read_block_lock(0).unwrap());
+}
+
+#[test]
+fn lock_all_refuses_writes() {
+    let config = W25QConfig::new()
+        .write_protection(WriteProtection::LockAll)
+        .verify_after_write(true);
+    let mut dev = W25Q::new(SimFlash::new(Chip::W25Q64), NoDelay, config).unwrap();
+    let sr3 = dev.read_status_register(SR::SR3(SR3::default())).unwrap();
+    assert!(SR3::from(sr3).wps);
+    assert!(matches!(
+        dev.program(0x1000, b"locked"),
+        Err(W25QError::VerifyFailed { .. })
+    ));
+    assert!(dev.sector_erase(0x1000).is_ok());
+    assert!(dev.periph.data()[0x1000..0x1006].iter().all(|b| *b == 0xFF));
+
+    // unlocking lets writes through again
+    let config = W25QConfig::new().write_protection(WriteProtection::UnlockAll);
+    let mut dev = W25Q::new(dev.periph, NoDelay, config).unwrap();
+    dev.program(0x1000, b"open").unwrap();
+    assert_eq!(&dev.periph.data()[0x1000..0x1004], b"open");
+}
+
+#[test]
+fn zero_poll_interval_times_out() {
+    let config = W25QConfig::new()
+        .poll_interval_us(0)
+        .erase_timeout_ms(5)
+        .program_timeout_ms(1);
+    let mut dev = W25Q::new(StuckBusy, NoDelay, config).unwrap();
+    assert!(matches!(dev.sector_erase(0), Err(W25QError::Timeout)));
+    assert!(matches!(dev.program(0, &[0]), Err(W25QError::Timeout)));
+}
+
+#[test]
+fn recover_brings_back_every_die() {
+    let mut dev = SimFlash::new(Chip::W25M512).into_device().unwrap();
+    let die = Chip::W25M512.die_capacity();
+    dev.select_die(1).unwrap();
+
+    // a warm reset left die 0 in 3-byte mode and both dies powered down, behind the
+    // driver's back
+    for command in [[0xC2, 0], [0xE9, 0], [0xB9, 0], [0xC2, 1], [0xB9, 0]] {
+        let len = if command[0] == 0xC2 { 2 } else { 1 };
+        dev.periph
+            .transaction(&mut [Operation::Write(&command[..len])])
+            .unwrap();
+    }
+
+    let report = dev.recover().unwrap();
+    assert!(report.powered_down);
+    assert!(report.unresponsive && !report.qpi_or_continuous_read);
+    assert_eq!(report.jedec_id[0], 0xEF);
+    assert_eq!(dev.active_die(), 1);
+
+    // both dies answer and take 4-byte addresses again
+    dev.program(0x100, b"die 0").unwrap();
+    dev.program(die + 0x100, b"die 1").unwrap();
+    assert_eq!(&dev.periph.data()[0x100..0x105], b"die 0");
+    assert_eq!(&dev.periph.data()[die as usize + 0x100..][..5], b"die 1");
+
+    // single die parts recover as before
+    let mut dev = SimFlash::new(Chip::W25Q64).into_device().unwrap();
+    dev.periph
+        .transaction(&mut [Operation::Write(&[0xB9])])
+        .unwrap();
+    assert!(dev.recover().unwrap().powered_down);
+    assert_eq!(dev.active_die(), 0);
+}
+
+#[test]
+fn access_wakes_a_powered_down_chip() {
+    let mut dev = recorded(W25QConfig::new());
+    dev.power_down().unwrap();
+    assert_eq!(dev.power_state(), PowerState::PoweredDown);
+    assert_eq!(dev.periph.commands.last(), Some(&0xB9));
+
+    // the chip ignores reads until released, the driver releases it first
+    dev.periph.commands.clear();
+    let mut buf = [0; 4];
+    dev.read_at(0x10, &mut buf).unwrap();
+    assert_eq!(buf, [0x10, 0x11, 0x12, 0x13]);
+    assert_eq!(dev.power_state(), PowerState::Active);
+    assert_eq!(dev.periph.commands, [0xAB, 0x0B]);
+
+    // and before a write
+    dev.power_down().unwrap();
+    dev.periph.commands.clear();
+    dev.program(0x2000, b"awake").unwrap();
+    assert_eq!(dev.periph.commands.first(), Some(&0xAB));
+    assert_eq!(&dev.periph.sim.data()[0x2000..0x2005], b"awake");
+}
+
+#[test]
+fn idle_time_powers_down() {
+    let mut dev = recorded(W25QConfig::new());
+    // disabled by default
+    dev.idle(u32::MAX).unwrap();
+    assert_eq!(dev.power_state(), PowerState::Active);
+
+    dev.set_auto_power_down(Some(10));
+    dev.idle(4).unwrap();
+    dev.idle(5).unwrap();
+    assert_eq!(dev.power_state(), PowerState::Active);
+    // an access restarts the idle time
+    dev.read_at(0, &mut [0; 4]).unwrap();
+    dev.idle(9).unwrap();
+    assert_eq!(dev.power_state(), PowerState::Active);
+    dev.idle(1).unwrap();
+    assert_eq!(dev.power_state(), PowerState::PoweredDown);
+    assert_eq!(dev.periph.commands.last(), Some(&0xB9));
+
+    // staying idle sends nothing more
+    let sent = dev.periph.commands.len();
+    dev.idle(100).unwrap();
+    assert_eq!(dev.periph.commands.len(), sent);
+    dev.read_at(0, &mut [0; 4]).unwrap();
+    assert_eq!(dev.power_state(), PowerState::Active);
+}
+
+#[test]
+fn reads_use_the_configured_command() {
+    for (command, opcode) in [(ReadCommand::Read, 0x03), (ReadCommand::FastRead, 0x0B)] {
+        let mut dev = recorded(W25QConfig::new().read_command(command));
+        dev.periph.commands.clear();
+        let mut buf = [0; 4];
+        dev.read_at(0x20, &mut buf).unwrap();
+        assert_eq!(buf, [0x20, 0x21, 0x22, 0x23]);
+
+        // the BufRead buffer too
+        dev.seek(SeekFrom::Start(0x130)).unwrap();
+        assert_eq!(dev.fill_buf().unwrap()[..2], [0x30, 0x31]);
+        assert_eq!(dev.periph.commands, [opcode, opcode]);
+    }
+}
@@ -0,0 +1,159 @@
+#![cfg(feature = "std")]
+
+use std::{fs, io::ErrorKind};
+
+use embedded_storage::nor_flash::ReadNorFlash;
+use w25q::{
+    kv::KvStore,
+    partition::Partition,
+    sim::{FileFlash, SimFlash},
+    Chip, SECTOR_SIZE, SR, SR1, SR3,
+};
+
+const KV: u32 = 0x20000;
+const KV_SIZE: u32 = 4 * SECTOR_SIZE as u32;
+
+#[test]
+fn chip_survives_a_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("chip.w25");
+
+    let mut flash = FileFlash::create(&path, Chip::W25Q64).unwrap();
+    flash.set_unique_id(*b"board-07").unwrap();
+    let mut dev = flash.into_device().unwrap();
+    dev.program(0x1000, b"persisted").unwrap();
+    // NOR: programming only clears bits
+    dev.program(0x2000, &[0xF0]).unwrap();
+    dev.program(0x2000, &[0x3C]).unwrap();
+    dev.program_security_register(2 << 12, b"calibration")
+        .unwrap();
+    dev.write_status_register(SR::SR1(SR1 {
+        bp: 0b101,
+        ..SR1::default()
+    }))
+    .unwrap();
+    dev.individual_block_unlock(0).unwrap();
+    drop(dev);
+
+    let mut dev = FileFlash::open(&path).unwrap().into_device().unwrap();
+    let mut buf = [0; 9];
+    dev.read(0x1000, &mut buf).unwrap();
+    assert_eq!(&buf, b"persisted");
+    dev.read(0x2000, &mut buf[..1]).unwrap();
+    assert_eq!(buf[0], 0x30);
+    let mut register = [0; 11];
+    dev.read_security_register(2 << 12, &mut register).unwrap();
+    assert_eq!(&register, b"calibration");
+    assert_eq!(dev.read_unique_id().unwrap(), *b"board-07");
+    let sr1 = dev.read_status_register(SR::SR1(SR1::default())).unwrap();
+    assert_eq!(SR1::from(sr1).bp, 0b101);
+    // reopening is a power-up: the write enable latch and the block locks are reset
+    assert!(!SR1::from(sr1).wel);
+    assert!(dev.read_block_lock(0).unwrap());
+
+    // the chip comes from the file, untouched sectors stay erased
+    let sim = dev.periph.sim();
+    assert_eq!(sim.chip(), Chip::W25Q64);
+    assert!(sim.data()[..0x1000].iter().all(|b| *b == 0xFF));
+}
+
+#[test]
+fn layers_run_on_a_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("chip.w25");
+
+    let mut dev = FileFlash::open_or_create(&path, Chip::W25Q16)
+        .unwrap()
+        .into_device()
+        .unwrap();
+    {
+        let partition = Partition::new(&mut dev, KV, KV_SIZE).unwrap();
+        let mut store = KvStore::new(partition, 0..KV_SIZE).unwrap();
+        store.format().unwrap();
+        store.set(b"boots", &1u32.to_le_bytes()).unwrap();
+        store.set(b"name", b"bench").unwrap();
+    }
+    dev.periph.sync_all().unwrap();
+    drop(dev);
+
+    // a second run of the program
+    for boots in 2..5u32 {
+        let mut dev = FileFlash::open_or_create(&path, Chip::W25Q16)
+            .unwrap()
+            .into_device()
+            .unwrap();
+        let partition = Partition::new(&mut dev, KV, KV_SIZE).unwrap();
+        let mut store = KvStore::new(partition, 0..KV_SIZE).unwrap();
+        let mut value = [0; 8];
+        let len = store.get(b"boots", &mut value).unwrap().unwrap();
+        assert_eq!(&value[..len], &(boots - 1).to_le_bytes());
+        store.set(b"boots", &boots.to_le_bytes()).unwrap();
+        let len = store.get(b"name", &mut value).unwrap().unwrap();
+        assert_eq!(&value[..len], b"bench");
+    }
+
+    // the array matches what a SimFlash would hold
+    let data = FileFlash::open(&path).unwrap().sim().data().to_vec();
+    let mut dev = SimFlash::from_image(Chip::W25Q16, &data)
+        .unwrap()
+        .into_device()
+        .unwrap();
+    let partition = Partition::new(&mut dev, KV, KV_SIZE).unwrap();
+    let mut store = KvStore::new(partition, 0..KV_SIZE).unwrap();
+    let mut value = [0; 4];
+    store.get(b"boots", &mut value).unwrap().unwrap();
+    assert_eq!(value, 4u32.to_le_bytes());
+}
+
+#[test]
+fn bad_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("chip.w25");
+    let kind = |result: std::io::Result<FileFlash>| result.err().unwrap().kind();
+
+    assert_eq!(kind(FileFlash::open(&path)), ErrorKind::NotFound);
+    FileFlash::create(&path, Chip::W25Q16).unwrap();
+    assert_eq!(
+        kind(FileFlash::open_or_create(&path, Chip::W25Q32)),
+        ErrorKind::InvalidData
+    );
+
+    // cut short
+    let len = fs::metadata(&path).unwrap().len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(len - 1)
+        .unwrap();
+    assert_eq!(kind(FileFlash::open(&path)), ErrorKind::InvalidData);
+
+    fs::write(&path, vec![0; len as usize]).unwrap();
+    assert_eq!(kind(FileFlash::open(&path)), ErrorKind::InvalidData);
+}
+
+#[test]
+fn locked_chip_stays_locked() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("chip.w25");
+    let mut dev = FileFlash::create(&path, Chip::W25Q16)
+        .unwrap()
+        .into_device()
+        .unwrap();
+    dev.write_status_register(SR::SR3(SR3 {
+        wps: true,
+        ..SR3::default()
+    }))
+    .unwrap();
+    drop(dev);
+
+    // WPS is non-volatile and every block powers up locked
+    let mut dev = FileFlash::open(&path).unwrap().into_device().unwrap();
+    let _ = dev.program(0, b"refused");
+    assert!(dev.periph.sim().data()[..7].iter().all(|b| *b == 0xFF));
+    dev.individual_block_unlock(0).unwrap();
+    dev.program(0, b"allowed").unwrap();
+    drop(dev);
+    let dev = FileFlash::open(&path).unwrap();
+    assert_eq!(&dev.sim().data()[..7], b"allowed");
+}
@@ -0,0 +1,201 @@
+#![cfg(feature = "std")]
+
+use std::{fs, process::Command};
+
+use embedded_storage::nor_flash::ReadNorFlash;
+
+use w25q::{
+    image::ImageBuilder,
+    kv::KvStore,
+    partition::{Partition, PartitionEntry, PartitionKind, PartitionTable},
+    sim::SimFlash,
+    slots::{ImageHeader, IMAGE_HEADER_SIZE},
+    Chip,
+};
+
+const APP: u32 = 0x10000;
+const CONFIG: u32 = 0x30000;
+const CONFIG_SIZE: u32 = 0x4000;
+
+fn payload() -> Vec<u8> {
+    (0..10_000u32).map(|i| (i * 11 + i / 251) as u8).collect()
+}
+
+fn table() -> PartitionTable {
+    let mut table = PartitionTable::new();
+    for (name, kind, offset, size) in [
+        ("app", PartitionKind::Firmware, APP, 0x20000),
+        ("config", PartitionKind::Config, CONFIG, CONFIG_SIZE),
+    ] {
+        table
+            .add(PartitionEntry::new(name, kind, offset, size).unwrap())
+            .unwrap();
+    }
+    table
+}
+
+/// the image read back through the driver, as the firmware would
+fn check(image: &[u8], chip: Chip) {
+    let mut dev = SimFlash::from_image(chip, image)
+        .unwrap()
+        .into_device()
+        .unwrap();
+    let table = PartitionTable::read(&mut dev, 0).unwrap();
+    assert_eq!(table, self::table());
+
+    let mut buf = [0; IMAGE_HEADER_SIZE];
+    dev.read(APP, &mut buf).unwrap();
+    let header = ImageHeader::from_bytes(&buf).unwrap();
+    assert_eq!((header.version, header.length), (3, 10_000));
+    let mut app = vec![0; header.length as usize];
+    dev.read(APP + IMAGE_HEADER_SIZE as u32, &mut app).unwrap();
+    assert_eq!(app, payload());
+
+    let config = Partition::new(&mut dev, CONFIG, CONFIG_SIZE).unwrap();
+    let mut store = KvStore::new(config, 0..CONFIG_SIZE).unwrap();
+    let mut value = [0; 16];
+    let len = store.get(b"ssid", &mut value).unwrap().unwrap();
+    assert_eq!(&value[..len], b"home");
+    let len = store.get(b"retries", &mut value).unwrap().unwrap();
+    assert_eq!(&value[..len], &3u32.to_le_bytes());
+}
+
+#[test]
+fn builder_round_trip() {
+    let mut builder = ImageBuilder::new(Chip::W25Q16).unwrap();
+    builder.firmware(APP, 3, &payload(), None).unwrap();
+    builder
+        .kv(
+            CONFIG..CONFIG + CONFIG_SIZE,
+            &[
+                (&b"ssid"[..], &b"home"[..]),
+                (b"retries", &3u32.to_le_bytes()),
+            ],
+        )
+        .unwrap();
+    builder.partition_table(&table(), 0).unwrap();
+    let image = builder.into_data();
+    assert_eq!(image.len(), Chip::W25Q16.capacity() as usize);
+    check(&image, Chip::W25Q16);
+
+    // adding to an image keeps what it held
+    let mut builder = ImageBuilder::from_image(Chip::W25Q16, &image).unwrap();
+    builder.raw(0x50000, b"more").unwrap();
+    assert_eq!(&builder.data()[0x50000..0x50004], b"more");
+    check(builder.data(), Chip::W25Q16);
+}
+
+const MANIFEST: &str = r#"
+chip = "W25Q16"
+table = 0x0
+
+[[partition]]
+name = "app"
+kind = "firmware"
+offset = 0x10000
+size = 0x20000
+firmware = "app.bin"
+version = 3
+
+[[partition]]
+name = "config"
+kind = "config"
+offset = 0x30000
+size = 0x4000
+[partition.kv]
+ssid = "home"
+retries = 3
+"#;
+
+fn w25q_image(args: &[&std::ffi::OsStr]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_w25q-image"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn build_and_inspect() {
+    let dir = tempfile::tempdir().unwrap();
+    let manifest = dir.path().join("manifest.toml");
+    let output = dir.path().join("flash.bin");
+    fs::write(&manifest, MANIFEST).unwrap();
+    fs::write(dir.path().join("app.bin"), payload()).unwrap();
+
+    let build = w25q_image(&[
+        "build".as_ref(),
+        manifest.as_ref(),
+        "-o".as_ref(),
+        output.as_ref(),
+    ]);
+    assert!(build.status.success(), "{build:?}");
+    let image = fs::read(&output).unwrap();
+    check(&image, Chip::W25Q16);
+
+    let inspect = w25q_image(&["inspect".as_ref(), output.as_ref()]);
+    assert!(inspect.status.success(), "{inspect:?}");
+    let listing = String::from_utf8(inspect.stdout).unwrap();
+    assert!(listing.contains("app"), "{listing}");
+    assert!(listing.contains("version 3 length 10000"), "{listing}");
+    assert!(listing.contains("ok"), "{listing}");
+    assert!(listing.contains("ssid = \"home\""), "{listing}");
+
+    // two sources for one partition
+    let both = MANIFEST.replace("version = 3", "version = 3\nfile = \"app.bin\"");
+    fs::write(&manifest, both).unwrap();
+    let build = w25q_image(&[
+        "build".as_ref(),
+        manifest.as_ref(),
+        "-o".as_ref(),
+        output.as_ref(),
+    ]);
+    assert!(!build.status.success());
+    assert!(String::from_utf8_lossy(&build.stderr).contains("only one of"));
+}
+
+#[cfg(feature = "littlefs2")]
+#[test]
+fn littlefs_partition() {
+    use littlefs2::{fs::Filesystem, path::Path};
+    use w25q::littlefs::LfsStorage;
+
+    const FS: u32 = 0x40000;
+    const BLOCKS: usize = 16;
+    let size = BLOCKS as u32 * w25q::SECTOR_SIZE as u32;
+
+    let mut builder = ImageBuilder::new(Chip::W25Q16).unwrap();
+    builder
+        .littlefs(
+            FS..FS + size,
+            &[
+                ("/etc/wifi.conf", &b"ssid=home"[..]),
+                ("/top.txt", b"top"),
+                ("/etc/net/dns", b"1.1.1.1"),
+            ],
+        )
+        .unwrap();
+    // not a power of two of sectors, and a relative path
+    assert!(builder
+        .littlefs(FS..FS + 3 * w25q::SECTOR_SIZE as u32, &[("/a", b"")])
+        .is_err());
+    assert!(builder.littlefs(FS..FS + size, &[("a", b"")]).is_err());
+
+    let mut dev = SimFlash::from_image(Chip::W25Q16, builder.data())
+        .unwrap()
+        .into_device()
+        .unwrap();
+    let mut storage =
+        LfsStorage::<_, BLOCKS>::new(Partition::new(&mut dev, FS, size).unwrap()).unwrap();
+    Filesystem::mount_and_then(&mut storage, |fs| {
+        for (path, data) in [
+            ("/etc/wifi.conf\0", &b"ssid=home"[..]),
+            ("/top.txt\0", b"top"),
+            ("/etc/net/dns\0", b"1.1.1.1"),
+        ] {
+            let read = fs.read::<64>(Path::from_bytes_with_nul(path.as_bytes()).unwrap())?;
+            assert_eq!(&read[..], data);
+        }
+        Ok(())
+    })
+    .unwrap();
+}
@@ -0,0 +1,246 @@
+mod common;
+
+use common::MemFlash;
+use w25q::{
+    import::{Format, ImageImporter, UF2_BLOCK_SIZE},
+    io::W25QError,
+    SECTOR_SIZE,
+};
+
+const SIZE: usize = 8 * SECTOR_SIZE;
+const RP2040: u32 = 0xE48B_FF56;
+const RP2350: u32 = 0xE48B_FF59;
+
+fn data(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(29).wrapping_add(seed))
+        .collect()
+}
+
+/// flash with a marker in every sector, to see what the import keeps
+fn marked() -> MemFlash {
+    let mut flash = MemFlash::new(SIZE);
+    for sector in flash.data.chunks_mut(SECTOR_SIZE) {
+        sector[SECTOR_SIZE - 4..].copy_from_slice(b"keep");
+    }
+    flash
+}
+
+/// feed `file` in pieces of `piece` bytes and finish
+fn import(
+    mut importer: ImageImporter<MemFlash>,
+    file: &[u8],
+    piece: usize,
+) -> Result<MemFlash, W25QError> {
+    for chunk in file.chunks(piece) {
+        importer.write(chunk)?;
+    }
+    importer.finish()?;
+    assert!(importer.is_done());
+    Ok(importer.into_inner())
+}
+
+/// change the hex digit at `at`
+fn corrupt(file: &mut String, at: usize) {
+    let digit = if &file[at..at + 1] == "0" { "1" } else { "0" };
+    file.replace_range(at..at + 1, digit);
+}
+
+fn hex_line(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8];
+    record.extend_from_slice(&address.to_be_bytes());
+    record.push(kind);
+    record.extend_from_slice(data);
+    let sum = record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    record.push(sum.wrapping_neg());
+    let digits: String = record.iter().map(|b| format!("{b:02X}")).collect();
+    format!(":{digits}\r\n")
+}
+
+/// `data` at the 32-bit `address` as Intel HEX, 16 bytes per record
+fn hex(address: u32, data: &[u8]) -> String {
+    let mut file = String::new();
+    let mut upper = None;
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let at = address + 16 * i as u32;
+        if upper != Some(at >> 16) {
+            upper = Some(at >> 16);
+            file += &hex_line(0x04, 0, &((at >> 16) as u16).to_be_bytes());
+        }
+        file += &hex_line(0x00, at as u16, chunk);
+    }
+    file
+}
+
+fn srec_line(kind: u8, address: u32, data: &[u8]) -> String {
+    let address_len = match kind {
+        0 | 1 | 5 | 9 => 2,
+        2 | 6 | 8 => 3,
+        _ => 4,
+    };
+    let mut record = vec![(address_len + data.len() + 1) as u8];
+    record.extend_from_slice(&address.to_be_bytes()[4 - address_len..]);
+    record.extend_from_slice(data);
+    let sum = record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    record.push(!sum);
+    let digits: String = record.iter().map(|b| format!("{b:02X}")).collect();
+    format!("S{kind}{digits}\n")
+}
+
+fn uf2_block(address: u32, data: &[u8], number: u32, count: u32, family: Option<u32>) -> Vec<u8> {
+    let mut block = vec![0; UF2_BLOCK_SIZE];
+    let flags = if family.is_some() { 0x2000 } else { 0 };
+    let words = [
+        0x0A32_4655,
+        0x9E5D_5157,
+        flags,
+        address,
+        data.len() as u32,
+        number,
+        count,
+        family.unwrap_or(0),
+    ];
+    for (i, word) in words.iter().enumerate() {
+        block[4 * i..4 * i + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    block[32..32 + data.len()].copy_from_slice(data);
+    block[UF2_BLOCK_SIZE - 4..].copy_from_slice(&0x0AB1_6F30u32.to_le_bytes());
+    block
+}
+
+/// `data` at `address` as UF2 blocks of 256 bytes
+fn uf2(address: u32, data: &[u8], family: Option<u32>) -> Vec<u8> {
+    let count = data.len().div_ceil(256) as u32;
+    data.chunks(256)
+        .enumerate()
+        .flat_map(|(i, chunk)| uf2_block(address + 256 * i as u32, chunk, i as u32, count, family))
+        .collect()
+}
+
+#[test]
+fn intel_hex() {
+    // across a 64KiB boundary of the file, and across sectors of the flash
+    let base = 0x0800_0000 + 0x1_0000 - 0x2000;
+    let image = data(3 * SECTOR_SIZE, 1);
+    let mut file = hex(base + 0x800, &image);
+    file += &hex_line(0x05, 0, &0x0800_0101u32.to_be_bytes());
+    file += ":00000001FF\n";
+
+    let importer = ImageImporter::new(marked(), Format::IntelHex, base).unwrap();
+    let flash = import(importer, file.as_bytes(), 37).unwrap();
+    assert_eq!(&flash.data[0x800..0x800 + image.len()], &image[..]);
+    assert!(flash.data[..0x800].iter().all(|b| *b == 0xFF));
+    assert_eq!(&flash.data[4 * SECTOR_SIZE - 4..4 * SECTOR_SIZE], b"keep");
+
+    // extended segment addresses
+    let mut file = hex_line(0x02, 0, &0x0100u16.to_be_bytes());
+    file += &hex_line(0x00, 0x10, b"segment");
+    file += ":00000001FF";
+    let importer = ImageImporter::new(marked(), Format::IntelHex, 0x1000).unwrap();
+    let flash = import(importer, file.as_bytes(), 5).unwrap();
+    assert_eq!(&flash.data[0x10..0x17], b"segment");
+}
+
+#[test]
+fn intel_hex_errors() {
+    let good = hex(0, &data(64, 2));
+    let mut file = good.clone();
+    // a wrong checksum in the third record
+    let third = file.match_indices(':').nth(2).unwrap().0;
+    corrupt(&mut file, third + 9);
+    let mut importer = ImageImporter::new(marked(), Format::IntelHex, 0).unwrap();
+    assert!(matches!(
+        importer.write(file.as_bytes()),
+        Err(W25QError::BadRecord { record: 3 })
+    ));
+
+    // no end of file record
+    let mut importer = ImageImporter::new(marked(), Format::IntelHex, 0).unwrap();
+    importer.write(good.as_bytes()).unwrap();
+    assert!(matches!(
+        importer.finish(),
+        Err(W25QError::BadRecord { .. })
+    ));
+
+    // data below the base
+    let mut importer = ImageImporter::new(marked(), Format::IntelHex, 0x100).unwrap();
+    assert!(matches!(
+        importer.write(good.as_bytes()),
+        Err(W25QError::OutOfBounds)
+    ));
+}
+
+#[test]
+fn srec() {
+    let image = data(1000, 3);
+    let mut file = srec_line(0, 0, b"header");
+    for (i, chunk) in image.chunks(32).enumerate() {
+        let kind = [1, 2, 3][i % 3];
+        file += &srec_line(kind, 0x100 + 32 * i as u32, chunk);
+    }
+    file += &srec_line(5, image.len().div_ceil(32) as u32, &[]);
+    file += &srec_line(9, 0, &[]);
+
+    let importer = ImageImporter::new(marked(), Format::Srec, 0).unwrap();
+    let flash = import(importer, file.as_bytes(), 64).unwrap();
+    assert_eq!(&flash.data[0x100..0x100 + image.len()], &image[..]);
+
+    // a wrong checksum in the second record
+    let second = file.find('\n').unwrap() + 1;
+    corrupt(&mut file, second + 8);
+    let mut importer = ImageImporter::new(marked(), Format::Srec, 0).unwrap();
+    assert!(matches!(
+        importer.write(file.as_bytes()),
+        Err(W25QError::BadRecord { record: 2 })
+    ));
+}
+
+#[test]
+fn uf2_single_family() {
+    let image = data(3000, 4);
+    let file = uf2(0x1000_0000 + 0x300, &image, Some(RP2040));
+    let mut importer = ImageImporter::new(marked(), Format::Uf2, 0x1000_0000).unwrap();
+    importer.set_uf2_family(Some(RP2040));
+    let flash = import(importer, &file, 333).unwrap();
+    assert_eq!(&flash.data[0x300..0x300 + image.len()], &image[..]);
+    assert_eq!(&flash.data[SECTOR_SIZE - 4..SECTOR_SIZE], b"keep");
+
+    // blocks without a family, and a block too many
+    let mut file = uf2(0, &image, None);
+    file.extend_from_within(..UF2_BLOCK_SIZE);
+    let mut importer = ImageImporter::new(marked(), Format::Uf2, 0).unwrap();
+    assert!(matches!(
+        importer.write(&file),
+        Err(W25QError::BadRecord { record: 13 })
+    ));
+}
+
+#[test]
+fn uf2_several_families() {
+    let first = data(1500, 5);
+    let second = data(2200, 6);
+    let mut file = uf2(0x100, &first, Some(RP2040));
+    file.extend(uf2(0x2000, &second, Some(RP2350)));
+
+    // the second image, numbered on its own
+    let mut importer = ImageImporter::new(marked(), Format::Uf2, 0).unwrap();
+    importer.set_uf2_family(Some(RP2350));
+    let flash = import(importer, &file, 1000).unwrap();
+    assert_eq!(&flash.data[0x2000..0x2000 + second.len()], &second[..]);
+    assert!(flash.data[..SECTOR_SIZE - 4].iter().all(|b| *b == 0xFF));
+
+    // without a family, the first one
+    let importer = ImageImporter::new(marked(), Format::Uf2, 0).unwrap();
+    let flash = import(importer, &file, 512).unwrap();
+    assert_eq!(&flash.data[0x100..0x100 + first.len()], &first[..]);
+    assert!(flash.data[0x2000..0x2000 + second.len()]
+        .iter()
+        .all(|b| *b == 0xFF));
+
+    // a family that is not in the file
+    let mut importer = ImageImporter::new(marked(), Format::Uf2, 0).unwrap();
+    importer.set_uf2_family(Some(0x1234_5678));
+    importer.write(&file).unwrap();
+    assert!(!importer.is_done());
+    assert!(importer.finish().is_err());
+}
@@ -0,0 +1,137 @@
+#![cfg(feature = "std")]
+
+use std::{cell::Cell, convert::Infallible, rc::Rc};
+
+use embedded_hal::spi::{ErrorType, Operation, SpiDevice};
+use embedded_io::{BufRead, ErrorKind, Read, Seek, SeekFrom};
+use w25q::{
+    io::{BufferedReader, W25QError},
+    sim::{NoDelay, SimFlash},
+    Chip, W25QConfig, W25Q,
+};
+
+/// a [`SimFlash`] counting its transactions
+struct Counted {
+    sim: SimFlash,
+    transactions: Rc<Cell<usize>>,
+}
+
+impl ErrorType for Counted {
+    type Error = Infallible;
+}
+
+impl SpiDevice for Counted {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+        self.transactions.set(self.transactions.get() + 1);
+        self.sim.transaction(operations)
+    }
+}
+
+fn data(len: usize) -> Vec<u8> {
+    (0..len as u32).map(|i| (i * 13 + i / 251) as u8).collect()
+}
+
+fn counted(image: &[u8]) -> (W25Q<Counted, NoDelay>, Rc<Cell<usize>>) {
+    let transactions = Rc::new(Cell::new(0));
+    let sim = Counted {
+        sim: SimFlash::from_image(Chip::W25Q16, image).unwrap(),
+        transactions: transactions.clone(),
+    };
+    let config = W25QConfig::new().chip(Chip::W25Q16);
+    (W25Q::new(sim, NoDelay, config).unwrap(), transactions)
+}
+
+#[test]
+fn buffered_reader_keeps_its_buffer_across_seeks() {
+    let image = data(4096);
+    let (mut dev, transactions) = counted(&image);
+    let capacity = dev.capacity();
+    dev.seek(SeekFrom::Start(100)).unwrap();
+    let mut reader = BufferedReader::<_, _, 64>::new(&mut dev);
+    assert_eq!(reader.position(), 100);
+    let reads = || transactions.get();
+
+    let start = reads();
+    let mut buf = [0; 10];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, image[100..110]);
+    assert_eq!(reads(), start + 1);
+
+    // seeks inside the 64 buffered bytes read nothing
+    assert_eq!(reader.seek(SeekFrom::Current(20)).unwrap(), 130);
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, image[130..140]);
+    assert_eq!(reader.seek(SeekFrom::Start(100)).unwrap(), 100);
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, image[100..110]);
+    assert_eq!(reader.seek(SeekFrom::Current(53)).unwrap(), 163);
+    assert_eq!(reader.fill_buf().unwrap(), &image[163..164]);
+    assert_eq!(reads(), start + 1);
+
+    // leaving the buffer refills it from the new position
+    assert_eq!(reader.seek(SeekFrom::Current(1)).unwrap(), 164);
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, image[164..174]);
+    assert_eq!(reads(), start + 2);
+    reader.seek(SeekFrom::Start(1000)).unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, image[1000..1010]);
+    assert_eq!(reads(), start + 3);
+
+    // long reads go around the buffer
+    let mut long = [0; 200];
+    reader.seek(SeekFrom::Start(2000)).unwrap();
+    reader.read_exact(&mut long).unwrap();
+    assert_eq!(long, image[2000..2200]);
+    assert_eq!(reads(), start + 4);
+
+    // invalidating drops the buffer, the position stays
+    reader.read_exact(&mut buf[..5]).unwrap();
+    assert_eq!(buf[..5], image[2200..2205]);
+    assert_eq!(reads(), start + 5);
+    reader.invalidate();
+    reader.read_exact(&mut buf[..5]).unwrap();
+    assert_eq!(buf[..5], image[2205..2210]);
+    assert_eq!(reads(), start + 6);
+
+    // the end of the chip
+    assert_eq!(reader.seek(SeekFrom::End(-4)).unwrap(), capacity - 4);
+    assert_eq!(reader.read(&mut buf).unwrap(), 4);
+    assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    let invalid = |result: Result<u64, W25QError>| {
+        matches!(result, Err(W25QError::Io(ErrorKind::InvalidInput)))
+    };
+    assert!(invalid(reader.seek(SeekFrom::End(1))));
+    assert!(invalid(reader.seek(SeekFrom::Start(u64::MAX))));
+    assert!(invalid(reader.seek(SeekFrom::Current(i64::MAX))));
+    assert!(invalid(reader.seek(SeekFrom::End(i64::MIN))));
+    assert_eq!(reader.position(), capacity);
+}
+
+#[test]
+fn device_seek_drops_its_buffer() {
+    let image = data(4096);
+    let mut dev = SimFlash::from_image(Chip::W25Q16, &image)
+        .unwrap()
+        .into_device()
+        .unwrap();
+    dev.seek(SeekFrom::Start(0x10)).unwrap();
+    assert_eq!(dev.fill_buf().unwrap()[..4], image[0x10..0x14]);
+
+    // changed behind the driver's back, the buffered page is stale until a seek
+    dev.periph.data_mut()[0x10..0x14].copy_from_slice(b"new!");
+    assert_eq!(dev.fill_buf().unwrap()[..4], image[0x10..0x14]);
+    dev.seek(SeekFrom::Current(0)).unwrap();
+    assert_eq!(&dev.fill_buf().unwrap()[..4], b"new!");
+    dev.consume(2);
+    assert_eq!(dev.stream_position().unwrap(), 0x12);
+
+    let capacity = dev.capacity();
+    assert_eq!(dev.seek(SeekFrom::End(-1)).unwrap(), capacity - 1);
+    assert_eq!(dev.seek(SeekFrom::End(-(capacity as i64))).unwrap(), 0);
+    assert!(dev.seek(SeekFrom::End(-(capacity as i64) - 1)).is_err());
+    assert!(dev.seek(SeekFrom::End(i64::MIN)).is_err());
+    assert!(dev.seek(SeekFrom::Current(i64::MAX)).is_err());
+    assert!(dev.seek(SeekFrom::Current(-1)).is_err());
+    assert_eq!(dev.stream_position().unwrap(), 0);
+}
@@ -0,0 +1,148 @@
+mod common;
+
+use std::collections::BTreeMap;
+
+use common::MemFlash;
+use w25q::{io::W25QError, kv::KvStore, SECTOR_SIZE};
+
+const SIZE: u32 = 3 * SECTOR_SIZE as u32;
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// the `i`th step of a workload over a few keys: a set, or a delete for `None`
+fn step(i: usize) -> (Vec<u8>, Option<Vec<u8>>) {
+    let key = format!("key{}", i * 7 % 13).into_bytes();
+    if i % 5 == 4 {
+        (key, None)
+    } else {
+        (key, Some(vec![i as u8; i * 31 % 300]))
+    }
+}
+
+fn apply(kv: &mut KvStore<MemFlash>, key: &[u8], value: Option<&[u8]>) -> Result<(), W25QError> {
+    match value {
+        Some(value) => kv.set(key, value),
+        None => kv.delete(key).map(|_| ()),
+    }
+}
+
+fn update(model: &mut Model, key: &[u8], value: Option<&[u8]>) {
+    match value {
+        Some(value) => model.insert(key.to_vec(), value.to_vec()),
+        None => model.remove(key),
+    };
+}
+
+/// flash holding an empty store
+fn formatted() -> MemFlash {
+    KvStore::new(MemFlash::new(SIZE as usize), 0..SIZE)
+        .unwrap()
+        .into_inner()
+}
+
+fn contents(kv: &mut KvStore<MemFlash>) -> Model {
+    let mut seen = Model::new();
+    kv.for_each(&mut [0; 512], |key, value| {
+        assert!(seen.insert(key.to_vec(), value.to_vec()).is_none());
+    })
+    .unwrap();
+    for (key, value) in &seen {
+        let mut buf = [0; 512];
+        let len = kv.get(key, &mut buf).unwrap().unwrap();
+        assert_eq!(&buf[..len], value);
+    }
+    seen
+}
+
+#[test]
+fn power_cut_keeps_the_old_or_the_new_value() {
+    for cut in (0..3000).step_by(11) {
+        let mut flash = formatted();
+        flash.cut_after(cut);
+        let mut kv = KvStore::new(flash, 0..SIZE).unwrap();
+        let mut model = Model::new();
+        let mut inflight = None;
+        for i in 0..400 {
+            let (key, value) = step(i);
+            if apply(&mut kv, &key, value.as_deref()).is_err() {
+                inflight = Some((key, value));
+                break;
+            }
+            update(&mut model, &key, value.as_deref());
+        }
+
+        let mut flash = kv.into_inner();
+        assert_eq!(flash.lost_power(), inflight.is_some());
+        flash.power_cycle();
+        let mut kv = KvStore::new(flash, 0..SIZE).unwrap();
+        let found = contents(&mut kv);
+        if let Some((key, value)) = inflight {
+            if found.get(&key) == value.as_ref() {
+                update(&mut model, &key, value.as_deref());
+            }
+        }
+        assert_eq!(found, model, "cut at {cut}");
+
+        // the store keeps working after the cut
+        for i in 400..450 {
+            let (key, value) = step(i);
+            apply(&mut kv, &key, value.as_deref()).unwrap();
+            update(&mut model, &key, value.as_deref());
+        }
+        assert_eq!(contents(&mut kv), model, "cut at {cut}");
+    }
+}
+
+#[test]
+fn failed_write_can_be_retried() {
+    for fail in (0..600).step_by(3) {
+        let mut flash = formatted();
+        flash.fail_after(fail);
+        let mut kv = KvStore::new(flash, 0..SIZE).unwrap();
+        let mut model = Model::new();
+        for i in 0..200 {
+            let (key, value) = step(i);
+            if apply(&mut kv, &key, value.as_deref()).is_err() {
+                apply(&mut kv, &key, value.as_deref()).unwrap();
+            }
+            update(&mut model, &key, value.as_deref());
+        }
+        assert_eq!(contents(&mut kv), model, "failure at {fail}");
+        let mut kv = KvStore::new(kv.into_inner(), 0..SIZE).unwrap();
+        assert_eq!(contents(&mut kv), model, "failure at {fail}");
+    }
+}
+
+#[test]
+fn no_space_while_collecting() {
+    let mut kv = KvStore::new(MemFlash::new(SIZE as usize), 0..SIZE).unwrap();
+    let mut model = Model::new();
+    let value = [0x5A; 300];
+    let mut full = None;
+    for i in 0..100 {
+        let key = format!("key{i}").into_bytes();
+        match kv.set(&key, &value) {
+            Ok(()) => update(&mut model, &key, Some(&value)),
+            Err(W25QError::NoSpace) => {
+                full = Some(key);
+                break;
+            }
+            Err(e) => panic!("{e:?}"),
+        }
+    }
+    let key = full.expect("the store never filled up");
+    assert_eq!(contents(&mut kv), model);
+    assert!(matches!(kv.set(&key, &value), Err(W25QError::NoSpace)));
+
+    // freeing space lets the same write through
+    for old in model.keys().take(3).cloned().collect::<Vec<_>>() {
+        assert!(kv.delete(&old).unwrap());
+        model.remove(&old);
+    }
+    kv.set(&key, &value).unwrap();
+    update(&mut model, &key, Some(&value));
+    assert_eq!(contents(&mut kv), model);
+
+    let mut kv = KvStore::new(kv.into_inner(), 0..SIZE).unwrap();
+    assert_eq!(contents(&mut kv), model);
+}
@@ -0,0 +1,60 @@
+#![cfg(all(feature = "std", feature = "littlefs2"))]
+
+use littlefs2::{fs::Filesystem, path, path::Path};
+use w25q::{
+    io::W25QError, littlefs::LfsStorage, partition::Partition, sim::SimFlash, Chip, SECTOR_SIZE,
+};
+
+const OFFSET: u32 = 0x10000;
+const BLOCKS: usize = 64;
+const SIZE: u32 = (BLOCKS * SECTOR_SIZE) as u32;
+
+#[test]
+fn format_mount_and_read_back() {
+    let mut dev = SimFlash::new(Chip::W25Q64).into_device().unwrap();
+    dev.program(OFFSET - 4, b"keep").unwrap();
+    dev.program(OFFSET + SIZE, b"keep").unwrap();
+    let big: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+
+    let partition = Partition::new(&mut dev, OFFSET, SIZE).unwrap();
+    let mut storage = LfsStorage::<_, BLOCKS>::new(partition).unwrap();
+    Filesystem::format(&mut storage).unwrap();
+    Filesystem::mount_and_then(&mut storage, |fs| {
+        fs.write(path!("/hello.txt"), b"hello")?;
+        fs.create_dir(path!("/logs"))?;
+        fs.write(path!("/logs/big.bin"), &big)?;
+        fs.write(path!("/hello.txt"), b"hello again")
+    })
+    .unwrap();
+
+    // a new mount reads the files back
+    let partition = Partition::new(&mut dev, OFFSET, SIZE).unwrap();
+    let mut storage = LfsStorage::<_, BLOCKS>::new(partition).unwrap();
+    Filesystem::mount_and_then(&mut storage, |fs| {
+        assert_eq!(&fs.read::<16>(path!("/hello.txt"))?[..], b"hello again");
+        assert_eq!(&fs.read::<10_000>(path!("/logs/big.bin"))?[..], &big[..]);
+        fs.remove(path!("/logs/big.bin"))?;
+        assert!(fs.read::<16>(path!("/logs/big.bin")).is_err());
+        Ok(())
+    })
+    .unwrap();
+
+    // nothing outside the partition was touched
+    let data = dev.periph.data();
+    assert_eq!(&data[OFFSET as usize - 4..OFFSET as usize], b"keep");
+    assert_eq!(&data[(OFFSET + SIZE) as usize..][..4], b"keep");
+}
+
+#[test]
+fn block_count_must_fit_the_flash() {
+    let mut dev = SimFlash::new(Chip::W25Q16).into_device().unwrap();
+    // one sector short
+    let partition =
+        Partition::new(&mut dev, OFFSET, (BLOCKS - 1) as u32 * SECTOR_SIZE as u32).unwrap();
+    assert!(matches!(
+        LfsStorage::<_, BLOCKS>::new(partition),
+        Err(W25QError::InvalidConfig)
+    ));
+    let partition = Partition::new(&mut dev, OFFSET, SIZE).unwrap();
+    assert!(LfsStorage::<_, BLOCKS>::new(partition).is_ok());
+}
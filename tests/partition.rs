@@ -0,0 +1,86 @@
+use w25q::{
+    checksum::Crc32,
+    io::W25QError,
+    partition::{PartitionEntry, PartitionKind, PartitionTable, PARTITION_TABLE_SIZE},
+};
+
+#[test]
+fn overflowing_entries_are_refused() {
+    assert!(matches!(
+        PartitionEntry::new("big", PartitionKind::Data, 0xFFFF_0000, 0x1_0000),
+        Err(W25QError::OutOfBounds)
+    ));
+    let last = PartitionEntry::new("last", PartitionKind::Data, 0xFFFF_0000, 0xFFFF).unwrap();
+    assert_eq!(last.range().unwrap(), 0xFFFF_0000..0xFFFF_FFFF);
+
+    // an entry changed after it was made
+    let mut entry = PartitionEntry::new("app", PartitionKind::Firmware, 0x1000, 0x1000).unwrap();
+    entry.size = u32::MAX;
+    assert!(matches!(entry.range(), Err(W25QError::OutOfBounds)));
+    let mut table = PartitionTable::new();
+    table.add(last).unwrap();
+    assert!(matches!(table.add(entry), Err(W25QError::OutOfBounds)));
+    assert_eq!(table.entries(), &[last]);
+}
+
+#[test]
+fn overflowing_entry_on_flash_is_corrupt() {
+    let mut table = PartitionTable::new();
+    table
+        .add(PartitionEntry::new("app", PartitionKind::Firmware, 0x1000, 0x1000).unwrap())
+        .unwrap();
+    let mut buf = table.to_bytes();
+    assert_eq!(PartitionTable::from_bytes(&buf).unwrap(), table);
+
+    // size field of the entry, behind its name, with the table CRC made to match
+    let name = buf.windows(3).position(|w| w == b"app").unwrap();
+    buf[name + 24..name + 28].copy_from_slice(&u32::MAX.to_le_bytes());
+    let crc = Crc32::checksum(&buf[..PARTITION_TABLE_SIZE - 4]);
+    buf[PARTITION_TABLE_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+    assert!(matches!(
+        PartitionTable::from_bytes(&buf),
+        Err(W25QError::Corrupt)
+    ));
+}
+
+/// `table` encoded, with the entry named `name` moved to `offset` and the CRC made to match
+fn moved(table: &PartitionTable, name: &[u8], offset: u32) -> [u8; PARTITION_TABLE_SIZE] {
+    let mut buf = table.to_bytes();
+    let at = buf.windows(name.len()).position(|w| w == name).unwrap();
+    buf[at + 20..at + 24].copy_from_slice(&offset.to_le_bytes());
+    let crc = Crc32::checksum(&buf[..PARTITION_TABLE_SIZE - 4]);
+    buf[PARTITION_TABLE_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+#[test]
+fn overlapping_entries_are_refused() {
+    let mut table = PartitionTable::new();
+    for (name, offset) in [("app", 0x1000), ("data", 0x3000)] {
+        table
+            .add(PartitionEntry::new(name, PartitionKind::Data, offset, 0x1000).unwrap())
+            .unwrap();
+    }
+    for offset in [0x1800, 0x0800, 0x1000] {
+        assert!(matches!(
+            table.add(PartitionEntry::new("other", PartitionKind::Data, offset, 0x1000).unwrap()),
+            Err(W25QError::Io(_))
+        ));
+    }
+    // touching is fine
+    let mut touching = table.clone();
+    touching
+        .add(PartitionEntry::new("gap", PartitionKind::Data, 0x2000, 0x1000).unwrap())
+        .unwrap();
+    assert_eq!(touching.entries().len(), 3);
+
+    // a table on flash whose entries overlap is corrupt
+    let read = PartitionTable::from_bytes(&moved(&table, b"data", 0x2000)).unwrap();
+    assert_eq!(read.find("data").unwrap().offset, 0x2000);
+    for offset in [0x1000, 0x1FFF, 0x0001] {
+        assert!(matches!(
+            PartitionTable::from_bytes(&moved(&table, b"data", offset)),
+            Err(W25QError::Corrupt)
+        ));
+    }
+}
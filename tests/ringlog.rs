@@ -0,0 +1,149 @@
+mod common;
+
+use common::MemFlash;
+use w25q::{ringlog::RingLog, SECTOR_SIZE};
+
+const SECTORS: u32 = 4;
+const SIZE: u32 = SECTORS * SECTOR_SIZE as u32;
+
+/// the `i`th record: its number followed by a varying amount of filler
+fn record(i: u32) -> Vec<u8> {
+    let mut data = i.to_le_bytes().to_vec();
+    data.resize(4 + (i as usize * 37) % 700, i as u8);
+    data
+}
+
+fn open(flash: MemFlash) -> RingLog<MemFlash> {
+    RingLog::new(flash, 0..SIZE).unwrap()
+}
+
+/// numbers of the records in the log, oldest first, checking that reading backwards agrees
+fn numbers(log: &mut RingLog<MemFlash>) -> Vec<u32> {
+    let mut buf = [0; SECTOR_SIZE];
+    let mut forward = Vec::new();
+    let mut records = log.oldest_first();
+    while let Some(len) = records.next(&mut buf).unwrap() {
+        let n = u32::from_le_bytes(buf[..4].try_into().unwrap());
+        assert_eq!(&buf[..len], &record(n)[..]);
+        forward.push(n);
+    }
+    let mut backward = Vec::new();
+    let mut records = log.newest_first();
+    while let Some(len) = records.next(&mut buf).unwrap() {
+        backward.push(u32::from_le_bytes(buf[..4].try_into().unwrap()));
+        assert_eq!(&buf[..len], &record(*backward.last().unwrap())[..]);
+    }
+    backward.reverse();
+    assert_eq!(forward, backward);
+    forward
+}
+
+/// the log holds the newest records up to `last`, without gaps
+fn assert_tail(found: &[u32], last: Option<u32>) {
+    let Some(last) = last else {
+        return assert!(found.is_empty(), "{found:?}");
+    };
+    assert_eq!(found.last(), Some(&last), "{found:?}");
+    let first = found[0];
+    assert_eq!(found, &(first..=last).collect::<Vec<_>>()[..]);
+}
+
+#[test]
+fn wraps_around() {
+    let mut log = open(MemFlash::new(SIZE as usize));
+    assert!(log.is_empty().unwrap());
+    // several times around the ring
+    for i in 0..200 {
+        log.append(&record(i)).unwrap();
+    }
+    let found = numbers(&mut log);
+    assert_tail(&found, Some(199));
+    assert!(found[0] > 100, "{found:?}");
+
+    let mut log = open(log.into_inner());
+    assert_eq!(numbers(&mut log), found);
+    log.append(&record(200)).unwrap();
+    assert_tail(&numbers(&mut log), Some(200));
+
+    log.clear().unwrap();
+    assert!(log.is_empty().unwrap());
+    assert!(numbers(&mut log).is_empty());
+}
+
+#[test]
+fn finds_the_head_in_every_sector() {
+    const LEN: usize = 1000;
+    // four records fill a sector, so record `i` is the `i % 4`th of sector `i / 4 % SECTORS`
+    let big = |i: u32| {
+        let mut data = i.to_le_bytes().to_vec();
+        data.resize(LEN, i as u8);
+        data
+    };
+    let mut flash = MemFlash::new(SIZE as usize);
+    // three times around the ring, reopening with the head at every sector and fill level
+    for i in 0..3 * 4 * SECTORS {
+        let mut log = open(flash);
+        log.append(&big(i)).unwrap();
+        flash = log.into_inner();
+        let at = (i / 4 % SECTORS) as usize * SECTOR_SIZE + 16 + (i % 4) as usize * (LEN + 12);
+        assert_eq!(&flash.data[at + 8..at + 8 + LEN], &big(i)[..]);
+
+        let mut log = open(flash);
+        let mut buf = [0; LEN];
+        let mut newest = log.newest_first();
+        // the oldest sector is erased only when the head moves into it
+        for n in (0..=i).rev().take(4 * (SECTORS as usize - 1) + 1) {
+            assert_eq!(newest.next(&mut buf).unwrap(), Some(LEN), "record {i}");
+            assert_eq!(buf, big(n)[..], "record {i}");
+        }
+        flash = log.into_inner();
+    }
+}
+
+#[test]
+fn power_cut_tears_the_last_record() {
+    let started = || open(MemFlash::new(SIZE as usize)).into_inner();
+    let ops = {
+        let flash = started();
+        let start = flash.ops;
+        let mut log = open(flash);
+        for i in 0..60 {
+            log.append(&record(i)).unwrap();
+        }
+        log.into_inner().ops - start
+    };
+
+    for cut in 0..ops {
+        let mut flash = started();
+        flash.cut_after(cut);
+        let mut log = open(flash);
+        let mut last = None;
+        let mut inflight = None;
+        for i in 0..60 {
+            if log.append(&record(i)).is_err() {
+                inflight = Some(i);
+                break;
+            }
+            last = Some(i);
+        }
+        let mut flash = log.into_inner();
+        assert_eq!(flash.lost_power(), inflight.is_some());
+        flash.power_cycle();
+
+        let mut log = open(flash);
+        let found = numbers(&mut log);
+        // the record being written is either complete or gone
+        if found.last().is_some() && found.last() == inflight.as_ref() {
+            last = inflight;
+        }
+        assert_tail(&found, last);
+
+        // appending goes on behind the torn record
+        let next = last.map_or(0, |n| n + 1);
+        for i in next..next + 10 {
+            log.append(&record(i)).unwrap();
+        }
+        let mut log = open(log.into_inner());
+        assert_tail(&numbers(&mut log), Some(next + 9));
+    }
+}
@@ -0,0 +1,209 @@
+mod common;
+
+use common::MemFlash;
+use w25q::{
+    block::{FlashBlocks, BLOCK_SIZE},
+    scsi::{Phase, ScsiHandler, Sense, Status},
+};
+
+const BLOCKS: u32 = 128;
+
+fn handler() -> ScsiHandler<MemFlash> {
+    let flash = MemFlash::new(BLOCKS as usize * BLOCK_SIZE);
+    ScsiHandler::new(FlashBlocks::new(flash).unwrap())
+}
+
+fn transfer(opcode: u8, lba: u32, blocks: u16) -> [u8; 10] {
+    let mut cb = [0; 10];
+    cb[0] = opcode;
+    cb[2..6].copy_from_slice(&lba.to_be_bytes());
+    cb[7..9].copy_from_slice(&blocks.to_be_bytes());
+    cb
+}
+
+/// run the data-in stage in packets of `packet` bytes
+fn data_in(scsi: &mut ScsiHandler<MemFlash>, packet: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut buf = vec![0; packet];
+    loop {
+        let n = scsi.data_in(&mut buf);
+        if n == 0 {
+            return data;
+        }
+        data.extend_from_slice(&buf[..n]);
+    }
+}
+
+fn data_out(scsi: &mut ScsiHandler<MemFlash>, data: &[u8], packet: usize) {
+    for chunk in data.chunks(packet) {
+        assert_eq!(scsi.data_out(chunk), chunk.len());
+    }
+}
+
+fn sense(scsi: &mut ScsiHandler<MemFlash>) -> Sense {
+    assert_eq!(scsi.command(&[0x03, 0, 0, 0, 18, 0]), Phase::DataIn(18));
+    let data = data_in(scsi, 64);
+    assert_eq!(data[0], 0x70);
+    Sense::new(data[2], data[12], data[13])
+}
+
+#[test]
+fn inquiry() {
+    let mut scsi = handler();
+    scsi.set_inquiry("Acme", "Flash Disk", "1.0");
+    assert_eq!(scsi.command(&[0x12, 0, 0, 0, 36, 0]), Phase::DataIn(36));
+    let data = data_in(&mut scsi, 64);
+    assert_eq!(scsi.status(), Status::Passed);
+    assert_eq!(data.len(), 36);
+    assert_eq!(&data[..2], &[0x00, 0x80]);
+    assert_eq!(&data[8..16], b"Acme    ");
+    assert_eq!(&data[16..32], b"Flash Disk      ");
+    assert_eq!(&data[32..36], b"1.0 ");
+
+    // the host may ask for less
+    assert_eq!(scsi.command(&[0x12, 0, 0, 0, 5, 0]), Phase::DataIn(5));
+    assert_eq!(data_in(&mut scsi, 64), &data[..5]);
+}
+
+#[test]
+fn read_capacity() {
+    let mut scsi = handler();
+    assert_eq!(
+        scsi.command(&[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+        Phase::DataIn(8)
+    );
+    let data = data_in(&mut scsi, 3);
+    assert_eq!(&data[..4], &(BLOCKS - 1).to_be_bytes());
+    assert_eq!(&data[4..], &(BLOCK_SIZE as u32).to_be_bytes());
+}
+
+#[test]
+fn write_and_read_in_odd_packets() {
+    let mut scsi = handler();
+    let data: Vec<u8> = (0..3 * BLOCK_SIZE as u32)
+        .map(|i| (i * 7 + i / 512) as u8)
+        .collect();
+
+    let phase = scsi.command(&transfer(0x2A, 9, 3));
+    assert_eq!(phase, Phase::DataOut(data.len() as u32));
+    data_out(&mut scsi, &data, 61);
+    assert_eq!(scsi.status(), Status::Passed);
+
+    assert_eq!(
+        scsi.command(&transfer(0x28, 9, 3)),
+        Phase::DataIn(data.len() as u32)
+    );
+    assert_eq!(data_in(&mut scsi, 13), data);
+    assert_eq!(scsi.status(), Status::Passed);
+
+    // a read straddling the written blocks
+    assert_eq!(scsi.command(&transfer(0x28, 8, 2)), Phase::DataIn(1024));
+    let read = data_in(&mut scsi, 100);
+    assert!(read[..BLOCK_SIZE].iter().all(|&b| b == 0xFF));
+    assert_eq!(&read[BLOCK_SIZE..], &data[..BLOCK_SIZE]);
+
+    // nothing is left in the cache, SYNCHRONIZE CACHE has nothing to do
+    assert_eq!(
+        scsi.command(&[0x35, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+        Phase::NoData
+    );
+    assert_eq!(scsi.status(), Status::Passed);
+    let flash = scsi.into_inner().into_inner();
+    assert_eq!(&flash.data[9 * BLOCK_SIZE..12 * BLOCK_SIZE], &data[..]);
+}
+
+#[test]
+fn out_of_range_then_request_sense() {
+    let mut scsi = handler();
+    assert_eq!(scsi.command(&transfer(0x28, BLOCKS - 1, 2)), Phase::NoData);
+    assert_eq!(scsi.status(), Status::Failed);
+    assert_eq!(scsi.command(&transfer(0x2A, BLOCKS, 1)), Phase::NoData);
+    assert_eq!(scsi.status(), Status::Failed);
+    assert_eq!(sense(&mut scsi), Sense::LBA_OUT_OF_RANGE);
+    assert_eq!(scsi.status(), Status::Passed);
+    assert_eq!(sense(&mut scsi), Sense::NO_SENSE);
+
+    // the last block is in range
+    assert_eq!(
+        scsi.command(&transfer(0x28, BLOCKS - 1, 1)),
+        Phase::DataIn(512)
+    );
+    assert_eq!(data_in(&mut scsi, 512).len(), 512);
+    assert_eq!(sense(&mut scsi), Sense::NO_SENSE);
+}
+
+#[test]
+fn short_command_blocks() {
+    let mut scsi = handler();
+    assert_eq!(scsi.command(&[0xFF, 0, 0]), Phase::NoData);
+    assert_eq!(scsi.status(), Status::Failed);
+    assert_eq!(sense(&mut scsi), Sense::INVALID_COMMAND);
+
+    assert_eq!(scsi.command(&[0x28, 0, 0, 0, 0, 0]), Phase::NoData);
+    assert_eq!(scsi.status(), Status::Failed);
+    assert_eq!(sense(&mut scsi), Sense::INVALID_FIELD_IN_CDB);
+
+    assert_eq!(scsi.command(&[]), Phase::NoData);
+    assert_eq!(sense(&mut scsi), Sense::INVALID_COMMAND);
+}
+
+#[test]
+fn sense_is_cleared_by_the_next_command() {
+    let mut scsi = handler();
+    scsi.command(&[0xFF; 10]);
+    assert_eq!(scsi.status(), Status::Failed);
+    assert_eq!(scsi.command(&[0x00, 0, 0, 0, 0, 0]), Phase::NoData);
+    assert_eq!(scsi.status(), Status::Passed);
+    assert_eq!(sense(&mut scsi), Sense::NO_SENSE);
+}
+
+fn block(seed: u8) -> Vec<u8> {
+    (0..BLOCK_SIZE)
+        .map(|i| (i as u8).wrapping_mul(seed))
+        .collect()
+}
+
+#[test]
+fn write_ends_on_the_flash() {
+    let mut scsi = handler();
+    let data = block(3);
+    assert_eq!(scsi.command(&transfer(0x2A, 5, 1)), Phase::DataOut(512));
+    data_out(&mut scsi, &data, 64);
+    assert_eq!(scsi.status(), Status::Passed);
+    // no SYNCHRONIZE CACHE, the host may be unplugged right after the CSW
+    let flash = scsi.into_inner().into_inner();
+    assert_eq!(&flash.data[5 * BLOCK_SIZE..6 * BLOCK_SIZE], &data[..]);
+
+    // a failed flush fails the write
+    let mut flash = MemFlash::new(BLOCKS as usize * BLOCK_SIZE);
+    flash.fail_after(0);
+    let mut scsi = ScsiHandler::new(FlashBlocks::new(flash).unwrap());
+    assert_eq!(scsi.command(&transfer(0x2A, 5, 1)), Phase::DataOut(512));
+    data_out(&mut scsi, &data, 512);
+    assert_eq!(scsi.status(), Status::Failed);
+    assert_eq!(sense(&mut scsi), Sense::WRITE_ERROR);
+}
+
+#[test]
+fn start_stop_unit_flushes() {
+    let mut scsi = handler();
+    let data = block(5);
+    scsi.blocks_mut().write_blocks(7, &data).unwrap();
+    // eject
+    assert_eq!(scsi.command(&[0x1B, 0, 0, 0, 0x02, 0]), Phase::NoData);
+    assert_eq!(scsi.status(), Status::Passed);
+    let flash = scsi.into_inner().into_inner();
+    assert_eq!(&flash.data[7 * BLOCK_SIZE..8 * BLOCK_SIZE], &data[..]);
+}
+
+#[test]
+fn prevent_allow_medium_removal_flushes() {
+    let mut scsi = handler();
+    let data = block(9);
+    scsi.blocks_mut().write_blocks(20, &data).unwrap();
+    // allow removal
+    assert_eq!(scsi.command(&[0x1E, 0, 0, 0, 0, 0]), Phase::NoData);
+    assert_eq!(scsi.status(), Status::Passed);
+    let flash = scsi.into_inner().into_inner();
+    assert_eq!(&flash.data[20 * BLOCK_SIZE..21 * BLOCK_SIZE], &data[..]);
+}
@@ -0,0 +1,117 @@
+#![cfg(all(feature = "std", feature = "embedded-sdmmc"))]
+
+use embedded_sdmmc::{
+    Block, BlockDevice, BlockIdx, Mode, TimeSource, Timestamp, VolumeIdx, VolumeManager,
+};
+use w25q::{
+    sdmmc::SdmmcBlocks,
+    sim::{NoDelay, SimFlash},
+    Chip, W25Q,
+};
+
+type Blocks = SdmmcBlocks<W25Q<SimFlash, NoDelay>>;
+
+const START: u32 = 64;
+const RESERVED: u32 = 1;
+const FAT_SIZE: u32 = 64;
+const ROOT_BLOCKS: u32 = 32;
+
+struct Clock;
+
+impl TimeSource for Clock {
+    fn get_timestamp(&self) -> Timestamp {
+        Timestamp {
+            year_since_1970: 56,
+            zero_indexed_month: 9,
+            zero_indexed_day: 18,
+            hours: 12,
+            minutes: 0,
+            seconds: 0,
+        }
+    }
+}
+
+fn write(blocks: &Blocks, idx: u32, f: impl FnOnce(&mut [u8; 512])) {
+    let mut block = Block::new();
+    f(&mut block.contents);
+    blocks.write(&[block], BlockIdx(idx)).unwrap();
+}
+
+/// an MBR with one FAT16 partition at `START`, one block per cluster
+fn format(blocks: &Blocks) {
+    let total = blocks.num_blocks().unwrap().0 - START;
+    write(blocks, 0, |b| {
+        let entry = &mut b[446..462];
+        entry[4] = 0x06;
+        entry[8..12].copy_from_slice(&START.to_le_bytes());
+        entry[12..16].copy_from_slice(&total.to_le_bytes());
+        b[510..].copy_from_slice(&[0x55, 0xAA]);
+    });
+    write(blocks, START, |b| {
+        b[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        b[3..11].copy_from_slice(b"MSWIN4.1");
+        b[11..13].copy_from_slice(&512u16.to_le_bytes());
+        b[13] = 1;
+        b[14..16].copy_from_slice(&(RESERVED as u16).to_le_bytes());
+        b[16] = 2;
+        b[17..19].copy_from_slice(&(ROOT_BLOCKS as u16 * 16).to_le_bytes());
+        b[19..21].copy_from_slice(&(total as u16).to_le_bytes());
+        b[21] = 0xF8;
+        b[22..24].copy_from_slice(&(FAT_SIZE as u16).to_le_bytes());
+        b[28..32].copy_from_slice(&START.to_le_bytes());
+        b[36] = 0x80;
+        b[38] = 0x29;
+        b[43..54].copy_from_slice(b"W25Q       ");
+        b[54..62].copy_from_slice(b"FAT16   ");
+        b[510..].copy_from_slice(&[0x55, 0xAA]);
+    });
+    let fats = START + RESERVED;
+    for fat in [fats, fats + FAT_SIZE] {
+        write(blocks, fat, |b| {
+            b[..4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF])
+        });
+        for idx in fat + 1..fat + FAT_SIZE {
+            write(blocks, idx, |_| {});
+        }
+    }
+    for idx in fats + 2 * FAT_SIZE..fats + 2 * FAT_SIZE + ROOT_BLOCKS {
+        write(blocks, idx, |_| {});
+    }
+}
+
+#[test]
+fn fat_volume_survives_a_remount() {
+    let dev = SimFlash::new(Chip::W25Q64).into_device().unwrap();
+    let blocks = SdmmcBlocks::new(dev).unwrap();
+    format(&blocks);
+
+    let data: Vec<u8> = (0..5000u32).map(|i| (i * 13 + i / 509) as u8).collect();
+    let mut volumes = VolumeManager::new(blocks, Clock);
+    {
+        let mut volume = volumes.open_volume(VolumeIdx(0)).unwrap();
+        let mut root = volume.open_root_dir().unwrap();
+        let mut file = root
+            .open_file_in_dir("DATA.BIN", Mode::ReadWriteCreateOrTruncate)
+            .unwrap();
+        file.write(&data).unwrap();
+        file.close().unwrap();
+        root.make_dir_in_dir("LOGS").unwrap();
+    }
+    let (blocks, _) = volumes.free();
+
+    // back on the raw flash, with nothing left in the cache
+    let dev = blocks.into_inner().unwrap();
+    let mut volumes = VolumeManager::new(SdmmcBlocks::new(dev).unwrap(), Clock);
+    let mut volume = volumes.open_volume(VolumeIdx(0)).unwrap();
+    let mut root = volume.open_root_dir().unwrap();
+    let mut file = root.open_file_in_dir("DATA.BIN", Mode::ReadOnly).unwrap();
+    assert_eq!(file.length(), data.len() as u32);
+    let mut back = vec![0; data.len() + 10];
+    let mut len = 0;
+    while !file.is_eof() {
+        len += file.read(&mut back[len..]).unwrap();
+    }
+    assert_eq!(&back[..len], &data[..]);
+    file.close().unwrap();
+    root.open_dir("LOGS").unwrap();
+}
@@ -0,0 +1,79 @@
+#![cfg(all(feature = "std", feature = "embassy-sync"))]
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+use embedded_io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use w25q::{
+    io::W25QError,
+    shared::{SharedMutex, SharedW25Q},
+    sim::{NoDelay, SimFlash},
+    Chip, SECTOR_SIZE,
+};
+
+type Shared = SharedMutex<NoopRawMutex, SimFlash, NoDelay>;
+
+fn shared() -> Shared {
+    Mutex::new(RefCell::new(
+        SimFlash::new(Chip::W25Q16).into_device().unwrap(),
+    ))
+}
+
+#[test]
+fn handles_share_the_chip() {
+    let shared = shared();
+    let mut a = SharedW25Q::new(&shared);
+    let mut b = SharedW25Q::new(&shared);
+    NorFlash::write(&mut a, 0x1000, b"from a").unwrap();
+    let mut buf = [0; 6];
+    ReadNorFlash::read(&mut b, 0x1000, &mut buf).unwrap();
+    assert_eq!(&buf, b"from a");
+    b.erase(0x1000, 0x1000 + SECTOR_SIZE as u32).unwrap();
+    a.lock(|dev| dev.read_at(0x1000, &mut buf)).unwrap();
+    assert_eq!(buf, [0xFF; 6]);
+
+    // each handle keeps its own position
+    a.seek(SeekFrom::Start(0x2000)).unwrap();
+    a.write_all(b"stream").unwrap();
+    let mut c = a.clone();
+    a.seek(SeekFrom::Current(-6)).unwrap();
+    a.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"stream");
+    assert_eq!(c.stream_position().unwrap(), 0x2006);
+    assert_eq!(b.stream_position().unwrap(), 0);
+    c.write_all(b"!").unwrap();
+    assert_eq!(Read::read(&mut a, &mut buf[..1]).unwrap(), 1);
+    assert_eq!(buf[0], b'!');
+}
+
+#[test]
+fn reads_and_seeks_stay_in_the_chip() {
+    let shared = shared();
+    let mut handle = SharedW25Q::new(&shared);
+    let capacity = handle.capacity();
+    let invalid = |result: Result<u64, W25QError>| {
+        matches!(result, Err(W25QError::Io(ErrorKind::InvalidInput)))
+    };
+
+    // a read at the end is cut short, then reports the end of the file
+    assert_eq!(handle.seek(SeekFrom::End(-4)).unwrap(), capacity - 4);
+    let mut buf = [0; 16];
+    assert_eq!(Read::read(&mut handle, &mut buf).unwrap(), 4);
+    assert_eq!(handle.stream_position().unwrap(), capacity);
+    assert_eq!(Read::read(&mut handle, &mut buf).unwrap(), 0);
+    assert!(Write::write(&mut handle, b"past the end").is_err());
+    assert_eq!(handle.stream_position().unwrap(), capacity);
+
+    assert!(invalid(handle.seek(SeekFrom::End(1))));
+    assert!(invalid(handle.seek(SeekFrom::Start(capacity + 1))));
+    assert!(invalid(handle.seek(SeekFrom::Start(u64::MAX))));
+    assert!(invalid(handle.seek(SeekFrom::End(i64::MAX))));
+    assert!(invalid(handle.seek(SeekFrom::Current(i64::MAX))));
+    assert!(invalid(
+        handle.seek(SeekFrom::Current(-(capacity as i64) - 1))
+    ));
+    assert!(invalid(handle.seek(SeekFrom::End(i64::MIN))));
+    // a failed seek keeps the position
+    assert_eq!(handle.stream_position().unwrap(), capacity);
+}
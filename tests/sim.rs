@@ -0,0 +1,60 @@
+#![cfg(feature = "std")]
+
+use embedded_hal::spi::{Operation, SpiDevice};
+use w25q::{sim::SimFlash, Chip};
+
+fn command(sim: &mut SimFlash, tx: &[u8]) {
+    sim.transaction(&mut [Operation::Write(tx)]).unwrap();
+}
+
+fn read(sim: &mut SimFlash, command: u8) -> u8 {
+    let mut value = [0];
+    sim.transaction(&mut [Operation::Write(&[command]), Operation::Read(&mut value)])
+        .unwrap();
+    value[0]
+}
+
+#[test]
+fn dies_keep_their_own_state() {
+    let mut sim = SimFlash::new(Chip::W25M512);
+
+    // write enable, 4-byte mode and SR1 on die 0 only
+    command(&mut sim, &[0x06]);
+    command(&mut sim, &[0xB7]);
+    command(&mut sim, &[0x50]);
+    command(&mut sim, &[0x01, 0x1C]);
+    command(&mut sim, &[0x06]);
+    assert_eq!(read(&mut sim, 0x05), 0x1E);
+    assert_eq!(read(&mut sim, 0x15) & 0x01, 0x01);
+
+    command(&mut sim, &[0xC2, 1]);
+    assert_eq!(read(&mut sim, 0x05), 0x00);
+    assert_eq!(read(&mut sim, 0x15) & 0x01, 0x00);
+    // a program on die 1 without its own write enable is ignored
+    command(&mut sim, &[0x02, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(sim.data()[32 << 20], 0xFF);
+
+    // die 1 takes a 3-byte address
+    command(&mut sim, &[0x06]);
+    command(&mut sim, &[0x02, 0x00, 0x00, 0x10, 0x5A]);
+    assert_eq!(sim.data()[(32 << 20) + 0x10], 0x5A);
+
+    command(&mut sim, &[0xC2, 0]);
+    assert_eq!(read(&mut sim, 0x05), 0x1E);
+    command(&mut sim, &[0x02, 0x00, 0x00, 0x00, 0x20, 0xA5]);
+    assert_eq!(sim.data()[0x20], 0xA5);
+}
+
+#[test]
+fn power_down_is_per_die() {
+    let mut sim = SimFlash::new(Chip::W25M512);
+    command(&mut sim, &[0xB9]);
+    assert_eq!(read(&mut sim, 0x9F), 0xFF);
+
+    command(&mut sim, &[0xC2, 1]);
+    assert_eq!(read(&mut sim, 0x9F), 0xEF);
+
+    command(&mut sim, &[0xC2, 0]);
+    command(&mut sim, &[0xAB]);
+    assert_eq!(read(&mut sim, 0x9F), 0xEF);
+}
@@ -0,0 +1,203 @@
+mod common;
+
+use common::MemFlash;
+use w25q::{
+    checksum::Crc32,
+    io::W25QError,
+    slots::{BootState, ImageHeader, Slot, SlotLayout, SlotManager, SlotState},
+    SECTOR_SIZE,
+};
+
+const SLOT_SIZE: u32 = 4 * SECTOR_SIZE as u32;
+const SIZE: usize = 2 * SECTOR_SIZE + 2 * SLOT_SIZE as usize;
+const ATTEMPTS: u8 = 3;
+
+fn layout() -> SlotLayout {
+    SlotLayout {
+        state: 0,
+        slot_a: 2 * SECTOR_SIZE as u32,
+        slot_b: 2 * SECTOR_SIZE as u32 + SLOT_SIZE,
+        slot_size: SLOT_SIZE,
+    }
+}
+
+fn image(version: u32) -> Vec<u8> {
+    let payload: Vec<u8> = (0..5000 + version % 7 * 100)
+        .map(|i| (i * 3 + version) as u8)
+        .collect();
+    let header = ImageHeader {
+        version,
+        length: payload.len() as u32,
+        crc32: Crc32::checksum(&payload),
+        sha256: None,
+    };
+    let mut image = header.to_bytes().to_vec();
+    image.extend_from_slice(&payload);
+    image
+}
+
+fn manager(flash: MemFlash) -> SlotManager<MemFlash> {
+    SlotManager::new(flash, layout(), ATTEMPTS).unwrap()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    Update(u32),
+    Boot,
+    Confirm,
+}
+
+fn run(m: &mut SlotManager<MemFlash>, step: Step) -> Result<(), W25QError> {
+    match step {
+        Step::Update(version) => {
+            let mut writer = m.begin_update()?;
+            for chunk in image(version).chunks(700) {
+                writer.write(chunk)?;
+            }
+            writer.finish().map(|_| ())
+        }
+        Step::Boot => m.boot().map(|_| ()),
+        Step::Confirm => m.confirm(),
+    }
+}
+
+/// every slot that may be booted holds an intact image
+fn check_images(m: &mut SlotManager<MemFlash>) {
+    let state = m.state();
+    for (slot, slot_state) in [Slot::A, Slot::B].into_iter().zip(state.slots) {
+        if matches!(slot_state, SlotState::Pending | SlotState::Confirmed) {
+            m.validate(slot).unwrap();
+        }
+    }
+}
+
+/// flash with a confirmed factory image in slot A, updated `cycles` times
+fn installed(cycles: u32) -> MemFlash {
+    let mut flash = MemFlash::new(SIZE);
+    let factory = image(0);
+    let a = layout().slot_a as usize;
+    flash.data[a..a + factory.len()].copy_from_slice(&factory);
+    let mut m = manager(flash);
+    m.confirm().unwrap();
+    for version in 1..=cycles {
+        for step in [Step::Update(version), Step::Boot, Step::Confirm] {
+            run(&mut m, step).unwrap();
+        }
+    }
+    m.into_inner()
+}
+
+#[test]
+fn update_needs_a_confirmed_image() {
+    let mut m = manager(MemFlash::new(SIZE));
+    assert!(matches!(m.begin_update(), Err(W25QError::InvalidConfig)));
+    assert_eq!(m.state(), BootState::default());
+
+    let mut m = manager(installed(0));
+    run(&mut m, Step::Update(1)).unwrap();
+    let pending = m.state();
+    assert_eq!(pending.active, Slot::B);
+    assert_eq!(pending.slots, [SlotState::Confirmed, SlotState::Pending]);
+
+    // the factory image in A is the fallback while B is on trial
+    assert!(matches!(m.begin_update(), Err(W25QError::InvalidConfig)));
+    assert_eq!(m.state(), pending);
+    assert_eq!(m.validate(Slot::A).unwrap().version, 0);
+
+    run(&mut m, Step::Boot).unwrap();
+    run(&mut m, Step::Confirm).unwrap();
+    run(&mut m, Step::Update(2)).unwrap();
+    assert_eq!(m.active(), Slot::A);
+    assert_eq!(m.validate(Slot::A).unwrap().version, 2);
+}
+
+#[test]
+fn unconfirmed_image_falls_back() {
+    let mut m = manager(installed(1));
+    run(&mut m, Step::Update(2)).unwrap();
+    for _ in 0..ATTEMPTS {
+        assert_eq!(m.boot().unwrap(), Slot::A);
+    }
+    assert_eq!(m.boot().unwrap(), Slot::B);
+    let state = m.state();
+    assert_eq!(state.slots, [SlotState::Failed, SlotState::Confirmed]);
+    assert_eq!(m.validate(Slot::B).unwrap().version, 1);
+
+    // the failed slot takes the next update
+    run(&mut m, Step::Update(3)).unwrap();
+    assert_eq!(m.active(), Slot::A);
+    assert_eq!(m.validate(Slot::A).unwrap().version, 3);
+}
+
+#[test]
+fn power_cut_keeps_a_bootable_state() {
+    // 1 + 63 * 4 records, so the boot state moves to the other sector during the steps
+    let base = installed(63);
+    let steps = [
+        Step::Update(64),
+        Step::Boot,
+        Step::Confirm,
+        Step::Update(65),
+        Step::Boot,
+        Step::Boot,
+        Step::Boot,
+        // unconfirmed after ATTEMPTS boots, back to 64
+        Step::Boot,
+        Step::Update(66),
+        Step::Boot,
+        Step::Confirm,
+    ];
+    let fresh = || {
+        let mut flash = MemFlash::new(SIZE);
+        flash.data.copy_from_slice(&base.data);
+        flash
+    };
+
+    let mut m = manager(fresh());
+    let mut states = vec![m.state()];
+    for step in steps {
+        run(&mut m, step).unwrap();
+        states.push(m.state());
+    }
+    assert_eq!(states[8].active, states[3].active);
+
+    for cut in 0.. {
+        let mut flash = fresh();
+        flash.cut_after(cut);
+        let mut m = manager(flash);
+        let done = steps
+            .iter()
+            .take_while(|step| run(&mut m, **step).is_ok())
+            .count();
+        let mut flash = m.into_inner();
+        if !flash.lost_power() {
+            assert_eq!(done, steps.len());
+            break;
+        }
+
+        flash.power_cycle();
+        let mut m = manager(flash);
+        let state = m.state();
+        // an update first marks its slot empty
+        let mut started = states[done];
+        if let Step::Update(_) = steps[done] {
+            started.slots[started.active.other() as usize] = SlotState::Empty;
+        }
+        let next = if state == states[done + 1] {
+            done + 1
+        } else {
+            assert!(
+                state == states[done] || state == started,
+                "cut at {cut}: {state:?}"
+            );
+            done
+        };
+        check_images(&mut m);
+
+        for step in &steps[next..] {
+            run(&mut m, *step).unwrap();
+        }
+        assert_eq!(m.state(), states[steps.len()], "cut at {cut}");
+        check_images(&mut m);
+    }
+}
@@ -0,0 +1,73 @@
+#![cfg(feature = "std")]
+
+use w25q::{
+    io::W25QError,
+    sim::{NoDelay, SimFlash},
+    sync::SyncStats,
+    Chip, SECTOR_SIZE, W25Q,
+};
+
+const BASE: u32 = 4 * SECTOR_SIZE as u32;
+
+fn image() -> Vec<u8> {
+    (0..5 * SECTOR_SIZE as u32 + 100)
+        .map(|i| (i * 7 + i / 4099) as u8)
+        .collect()
+}
+
+fn sync(dev: &mut W25Q<SimFlash, NoDelay>, image: &[u8]) -> SyncStats {
+    let stats = dev.sync_image(BASE, &mut &image[..]).unwrap();
+    assert_eq!(stats.bytes, image.len() as u32);
+    assert_eq!(&dev.periph.data()[BASE as usize..][..image.len()], image);
+    stats
+}
+
+#[test]
+fn only_what_differs_is_written() {
+    let mut dev = SimFlash::new(Chip::W25Q64).into_device().unwrap();
+    let mut image = image();
+    let end = BASE + image.len() as u32;
+    dev.program(end, b"kept after the image").unwrap();
+
+    // a blank chip only needs bits cleared
+    let stats = sync(&mut dev, &image);
+    assert_eq!((stats.skipped, stats.programmed, stats.erased), (0, 6, 0));
+    let stats = sync(&mut dev, &image);
+    assert_eq!((stats.skipped, stats.programmed, stats.erased), (6, 0, 0));
+
+    // clearing bits in sector 1, setting bits in sector 3 and the last sector
+    let clear = SECTOR_SIZE + 10;
+    let set = 3 * SECTOR_SIZE + 700;
+    let last = image.len() - 1;
+    assert_ne!(image[clear], 0);
+    image[clear] = 0;
+    for at in [set, last] {
+        assert_ne!(image[at], 0xFF);
+        image[at] = !image[at];
+    }
+    let stats = sync(&mut dev, &image);
+    assert_eq!((stats.skipped, stats.programmed, stats.erased), (3, 1, 2));
+    assert_eq!(
+        &dev.periph.data()[end as usize..][..20],
+        b"kept after the image"
+    );
+}
+
+#[test]
+fn bad_targets() {
+    let mut dev = SimFlash::new(Chip::W25Q64).into_device().unwrap();
+    let image = image();
+    assert!(matches!(
+        dev.sync_image(BASE + 1, &mut &image[..]),
+        Err(W25QError::NotAligned)
+    ));
+    let last = Chip::W25Q64.capacity() - SECTOR_SIZE as u32;
+    assert!(matches!(
+        dev.sync_image(last, &mut &image[..]),
+        Err(W25QError::OutOfBounds)
+    ));
+    assert_eq!(
+        dev.sync_image(BASE, &mut &[][..]).unwrap(),
+        SyncStats::default()
+    );
+}
@@ -0,0 +1,183 @@
+#![cfg(feature = "std")]
+
+use embedded_storage::nor_flash::NorFlash;
+use w25q::{
+    io::W25QError,
+    sim::{NoDelay, SimNand},
+    w25n::{
+        EccStatus, ManagedW25N, NAND_BLOCK_COUNT, NAND_BLOCK_SIZE, NAND_PAGES_PER_BLOCK,
+        NAND_PAGE_SIZE, W25N,
+    },
+};
+
+const RESERVED: u32 = 20;
+/// first replacement block
+const SPARE: u32 = NAND_BLOCK_COUNT - RESERVED;
+const BLOCK: u32 = NAND_BLOCK_SIZE as u32;
+const PAGE: u32 = NAND_PAGE_SIZE as u32;
+
+fn data(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(29).wrapping_add(seed) ^ (i >> 11) as u8)
+        .collect()
+}
+
+fn managed(sim: SimNand) -> ManagedW25N<SimNand, NoDelay> {
+    ManagedW25N::new(sim.into_device().unwrap(), RESERVED).unwrap()
+}
+
+/// data area of physical page `page`
+fn page(nand: &mut ManagedW25N<SimNand, NoDelay>, page: u32) -> Vec<u8> {
+    nand.nand_mut().periph.page(page)[..NAND_PAGE_SIZE].to_vec()
+}
+
+#[test]
+fn program_and_read_across_blocks() {
+    let mut nand = managed(SimNand::new());
+    assert_eq!(nand.capacity(), SPARE as u64 * BLOCK as u64);
+
+    // three pages from the end of block 3 into block 4
+    let at = 4 * BLOCK - 2 * PAGE;
+    let image = data(3 * NAND_PAGE_SIZE, 1);
+    nand.program(at, &image).unwrap();
+    assert_eq!(
+        page(&mut nand, 4 * NAND_PAGES_PER_BLOCK - 1),
+        &image[PAGE as usize..][..NAND_PAGE_SIZE]
+    );
+    assert_eq!(
+        page(&mut nand, 4 * NAND_PAGES_PER_BLOCK),
+        &image[2 * PAGE as usize..]
+    );
+
+    // unaligned reads span pages and blocks
+    let mut back = vec![0; image.len() - 300];
+    nand.read_at(at + 100, &mut back).unwrap();
+    assert_eq!(back, image[100..image.len() - 200]);
+
+    nand.erase(3 * BLOCK, 4 * BLOCK).unwrap();
+    nand.read_at(at, &mut back).unwrap();
+    assert!(back[..2 * PAGE as usize].iter().all(|b| *b == 0xFF));
+    assert_eq!(
+        back[2 * PAGE as usize..],
+        image[2 * PAGE as usize..image.len() - 300]
+    );
+
+    assert!(matches!(
+        nand.program(at + 1, &image[..NAND_PAGE_SIZE]),
+        Err(W25QError::NotAligned)
+    ));
+    assert!(matches!(
+        nand.program(at, &image[..100]),
+        Err(W25QError::NotAligned)
+    ));
+    assert!(matches!(
+        nand.erase(BLOCK, BLOCK + PAGE),
+        Err(W25QError::NotAligned)
+    ));
+    assert!(matches!(
+        nand.read_at(SPARE * BLOCK - 1, &mut [0; 2]),
+        Err(W25QError::OutOfBounds)
+    ));
+}
+
+#[test]
+fn bad_blocks_are_remapped_through_the_lut() {
+    let mut sim = SimNand::new();
+    sim.mark_bad(5);
+    // the first replacement is bad too
+    sim.mark_bad(SPARE);
+    let mut nand = managed(sim);
+    let lut = nand.nand_mut().read_bbm_lut().unwrap();
+    assert!(lut[0].enabled);
+    assert_eq!((lut[0].logical, lut[0].physical), (5, SPARE as u16 + 1));
+    assert!(!lut[1].enabled);
+
+    // block 5 is now served by the replacement
+    let image = data(NAND_PAGE_SIZE, 2);
+    nand.program(5 * BLOCK, &image).unwrap();
+    assert_eq!(page(&mut nand, (SPARE + 1) * NAND_PAGES_PER_BLOCK), image);
+    let mut back = vec![0; NAND_PAGE_SIZE];
+    nand.read_at(5 * BLOCK, &mut back).unwrap();
+    assert_eq!(back, image);
+
+    // the mapping lives in the chip, opening again adds nothing
+    let mut nand = managed(nand.into_inner().periph);
+    let lut = nand.nand_mut().read_bbm_lut().unwrap();
+    assert_eq!(lut.iter().filter(|entry| entry.enabled).count(), 1);
+
+    // a failed program retires the block, the retry lands on the next replacement
+    nand.nand_mut().periph.fail_block(7);
+    assert!(matches!(
+        nand.program(7 * BLOCK, &image),
+        Err(W25QError::ProgramFailed)
+    ));
+    let lut = nand.nand_mut().read_bbm_lut().unwrap();
+    assert_eq!((lut[1].logical, lut[1].physical), (7, SPARE as u16 + 2));
+    nand.program(7 * BLOCK, &image).unwrap();
+    assert_eq!(page(&mut nand, (SPARE + 2) * NAND_PAGES_PER_BLOCK), image);
+
+    // a failed erase is remapped and erased again
+    nand.nand_mut().periph.fail_block(9);
+    nand.erase(8 * BLOCK, 10 * BLOCK).unwrap();
+    let lut = nand.nand_mut().read_bbm_lut().unwrap();
+    assert_eq!((lut[2].logical, lut[2].physical), (9, SPARE as u16 + 3));
+}
+
+#[test]
+fn full_lut_runs_out_of_space() {
+    let mut nand = SimNand::new().into_device().unwrap();
+    for block in 0..RESERVED as u16 {
+        nand.swap_blocks(block, SPARE as u16 + block).unwrap();
+    }
+    assert!(matches!(nand.swap_blocks(30, 31), Err(W25QError::NoSpace)));
+}
+
+#[test]
+fn ecc_status_is_decoded() {
+    assert_eq!(EccStatus::from(0b0000_0000), EccStatus::Clean);
+    assert_eq!(EccStatus::from(0b0001_0000), EccStatus::Corrected);
+    assert_eq!(EccStatus::from(0b0010_0000), EccStatus::Uncorrectable);
+    assert_eq!(
+        EccStatus::from(0b0011_0001),
+        EccStatus::UncorrectableMultiple
+    );
+
+    let mut sim = SimNand::new();
+    sim.set_ecc(10, EccStatus::Corrected);
+    sim.set_ecc(11, EccStatus::Uncorrectable);
+    sim.set_ecc(12, EccStatus::UncorrectableMultiple);
+    let mut nand = sim.into_device().unwrap();
+    let mut buf = [0; 4];
+    assert_eq!(nand.read_page(9, 0, &mut buf).unwrap(), EccStatus::Clean);
+    assert_eq!(
+        nand.read_page(10, 0, &mut buf).unwrap(),
+        EccStatus::Corrected
+    );
+    assert!(matches!(
+        nand.read_page(11, 0, &mut buf),
+        Err(W25QError::EccUncorrectable { page: 11 })
+    ));
+    assert_eq!(nand.last_ecc_failure_page().unwrap(), 11);
+    assert!(matches!(
+        nand.read_page(12, 0, &mut buf),
+        Err(W25QError::EccUncorrectable { page: 12 })
+    ));
+
+    // the managed view reports the page it failed on
+    let mut nand = ManagedW25N::new(nand, RESERVED).unwrap();
+    let mut back = vec![0; 3 * NAND_PAGE_SIZE];
+    assert!(matches!(
+        nand.read_at(9 * PAGE, &mut back),
+        Err(W25QError::EccUncorrectable { page: 11 })
+    ));
+}
+
+#[test]
+fn zero_poll_interval_times_out() {
+    let mut nand: W25N<SimNand, NoDelay> = SimNand::new().into_device().unwrap();
+    nand.set_poll_interval_us(0);
+    nand.periph.set_busy(true);
+    assert!(matches!(nand.block_erase(0), Err(W25QError::Timeout)));
+    nand.periph.set_busy(false);
+    nand.block_erase(0).unwrap();
+}
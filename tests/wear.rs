@@ -0,0 +1,130 @@
+mod common;
+
+use common::MemFlash;
+use w25q::{wear::WearLeveler, SECTOR_SIZE};
+
+const SECTORS: usize = 6;
+const LOGICAL: usize = 4;
+
+type Leveler = WearLeveler<MemFlash, SECTORS>;
+
+/// the `i`th write of a workload that keeps sector 0 cold: the sector, the offset in it and
+/// the bytes
+fn step(i: usize) -> (usize, usize, Vec<u8>) {
+    let sector = 1 + i % (LOGICAL - 1);
+    let offset = i * 97 % (SECTOR_SIZE - 300);
+    (sector, offset, vec![i as u8; 1 + i * 31 % 300])
+}
+
+fn formatted() -> Leveler {
+    let flash = MemFlash::new(Leveler::REGION_SIZE as usize);
+    let mut wl = Leveler::format(flash, 0, LOGICAL).unwrap();
+    wl.write_at(0, b"cold data").unwrap();
+    wl
+}
+
+fn mount(flash: MemFlash) -> Leveler {
+    let mut wl = Leveler::mount(flash, 0, LOGICAL).unwrap();
+    // low enough that the cold sector moves during the test
+    wl.set_static_threshold(5);
+    wl
+}
+
+fn contents(wl: &mut Leveler) -> Vec<u8> {
+    let mut data = vec![0; LOGICAL * SECTOR_SIZE];
+    wl.read_at(0, &mut data).unwrap();
+    data
+}
+
+fn write(model: &mut [u8], (sector, offset, data): &(usize, usize, Vec<u8>)) {
+    let at = sector * SECTOR_SIZE + offset;
+    model[at..at + data.len()].copy_from_slice(data);
+}
+
+#[test]
+fn mapping_and_counts_survive_a_remount() {
+    let mut wl = formatted();
+    wl.set_static_threshold(5);
+    let mut model = contents(&mut wl);
+    // enough writes to fill the journal several times
+    for i in 0..800 {
+        let step = step(i);
+        wl.write_at((step.0 * SECTOR_SIZE + step.1) as u32, &step.2)
+            .unwrap();
+        write(&mut model, &step);
+    }
+    let counts = *wl.erase_counts();
+    assert!(counts.iter().all(|count| *count > 100), "{counts:?}");
+
+    let mut wl = mount(wl.into_inner());
+    assert_eq!(contents(&mut wl), model);
+    assert_eq!(*wl.erase_counts(), counts);
+}
+
+#[test]
+fn power_cut_keeps_the_old_or_the_new_sector() {
+    let ops = {
+        let flash = formatted().into_inner();
+        let start = flash.ops;
+        let mut wl = mount(flash);
+        for i in 0..300 {
+            let step = step(i);
+            wl.write_at((step.0 * SECTOR_SIZE + step.1) as u32, &step.2)
+                .unwrap();
+        }
+        wl.into_inner().ops - start
+    };
+
+    for cut in (0..ops).step_by(7) {
+        let mut flash = formatted().into_inner();
+        flash.cut_after(cut);
+        let mut wl = mount(flash);
+        let mut model = contents(&mut wl);
+        let mut inflight = None;
+        for i in 0..300 {
+            let step = step(i);
+            if wl
+                .write_at((step.0 * SECTOR_SIZE + step.1) as u32, &step.2)
+                .is_err()
+            {
+                inflight = Some(step);
+                break;
+            }
+            write(&mut model, &step);
+        }
+        let counts = *wl.erase_counts();
+
+        let mut flash = wl.into_inner();
+        assert_eq!(flash.lost_power(), inflight.is_some());
+        flash.power_cycle();
+        let mut wl = mount(flash);
+        let found = contents(&mut wl);
+        if let Some(step) = &inflight {
+            if found != model {
+                write(&mut model, step);
+            }
+        }
+        assert_eq!(found, model, "cut at {cut}");
+        // at most the erase the cut hit, or the one not committed yet, is not counted
+        let lost: u32 = counts
+            .iter()
+            .zip(wl.erase_counts())
+            .map(|(before, after)| before - after)
+            .sum();
+        assert!(
+            lost <= 1,
+            "cut at {cut}: {counts:?} {:?}",
+            wl.erase_counts()
+        );
+
+        // it keeps working after the cut
+        for i in 300..330 {
+            let step = step(i);
+            wl.write_at((step.0 * SECTOR_SIZE + step.1) as u32, &step.2)
+                .unwrap();
+            write(&mut model, &step);
+        }
+        let mut wl = mount(wl.into_inner());
+        assert_eq!(contents(&mut wl), model, "cut at {cut}");
+    }
+}